@@ -1,4 +1,4 @@
-import { Diagnostic, RawFileContents } from "@types";
+import { ContentChange, Diagnostic, RawFileContents } from "@types";
 import * as monaco from "monaco-editor/esm/vs/editor/editor.api";
 import { createSignal } from "solid-js";
 import { setState, state } from ".";
@@ -37,11 +37,34 @@ export const setEditorFile = async (fileId: number) => {
     }
 };
 
+const byteLength = (text: string) => new TextEncoder().encode(text).length;
+
+// Monaco counts in UTF-16 code units, the compiler in bytes
+const toContentChange = (previous: string, change: monaco.editor.IModelContentChange): ContentChange => {
+    const start = byteLength(previous.slice(0, change.rangeOffset));
+    const end = start + byteLength(previous.slice(change.rangeOffset, change.rangeOffset + change.rangeLength));
+    return { start, end, text: change.text };
+};
+
+// Edits which are still being compiled and saved, for each model
+const unsavedEdits = new WeakMap<monaco.editor.ITextModel, number>();
+
+export const hasUnsavedEdits = (model: monaco.editor.ITextModel) => (unsavedEdits.get(model) || 0) > 0;
+
 export const createModel = (fileId: number, contents: RawFileContents) => {
     const model = monaco.editor.createModel(contents.textContent || "", "markdown");
-    model.onDidChangeContent(async () => {
-        const newDia = await recompileFile(fileId, model.getValue());
-        setModelDiagnostics(model, newDia);
+    let previous = model.getValue();
+    let compiling = Promise.resolve();
+    model.onDidChangeContent((event) => {
+        const content = model.getValue();
+        const change = event.changes.length === 1 ? toContentChange(previous, event.changes[0]) : undefined;
+        previous = content;
+        unsavedEdits.set(model, (unsavedEdits.get(model) || 0) + 1);
+        // A change only applies to the content compiled before it, so files are compiled in order
+        compiling = compiling.then(async () => {
+            const newDia = await recompileFile(fileId, content, change);
+            setModelDiagnostics(model, newDia);
+        }).catch(console.error).finally(() => unsavedEdits.set(model, (unsavedEdits.get(model) || 1) - 1));
     });
     setModelDiagnostics(model, contents.diagnostics);
     return model;
@@ -1,8 +1,9 @@
 /* eslint-disable @typescript-eslint/no-non-null-assertion */
 import { invoke } from "@tauri-apps/api";
-import { File, BlobType, Diagnostic, RawFileContents } from "@types";
+import { listen, UnlistenFn } from "@tauri-apps/api/event";
+import { File, BlobType, ContentChange, Diagnostic, RawFileContents, ProjectChange } from "@types";
 import { state, setState } from ".";
-import { saveFileModelState, setEditorFile } from "./editor";
+import { createModel, hasUnsavedEdits, saveFileModelState, setEditorFile, setModelDiagnostics } from "./editor";
 import { createPanel, removePanel, setActivePanel } from "./panel";
 
 export const setCurrentFile = async (fileId?: number) => {
@@ -63,10 +64,12 @@ const deleteBlobsRecursive = (file: File, blobs: Record<number, File>) => {
 };
 
 export const refreshBlobs = async () => {
-    const refreshed = JSON.parse(await invoke<string>("refresh_blobs")) as {
+    const { warnings, ...refreshed } = JSON.parse(await invoke<string>("refresh_blobs")) as {
         blobs: Record<string, File>,
         global: number[],
+        warnings: Diagnostic[]
     };
+    for (const warning of warnings) console.warn(warning.message);
     for (const blob in state.fileExplorer.blobs) {
         refreshed.blobs[blob].isOpen = state.fileExplorer.blobs[blob].isOpen;
     }
@@ -87,9 +90,72 @@ export const openDirectoryRecursive = (dir: number) => {
     if (dirObj.parent) openDirectoryRecursive(dirObj.parent);
 };
 
-export const recompileFile = async (fileId: number, content: string) : Promise<Diagnostic[]|undefined> => {
-    const res = await JSON.parse(await invoke("recompile_file", {fileId, content})) as RawFileContents;
+export const recompileFile = async (fileId: number, content: string, change?: ContentChange) : Promise<Diagnostic[]|undefined> => {
+    const res = await JSON.parse(await invoke("recompile_file", {fileId, content, change: change || null})) as RawFileContents;
     setState("contents", fileId, "diagnostics", res.diagnostics.length ? res.diagnostics : undefined);
     setState("contents", fileId, "compiledContent", res.compiledContent);
     return res.diagnostics;
-};
\ No newline at end of file
+};
+
+const detachBlob = (file: File) => {
+    if (file.parent) setState("fileExplorer", "blobs", file.parent, "children", (children) => children!.filter(f => f !== file.id));
+    else setState("fileExplorer", "global", (g) => g.filter(f => f !== file.id));
+};
+
+const attachBlob = (file: File) => {
+    if (file.parent) setState("fileExplorer", "blobs", file.parent, "children", (children) => children!.includes(file.id) ? children! : [...children!, file.id]);
+    else setState("fileExplorer", "global", (g) => g.includes(file.id) ? g : [...g, file.id]);
+};
+
+const applyProjectChange = (change: ProjectChange) => {
+    switch (change.kind) {
+    case "created":
+        setState("fileExplorer", "blobs", change.blob.id, change.blob);
+        attachBlob(change.blob);
+        break;
+    case "moved": {
+        const previous = state.fileExplorer.blobs[change.blob.id];
+        if (previous) detachBlob(previous);
+        setState("fileExplorer", "blobs", change.blob.id, (f) => ({...f, ...change.blob}));
+        attachBlob(change.blob);
+        const filePanel = state.openPanels.findIndex(p => p.fileId === change.blob.id);
+        if (filePanel !== -1) setState("openPanels", filePanel, "name", change.blob.name);
+        break;
+    }
+    case "deleted": {
+        const file = state.fileExplorer.blobs[change.id];
+        if (!file) break;
+        const newBlobs = {...state.fileExplorer.blobs};
+        deleteBlobsRecursive(file, newBlobs);
+        setState("fileExplorer", "blobs", newBlobs);
+        detachBlob(file);
+        break;
+    }
+    case "compiled": {
+        const { id, textContent, compiledContent, diagnostics } = change.contents;
+        const model = state.contents[id]?.model;
+        if (!model) setState("contents", id, { model: createModel(id, change.contents) });
+        else {
+            // The edits which aren't saved yet win, they're saved over the change once they're compiled
+            if (model.getValue() !== textContent && !hasUnsavedEdits(model)) model.setValue(textContent || "");
+            setModelDiagnostics(model, diagnostics);
+        }
+        setState("contents", id, "compiledContent", compiledContent);
+        setState("contents", id, "diagnostics", diagnostics.length ? diagnostics : undefined);
+        break;
+    }
+    case "failed":
+        for (const diagnostic of change.diagnostics) console.error(diagnostic.message);
+        break;
+    }
+};
+
+let stopListening: UnlistenFn | undefined;
+
+// Files which are changed by other programs (another editor, git...) get recompiled by the back-end
+export const listenToProjectChanges = async () => {
+    stopListening?.();
+    stopListening = await listen<string>("project_changed", (event) => {
+        for (const change of JSON.parse(event.payload) as ProjectChange[]) applyProjectChange(change);
+    });
+};
@@ -1,9 +1,10 @@
 import { invoke } from "@tauri-apps/api";
 import { appWindow } from "@tauri-apps/api/window";
-import { Panel, Project, FileContents, FileDiagnostic, Pages, File, RawFileContents, ASTBlock } from "@types";
+import { Panel, Project, FileContents, FileDiagnostic, Pages, File, RawFileContents, ASTBlock, Diagnostic } from "@types";
 import { JSXElement } from "solid-js";
 import { createStore } from "solid-js/store";
 import { createModel } from "./editor";
+import { listenToProjectChanges } from "./file";
 
 export const [state, setState] = createStore<{
     projects: Project[],
@@ -56,13 +57,16 @@ export const initCompiler = async (projectId: string) : Promise<number|undefined
             global: number[],
         },
         contents: RawFileContents[],
+        warnings: Diagnostic[],
         openFolders: number[],
         pinnedPanels: number[],
         openPanels: number[],
         lastOpen?: number
     };
+    for (const warning of result.warnings) console.warn(warning.message);
     for (const openFolder of result.openFolders) result.fileExplorer.blobs[openFolder].isOpen = true;
     setState("fileExplorer", result.fileExplorer);
+    listenToProjectChanges();
 
     const contents: Record<number, FileContents> = {};
     for (const content of result.contents) {
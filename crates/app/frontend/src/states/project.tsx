@@ -16,13 +16,13 @@ export const createProject = async (name: string, description: string): Promise<
 };
 
 export const editProject = async (id: string, name: string, description?: string) => {
+    await invoke("edit_project", {id, name, description});
     setState("projects", (p) => p.metadata.id === id, "metadata", {name, description: description || ""});
-    invoke("edit_project", {id, name, description});
 };
 
 export const deleteProject = async (id: string) => {
-    setState("projects", (p) => p.filter(p => p.metadata.id !== id));
     await invoke<string>("delete_project", {id});
+    setState("projects", (p) => p.filter(p => p.metadata.id !== id));
 };
 
 export const openProject = (project: Project) => {
@@ -35,6 +35,13 @@ export interface File {
     isCreating?: BlobType
 }
 
+/**
+ * An edit of a file, with the range in bytes of the content before the edit
+ */
+export interface ContentChange extends Range {
+    text: string
+}
+
 export interface RawFileContents {
     /**
      * Only present when provided by INIT_COMPILER command
@@ -45,6 +52,15 @@ export interface RawFileContents {
     compiledContent?: ASTHeader
 }
 
+/**
+ * Sent in `project_changed` events, when the project is changed by another program
+ */
+export type ProjectChange =
+    { kind: "created" | "moved", blob: File } |
+    { kind: "deleted", id: number } |
+    { kind: "compiled", contents: RawFileContents } |
+    { kind: "failed", diagnostics: Diagnostic[] };
+
 export interface FileContents {
     model?: monaco.editor.ITextModel,
     diagnostics?: Diagnostic[],
@@ -75,15 +91,22 @@ export interface ASTAttriute extends Node<false> {
     parameters: string[]
 }
 
+export interface ASTComment extends Node<false> {
+    text: string
+}
+
 export interface Node<Attributes extends boolean = true> {
     range: Range,
-    attributes: Attributes extends true ? ASTAttriute[] : undefined
+    attributes: Attributes extends true ? ASTAttriute[] : undefined,
+    comments: Attributes extends true ? ASTComment[] : undefined
 }
 
 export interface ASTHeader extends Node {
     title: string,
     canonicalTitle: string,
     childPaths: ASTHeader[],
+    // Comments at the end of the file, after the last child
+    trailingComments: ASTComment[],
     children: ASTBlock[],
 }
 
@@ -101,7 +124,11 @@ export const enum ASTBlockKind {
     CodeBlock,
     ChoiceGroup,
     Divert,
-    Match
+    Match,
+    Error,
+    Include,
+    End,
+    Back
 }
 
 export interface ASTInlineText extends Node<false> {
@@ -132,6 +159,9 @@ export interface ASTCodeBlock extends Node {
 }
 
 export interface ASTChoice extends Node {
+    // Stable id of the choice, so a runtime can remember which choices were picked. Match arms don't have one
+    id?: string,
+    once?: boolean,
     text: ASTText,
     children: ASTBlock[],
     condition?: {
@@ -142,12 +172,33 @@ export interface ASTChoice extends Node {
 
 export interface ASTChoiceGroup extends Node {
     kind: ASTBlockKind.ChoiceGroup,
+    exhaust: boolean,
     choices: ASTChoice[]
 }
 
 export interface ASTDivert extends Node {
     kind: ASTBlockKind.Divert,
-    path: string[]
+    path: string[],
+    absolute: boolean,
+    // The full path, resolved by the compiler. Null when it isn't a path (like a label)
+    target: string[] | null
+}
+
+export interface ASTInclude extends Node {
+    kind: ASTBlockKind.Include,
+    path: string[],
+    absolute: boolean,
+    // The full path, resolved by the compiler. Null when it isn't a path (like a label)
+    target: string[] | null
+}
+
+// `-> END` and `-> BACK`
+export interface ASTEnd extends Node {
+    kind: ASTBlockKind.End
+}
+
+export interface ASTBack extends Node {
+    kind: ASTBlockKind.Back
 }
 
 export interface ASTMatch extends Node {
@@ -158,4 +209,9 @@ export interface ASTMatch extends Node {
     children: ASTBlock[]
 }
 
-export type ASTBlock = ASTParagraph | ASTCodeBlock | ASTChoiceGroup | ASTDivert | ASTMatch;
\ No newline at end of file
+export interface ASTError extends Node {
+    kind: ASTBlockKind.Error,
+    text: string
+}
+
+export type ASTBlock = ASTParagraph | ASTCodeBlock | ASTChoiceGroup | ASTDivert | ASTMatch | ASTError | ASTInclude | ASTEnd | ASTBack;
\ No newline at end of file
@@ -1,9 +1,19 @@
-use storytell_compiler::{base::{Compiler, files::BlobId}, json_compiler::{JSONCompilerProvider, JSONCompilerContext}, json};
-use storytell_fs::{SysFileHost, FileHost};
-use tauri::State;
+use storytell_compiler::{base::{Compiler, ProjectChange, files::{BlobId, CompiledFileData}}, json_compiler::{JSONCompilerProvider, JSONCompilerContext, compile::JSONSafeCompilable}, localization::to_po, references::Reference, json};
+use storytell_diagnostics::{diagnostic::{Diagnostic, StorytellResult}, location::Range};
+use storytell_parser::ast::incremental::TextChange;
+use storytell_fs::{SysFileHost, FileHost, watcher::FileWatcher};
+use tauri::{AppHandle, Manager, State};
 use crate::{state::StorytellState, projects::Project, deserialization::JSONSerializable};
+use serde::Deserialize;
 use serde_json::to_string;
-use std::env;
+use std::{env, path::PathBuf, thread};
+
+const NO_PROJECT: &str = "No project is open.";
+
+// Commands which fail reject with the messages of the diagnostics
+fn errors(diagnostics: Vec<Diagnostic>) -> String {
+    diagnostics.into_iter().map(|diagnostic| diagnostic.msg).collect::<Vec<String>>().join("\n")
+}
 
 #[tauri::command]
 pub fn list_projects(state: State<StorytellState>) -> String {
@@ -12,78 +22,93 @@ pub fn list_projects(state: State<StorytellState>) -> String {
 }
 
 #[tauri::command]
-pub fn create_project(state: State<StorytellState>, name: String, description: String) -> String {
+pub fn create_project(state: State<StorytellState>, name: String, description: String) -> Result<String, String> {
     let mut inner_state = state.lock().unwrap();
-    to_string::<Option<&Project>>(&inner_state.projects.create_project(name, description)).unwrap()
+    let project = inner_state.projects.create_project(name, description)?;
+    to_string::<&Project>(&project).map_err(|error| error.to_string())
 }
 
 #[tauri::command]
-pub fn edit_project(state: State<StorytellState>, id: String, name: String, description: Option<String>) {
+pub fn edit_project(state: State<StorytellState>, id: String, name: String, description: Option<String>) -> Result<(), String> {
     let mut inner_state = state.lock().unwrap();
-    inner_state.projects.update_project(id, name, description.unwrap_or_default());
+    inner_state.projects.update_project(id, name, description.unwrap_or_default())
 }
 
 #[tauri::command]
-pub fn delete_project(state: State<StorytellState>, id: String) {
+pub fn delete_project(state: State<StorytellState>, id: String) -> Result<(), String> {
     let mut inner_state = state.lock().unwrap();
-    inner_state.projects.delete_project(&id);
+    inner_state.projects.delete_project(&id)
 }
 
 #[tauri::command]
-pub fn rename_blob(state: State<StorytellState>, id: u16, name: String) {
+pub fn rename_blob(app: AppHandle, state: State<StorytellState>, id: u16, name: String) -> Result<(), String> {
     let mut inner_state = state.lock().unwrap();
-    let compiler = inner_state.compiler.as_mut().unwrap();
-    compiler.host.rename_blob(&id, name);
+    let compiler = inner_state.compiler.as_mut().ok_or(NO_PROJECT)?;
+    compiler.host.rename_blob(&id, name).map_err(errors)?;
+    emit_stale_files(&app, compiler);
+    Ok(())
 }
 
 #[tauri::command]
-pub fn delete_blob(state: State<StorytellState>, id: u16) {
+pub fn delete_blob(app: AppHandle, state: State<StorytellState>, id: u16) -> Result<(), String> {
     let mut inner_state = state.lock().unwrap();
-    let compiler = inner_state.compiler.as_mut().unwrap();
-    compiler.host.delete_blob(&id);
+    let compiler = inner_state.compiler.as_mut().ok_or(NO_PROJECT)?;
+    compiler.host.delete_blob(&id).map_err(errors)?;
+    emit_stale_files(&app, compiler);
+    Ok(())
 }
 
 #[tauri::command]
-pub fn create_blob(state: State<StorytellState>, name: String, parent: Option<BlobId>, dir: bool) -> String {
+pub fn create_blob(state: State<StorytellState>, name: String, parent: Option<BlobId>, dir: bool) -> Result<String, String> {
     let mut inner_state = state.lock().unwrap();
-    let compiler = inner_state.compiler.as_mut().unwrap();
-    let file_id = compiler.host.create_blob(name, parent, dir);
-    if dir {
-        let dir = compiler.host.dirs.get(&file_id).unwrap().borrow();
-        dir.compile()
-    } else {
-        let file = compiler.host.files.get(&file_id).unwrap().borrow();
-        file.compile()
-    }
+    let compiler = inner_state.compiler.as_mut().ok_or(NO_PROJECT)?;
+    let file_id = compiler.host.create_blob(name, parent, dir).map_err(errors)?;
+    Ok(blob_json(compiler, &file_id))
 }
 
 #[tauri::command]
-pub fn refresh_blobs(state: State<StorytellState>) -> String {
+pub fn refresh_blobs(state: State<StorytellState>) -> Result<String, String> {
     let mut inner_state = state.lock().unwrap();
-    let compiler = inner_state.compiler.as_mut().unwrap();
-    let (global_files, compiled_files) = compiler.reset();
-    json!({
+    let compiler = inner_state.compiler.as_mut().ok_or(NO_PROJECT)?;
+    let (global_files, compiled_files, warnings) = compiler.reset().map_err(errors)?;
+    Ok(json!({
         blobs: format!("{{{}}}", compiler.host.dirs.iter()
             .map(|i| format!("\"{}\":{}", i.0, i.1.borrow().compile()))
             .chain(compiler.host.files.iter()
                 .map(|i| format!("\"{}\":{}", i.0, i.1.borrow().compile())))
             .collect::<Vec<String>>().join(",")),
         global: global_files.compile(),
-        contents: compiled_files.compile()
-    })
+        contents: compiled_files.compile(),
+        warnings: warnings.compile()
+    }))
+}
+
+/// An edit the editor made, with the range in bytes of the content before the edit.
+#[derive(Deserialize)]
+pub struct ContentChange {
+    start: usize,
+    end: usize,
+    text: String
 }
 
+// Failing to save the file is reported like the other diagnostics of the file
 #[tauri::command]
-pub fn recompile_file(state: State<StorytellState>, file_id: BlobId, content: String) -> String {
+pub fn recompile_file(app: AppHandle, state: State<StorytellState>, file_id: BlobId, content: String, change: Option<ContentChange>) -> Result<String, String> {
     let mut inner_state = state.lock().unwrap();
-    let compiler = inner_state.compiler.as_mut().unwrap();
-    let (compiled, diagnostics) = compiler.compile_file_with_content(file_id, &content);
-    let file = compiler.host.files.get(&file_id).unwrap().borrow();
-    compiler.host.raw.write_file(&compiler.host.build_path(&file.path, &file.name), &content).expect("Couldn't write to file.");
-    json!({
+    let compiler = inner_state.compiler.as_mut().ok_or(NO_PROJECT)?;
+    let (compiled, mut diagnostics) = match change {
+        // Only the part of the file around the edit is parsed again
+        Some(change) => compiler.compile_file_with_change(file_id, &content, &TextChange { range: Range::new(change.start, change.end), text: change.text }, compiler.host.line_endings),
+        None => compiler.compile_file_with_content(file_id, &content, compiler.host.line_endings)
+    };
+    if let Err(mut error) = compiler.host.save_file(&file_id, &content) {
+        diagnostics.append(&mut error);
+    }
+    emit_stale_files(&app, compiler);
+    Ok(json!({
         compiledContent: if let Some(compiled) = compiled { compiled } else { String::from("null") },
         diagnostics: diagnostics.compile()
-    })
+    }))
 }
 
 #[tauri::command]
@@ -92,29 +117,30 @@ pub fn save_data(state: State<StorytellState>,
     open_folders: Vec<BlobId>,
     pinned_panels: Vec<BlobId>,
     last_open: Option<BlobId>
-) {
+) -> Result<(), String> {
     let mut inner_state = state.lock().unwrap();
-    let current_project = inner_state.projects.get_open_project().unwrap();
+    let current_project = inner_state.projects.get_open_project().ok_or(NO_PROJECT)?;
     current_project.metadata.open_folders = open_folders;
     current_project.metadata.open_panels = open_panels;
     current_project.metadata.pinned_panels = pinned_panels;
     current_project.metadata.last_open = last_open;
-    current_project.save();
+    current_project.save()
 }
 
 // Returns all the files for the file manager
 // Compiles the last opened file if necessary
 #[tauri::command]
-pub fn init_compiler(state: State<StorytellState>, project_id: String) -> Option<String> {
+pub fn init_compiler(app: AppHandle, state: State<StorytellState>, project_id: String) -> Result<String, String> {
     let mut inner_state = state.lock().unwrap();
     inner_state.projects.open_project(&project_id);
-    let project = inner_state.projects.projects.get(&project_id)?;
+    let project = inner_state.projects.projects.get(&project_id).ok_or_else(|| format!("There's no project with the id '{}'.", project_id))?;
     let line_endings = match env::consts::OS {
         "windows" => 2,
         _ => 1
     };
-    let mut compiler = Compiler::<JSONCompilerProvider, SysFileHost>::new(project.files_directory.to_str().unwrap(), line_endings, SysFileHost::default(), JSONCompilerContext::new(Some("this".to_string())));
-    let (global_files, compiled_files) = compiler.init_fs();
+    let mut compiler = Compiler::<JSONCompilerProvider, SysFileHost>::new(&project.files_directory.to_string_lossy(), line_endings, SysFileHost::default(), JSONCompilerContext::new(Some("this".to_string())));
+    compiler.host.manifest_path = Some(project.directory.join("blobs.txt"));
+    let (global_files, compiled_files, warnings) = compiler.init_fs().map_err(errors)?;
     let json_str = json!({
         fileExplorer: json!({
             blobs: format!("{{{}}}", compiler.host.dirs.iter()
@@ -125,11 +151,133 @@ pub fn init_compiler(state: State<StorytellState>, project_id: String) -> Option
             global: global_files.compile()
         }),
         contents: compiled_files.compile(),
+        warnings: warnings.compile(),
         openPanels: project.metadata.open_panels.compile(),
         openFolders: project.metadata.open_folders.compile(),
         pinnedPanels: project.metadata.pinned_panels.compile(),
         lastOpen: project.metadata.last_open.compile()
     });
+    watch_project(app, project.files_directory.clone());
     inner_state.compiler = Some(compiler);
-    Some(json_str)
-}
\ No newline at end of file
+    Ok(json_str)
+}
+
+fn blob_json(compiler: &Compiler<JSONCompilerProvider, SysFileHost>, id: &BlobId) -> String {
+    if let Some(dir) = compiler.host.dirs.get(id) {
+        dir.borrow().compile()
+    } else {
+        compiler.host.files[id].borrow().compile()
+    }
+}
+
+fn compiled_change(data: &CompiledFileData<String>) -> String {
+    json!({ kind: "compiled".compile(), contents: data.compile() })
+}
+
+// Changing a file can change the diagnostics of other files, like ones with the same main path.
+// Those files are sent to the front-end as a `project_changed` event
+fn emit_stale_files(app: &AppHandle, compiler: &mut Compiler<JSONCompilerProvider, SysFileHost>) {
+    let changes = compiler.compile_stale_files().iter().map(compiled_change).collect::<Vec<String>>();
+    if !changes.is_empty() {
+        app.emit_all("project_changed", format!("[{}]", changes.join(","))).ok();
+    }
+}
+
+// Files changed by other programs get recompiled, the changes are sent
+// to the front-end as `project_changed` events
+fn watch_project(app: AppHandle, directory: PathBuf) {
+    let mut watcher = match FileWatcher::new(&directory) {
+        Some(watcher) => watcher,
+        None => return
+    };
+    thread::spawn(move || {
+        while let Some(events) = watcher.next_events() {
+            let state = app.state::<StorytellState>();
+            let mut inner_state = state.lock().unwrap();
+            // Another project was opened
+            let compiler = match inner_state.compiler.as_mut().filter(|compiler| compiler.host.cwd == directory.to_string_lossy()) {
+                Some(compiler) => compiler,
+                None => break
+            };
+            let changes = compiler.apply_file_events(&events).iter().map(|change| match change {
+                ProjectChange::Created(id) => json!({ kind: "created".compile(), blob: blob_json(compiler, id) }),
+                ProjectChange::Moved(id) => json!({ kind: "moved".compile(), blob: blob_json(compiler, id) }),
+                ProjectChange::Deleted(id) => json!({ kind: "deleted".compile(), id: id }),
+                ProjectChange::Compiled(data) => compiled_change(data),
+                ProjectChange::Failed(diagnostics) => json!({ kind: "failed".compile(), diagnostics: diagnostics.compile() })
+            }).collect::<Vec<String>>();
+            if !changes.is_empty() {
+                app.emit_all("project_changed", format!("[{}]", changes.join(","))).ok();
+            }
+        }
+    });
+}
+#[tauri::command]
+pub fn export_script(state: State<StorytellState>, csv: bool) -> Result<String, String> {
+    let inner_state = state.lock().unwrap();
+    let script = inner_state.compiler.as_ref().ok_or(NO_PROJECT)?.export_script();
+    Ok(if csv { script.to_csv() } else { script.to_text() })
+}
+
+#[tauri::command]
+pub fn export_translations(state: State<StorytellState>) -> Result<String, String> {
+    let inner_state = state.lock().unwrap();
+    Ok(to_po(&inner_state.compiler.as_ref().ok_or(NO_PROJECT)?.extract_strings()))
+}
+
+#[tauri::command]
+pub fn story_graph(state: State<StorytellState>, dot: bool) -> Result<String, String> {
+    let inner_state = state.lock().unwrap();
+    let graph = inner_state.compiler.as_ref().ok_or(NO_PROJECT)?.build_graph();
+    Ok(if dot { graph.to_dot() } else { graph.to_json() })
+}
+
+#[tauri::command]
+pub fn story_analysis(state: State<StorytellState>) -> Result<String, String> {
+    let inner_state = state.lock().unwrap();
+    Ok(inner_state.compiler.as_ref().ok_or(NO_PROJECT)?.analyze().to_json())
+}
+
+#[tauri::command]
+pub fn story_metrics(state: State<StorytellState>) -> Result<String, String> {
+    let inner_state = state.lock().unwrap();
+    Ok(inner_state.compiler.as_ref().ok_or(NO_PROJECT)?.metrics().to_json())
+}
+
+#[tauri::command]
+pub fn complete(state: State<StorytellState>, file_id: BlobId, content: String, offset: usize) -> Result<String, String> {
+    let inner_state = state.lock().unwrap();
+    Ok(inner_state.compiler.as_ref().ok_or(NO_PROJECT)?.complete(file_id, &content, offset).safe_compile())
+}
+
+fn renamed_files(result: StorytellResult<Vec<CompiledFileData<String>>>) -> String {
+    match result {
+        Ok(files) => json!({
+            contents: files.compile(),
+            diagnostics: String::from("[]")
+        }),
+        Err(diagnostics) => json!({
+            contents: String::from("[]"),
+            diagnostics: diagnostics.compile()
+        })
+    }
+}
+
+#[tauri::command]
+pub fn rename_path(state: State<StorytellState>, path: String, new_name: String) -> Result<String, String> {
+    let mut inner_state = state.lock().unwrap();
+    Ok(renamed_files(inner_state.compiler.as_mut().ok_or(NO_PROJECT)?.rename_path(&path, &new_name)))
+}
+
+#[tauri::command]
+pub fn rename_variable(state: State<StorytellState>, name: String, new_name: String) -> Result<String, String> {
+    let mut inner_state = state.lock().unwrap();
+    Ok(renamed_files(inner_state.compiler.as_mut().ok_or(NO_PROJECT)?.rename_variable(&name, &new_name)))
+}
+
+#[tauri::command]
+pub fn find_references(state: State<StorytellState>, symbol: String) -> Result<String, String> {
+    let inner_state = state.lock().unwrap();
+    let index = inner_state.compiler.as_ref().ok_or(NO_PROJECT)?.references();
+    Ok(index.find(&symbol).into_iter().cloned().collect::<Vec<Reference>>().safe_compile())
+}
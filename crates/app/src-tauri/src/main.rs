@@ -16,7 +16,7 @@ fn main() {
   let context = tauri::generate_context!();
   tauri::Builder::default()
     .manage(state)
-    .invoke_handler(tauri::generate_handler![list_projects, create_project, delete_project, edit_project, init_compiler, rename_blob, delete_blob, create_blob, refresh_blobs, recompile_file, save_data])
+    .invoke_handler(tauri::generate_handler![list_projects, create_project, delete_project, edit_project, init_compiler, rename_blob, delete_blob, create_blob, refresh_blobs, recompile_file, save_data, export_script, export_translations, story_graph, story_analysis, story_metrics, complete, rename_path, rename_variable, find_references])
     .menu(if cfg!(target_os = "macos") {
       tauri::Menu::os_default(&context.package_info().name)
     } else {
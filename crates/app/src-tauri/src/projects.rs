@@ -25,10 +25,15 @@ pub struct Project {
     pub files_directory: PathBuf
 }
 
+fn write_metadata(directory: &Path, metadata: &ProjectMetadata) -> Result<(), String> {
+    let path = directory.join("metadata.json");
+    fs::write(&path, to_string(metadata).map_err(|error| error.to_string())?).map_err(|error| format!("Couldn't write to '{}': {}", path.display(), error))
+}
+
 impl Project {
 
-    pub fn save(&self) {
-        fs::write(self.directory.join("metadata.json"), to_string(&self.metadata).unwrap()).expect("Couldn't write to metadata.json.");
+    pub fn save(&self) -> Result<(), String> {
+        write_metadata(&self.directory, &self.metadata)
     }
 
 }
@@ -40,23 +45,26 @@ pub struct Projects {
 }
 
 impl Projects {
+    /// Loads every project which can be read. Projects with invalid metadata are skipped, and
+    /// a missing Storytell folder only becomes an error once a project is created.
     pub fn new() -> Self {
-        let docs_dir = UserDirs::new().expect("Couldn't load file system.").document_dir().expect("Couldn't find a documents directory.").to_path_buf();
+        // Without a documents directory, projects go next to the app
+        let docs_dir = UserDirs::new().and_then(|dirs| dirs.document_dir().map(Path::to_path_buf)).unwrap_or_default();
         let storytell_dir = docs_dir.join("Storytell");
         if !Path::exists(storytell_dir.as_path()) {
-            fs::create_dir(&storytell_dir).expect("Couldn't create Storytell folder.");
+            fs::create_dir(&storytell_dir).ok();
         }
 
         let mut projects: HashMap<String, Project> = HashMap::new();
-        for file in fs::read_dir(&storytell_dir).unwrap().flatten() {
-            if file.file_type().unwrap().is_dir() {
+        for file in fs::read_dir(&storytell_dir).into_iter().flatten().flatten() {
+            if file.file_type().map_or(false, |file_type| file_type.is_dir()) {
                 let project_dir = file.path();
                 let content_dir = project_dir.join("content");
                 if !Path::is_dir(&content_dir) {
                     continue;
                 }
-                if let Ok(content) = fs::read(project_dir.join("metadata.json")) {
-                    let project_info = from_slice::<ProjectMetadata>(content.as_slice()).expect("Invalid JSON.");
+                let metadata = fs::read(project_dir.join("metadata.json")).ok().and_then(|content| from_slice::<ProjectMetadata>(content.as_slice()).ok());
+                if let Some(project_info) = metadata {
                     projects.insert(project_info.id.clone(), Project {
                         metadata: project_info,
                         files_directory: content_dir,
@@ -80,15 +88,14 @@ impl Projects {
         self.projects.get_mut(&self.current_project)
     }
 
-    pub fn create_project(&mut self, name: String, description: String) -> Option<&Project> {
+    pub fn create_project(&mut self, name: String, description: String) -> Result<&Project, String> {
         if self.projects.contains_key(&name) {
-            None
+            Err(format!("A project called '{}' already exists.", name))
         } else {
             let project_id = Uuid::new_v4().to_string();
             let project_dir = self.storytell_dir.join(project_id.clone());
-            fs::create_dir(&project_dir).expect("Failed to create directory.");
             let files_dir = project_dir.join("content");
-            fs::create_dir(&files_dir).expect("Failed to create directory.");
+            fs::create_dir_all(&files_dir).map_err(|error| format!("Couldn't create '{}': {}", files_dir.display(), error))?;
             let project_info = ProjectMetadata {
                 id: project_id.clone(),
                 name,
@@ -98,32 +105,31 @@ impl Projects {
                 open_panels: vec![],
                 description
             };
-            fs::write(project_dir.join("metadata.json"), to_string(&project_info).unwrap()).expect("Couldn't create file.");
-            fs::write(files_dir.join("main.md"), "# Welcome\r\nWelcome to storytell!\r\n\r\n- Check out the guides!\r\n    -> check_out_the_guides\r\n\r\n## Check out the guides\r\n\r\nCheck out the guide here: {link}").expect("Couldn't create file.");
+            write_metadata(&project_dir, &project_info)?;
+            let main_path = files_dir.join("main.md");
+            fs::write(&main_path, "# Welcome\r\nWelcome to storytell!\r\n\r\n- Check out the guides!\r\n    -> check_out_the_guides\r\n\r\n## Check out the guides\r\n\r\nCheck out the guide here: {link}").map_err(|error| format!("Couldn't create '{}': {}", main_path.display(), error))?;
             self.projects.insert(project_id.clone(), Project {
                 metadata: project_info,
                 files_directory: files_dir,
                 directory: project_dir
             });
-            self.projects.get(&project_id)
+            Ok(&self.projects[&project_id])
         }
     }
 
-    pub fn update_project(&mut self, id: String, name: String, description: String) -> Option<()> {
-        let project = self.projects.get_mut(&id)?;
+    pub fn update_project(&mut self, id: String, name: String, description: String) -> Result<(), String> {
+        let project = self.projects.get_mut(&id).ok_or_else(|| format!("There's no project with the id '{}'.", id))?;
         project.metadata.name = name;
         project.metadata.description = description;
-        fs::write(project.directory.join("metadata.json"), to_string(&project.metadata).unwrap()).expect("Couldn't write to file.");
-        Some(())
+        project.save()
     }
 
-    pub fn delete_project(&mut self, id: &str) -> bool {
-        if let Some(project) = self.projects.remove(id) {
-            fs::remove_dir_all(project.directory).expect("Failed to delete directory.");
-            true
-        } else {
-            false
-        }
+    /// The project is only forgotten if its directory could be deleted.
+    pub fn delete_project(&mut self, id: &str) -> Result<(), String> {
+        let project = self.projects.get(id).ok_or_else(|| format!("There's no project with the id '{}'.", id))?;
+        fs::remove_dir_all(&project.directory).map_err(|error| format!("Couldn't delete '{}': {}", project.directory.display(), error))?;
+        self.projects.remove(id);
+        Ok(())
     }
 
 }
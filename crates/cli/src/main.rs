@@ -0,0 +1,107 @@
+use std::{env, fs, process};
+use storytell_compiler::{base::Compiler, json_compiler::{JSONCompilerProvider, JSONCompilerContext, compile::JSONSafeCompilable}, references::Reference};
+use storytell_diagnostics::diagnostic::Diagnostic;
+use storytell_fs::{FileHost, SysFileHost};
+use storytell_parser::ast::format::format;
+
+const USAGE: &str = "Usage:
+    storytell stats <project directory> [--json]
+    storytell metrics <project directory> [--json]
+    storytell references <project directory> <symbol> [--json]
+    storytell format <file> [--write]";
+
+fn line_endings() -> usize {
+    match env::consts::OS {
+        "windows" => 2,
+        _ => 1
+    }
+}
+
+fn errors(diagnostics: Vec<Diagnostic>) -> String {
+    diagnostics.into_iter().map(|diagnostic| diagnostic.msg).collect::<Vec<String>>().join("\n")
+}
+
+fn open_project(directory: &str) -> Result<Compiler<JSONCompilerProvider, SysFileHost>, String> {
+    let mut compiler = Compiler::<JSONCompilerProvider, SysFileHost>::new(directory, line_endings(), SysFileHost, JSONCompilerContext::new(Some("this".to_string())));
+    let (_, _, warnings) = compiler.init_fs().map_err(errors)?;
+    for warning in warnings {
+        eprintln!("Warning: {}", warning.msg);
+    }
+    Ok(compiler)
+}
+
+fn stats(args: &[String]) -> Result<String, String> {
+    let directory = args.iter().find(|arg| !arg.starts_with("--")).ok_or("Missing project directory.")?;
+    let analysis = open_project(directory)?.analyze();
+    if args.iter().any(|arg| arg == "--json") {
+        Ok(analysis.to_json())
+    } else {
+        Ok(analysis.to_report())
+    }
+}
+
+fn metrics(args: &[String]) -> Result<String, String> {
+    let directory = args.iter().find(|arg| !arg.starts_with("--")).ok_or("Missing project directory.")?;
+    let metrics = open_project(directory)?.metrics();
+    if args.iter().any(|arg| arg == "--json") {
+        Ok(metrics.to_json())
+    } else {
+        Ok(metrics.to_report())
+    }
+}
+
+fn references(args: &[String]) -> Result<String, String> {
+    let mut positional = args.iter().filter(|arg| !arg.starts_with("--"));
+    let directory = positional.next().ok_or("Missing project directory.")?;
+    let symbol = positional.next().ok_or("Missing symbol.")?;
+    let compiler = open_project(directory)?;
+    let found = compiler.references().find(symbol);
+    if args.iter().any(|arg| arg == "--json") {
+        return Ok(found.into_iter().cloned().collect::<Vec<Reference>>().safe_compile());
+    }
+    if found.is_empty() {
+        return Err(format!("No references to '{}'.", symbol));
+    }
+    let mut lines = vec![];
+    for reference in found {
+        let file = compiler.host.files[&reference.file].borrow();
+        let path = compiler.host.build_path(&file.path, &file.name);
+        let content = compiler.host.raw.read_file(&path).unwrap_or_default();
+        let before = &content[..reference.range.start.min(content.len())];
+        let line = before.matches('\n').count() + 1;
+        let column = before.chars().rev().take_while(|character| *character != '\n').count() + 1;
+        lines.push(format!("{}:{}:{} {}", path.display(), line, column, reference.kind.name()));
+    }
+    Ok(lines.join("\n"))
+}
+
+fn format_file(args: &[String]) -> Result<String, String> {
+    let file = args.iter().find(|arg| !arg.starts_with("--")).ok_or("Missing file.")?;
+    let content = fs::read_to_string(file).map_err(|_| format!("Couldn't read '{}'.", file))?;
+    let line_endings = if content.contains("\r\n") { 2 } else { 1 };
+    let formatted = format(&content, line_endings);
+    if args.iter().any(|arg| arg == "--write") {
+        fs::write(file, formatted).map_err(|_| format!("Couldn't write '{}'.", file))?;
+        Ok(format!("Formatted '{}'.", file))
+    } else {
+        Ok(formatted.trim_end().to_string())
+    }
+}
+
+fn main() {
+    let args = env::args().skip(1).collect::<Vec<String>>();
+    let result = match args.first().map(|arg| arg.as_str()) {
+        Some("stats") => stats(&args[1..]),
+        Some("metrics") => metrics(&args[1..]),
+        Some("references") => references(&args[1..]),
+        Some("format") => format_file(&args[1..]),
+        _ => Err(USAGE.to_string())
+    };
+    match result {
+        Ok(output) => println!("{}", output),
+        Err(error) => {
+            eprintln!("{}", error);
+            process::exit(1);
+        }
+    }
+}
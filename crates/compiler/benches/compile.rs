@@ -0,0 +1,83 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use storytell_compiler::{base::compile_str, json_compiler::{JSONCompilerProvider, JSONCompilerContext}, visitors::{MagicVarCollector, MagicVariableCollectorContext, Rebuilder}};
+use storytell_diagnostics::location::Range;
+use storytell_js_parser::{ast::*, input::InputPresenter, JsParser};
+
+/// `count` statements which assign and read magic variables.
+fn statements(count: usize) -> String {
+    (0..count).map(|index| match index % 4 {
+        0 => format!("gold_{} += {index}", index % 10),
+        1 => format!("player.stats.hp = player.stats.hp - {index} * 2"),
+        2 => format!("inventory.push(\"item {index}\")"),
+        _ => format!("visited = gold_{} > {index} && !visited", index % 10)
+    }).collect::<Vec<String>>().join("; ")
+}
+
+/// A choice group whose choices contain another group, `depth` levels deep.
+fn choices(out: &mut String, level: usize, depth: usize) {
+    let indent = "    ".repeat(level);
+    for choice in 0..2 {
+        out.push_str(&format!("{indent}- {{:if gold > {choice}}} Choice **{choice}** on level {level}\n"));
+        out.push_str(&format!("{indent}    Some _text_ with {{gold += {choice}}} and {{player.visits.push({level})}}.\n"));
+        if level + 1 < depth {
+            choices(out, level + 1, depth);
+        } else {
+            out.push_str(&format!("{indent}    -> part_{choice}\n"));
+        }
+    }
+}
+
+/// A story with `paths` second-level paths, each one with choices nested `depth` levels deep.
+fn story(paths: usize, depth: usize) -> String {
+    let mut out = String::from("# Story\nThe story starts, {visits += 1}.\n");
+    for path in 0..paths {
+        out.push_str(&format!("\n## Part {path}\nSomeone says **something** about {{player.name}}.\n\n#[Label(Pick{path})]\n"));
+        choices(&mut out, 0, depth);
+        out.push_str("\n@{gold}\n- {1}\n    One gold.\n- {2}\n    Two gold.\n");
+    }
+    out
+}
+
+fn collect_magic_variables(c: &mut Criterion) {
+    let mut group = c.benchmark_group("magic_var_collector");
+    for count in [10, 100, 1000] {
+        let content = statements(count);
+        let (expressions, _, _) = JsParser::parse(&content);
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &expressions, |b, expressions| b.iter(|| {
+            let mut ctx = MagicVariableCollectorContext::new();
+            let mut collector = MagicVarCollector::new(InputPresenter::new(&content), Range::new(0, content.len()), &mut ctx);
+            expressions.visit_each_child(&mut collector);
+            collector.collected.len()
+        }));
+    }
+    group.finish();
+}
+
+fn rebuild(c: &mut Criterion) {
+    let mut group = c.benchmark_group("rebuilder");
+    for count in [10, 100, 1000] {
+        let content = statements(count);
+        let (expressions, _, _) = JsParser::parse(&content);
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &expressions, |b, expressions| {
+            b.iter(|| Rebuilder::run(InputPresenter::new(&content), expressions, Some("this".to_string())))
+        });
+    }
+    group.finish();
+}
+
+fn compile(c: &mut Criterion) {
+    let mut group = c.benchmark_group("compile_str");
+    for (paths, depth) in [(10, 2), (100, 2), (1000, 2), (10, 4), (10, 8)] {
+        let content = story(paths, depth);
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::new(format!("depth_{depth}"), paths), &content, |b, content| {
+            b.iter(|| compile_str::<JSONCompilerProvider>(content, JSONCompilerContext::new(Some("this".to_string())), 1))
+        });
+    }
+    group.finish();
+}
+
+criterion_group!(benches, collect_magic_variables, rebuild, compile);
+criterion_main!(benches);
@@ -0,0 +1,49 @@
+use std::{fs, path::PathBuf};
+use criterion::{criterion_group, criterion_main, Criterion};
+use storytell_compiler::{base::Compiler, json_compiler::{JSONCompilerProvider, JSONCompilerContext}};
+use storytell_fs::SysFileHost;
+
+const DIRECTORIES: usize = 20;
+const FILES_PER_DIRECTORY: usize = 50;
+
+fn story(index: usize) -> String {
+    let mut content = format!("# Chapter {index}\nThe chapter starts, {{visits_{index} += 1}}.\n");
+    for part in 0..5 {
+        content.push_str(&format!("\n## Part {part}\n**Someone** says something _interesting_ about {{player.name}}.\n\n"));
+        content.push_str(&format!("#[Label(Pick{part})]\n- Go left\n    {{gold += {part}}}\n    -> part_{}\n- {{:if gold > 2}} Go right\n    The path is `dark`.\n", (part + 1) % 5));
+        content.push_str("@{gold}\n- {1}\n    One gold.\n- {2}\n    Two gold.\n");
+    }
+    content
+}
+
+/// A project with 1,000 files, spread over a few directories.
+fn generate_project() -> PathBuf {
+    let root = std::env::temp_dir().join("storytell_bench_project");
+    if root.exists() {
+        fs::remove_dir_all(&root).unwrap();
+    }
+    for dir in 0..DIRECTORIES {
+        let path = root.join(format!("dir_{dir}"));
+        fs::create_dir_all(&path).unwrap();
+        for file in 0..FILES_PER_DIRECTORY {
+            let index = dir * FILES_PER_DIRECTORY + file;
+            fs::write(path.join(format!("chapter_{index}.md")), story(index)).unwrap();
+        }
+    }
+    root
+}
+
+fn compile_project(c: &mut Criterion) {
+    let root = generate_project();
+    let mut compiler = Compiler::<JSONCompilerProvider, SysFileHost>::new(root.to_str().unwrap(), 1, SysFileHost, JSONCompilerContext::new(Some("this".to_string())));
+    let single_thread = rayon::ThreadPoolBuilder::new().num_threads(1).build().unwrap();
+    let mut group = c.benchmark_group("compile_project");
+    group.sample_size(10);
+    group.bench_function("parallel", |b| b.iter(|| compiler.reset()));
+    group.bench_function("single_thread", |b| b.iter(|| single_thread.install(|| compiler.reset())));
+    group.finish();
+    fs::remove_dir_all(root).unwrap();
+}
+
+criterion_group!(benches, compile_project);
+criterion_main!(benches);
@@ -0,0 +1,398 @@
+use std::fmt::Write as _;
+use rustc_hash::{FxHashMap, FxHashSet};
+use storytell_parser::ast::model::*;
+use crate::graph::{StoryGraph, GraphNodeKind, GraphEdgeKind};
+use crate::json;
+use crate::json_compiler::compile::JSONSafeCompilable;
+use crate::visitors::{BlockVisitor, walk_blocks};
+
+/// Route counting stops once this many routes were found.
+pub const MAX_ROUTES: usize = 10_000;
+
+#[derive(Clone, Debug, Default)]
+pub struct PathStats {
+    pub path: String,
+    /// Words in paragraphs and choices directly inside the path, without sub-paths.
+    pub words: usize,
+    pub choices: usize,
+    pub choice_groups: usize,
+    /// Average number of choices per choice group.
+    pub branching_factor: f32
+}
+
+impl JSONSafeCompilable for PathStats {
+    fn safe_compile(&self) -> String {
+        json!({
+            path: self.path.safe_compile(),
+            words: self.words,
+            choices: self.choices,
+            choiceGroups: self.choice_groups,
+            branchingFactor: self.branching_factor
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct StoryAnalysis {
+    /// The path the story starts from.
+    pub start: Option<String>,
+    /// Paths which can't be reached from the start.
+    pub unreachable: Vec<String>,
+    /// Paths and choices after which the story stops without diverting to `end`.
+    pub dead_ends: Vec<String>,
+    /// Groups of paths and labels which divert to each other without the reader getting a choice.
+    pub choiceless_cycles: Vec<Vec<String>>,
+    pub routes: usize,
+    /// `true` if there are more than [MAX_ROUTES] routes.
+    pub routes_capped: bool,
+    pub paths: Vec<PathStats>
+}
+
+impl StoryAnalysis {
+
+    /// {
+    ///     start: string?,
+    ///     unreachable: string[],
+    ///     deadEnds: string[],
+    ///     choicelessCycles: string[][],
+    ///     routes: number,
+    ///     routesCapped: boolean,
+    ///     paths: { path: string, words: number, choices: number, choiceGroups: number, branchingFactor: number }[]
+    /// }
+    pub fn to_json(&self) -> String {
+        json!({
+            start: self.start.safe_compile(),
+            unreachable: self.unreachable.safe_compile(),
+            deadEnds: self.dead_ends.safe_compile(),
+            choicelessCycles: self.choiceless_cycles.safe_compile(),
+            routes: self.routes,
+            routesCapped: self.routes_capped,
+            paths: self.paths.safe_compile()
+        })
+    }
+
+    /// Human readable report, used by the CLI.
+    pub fn to_report(&self) -> String {
+        let mut result = String::new();
+        writeln!(result, "Start: {}", self.start.as_deref().unwrap_or("-")).unwrap();
+        writeln!(result, "Routes: {}{}", self.routes, if self.routes_capped { "+" } else { "" }).unwrap();
+        let total_words: usize = self.paths.iter().map(|path| path.words).sum();
+        writeln!(result, "Words: {}", total_words).unwrap();
+        write_list(&mut result, "Unreachable paths", &self.unreachable);
+        write_list(&mut result, "Dead ends", &self.dead_ends);
+        write_list(&mut result, "Cycles without a choice", &self.choiceless_cycles.iter().map(|cycle| cycle.join(" -> ")).collect::<Vec<String>>());
+        writeln!(result, "\nPaths:").unwrap();
+        for path in &self.paths {
+            writeln!(result, "    {} - {} words, {} choices, branching factor {:.2}", path.path, path.words, path.choices, path.branching_factor).unwrap();
+        }
+        result
+    }
+
+}
+
+fn write_list(result: &mut String, title: &str, items: &[String]) {
+    writeln!(result, "\n{} ({}):", title, items.len()).unwrap();
+    for item in items {
+        writeln!(result, "    {}", item).unwrap();
+    }
+}
+
+/// Plain text without inline javascript.
+pub fn text_without_js(parts: &[TextPart], tail: &str) -> String {
+    let mut result = String::new();
+    for part in parts {
+        result.push_str(&part.before);
+        match &part.text.kind {
+            ASTInlineKind::Javascript(_) | ASTInlineKind::Join => {},
+            other => result.push_str(&other.to_raw())
+        }
+    }
+    result.push_str(tail);
+    result
+}
+
+/// Words are whitespace separated and contain at least one letter or digit.
+pub fn count_words(text: &str) -> usize {
+    text.split_whitespace().filter(|word| word.chars().any(char::is_alphanumeric)).count()
+}
+
+#[derive(Default)]
+struct PathStatsCollector {
+    stats: Vec<PathStats>,
+    indexes: FxHashMap<String, usize>
+}
+
+impl PathStatsCollector {
+    fn get(&mut self, path: &str) -> &mut PathStats {
+        let ind = self.indexes[path];
+        &mut self.stats[ind]
+    }
+}
+
+impl BlockVisitor for PathStatsCollector {
+    fn visit_header(&mut self, _header: &ASTHeader, path: &str) {
+        self.indexes.insert(path.to_string(), self.stats.len());
+        self.stats.push(PathStats { path: path.to_string(), ..Default::default() });
+    }
+
+    fn visit_block(&mut self, block: &ASTBlock, path: &str, _position: &str) {
+        match block {
+            ASTBlock::Paragraph(paragraph) => self.get(path).words += count_words(&text_without_js(&paragraph.parts, &paragraph.tail)),
+            ASTBlock::ChoiceGroup(group) => {
+                let stats = self.get(path);
+                stats.choice_groups += 1;
+                stats.choices += group.choices.len();
+            },
+            _ => {}
+        }
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, path: &str, _position: &str) {
+        self.get(path).words += count_words(&text_without_js(&choice.text.parts, &choice.text.tail));
+    }
+}
+
+/// Analyzes the story graph of a project. `files` are the parsed files the graph was built from.
+/// If `start` is not provided, the first top-level path of the graph is used.
+pub fn analyze(graph: &StoryGraph, files: &[&[ASTBlock]], start: Option<&str>) -> StoryAnalysis {
+    let mut collector = PathStatsCollector::default();
+    for blocks in files {
+        walk_blocks(blocks, &mut collector);
+    }
+    for stats in &mut collector.stats {
+        if stats.choice_groups != 0 {
+            stats.branching_factor = stats.choices as f32 / stats.choice_groups as f32;
+        }
+    }
+    let start = match start {
+        Some(start) => graph.node_ids.get(start).copied(),
+        None => graph.nodes.iter().position(|node| node.kind == GraphNodeKind::Path && !node.id.contains('.'))
+    };
+    let successors = route_successors(graph);
+    let mut analysis = StoryAnalysis {
+        start: start.map(|start| graph.nodes[start].id.clone()),
+        paths: collector.stats,
+        ..Default::default()
+    };
+    let mut exits: Vec<Vec<usize>> = vec![vec![]; graph.nodes.len()];
+    for edge in graph.edges.iter().filter(|edge| edge.kind != GraphEdgeKind::Include) {
+        exits[edge.from].push(edge.to);
+    }
+    for (ind, node) in graph.nodes.iter().enumerate() {
+        if matches!(node.kind, GraphNodeKind::Path | GraphNodeKind::Choice) && !exits[ind].iter().any(|to| is_exit(graph, &successors, *to)) {
+            analysis.dead_ends.push(node.id.clone());
+        }
+    }
+    if let Some(start) = start {
+        let mut reached = FxHashSet::default();
+        let mut stack = vec![start];
+        while let Some(node) = stack.pop() {
+            if reached.insert(node) {
+                stack.extend(successors[node].iter().copied());
+            }
+        }
+        analysis.unreachable = graph.nodes.iter().enumerate()
+            .filter(|(ind, node)| node.kind == GraphNodeKind::Path && !reached.contains(ind))
+            .map(|(_, node)| node.id.clone())
+            .collect();
+        let routes = walk_routes(graph, start, &vec![0; graph.nodes.len()]);
+        analysis.routes = routes.count.min(MAX_ROUTES);
+        analysis.routes_capped = routes.count >= MAX_ROUTES;
+    }
+    analysis.choiceless_cycles = find_choiceless_cycles(graph);
+    analysis
+}
+
+/// The unique nodes every node of the graph has an edge to.
+fn route_successors(graph: &StoryGraph) -> Vec<Vec<usize>> {
+    let mut successors: Vec<Vec<usize>> = vec![vec![]; graph.nodes.len()];
+    for edge in &graph.edges {
+        if !successors[edge.from].contains(&edge.to) {
+            successors[edge.from].push(edge.to);
+        }
+    }
+    successors
+}
+
+/// Diverting to a label or including a path goes back to where the divert was, so they're never an exit
+fn is_exit(graph: &StoryGraph, successors: &[Vec<usize>], node: usize) -> bool {
+    graph.nodes[node].kind != GraphNodeKind::Label || !successors[node].is_empty()
+}
+
+/// The routes from a node until the story stops.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub(crate) struct Routes {
+    /// Stops counting at [MAX_ROUTES].
+    pub count: usize,
+    /// The smallest and largest sum of node weights along a single route.
+    pub min_weight: usize,
+    pub max_weight: usize
+}
+
+/// Walks every route from `start` until the story stops, adding up the `weights` of the nodes
+/// along each one. Nodes which are already on the current route are skipped, so every cycle
+/// gets walked at most once per route.
+pub(crate) fn walk_routes(graph: &StoryGraph, start: usize, weights: &[usize]) -> Routes {
+    struct RouteWalker<'a> {
+        graph: &'a StoryGraph,
+        successors: Vec<Vec<usize>>,
+        weights: &'a [usize],
+        on_route: Vec<bool>,
+        routes: Routes
+    }
+
+    impl<'a> RouteWalker<'a> {
+        fn walk(&mut self, node: usize, weight: usize) {
+            if self.routes.count >= MAX_ROUTES {
+                return;
+            }
+            let weight = weight + self.weights[node];
+            self.on_route[node] = true;
+            let mut ended = true;
+            for ind in 0..self.successors[node].len() {
+                let next = self.successors[node][ind];
+                if is_exit(self.graph, &self.successors, next) && !self.on_route[next] {
+                    ended = false;
+                    self.walk(next, weight);
+                }
+            }
+            if ended {
+                self.routes.count += 1;
+                self.routes.min_weight = self.routes.min_weight.min(weight);
+                self.routes.max_weight = self.routes.max_weight.max(weight);
+            }
+            self.on_route[node] = false;
+        }
+    }
+
+    let mut walker = RouteWalker {
+        graph,
+        successors: route_successors(graph),
+        weights,
+        on_route: vec![false; graph.nodes.len()],
+        routes: Routes { count: 0, min_weight: usize::MAX, max_weight: 0 }
+    };
+    walker.walk(start, 0);
+    walker.routes
+}
+
+/// Strongly connected components made only of diverts between paths and labels.
+fn find_choiceless_cycles(graph: &StoryGraph) -> Vec<Vec<String>> {
+    struct Tarjan<'a> {
+        successors: Vec<Vec<usize>>,
+        graph: &'a StoryGraph,
+        index: Vec<Option<usize>>,
+        low: Vec<usize>,
+        on_stack: Vec<bool>,
+        stack: Vec<usize>,
+        counter: usize,
+        cycles: Vec<Vec<String>>
+    }
+
+    impl<'a> Tarjan<'a> {
+        fn visit(&mut self, node: usize) {
+            self.index[node] = Some(self.counter);
+            self.low[node] = self.counter;
+            self.counter += 1;
+            self.stack.push(node);
+            self.on_stack[node] = true;
+            for next in self.successors[node].clone() {
+                match self.index[next] {
+                    None => {
+                        self.visit(next);
+                        self.low[node] = self.low[node].min(self.low[next]);
+                    },
+                    Some(next_index) if self.on_stack[next] => self.low[node] = self.low[node].min(next_index),
+                    _ => {}
+                }
+            }
+            if Some(self.low[node]) == self.index[node] {
+                let mut component = vec![];
+                while let Some(member) = self.stack.pop() {
+                    self.on_stack[member] = false;
+                    component.push(member);
+                    if member == node {
+                        break;
+                    }
+                }
+                if component.len() > 1 || self.successors[node].contains(&node) {
+                    component.sort();
+                    self.cycles.push(component.into_iter().map(|member| self.graph.nodes[member].id.clone()).collect());
+                }
+            }
+        }
+    }
+
+    let mut successors = vec![vec![]; graph.nodes.len()];
+    for edge in &graph.edges {
+        if edge.kind == GraphEdgeKind::Divert && graph.nodes[edge.from].kind != GraphNodeKind::Choice {
+            successors[edge.from].push(edge.to);
+        }
+    }
+    let mut tarjan = Tarjan {
+        successors,
+        graph,
+        index: vec![None; graph.nodes.len()],
+        low: vec![0; graph.nodes.len()],
+        on_stack: vec![false; graph.nodes.len()],
+        stack: vec![],
+        counter: 0,
+        cycles: vec![]
+    };
+    for node in 0..graph.nodes.len() {
+        if tarjan.index[node].is_none() {
+            tarjan.visit(node);
+        }
+    }
+    tarjan.cycles.sort();
+    tarjan.cycles
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::GraphBuilder;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn analyze_story() {
+        let (blocks, _) = Parser::parse("
+# Start
+Two roads, which {one} to take?
+
+- Left
+    -> left
+- Right
+    -> right
+
+## Left
+A quiet road.
+-> END
+
+## Right
+A loud road.
+- Go back
+    -> start
+- Stay
+
+# Loop
+-> other_loop
+
+# Other Loop
+-> loop
+", 1);
+        let mut builder = GraphBuilder::new();
+        builder.add_file(1, &blocks);
+        let graph = builder.build();
+        let analysis = analyze(&graph, &[&blocks], None);
+        assert_eq!(analysis.start, Some("start".to_string()));
+        assert_eq!(analysis.unreachable, vec!["loop".to_string(), "other_loop".to_string()]);
+        assert_eq!(analysis.dead_ends, vec!["start.right:1.1".to_string()]);
+        assert_eq!(analysis.choiceless_cycles, vec![vec!["loop".to_string(), "other_loop".to_string()]]);
+        // Left -> END, Right -> Stay, Right -> Go back (stops, already on the route)
+        assert_eq!(analysis.routes, 3);
+        assert_eq!(analysis.paths[0].words, 7);
+        assert_eq!(analysis.paths[0].branching_factor, 2.0);
+        assert!(analysis.to_report().contains("Dead ends (1):\n    start.right:1.1"));
+    }
+}
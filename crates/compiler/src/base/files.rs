@@ -1,10 +1,13 @@
-use storytell_diagnostics::diagnostic::Diagnostic;
+use storytell_diagnostics::{diagnostic::*, dia, location::Range};
 use storytell_fs::FileHost;
-use rustc_hash::{FxHashMap, FxHashSet};
+use rustc_hash::{FxHashMap, FxHashSet, FxHasher};
 use storytell_parser::ast::model::ASTBlock;
-use std::fs::DirEntry;
 use std::path::{PathBuf, Path};
 use std::cell::RefCell;
+use std::hash::{Hash, Hasher};
+use crate::path::Path as StoryPath;
+use super::manifest::{BlobManifest, ScannedBlob};
+use super::{Diagnostics, file_error};
 
 pub type BlobId = u16;
 
@@ -16,6 +19,8 @@ pub struct FileDiagnostic {
 pub struct File {
     pub name: String,
     pub parsed_content: Vec<ASTBlock>,
+    /// The diagnostics of parsing `parsed_content`, kept for incremental reparsing.
+    pub parse_diagnostics: Vec<Diagnostic>,
     pub path: Vec<BlobId>,
     pub parent: Option<BlobId>,
     pub id: BlobId
@@ -47,7 +52,13 @@ pub struct CompilerFileHost<H: FileHost> {
     pub files: FxHashMap<BlobId, RefCell<File>>,
     pub dirs: FxHashMap<BlobId, RefCell<Directory>>,
     pub line_endings: usize,
-    pub counter: BlobId
+    pub counter: BlobId,
+    /// Where the ids of the blobs are saved, so they stay the same between runs. Without it,
+    /// ids are given out in the order of the directory entries.
+    pub manifest_path: Option<PathBuf>,
+    /// A hash of the content [Self::save_file] last wrote to each file, so the watcher's events
+    /// for those writes can be told apart from changes made by something else.
+    saved: FxHashMap<BlobId, u64>
 }
 
 impl<H: FileHost> CompilerFileHost<H> {
@@ -59,7 +70,9 @@ impl<H: FileHost> CompilerFileHost<H> {
             files: FxHashMap::default(),
             dirs: FxHashMap::default(),
             line_endings,
-            counter: 1
+            counter: 1,
+            manifest_path: None,
+            saved: FxHashMap::default()
         }
     }
 
@@ -72,61 +85,335 @@ impl<H: FileHost> CompilerFileHost<H> {
         res
     }
 
-    pub fn register_dir<P: AsRef<Path>>(&mut self, dir: P, path: Vec<BlobId>,
-        hit_dir: &mut dyn FnMut(DirEntry, Vec<BlobId>, FxHashSet<BlobId>, BlobId) -> Directory,
-        hit_file: &mut dyn FnMut(&Self, DirEntry, Vec<BlobId>, BlobId) -> File
-    ) -> FxHashSet<BlobId> {
-        let mut children: FxHashSet<BlobId> = FxHashSet::default();
-        let mut vec_of_blobs = self.raw.get_entries_from_directory(dir).collect::<Vec<DirEntry>>();
-        // Assures that the dir entries are always in the same order, so BlobIds awlays match
-        // even when a new file is created by something else other than the program
-        // Is this cutting corners? Yes it is.
-        vec_of_blobs.sort_by_key(|a| a.metadata().unwrap().created().unwrap());
-        for entry in vec_of_blobs {
-            let blob_id = self.counter;
-            children.insert(blob_id);
-            self.counter += 1;
-            if entry.file_type().unwrap().is_dir() {
-                let mut new_path = path.clone();
-                new_path.push(blob_id);
-                let children = self.register_dir(entry.path(), new_path, hit_dir, hit_file);
-                self.dirs.insert(blob_id,  RefCell::from(hit_dir(entry, path.clone(), children, blob_id)));
+    /// Finds the file which is located at `path` on the disk.
+    pub fn file_id_from_path<P: AsRef<Path>>(&self, path: P) -> Option<BlobId> {
+        let path = path.as_ref();
+        self.files.values().find_map(|file| {
+            let file = file.borrow();
+            if self.build_path(&file.path, &file.name) == path { Some(file.id) } else { None }
+        })
+    }
+
+    /// Finds the file which contains `path`. The first part of the path is the canonical name of
+    /// a file's main path, so it doesn't matter where the file is.
+    pub fn resolve_path(&self, path: &[String]) -> Option<BlobId> {
+        let mut ids = self.files.keys().collect::<Vec<&BlobId>>();
+        ids.sort();
+        self.resolve_path_in(path, ids)
+    }
+
+    /// Like [Self::resolve_path], but only the files in `ids` are looked at, in that order.
+    /// Ids which aren't known are skipped.
+    pub fn resolve_path_in<'a>(&self, path: &[String], ids: impl IntoIterator<Item = &'a BlobId>) -> Option<BlobId> {
+        let first = path.first()?;
+        ids.into_iter().find(|id| match self.files.get(id).map(|file| file.borrow()).as_deref().and_then(|file| file.parsed_content.first()) {
+            Some(ASTBlock::Header(header)) if header.depth == 1 && StoryPath::canonicalize_name(&header.title.text) == *first => {
+                let mut root = StoryPath::new("");
+                root.add_child_ast(header);
+                root.get_child_by_path(path).is_some()
+            },
+            _ => false
+        }).copied()
+    }
+
+    fn scan_dir(&self, dir: PathBuf, parent: Option<usize>, scanned: &mut Vec<ScannedBlob>) -> StorytellResult<()> {
+        let mut entries = self.raw.get_entries_from_directory(&dir)
+            .map_err(|error| file_error(&Diagnostics::DIRECTORY_NOT_READ, error))?
+            .into_iter()
+            .filter(|entry| self.manifest_path.as_ref() != Some(&entry.path))
+            .map(|entry| (entry.name, entry.is_dir))
+            .collect::<Vec<(String, bool)>>();
+        entries.sort();
+        let first = scanned.len();
+        scanned.extend(entries.into_iter().map(|(name, is_dir)| ScannedBlob { name, is_dir, parent }));
+        for ind in first..scanned.len() {
+            if scanned[ind].is_dir {
+                self.scan_dir(dir.join(&scanned[ind].name), Some(ind), scanned)?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Registers every file and directory in the project, with the ids from the manifest.
+    /// Returns the blobs in the project directory itself, and warnings which didn't stop the project from opening.
+    pub fn register_project(&mut self) -> StorytellResult<(FxHashSet<BlobId>, Vec<Diagnostic>)> {
+        let manifest = self.manifest_path.as_ref()
+            .and_then(|path| self.raw.read_file(path).ok())
+            .map(|content| BlobManifest::parse(&content))
+            .unwrap_or_default();
+        let mut scanned = vec![];
+        self.scan_dir(PathBuf::from(&self.cwd), None, &mut scanned)?;
+        let (ids, next) = manifest.assign_ids(&scanned).ok_or_else(|| vec![dia!(NO_BLOB_IDS_LEFT, Range::default())])?;
+        self.counter = next;
+        let global = self.insert_scanned(scanned, &ids, None);
+        // The project works without it, the ids are saved again with the next change
+        let warnings = self.save_manifest().err().unwrap_or_default().into_iter().map(|mut diagnostic| {
+            diagnostic.variant = DiagnosticVariants::Warning;
+            diagnostic
+        }).collect();
+        Ok((global, warnings))
+    }
+
+    /// Adds scanned blobs with the given ids. Blobs without a parent go into `parent`.
+    /// Returns the ids of the blobs without a parent.
+    fn insert_scanned(&mut self, scanned: Vec<ScannedBlob>, ids: &[BlobId], parent: Option<BlobId>) -> FxHashSet<BlobId> {
+        let mut top = FxHashSet::default();
+        let top_path = match parent {
+            Some(parent) => {
+                let mut path = self.dirs[&parent].borrow().path.clone();
+                path.push(parent);
+                path
+            },
+            None => vec![]
+        };
+        let mut paths: Vec<Vec<BlobId>> = Vec::with_capacity(scanned.len());
+        for (blob, id) in scanned.into_iter().zip(ids.iter().cloned()) {
+            let path = if let Some(parent) = blob.parent {
+                self.dirs[&ids[parent]].borrow_mut().children.insert(id);
+                let mut path = paths[parent].clone();
+                path.push(ids[parent]);
+                path
             } else {
-                self.files.insert(blob_id, RefCell::from(hit_file(self, entry, path.clone(), blob_id)));
+                if let Some(parent) = parent {
+                    self.dirs[&parent].borrow_mut().children.insert(id);
+                }
+                top.insert(id);
+                top_path.clone()
+            };
+            if blob.is_dir {
+                self.dirs.insert(id, RefCell::from(Directory {
+                    name: blob.name,
+                    parent: path.last().cloned(),
+                    path: path.clone(),
+                    children: FxHashSet::default(),
+                    id
+                }));
+            } else {
+                self.files.insert(id, RefCell::from(File {
+                    name: blob.name,
+                    parent: path.last().cloned(),
+                    path: path.clone(),
+                    parsed_content: vec![],
+                    parse_diagnostics: vec![],
+                    id
+                }));
             }
+            paths.push(path);
         }
-        children
+        top
     }
-    
-    pub fn rename_blob(&mut self, id: &BlobId, name: String) {
-        let path = if let Some(file) = self.files.get(id) {
-            let mut borrowed = file.borrow_mut();
-            let built_path = self.build_path(&borrowed.path, &borrowed.name);
-            borrowed.name = name.clone();
-            built_path
-        } else if let Some(dir) = self.dirs.get(id) {
-            let mut borrowed = dir.borrow_mut();
-            let built_path = self.build_path(&borrowed.path, &borrowed.name);
-            borrowed.name = name.clone();
-            built_path
+
+    /// Finds the file or directory which is located at `path` on the disk.
+    pub fn blob_id_from_path<P: AsRef<Path>>(&self, path: P) -> Option<BlobId> {
+        let path = path.as_ref();
+        self.file_id_from_path(path).or_else(|| self.dirs.values().find_map(|dir| {
+            let dir = dir.borrow();
+            if self.build_path(&dir.path, &dir.name) == path { Some(dir.id) } else { None }
+        }))
+    }
+
+    /// The directory `path` is in (`None` for the project directory) and its name.
+    fn locate(&self, path: &Path) -> Option<(Option<BlobId>, String)> {
+        let relative = path.strip_prefix(&self.cwd).ok()?;
+        let name = relative.file_name()?.to_str()?.to_string();
+        let parent = relative.parent()?;
+        if parent.as_os_str().is_empty() {
+            Some((None, name))
         } else {
-            return;
+            let parent = self.blob_id_from_path(Path::new(&self.cwd).join(parent))?;
+            if self.dirs.contains_key(&parent) { Some((Some(parent), name)) } else { None }
+        }
+    }
+
+    /// Registers a file or directory which was created by something else, with everything inside it.
+    /// Returns the ids of the new blobs. The manifest isn't saved.
+    pub fn register_blob(&mut self, path: &Path) -> StorytellResult<Vec<BlobId>> {
+        if self.manifest_path.as_deref() == Some(path) || self.blob_id_from_path(path).is_some() {
+            return Ok(vec![]);
+        }
+        let Some((parent, name)) = self.locate(path) else { return Ok(vec![]) };
+        let mut scanned = vec![ScannedBlob { name, is_dir: self.raw.is_dir(path), parent: None }];
+        // Already gone again, or it can't be read
+        if scanned[0].is_dir && self.scan_dir(path.to_path_buf(), Some(0), &mut scanned).is_err() {
+            return Ok(vec![]);
+        }
+        let ids = (0..scanned.len()).map(|_| self.next_id()).collect::<StorytellResult<Vec<BlobId>>>()?;
+        self.insert_scanned(scanned, &ids, parent);
+        Ok(ids)
+    }
+
+    /// Gives out the id of a new blob.
+    fn next_id(&mut self) -> StorytellResult<BlobId> {
+        let id = self.peek_id()?;
+        self.counter = id + 1;
+        Ok(id)
+    }
+
+    /// The id [Self::next_id] gives out next, without taking it.
+    fn peek_id(&self) -> StorytellResult<BlobId> {
+        match self.counter.checked_add(1) {
+            Some(_) => Ok(self.counter),
+            None => Err(vec![dia!(NO_BLOB_IDS_LEFT, Range::default())])
+        }
+    }
+
+    /// Updates a blob which was renamed or moved to `path` by something else. Returns
+    /// false if the new path isn't in the project. The manifest isn't saved.
+    pub fn move_blob(&mut self, id: BlobId, path: &Path) -> bool {
+        let Some((parent, name)) = self.locate(path) else { return false };
+        if parent == Some(id) {
+            return false;
+        }
+        let (old_parent, new_path) = if let Some(file) = self.files.get(&id) {
+            let mut file = file.borrow_mut();
+            let old_parent = file.parent;
+            file.name = name;
+            file.parent = parent;
+            file.path = self.parent_path(parent);
+            (old_parent, None)
+        } else if let Some(dir) = self.dirs.get(&id) {
+            let mut dir = dir.borrow_mut();
+            let old_parent = dir.parent;
+            dir.name = name;
+            dir.parent = parent;
+            dir.path = self.parent_path(parent);
+            (old_parent, Some(dir.path.clone()))
+        } else {
+            return false;
         };
-        self.raw.rename_item(path, &name);
-    }
-
-    pub fn create_blob(&mut self, name: String, parent: Option<BlobId>, is_dir: bool) -> BlobId {
-        let file_id = self.counter;
-        self.counter += 1;
-        let path = if let Some(id) = &parent {
-            let mut folder = self.dirs.get(id).unwrap().borrow_mut();
-            folder.children.insert(file_id);
-            let mut new_path = folder.path.clone();
-            new_path.push(folder.id);
-            new_path
-        } else { vec![] };
+        if let Some(old_parent) = old_parent {
+            self.dirs[&old_parent].borrow_mut().children.remove(&id);
+        }
+        if let Some(parent) = parent {
+            self.dirs[&parent].borrow_mut().children.insert(id);
+        }
+        if let Some(mut path) = new_path {
+            path.push(id);
+            self.update_paths(id, path);
+        }
+        true
+    }
+
+    fn parent_path(&self, parent: Option<BlobId>) -> Vec<BlobId> {
+        match parent {
+            Some(parent) => {
+                let parent = self.dirs[&parent].borrow();
+                let mut path = parent.path.clone();
+                path.push(parent.id);
+                path
+            },
+            None => vec![]
+        }
+    }
+
+    /// Gives every blob inside the directory `id` the new `path` of the directory.
+    fn update_paths(&self, id: BlobId, path: Vec<BlobId>) {
+        for child in self.dirs[&id].borrow().children.iter() {
+            if let Some(file) = self.files.get(child) {
+                file.borrow_mut().path = path.clone();
+            } else if let Some(dir) = self.dirs.get(child) {
+                dir.borrow_mut().path = path.clone();
+                let mut child_path = path.clone();
+                child_path.push(*child);
+                self.update_paths(*child, child_path);
+            }
+        }
+    }
+
+    /// Forgets a blob which was deleted by something else. The manifest isn't saved.
+    /// Blobs which aren't known are ignored.
+    pub fn forget_blob(&mut self, id: &BlobId) {
+        let parent = match self.delete_blob_in_memory(id) {
+            Some(FileOrDir::Directory(dir)) => dir.borrow().parent,
+            Some(FileOrDir::File(file)) => file.borrow().parent,
+            None => return
+        };
+        if let Some(parent) = parent.and_then(|parent| self.dirs.get(&parent)) {
+            parent.borrow_mut().children.remove(id);
+        }
+    }
+
+    /// The path of a blob relative to the project directory, with `/` between the parts.
+    pub fn relative_path(&self, path: &[BlobId], name: &str) -> String {
+        path.iter().map(|id| self.dirs[id].borrow().name.clone()).chain(std::iter::once(name.to_string())).collect::<Vec<String>>().join("/")
+    }
+
+    /// Writes the ids of all blobs to the manifest, if the project has one.
+    pub fn save_manifest(&mut self) -> StorytellResult<()> {
+        if let Some(manifest_path) = &self.manifest_path {
+            let mut manifest = BlobManifest { next: self.counter, ..Default::default() };
+            for dir in self.dirs.values() {
+                let dir = dir.borrow();
+                manifest.blobs.insert(self.relative_path(&dir.path, &dir.name), (dir.id, true));
+            }
+            for file in self.files.values() {
+                let file = file.borrow();
+                manifest.blobs.insert(self.relative_path(&file.path, &file.name), (file.id, false));
+            }
+            let manifest_path = manifest_path.clone();
+            self.raw.write_file(manifest_path, &manifest.to_source()).map_err(|error| file_error(&Diagnostics::FILE_NOT_WRITTEN, error))?;
+        }
+        Ok(())
+    }
+
+    fn unknown_blob(id: &BlobId) -> Vec<Diagnostic> {
+        vec![dia!(UNKNOWN_BLOB, Range::default(), &id.to_string())]
+    }
+
+    /// The path of a blob on the disk, and whether it's a directory.
+    fn blob_path(&self, id: &BlobId) -> StorytellResult<(PathBuf, bool)> {
+        if let Some(file) = self.files.get(id) {
+            let file = file.borrow();
+            Ok((self.build_path(&file.path, &file.name), false))
+        } else if let Some(dir) = self.dirs.get(id) {
+            let dir = dir.borrow();
+            Ok((self.build_path(&dir.path, &dir.name), true))
+        } else {
+            Err(Self::unknown_blob(id))
+        }
+    }
+
+    /// Writes new content to a file of the project.
+    pub fn save_file(&mut self, id: &BlobId, content: &str) -> StorytellResult<()> {
+        let (path, _) = self.blob_path(id)?;
+        self.raw.write_file(path, content).map_err(|error| file_error(&Diagnostics::FILE_NOT_WRITTEN, error))?;
+        self.saved.insert(*id, content_hash(content));
+        Ok(())
+    }
+
+    /// If `content` is what [Self::save_file] last wrote to the file.
+    pub fn is_saved(&self, id: &BlobId, content: &str) -> bool {
+        self.saved.get(id) == Some(&content_hash(content))
+    }
+
+    /// Renames the blob on the disk first, it keeps its old name if that fails.
+    pub fn rename_blob(&mut self, id: &BlobId, name: String) -> StorytellResult<()> {
+        let (path, is_dir) = self.blob_path(id)?;
+        self.raw.rename_item(path, &name).map_err(|error| file_error(&Diagnostics::BLOB_NOT_RENAMED, error))?;
+        if is_dir {
+            self.dirs[id].borrow_mut().name = name;
+        } else {
+            self.files[id].borrow_mut().name = name;
+        }
+        self.save_manifest()
+    }
+
+    /// Creates the blob on the disk first, it's only added to the project if that works.
+    pub fn create_blob(&mut self, name: String, parent: Option<BlobId>, is_dir: bool) -> StorytellResult<BlobId> {
+        if let Some(parent) = parent.filter(|parent| !self.dirs.contains_key(parent)) {
+            return Err(Self::unknown_blob(&parent));
+        }
+        let path = self.parent_path(parent);
+        let built_path = self.build_path(&path, &name);
+        // Nothing is created without an id left for it, but the id is only taken once the blob exists
+        self.peek_id()?;
+        let created = if is_dir { self.raw.create_dir(built_path) } else { self.raw.write_file(built_path, "") };
+        created.map_err(|error| file_error(&Diagnostics::BLOB_NOT_CREATED, error))?;
+        let file_id = self.next_id()?;
+        if let Some(parent) = &parent {
+            self.dirs[parent].borrow_mut().children.insert(file_id);
+        }
         if is_dir {
-            self.raw.create_dir(self.build_path(&path, &name));
             self.dirs.insert(file_id, RefCell::from(Directory {
                 name,
                 parent,
@@ -135,47 +422,45 @@ impl<H: FileHost> CompilerFileHost<H> {
                 id: file_id
             }));
         } else {
-            self.raw.write_file(self.build_path(&path, &name), "");
             self.files.insert(file_id, RefCell::from(File {
                 name,
                 parent,
                 path,
                 parsed_content: vec![],
+                parse_diagnostics: vec![],
                 id: file_id
             }));
         }
-        file_id
+        self.save_manifest()?;
+        Ok(file_id)
     }
 
-    pub fn delete_blob(&mut self, id: &BlobId) {
-        match self.delete_blob_in_memory(id) {
-            FileOrDir::Directory(dir) => {
-                let borrowed = dir.borrow();
-                if let Some(parent) = &borrowed.parent {
-                    self.dirs.get(parent).unwrap().borrow_mut().children.remove(&borrowed.id);
-                }
-                self.raw.delete_dir_recursive(self.build_path(&borrowed.path, &borrowed.name));
-            }
-            FileOrDir::File(file) => {
-                let borrowed = file.borrow();
-                if let Some(parent) = &borrowed.parent {
-                    self.dirs.get(parent).unwrap().borrow_mut().children.remove(&borrowed.id);
-                }
-                self.raw.delete_file(self.build_path(&borrowed.path, &borrowed.name));
-            }
-        }
+    /// Deletes the blob from the disk first, it stays in the project if that fails.
+    pub fn delete_blob(&mut self, id: &BlobId) -> StorytellResult<()> {
+        let (path, is_dir) = self.blob_path(id)?;
+        let deleted = if is_dir { self.raw.delete_dir_recursive(path) } else { self.raw.delete_file(path) };
+        deleted.map_err(|error| file_error(&Diagnostics::BLOB_NOT_DELETED, error))?;
+        self.forget_blob(id);
+        self.save_manifest()
     }
 
-    fn delete_blob_in_memory(&mut self, id: &BlobId) -> FileOrDir {
+    fn delete_blob_in_memory(&mut self, id: &BlobId) -> Option<FileOrDir> {
         if let Some(directory) = self.dirs.remove(id) {
             for child in &directory.borrow().children {
                 self.delete_blob_in_memory(child);
             }
-            FileOrDir::Directory(directory)
+            Some(FileOrDir::Directory(directory))
         } else {
-            FileOrDir::File(self.files.remove(id).unwrap())
+            self.saved.remove(id);
+            self.files.remove(id).map(FileOrDir::File)
         }
     }
 
 }
 
+fn content_hash(content: &str) -> u64 {
+    let mut hasher = FxHasher::default();
+    content.hash(&mut hasher);
+    hasher.finish()
+}
+
@@ -0,0 +1,178 @@
+use rustc_hash::FxHashMap;
+use super::files::BlobId;
+
+/// A blob which was found in the project directory.
+#[derive(Clone, Debug)]
+pub struct ScannedBlob {
+    pub name: String,
+    pub is_dir: bool,
+    /// Index of the parent directory in the scanned blobs. Parents always come before their children.
+    pub parent: Option<usize>
+}
+
+/// The ids of the blobs in a project, mapped by their path relative to the project directory.
+/// Saved next to the project, so ids stay the same between runs.
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct BlobManifest {
+    pub blobs: FxHashMap<String, (BlobId, bool)>,
+    /// The id the next new blob gets. Ids of deleted blobs are never given out again.
+    pub next: BlobId
+}
+
+fn parent_of(path: &str) -> &str {
+    path.rsplit_once('/').map(|(parent, _)| parent).unwrap_or_default()
+}
+
+fn name_of(path: &str) -> &str {
+    path.rsplit_once('/').map(|(_, name)| name).unwrap_or(path)
+}
+
+fn join(dir: &str, name: &str) -> String {
+    if dir.is_empty() { name.to_string() } else { format!("{dir}/{name}") }
+}
+
+impl BlobManifest {
+
+    /// Reads a manifest in the format of [BlobManifest::to_source]. Invalid lines are skipped.
+    pub fn parse(content: &str) -> Self {
+        let mut manifest = Self::default();
+        for line in content.lines() {
+            let Some((first, rest)) = line.split_once(' ') else { continue };
+            if first == "next" {
+                manifest.next = rest.parse().unwrap_or_default();
+            } else if let (Ok(id), Some((kind, path))) = (first.parse::<BlobId>(), rest.split_once(' ')) {
+                manifest.blobs.insert(path.to_string(), (id, kind == "dir"));
+            }
+        }
+        manifest
+    }
+
+    /// One blob per line, ordered by id: `<id> <file|dir> <path>`.
+    pub fn to_source(&self) -> String {
+        let mut blobs = self.blobs.iter().collect::<Vec<(&String, &(BlobId, bool))>>();
+        blobs.sort_by_key(|(_, (id, _))| *id);
+        let mut result = format!("next {}\n", self.next);
+        for (path, (id, is_dir)) in blobs {
+            result.push_str(&format!("{id} {} {path}\n", if *is_dir { "dir" } else { "file" }));
+        }
+        result
+    }
+
+    fn take(&mut self, path: &str, is_dir: bool) -> Option<BlobId> {
+        match self.blobs.get(path) {
+            Some((id, dir)) if *dir == is_dir => {
+                let id = *id;
+                self.blobs.remove(path);
+                Some(id)
+            },
+            _ => None
+        }
+    }
+
+    /// Matches a single new blob in `siblings` to a single missing blob in `old_dir`.
+    fn rename(&mut self, scanned: &[ScannedBlob], siblings: &[usize], old_dir: &str, is_dir: bool, ids: &mut [Option<BlobId>], old_paths: &mut [Option<String>]) {
+        let new = siblings.iter().filter(|ind| ids[**ind].is_none() && scanned[**ind].is_dir == is_dir).collect::<Vec<&usize>>();
+        let missing = self.blobs.iter().filter(|(path, (_, dir))| *dir == is_dir && parent_of(path) == old_dir).map(|(path, _)| path.clone()).collect::<Vec<String>>();
+        if let ([ind], [old_path]) = (new.as_slice(), missing.as_slice()) {
+            ids[**ind] = self.take(old_path, is_dir);
+            old_paths[**ind] = Some(old_path.clone());
+        }
+    }
+
+    /// Gives every scanned blob an id. Blobs keep the id of their path in the manifest. Blobs which were
+    /// renamed or moved outside of the app keep their old id when there's only one way they could have
+    /// changed: a single new file with the name of a single missing file was moved, and a single new blob
+    /// in a directory where a single blob is missing was renamed. Everything else gets a new id.
+    /// Returns the ids, in the order of `scanned`, and the id the next new blob should get,
+    /// or `None` when there are no ids left for the new blobs.
+    pub fn assign_ids(mut self, scanned: &[ScannedBlob]) -> Option<(Vec<BlobId>, BlobId)> {
+        let mut next = self.blobs.values().map(|(id, _)| id.saturating_add(1)).max().unwrap_or(1).max(self.next).max(1);
+        let mut ids: Vec<Option<BlobId>> = vec![None; scanned.len()];
+        // The path each blob had in the manifest, when it's known
+        let mut old_paths: Vec<Option<String>> = vec![None; scanned.len()];
+        let mut children: FxHashMap<Option<usize>, Vec<usize>> = FxHashMap::default();
+        for (ind, blob) in scanned.iter().enumerate() {
+            children.entry(blob.parent).or_default().push(ind);
+        }
+        let parents = std::iter::once(None).chain((0..scanned.len()).filter(|ind| scanned[*ind].is_dir).map(Some)).collect::<Vec<Option<usize>>>();
+        // Directories first, the paths of their children depend on them
+        for parent in &parents {
+            let Some(siblings) = children.get(parent) else { continue };
+            let Some(old_dir) = parent.map_or(Some(String::new()), |parent| old_paths[parent].clone()) else { continue };
+            for ind in siblings {
+                let old_path = join(&old_dir, &scanned[*ind].name);
+                if let Some(id) = self.take(&old_path, scanned[*ind].is_dir) {
+                    ids[*ind] = Some(id);
+                    old_paths[*ind] = Some(old_path);
+                }
+            }
+            self.rename(scanned, siblings, &old_dir, true, &mut ids, &mut old_paths);
+        }
+        for ind in 0..scanned.len() {
+            let blob = &scanned[ind];
+            if ids[ind].is_some() || blob.is_dir {
+                continue;
+            }
+            let new = (0..scanned.len()).filter(|other| ids[*other].is_none() && !scanned[*other].is_dir && scanned[*other].name == blob.name).count();
+            let missing = self.blobs.iter().filter(|(path, (_, dir))| !*dir && name_of(path) == blob.name).map(|(path, _)| path.clone()).collect::<Vec<String>>();
+            if let (1, [old_path]) = (new, missing.as_slice()) {
+                ids[ind] = self.take(old_path, false);
+            }
+        }
+        for parent in &parents {
+            if let (Some(siblings), Some(old_dir)) = (children.get(parent), parent.map_or(Some(String::new()), |parent| old_paths[parent].clone())) {
+                self.rename(scanned, siblings, &old_dir, false, &mut ids, &mut old_paths);
+            }
+        }
+        let ids = ids.into_iter().map(|id| id.or_else(|| {
+            let id = next;
+            next = next.checked_add(1)?;
+            Some(id)
+        })).collect::<Option<Vec<BlobId>>>()?;
+        Some((ids, next))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn blob(name: &str, is_dir: bool, parent: Option<usize>) -> ScannedBlob {
+        ScannedBlob { name: name.to_string(), is_dir, parent }
+    }
+
+    #[test]
+    fn assign_ids() {
+        let manifest = BlobManifest::parse("next 9
+1 file main.md
+2 dir chapters
+3 file chapters/intro.md
+4 file chapters/end.md
+5 dir extras
+6 file extras/notes.md
+7 file extras/old.md
+");
+        assert_eq!(BlobManifest::parse(&manifest.to_source()), manifest);
+        // `chapters` was renamed to `book`, `intro.md` was moved to the top,
+        // `old.md` was renamed to `new.md` and `extra.md` was created
+        let (ids, next) = manifest.assign_ids(&[
+            blob("main.md", false, None),
+            blob("intro.md", false, None),
+            blob("book", true, None),
+            blob("extras", true, None),
+            blob("end.md", false, Some(2)),
+            blob("extra.md", false, Some(2)),
+            blob("notes.md", false, Some(3)),
+            blob("new.md", false, Some(3))
+        ]).unwrap();
+        assert_eq!(ids, vec![1, 3, 2, 5, 4, 9, 6, 7]);
+        assert_eq!(next, 10);
+        let (ids, next) = BlobManifest::default().assign_ids(&[blob("main.md", false, None), blob("chapters", true, None)]).unwrap();
+        assert_eq!(ids, vec![1, 2]);
+        assert_eq!(next, 3);
+        // Ids run out
+        let full = BlobManifest { next: BlobId::MAX - 1, ..Default::default() };
+        assert_eq!(full.assign_ids(&[blob("main.md", false, None), blob("chapters", true, None)]), None);
+    }
+}
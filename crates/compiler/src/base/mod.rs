@@ -1,31 +1,166 @@
-use std::{marker::PhantomData, fs::DirEntry};
-use rustc_hash::FxHashSet;
+use std::{marker::PhantomData, cell::{OnceCell, Ref}, path::{Path, PathBuf}};
+use rustc_hash::{FxHashMap, FxHashSet};
+use rayon::prelude::*;
 use storytell_diagnostics::{diagnostic::*, make_diagnostics, dia, location::Range};
-use storytell_parser::ast::{model::{ASTHeader, ASTBlock}, Parser};
-use storytell_fs::FileHost;
+use storytell_parser::ast::{model::{ASTHeader, ASTBlock}, incremental::{TextChange, reparse}, Parser};
+use storytell_fs::{FileHost, FileError, watcher::FileEvent};
+use crate::script::Script;
+use crate::graph::{GraphBuilder, StoryGraph};
+use crate::analysis::{StoryAnalysis, analyze};
+use crate::metrics::{ProjectMetrics, collect_metrics};
+use crate::completion::{Completion, CompletionContext, complete};
+use crate::rename::{FileEdits, rename_path, rename_variable};
+use crate::references::ReferenceIndex;
+use crate::json_compiler::JSONCompilerContext;
+use crate::localization::{TranslatableString, extract_strings};
+use crate::includes::{Include, check_includes, collect_includes};
+use crate::path::Path as StoryPath;
 pub mod files;
+pub mod manifest;
 use files::CompilerFileHost;
 
-use self::files::{BlobId, Directory, File, CompiledFileData};
+use self::files::{BlobId, File, CompiledFileData};
 
 make_diagnostics!(define [
     MISSING_HEADER,
     "File must contain just one top-level (#) path."
+], [
+    FILE_NOT_WRITTEN,
+    "Couldn't save the changes to '$'. $"
+], [
+    FILE_NOT_READ,
+    "Couldn't read '$'. $"
+], [
+    BLOB_NOT_CREATED,
+    "Couldn't create '$'. $"
+], [
+    BLOB_NOT_RENAMED,
+    "Couldn't rename '$'. $"
+], [
+    BLOB_NOT_DELETED,
+    "Couldn't delete '$'. $"
+], [
+    DIRECTORY_NOT_READ,
+    "Couldn't read the directory '$'. $"
+], [
+    UNKNOWN_BLOB,
+    "There's no file or directory with the id $."
+], [
+    NO_BLOB_IDS_LEFT,
+    "There are no ids left for new files and directories in this project."
+], [
+    DUPLICATE_PATH,
+    "There's already a main path called '$' in '$'. Main paths have to be unique."
 ]);
 
+/// The diagnostic of a failed file operation, which doesn't belong to a range in a file.
+pub(crate) fn file_error(message: &DiagnosticMessage, error: FileError) -> Vec<Diagnostic> {
+    vec![Diagnostic {
+        msg: format_diagnostic(message, vec![&error.path.to_string_lossy(), &error.kind.to_string()]),
+        range: Range::default(),
+        variant: DiagnosticVariants::Error
+    }]
+}
+
 pub trait CompilerContext {
+    /// Called with the main path of every file before a file is compiled, so diverts to other files can be resolved.
     fn process_path(&mut self, path: &ASTHeader);
+    /// Takes out what [Self::process_path] added for `path`.
+    fn remove_path(&mut self, path: &ASTHeader);
+    /// Forgets the paths given to [Self::process_path].
+    fn clear_paths(&mut self);
+    /// Diagnostics which were collected during compilation but didn't make it fail.
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic>;
+    /// An empty context with the same settings, for compiling a single file on another thread. It doesn't
+    /// know what the other files collected, like the types of their variables, so nothing is inferred from them
+    /// until the contexts are merged. Files which are compiled on their own later do get them.
+    fn fork(&self) -> Self;
+    /// Adds what was collected with a forked context. Conflicts with the data which is
+    /// already here are reported where the forked context found them, or at `range`.
+    fn merge(&mut self, forked: Self, range: Range<usize>) -> Vec<Diagnostic>;
 }
 
 pub trait CompilerProvider {
-    type Output;
-    type Context: CompilerContext;
+    type Output: Send;
+    type Context: CompilerContext + Send;
     fn compile_header(file: &ASTHeader, ctx: &mut Self::Context) -> StorytellResult<Self::Output>;
 }
 
+/// The blobs in the project directory itself, every compiled file, and warnings about the project
+/// which didn't stop it from opening, like the manifest not being saved.
+pub type ProjectFiles<O> = (FxHashSet<BlobId>, Vec<CompiledFileData<O>>, Vec<Diagnostic>);
+
+/// A change to the project which was made by something else, like another editor or git.
+pub enum ProjectChange<O> {
+    Created(BlobId),
+    /// The blob was renamed or moved to another directory.
+    Moved(BlobId),
+    Deleted(BlobId),
+    /// The file was created or changed and compiled again.
+    Compiled(CompiledFileData<O>),
+    /// The changes couldn't be saved to the manifest.
+    Failed(Vec<Diagnostic>)
+}
+
+struct IndexedFile {
+    /// The canonical name of the main path and the range of its title.
+    main_path: Option<(String, Range<usize>)>,
+    includes: Vec<Include>
+}
+
+/// The main path and includes of every file, as of the last time the file got new parsed content.
+/// Only the file which changed is walked again, for [Compiler::check_project] and the paths of the context.
+#[derive(Default)]
+struct ProjectIndex {
+    files: FxHashMap<BlobId, IndexedFile>,
+    /// The files with each main path, ordered by id.
+    main_paths: FxHashMap<String, Vec<BlobId>>
+}
+
+impl ProjectIndex {
+
+    fn add(&mut self, file_id: BlobId, parsed: &[ASTBlock]) {
+        self.remove(file_id);
+        let main_path = match parsed.first() {
+            Some(ASTBlock::Header(header)) if header.depth == 1 => Some((StoryPath::canonicalize_name(&header.title.text), header.title.range.clone())),
+            _ => None
+        };
+        if let Some((name, _)) = &main_path {
+            let files = self.main_paths.entry(name.clone()).or_default();
+            files.push(file_id);
+            files.sort();
+        }
+        self.files.insert(file_id, IndexedFile { main_path, includes: collect_includes(file_id, parsed) });
+    }
+
+    /// Returns the canonical name of the main path the file had.
+    fn remove(&mut self, file_id: BlobId) -> Option<String> {
+        let (name, _) = self.files.remove(&file_id)?.main_path?;
+        if let Some(files) = self.main_paths.get_mut(&name) {
+            files.retain(|id| *id != file_id);
+            if files.is_empty() {
+                self.main_paths.remove(&name);
+            }
+        }
+        Some(name)
+    }
+
+    fn clear(&mut self) {
+        self.files.clear();
+        self.main_paths.clear();
+    }
+
+}
+
 pub struct Compiler<P: CompilerProvider, F: FileHost> {
     pub host: CompilerFileHost<F>,
     pub ctx: P::Context,
+    /// The diagnostics of [Self::check_project] each file got the last time it was compiled.
+    project_diagnostics: FxHashMap<BlobId, Vec<Diagnostic>>,
+    /// Built from the parsed content of every file the first time it's needed after a file was compiled.
+    references: OnceCell<ReferenceIndex>,
+    /// The files whose main path the context knows, see [Self::process_paths].
+    index: ProjectIndex,
     _provider: PhantomData<P>
 }
 
@@ -35,22 +170,39 @@ impl<P: CompilerProvider, F: FileHost> Compiler<P, F> {
         Self {
             host: CompilerFileHost::new(cwd, line_endings, host),
             ctx,
-            _provider: PhantomData::default()
+            project_diagnostics: FxHashMap::default(),
+            references: OnceCell::new(),
+            index: ProjectIndex::default(),
+            _provider: PhantomData
         }
     }
 
-    pub fn reset(&mut self) -> (FxHashSet<BlobId>, Vec<CompiledFileData<P::Output>>) {
+    pub fn reset(&mut self) -> StorytellResult<ProjectFiles<P::Output>> where F: Sync {
         self.host.counter = 1;
         self.host.files.clear();
         self.host.dirs.clear();
+        self.project_diagnostics.clear();
+        self.references.take();
+        self.index.clear();
         self.init_fs()
     }
 
+    /// Compiles text which isn't part of the project. Its main path is added to the paths `ctx` already knows.
     pub fn compile_string(ctx: &mut P::Context, line_endings: usize, text: &str) -> (Option<P::Output>, Vec<ASTBlock>, Vec<Diagnostic>) {
-        let (parsed_content, mut dias) = Parser::parse(text, line_endings);
-        match parsed_content.get(0) {
+        let (parsed_content, dias) = Parser::parse(text, line_endings);
+        if let Some(ASTBlock::Header(header)) = parsed_content.first() {
+            ctx.process_path(header);
+        }
+        Self::compile_blocks(ctx, text, parsed_content, dias)
+    }
+
+    /// Compiles already parsed content. `dias` are the diagnostics of parsing it.
+    pub fn compile_blocks(ctx: &mut P::Context, text: &str, parsed_content: Vec<ASTBlock>, mut dias: Vec<Diagnostic>) -> (Option<P::Output>, Vec<ASTBlock>, Vec<Diagnostic>) {
+        match parsed_content.first() {
             Some(ASTBlock::Header(header)) if header.depth == 1 => {
-                match P::compile_header(header, ctx) {
+                let compiled = P::compile_header(header, ctx);
+                dias.append(&mut ctx.take_diagnostics());
+                match compiled {
                     Ok(compiled) => (Some(compiled), parsed_content, dias),
                     Err(mut error) => {
                         dias.append(&mut error);
@@ -65,53 +217,486 @@ impl<P: CompilerProvider, F: FileHost> Compiler<P, F> {
         }
     }
 
-    pub fn init_fs(&mut self) -> (FxHashSet<BlobId>, Vec<CompiledFileData<P::Output>>) {
-        let mut parsed_files: Vec<CompiledFileData<P::Output>> = vec![];
+    /// Registers every file and directory in the project (see [CompilerFileHost::register_project]), then reads and parses the files
+    /// in parallel. Once the paths of all files are known, the files are compiled in parallel too. Each file is compiled with its own forked context, which are merged in file id order,
+    /// so the result doesn't depend on which thread finishes first. The types of variables from other files aren't inferred
+    /// while compiling (see [CompilerContext::fork]). Diagnostics which depend on other files
+    /// are added once every file is parsed. Files which can't be read are empty and only have a diagnostic, only failing to read
+    /// a directory makes the whole thing fail.
+    pub fn init_fs(&mut self) -> StorytellResult<ProjectFiles<P::Output>> where F: Sync {
+        let (global, warnings) = self.host.register_project()?;
+        let mut ids = self.host.files.keys().cloned().collect::<Vec<BlobId>>();
+        ids.sort();
+        let jobs = ids.into_iter().map(|id| {
+            let file = self.host.files[&id].borrow();
+            (id, self.host.build_path(&file.path, &file.name))
+        }).collect::<Vec<(BlobId, PathBuf)>>();
         let line_endings = self.host.line_endings;
-        let cwd = self.host.cwd.clone();
-        let global = self.host.register_dir(cwd, vec![], &mut |entry, path, children, id| {
-            Directory {
-                name: entry.file_name().to_str().unwrap().to_string(),
-                path: path.clone(),
-                id,
-                parent: path.last().cloned(),
-                children
-            }
-        }, &mut |c: &CompilerFileHost<F>, entry: DirEntry, path: Vec<BlobId>, id: BlobId| {
-            let file_contents = c.raw.read_file(entry.path()).unwrap();
-            let (compiled_content, parsed_content,  diagnostics) = Self::compile_string(&mut self.ctx, line_endings, &file_contents);
-            parsed_files.push(CompiledFileData {
-                id,
-                compiled_content,
-                diagnostics,
-                content: file_contents,
-            });
-            File {
-                parsed_content,
-                name: entry.file_name().to_str().unwrap().to_string(),
-                path: path.clone(),
-                parent: path.last().cloned(),
-                id
+        let raw = &self.host.raw;
+        let parsed = jobs.into_par_iter().map(|(id, path)| match raw.read_file(path) {
+            Ok(content) => {
+                let (parsed_content, parse_diagnostics) = Parser::parse(&content, line_endings);
+                (id, Ok(content), parsed_content, parse_diagnostics)
+            },
+            Err(error) => (id, Err(file_error(&Diagnostics::FILE_NOT_READ, error)), vec![], vec![])
+        }).collect::<Vec<(BlobId, StorytellResult<String>, Vec<ASTBlock>, Vec<Diagnostic>)>>();
+        // Every file has to know the paths of the others before they're compiled
+        self.ctx.clear_paths();
+        self.index.clear();
+        for (id, _, parsed_content, _) in &parsed {
+            if let Some(ASTBlock::Header(header)) = parsed_content.first() {
+                self.ctx.process_path(header);
             }
-        });
-        (global, parsed_files)
+            self.index.add(*id, parsed_content);
+        }
+        let jobs = parsed.into_iter().map(|job| (job, self.ctx.fork())).collect::<Vec<_>>();
+        let compiled = jobs.into_par_iter().map(|((id, content, parsed_content, parse_diagnostics), mut ctx)| match content {
+            Ok(content) => {
+                let (compiled_content, parsed_content, diagnostics) = Self::compile_blocks(&mut ctx, &content, parsed_content, parse_diagnostics.clone());
+                (CompiledFileData { id, compiled_content, content, diagnostics }, parsed_content, parse_diagnostics, ctx)
+            },
+            Err(diagnostics) => (CompiledFileData { id, compiled_content: None, content: String::new(), diagnostics }, parsed_content, parse_diagnostics, ctx)
+        }).collect::<Vec<(CompiledFileData<P::Output>, Vec<ASTBlock>, Vec<Diagnostic>, P::Context)>>();
+        let mut parsed_files = compiled.into_iter().map(|(mut data, parsed_content, parse_diagnostics, ctx)| {
+            data.diagnostics.append(&mut self.ctx.merge(ctx, Range::new(0, data.content.len())));
+            let mut file = self.host.files[&data.id].borrow_mut();
+            file.parsed_content = parsed_content;
+            file.parse_diagnostics = parse_diagnostics;
+            data
+        }).collect::<Vec<CompiledFileData<P::Output>>>();
+        self.project_diagnostics = self.check_project();
+        self.references.take();
+        for data in &mut parsed_files {
+            data.diagnostics.extend(self.project_diagnostics.get(&data.id).into_iter().flatten().cloned());
+        }
+        Ok((global, parsed_files, warnings))
     }
 
-    pub fn compile_file(&mut self, file_id: BlobId) -> (Option<P::Output>, String, Vec<Diagnostic>) {
-        let mut file = self.host.files.get(&file_id).unwrap().borrow_mut();
-        let file_contents = self.host.raw.read_file(self.host.build_path(&file.path, &file.name)).unwrap();
-        let (output, parsed, diagnostics) = Self::compile_string(&mut self.ctx, self.host.line_endings, &file_contents);
+    pub fn compile_file(&mut self, file_id: BlobId) -> StorytellResult<(Option<P::Output>, String, Vec<Diagnostic>)> {
+        let path = {
+            let file = self.host.files.get(&file_id).ok_or_else(|| vec![dia!(UNKNOWN_BLOB, Range::default(), &file_id.to_string())])?.borrow();
+            self.host.build_path(&file.path, &file.name)
+        };
+        let file_contents = self.host.raw.read_file(path).map_err(|error| file_error(&Diagnostics::FILE_NOT_READ, error))?;
+        let (parsed, parse_diagnostics) = Parser::parse(&file_contents, self.host.line_endings);
+        self.forget_paths(file_id);
+        self.process_paths(file_id, &parsed);
+        let (output, parsed, mut diagnostics) = Self::compile_blocks(&mut self.ctx, &file_contents, parsed, parse_diagnostics.clone());
+        let mut file = self.host.files[&file_id].borrow_mut();
         file.parsed_content = parsed;
-        (output, file_contents, diagnostics)
+        file.parse_diagnostics = parse_diagnostics;
+        drop(file);
+        diagnostics.append(&mut self.update_project_diagnostics(file_id));
+        Ok((output, file_contents, diagnostics))
     }
 
-    pub fn compile_file_with_content(&mut self, file_id: BlobId, content: &str) -> (Option<P::Output>, Vec<Diagnostic>) {
-        let (compiled, parsed, diagnostics) = Self::compile_string(&mut self.ctx, self.host.line_endings, content);
-        let mut file = self.host.files.get(&file_id).unwrap().borrow_mut();
+    /// Compiles unsaved content of a file. The parsed content is only kept if the file is in the project.
+    /// `line_endings` is the length of the line endings of `content`, which an editor might not save like the rest of the project.
+    pub fn compile_file_with_content(&mut self, file_id: BlobId, content: &str, line_endings: usize) -> (Option<P::Output>, Vec<Diagnostic>) {
+        let (parsed, parse_diagnostics) = Parser::parse(content, line_endings);
+        self.forget_paths(file_id);
+        self.process_paths(file_id, &parsed);
+        let (compiled, parsed, mut diagnostics) = Self::compile_blocks(&mut self.ctx, content, parsed, parse_diagnostics.clone());
+        if let Some(file) = self.host.files.get(&file_id) {
+            let mut file = file.borrow_mut();
+            file.parsed_content = parsed;
+            file.parse_diagnostics = parse_diagnostics;
+            drop(file);
+            diagnostics.append(&mut self.update_project_diagnostics(file_id));
+        }
+        (compiled, diagnostics)
+    }
+
+    /// Like [Self::compile_file_with_content], but only the part of the file `change` touches is parsed again.
+    /// `content` is the new content of the file, with `change` already applied.
+    pub fn compile_file_with_change(&mut self, file_id: BlobId, content: &str, change: &TextChange, line_endings: usize) -> (Option<P::Output>, Vec<Diagnostic>) {
+        if !self.host.files.contains_key(&file_id) {
+            return self.compile_file_with_content(file_id, content, line_endings);
+        }
+        // The old main path is taken out of the context before the parsed content is
+        self.forget_paths(file_id);
+        let (previous, previous_diagnostics) = {
+            let mut file = self.host.files[&file_id].borrow_mut();
+            (std::mem::take(&mut file.parsed_content), std::mem::take(&mut file.parse_diagnostics))
+        };
+        let (parsed, parse_diagnostics) = reparse(previous, previous_diagnostics, content, change, line_endings);
+        self.process_paths(file_id, &parsed);
+        let (compiled, parsed, mut diagnostics) = Self::compile_blocks(&mut self.ctx, content, parsed, parse_diagnostics.clone());
+        let mut file = self.host.files[&file_id].borrow_mut();
         file.parsed_content = parsed;
+        file.parse_diagnostics = parse_diagnostics;
+        drop(file);
+        diagnostics.append(&mut self.update_project_diagnostics(file_id));
         (compiled, diagnostics)
     }
 
+    /// Takes the main path of `file_id` out of the context, before the file gets new parsed content. Files with
+    /// the same main path share it in the context, so they're processed again. The paths of deleted files can't
+    /// be taken out anymore, so if there are any, the context starts over with the paths of the other files.
+    fn forget_paths(&mut self, file_id: BlobId) {
+        if self.index.files.keys().any(|id| !self.host.files.contains_key(id)) {
+            let mut ids = self.host.files.keys().filter(|id| **id != file_id && self.index.files.contains_key(id)).cloned().collect::<Vec<BlobId>>();
+            ids.sort();
+            self.ctx.clear_paths();
+            self.index.clear();
+            for id in ids {
+                let file = self.host.files[&id].borrow();
+                if let Some(ASTBlock::Header(header)) = file.parsed_content.first() {
+                    self.ctx.process_path(header);
+                }
+                self.index.add(id, &file.parsed_content);
+            }
+            return;
+        }
+        if !self.index.files.contains_key(&file_id) {
+            return;
+        }
+        let others = self.index.remove(file_id).and_then(|name| self.index.main_paths.get(&name).cloned()).unwrap_or_default();
+        for id in others.iter().chain(std::iter::once(&file_id)) {
+            if let Some(ASTBlock::Header(header)) = self.host.files[id].borrow().parsed_content.first() {
+                self.ctx.remove_path(header);
+            }
+        }
+        for id in &others {
+            if let Some(ASTBlock::Header(header)) = self.host.files[id].borrow().parsed_content.first() {
+                self.ctx.process_path(header);
+            }
+        }
+    }
+
+    /// Gives the context the main path of `parsed`, the new content of `file_id`. The old one has to be taken
+    /// out with [Self::forget_paths] first. Files which were created since the last time are processed too.
+    fn process_paths(&mut self, file_id: BlobId, parsed: &[ASTBlock]) {
+        let mut created = self.host.files.keys().filter(|id| **id != file_id && !self.index.files.contains_key(id)).cloned().collect::<Vec<BlobId>>();
+        created.sort();
+        for id in created {
+            let file = self.host.files[&id].borrow();
+            if let Some(ASTBlock::Header(header)) = file.parsed_content.first() {
+                self.ctx.process_path(header);
+            }
+            self.index.add(id, &file.parsed_content);
+        }
+        if let Some(ASTBlock::Header(header)) = parsed.first() {
+            self.ctx.process_path(header);
+        }
+        self.index.add(file_id, parsed);
+    }
+
+    /// Checks the project again and remembers the diagnostics `file_id` got. The reference index
+    /// is built again the next time it's needed, since `file_id` just got new parsed content.
+    fn update_project_diagnostics(&mut self, file_id: BlobId) -> Vec<Diagnostic> {
+        let diagnostics = self.check_project().remove(&file_id).unwrap_or_default();
+        self.project_diagnostics.insert(file_id, diagnostics.clone());
+        self.references.take();
+        diagnostics
+    }
+
+    /// Compiles the files whose diagnostics from [Self::check_project] aren't the same as the last time they
+    /// were compiled, because another file changed. For example, a file with a duplicate main path has
+    /// to be compiled again once the other file is renamed or deleted. Files which can't be read are skipped.
+    pub fn compile_stale_files(&mut self) -> Vec<CompiledFileData<P::Output>> {
+        let current = self.check_project();
+        self.project_diagnostics.retain(|id, _| self.host.files.contains_key(id));
+        // Files might have been deleted without anything being compiled
+        self.references.take();
+        let mut stale = self.host.files.keys().filter(|id| {
+            current.get(id).map_or(&[] as &[Diagnostic], Vec::as_slice) != self.project_diagnostics.get(id).map_or(&[] as &[Diagnostic], Vec::as_slice)
+        }).cloned().collect::<Vec<BlobId>>();
+        stale.sort();
+        stale.into_iter().filter_map(|id| {
+            let (compiled_content, content, diagnostics) = self.compile_file(id).ok()?;
+            Some(CompiledFileData { id, compiled_content, content, diagnostics })
+        }).collect()
+    }
+
+    /// Content which was saved by the compiler itself was already compiled, with `skip_saved` it isn't compiled again.
+    fn recompile(&mut self, file_id: BlobId, skip_saved: bool, changes: &mut Vec<ProjectChange<P::Output>>) {
+        let path = {
+            let file = self.host.files[&file_id].borrow();
+            self.host.build_path(&file.path, &file.name)
+        };
+        // The file might already be gone again
+        if let Ok(content) = self.host.raw.read_file(path) {
+            if skip_saved && self.host.is_saved(&file_id, &content) {
+                return;
+            }
+            let (compiled_content, diagnostics) = self.compile_file_with_content(file_id, &content, self.host.line_endings);
+            changes.retain(|change| !matches!(change, ProjectChange::Compiled(data) if data.id == file_id));
+            changes.push(ProjectChange::Compiled(CompiledFileData { id: file_id, compiled_content, content, diagnostics }));
+        }
+    }
+
+    fn register(&mut self, path: &Path, changes: &mut Vec<ProjectChange<P::Output>>) {
+        let ids = match self.host.register_blob(path) {
+            Ok(ids) => ids,
+            Err(diagnostics) => {
+                changes.push(ProjectChange::Failed(diagnostics));
+                return;
+            }
+        };
+        for id in ids {
+            changes.push(ProjectChange::Created(id));
+            if self.host.files.contains_key(&id) {
+                self.recompile(id, false, changes);
+            }
+        }
+    }
+
+    /// The blob `old` was moved to where `new` is, but `new` was registered before that was known.
+    fn relink(&mut self, old: BlobId, new: BlobId, changes: &mut Vec<ProjectChange<P::Output>>) {
+        changes.retain(|change| match change {
+            ProjectChange::Created(id) => *id != new,
+            ProjectChange::Compiled(data) => data.id != new,
+            _ => true
+        });
+        let path = {
+            let file = self.host.files[&new].borrow();
+            self.host.build_path(&file.path, &file.name)
+        };
+        self.host.forget_blob(&new);
+        if self.host.move_blob(old, &path) {
+            changes.push(ProjectChange::Moved(old));
+            self.recompile(old, false, changes);
+        }
+    }
+
+    /// Updates the project after changes which were made to its directory by something else.
+    /// Only the files which changed are compiled again. Files whose content is what [CompilerFileHost::save_file]
+    /// last wrote to them aren't, those changes were made by the compiler itself. The manifest is saved once at the end.
+    pub fn apply_file_events(&mut self, events: &[FileEvent]) -> Vec<ProjectChange<P::Output>> {
+        let mut changes = vec![];
+        // Blobs are only forgotten at the end, they might come back or turn out to be moved
+        let mut removed: Vec<BlobId> = vec![];
+        for event in events {
+            match event {
+                FileEvent::Created(path) | FileEvent::Modified(path) => match self.host.blob_id_from_path(path) {
+                    Some(id) => {
+                        let recreated = removed.contains(&id);
+                        removed.retain(|removed_id| *removed_id != id);
+                        if (recreated || matches!(event, FileEvent::Modified(_))) && self.host.files.contains_key(&id) {
+                            self.recompile(id, !recreated, &mut changes);
+                        }
+                    },
+                    None => self.register(path, &mut changes)
+                },
+                FileEvent::Removed(path) => if let Some(id) = self.host.blob_id_from_path(path) {
+                    if !removed.contains(&id) {
+                        removed.push(id);
+                    }
+                },
+                FileEvent::Renamed(from, to) => match (self.host.blob_id_from_path(from), self.host.blob_id_from_path(to)) {
+                    // Registered with its directory, before the rename was known
+                    (Some(from_id), Some(to_id)) if self.host.files.contains_key(&from_id) && changes.iter().any(|change| matches!(change, ProjectChange::Created(id) if *id == to_id)) => {
+                        self.relink(from_id, to_id, &mut changes);
+                    },
+                    // Replaced another file, like editors which save to a temporary file first
+                    (from_id, Some(to_id)) => {
+                        if let Some(from_id) = from_id {
+                            removed.push(from_id);
+                        }
+                        if self.host.files.contains_key(&to_id) {
+                            self.recompile(to_id, false, &mut changes);
+                        }
+                    },
+                    (Some(id), None) => if self.host.move_blob(id, to) {
+                        changes.push(ProjectChange::Moved(id));
+                    } else {
+                        removed.push(id);
+                    },
+                    (None, None) => self.register(to, &mut changes)
+                }
+            }
+        }
+        let file_name = |host: &CompilerFileHost<F>, id: &BlobId| host.files.get(id).map(|file| file.borrow().name.clone());
+        for id in &removed {
+            if !self.host.files.contains_key(id) && !self.host.dirs.contains_key(id) {
+                continue;
+            }
+            // A file which was moved into a new directory can show up as a new file
+            if let Some(name) = file_name(&self.host, id) {
+                let created = changes.iter().filter_map(|change| match change {
+                    ProjectChange::Created(new) if new != id && file_name(&self.host, new).as_ref() == Some(&name) => Some(*new),
+                    _ => None
+                }).collect::<Vec<BlobId>>();
+                let same_name = removed.iter().filter(|other| file_name(&self.host, other).as_ref() == Some(&name)).count();
+                if let ([new], 1) = (created.as_slice(), same_name) {
+                    self.relink(*id, *new, &mut changes);
+                    continue;
+                }
+            }
+            // Blobs which only existed for a moment aren't reported
+            if !changes.iter().any(|change| matches!(change, ProjectChange::Created(created) if created == id)) {
+                changes.push(ProjectChange::Deleted(*id));
+            }
+            self.host.forget_blob(id);
+        }
+        changes.retain(|change| match change {
+            ProjectChange::Created(id) | ProjectChange::Moved(id) => self.host.files.contains_key(id) || self.host.dirs.contains_key(id),
+            ProjectChange::Compiled(data) => self.host.files.contains_key(&data.id),
+            ProjectChange::Deleted(_) | ProjectChange::Failed(_) => true
+        });
+        for data in self.compile_stale_files() {
+            changes.retain(|change| !matches!(change, ProjectChange::Compiled(compiled) if compiled.id == data.id));
+            changes.push(ProjectChange::Compiled(data));
+        }
+        if let Err(diagnostics) = self.host.save_manifest() {
+            changes.push(ProjectChange::Failed(diagnostics));
+        }
+        changes
+    }
+
+    /// Flattens every file in the project into a single linear script, ordered by file id.
+    pub fn export_script(&self) -> Script {
+        let mut script = Script::new();
+        let mut ids = self.host.files.keys().collect::<Vec<&BlobId>>();
+        ids.sort();
+        for id in ids {
+            script.add_blocks(&self.host.files[id].borrow().parsed_content);
+        }
+        script
+    }
+
+    /// Collects translatable strings from every file in the project, ordered by file id.
+    pub fn extract_strings(&self) -> Vec<TranslatableString> {
+        let mut ids = self.host.files.keys().collect::<Vec<&BlobId>>();
+        ids.sort();
+        ids.into_iter().flat_map(|id| extract_strings(&self.host.files[id].borrow().parsed_content)).collect()
+    }
+
+    /// Borrows every file in the project, ordered by file id.
+    fn sorted_files(&self) -> Vec<Ref<'_, File>> {
+        let mut ids = self.host.files.keys().collect::<Vec<&BlobId>>();
+        ids.sort();
+        ids.into_iter().map(|id| self.host.files[id].borrow()).collect()
+    }
+
+    /// Diagnostics which depend on more than one file: main paths with the same name, and includes.
+    /// Returns the diagnostics of each file.
+    pub fn check_project(&self) -> FxHashMap<BlobId, Vec<Diagnostic>> {
+        let mut result = self.check_includes();
+        let mut main_paths = self.index.main_paths.iter()
+            .map(|(name, files)| (name, files.iter().filter(|id| self.host.files.contains_key(id)).copied().collect::<Vec<BlobId>>()))
+            .filter(|(_, files)| files.len() > 1)
+            .collect::<Vec<_>>();
+        main_paths.sort_by_key(|(_, files)| files[0]);
+        for (name, files) in main_paths {
+            for id in &files {
+                let Some((_, range)) = &self.index.files[id].main_path else { continue };
+                for other in files.iter().filter(|other| *other != id) {
+                    let other = self.host.files[other].borrow();
+                    result.entry(*id).or_default().push(dia!(DUPLICATE_PATH, range.clone(), name, &self.host.relative_path(&other.path, &other.name)));
+                }
+            }
+        }
+        result
+    }
+
+    /// Checks the includes of every file (see [crate::includes]). Included paths are found
+    /// with [CompilerFileHost::resolve_path_in], in the files with the right main path. Returns the diagnostics of each file.
+    pub fn check_includes(&self) -> FxHashMap<BlobId, Vec<Diagnostic>> {
+        let mut ids = self.index.files.keys().filter(|id| self.host.files.contains_key(id)).collect::<Vec<&BlobId>>();
+        ids.sort();
+        let includes = ids.into_iter().flat_map(|id| self.index.files[id].includes.iter().cloned()).collect::<Vec<Include>>();
+        check_includes(&includes, |path| self.host.resolve_path_in(path, self.index.main_paths.get(path.first()?)?))
+    }
+
+    /// Builds the flow graph of the whole project.
+    pub fn build_graph(&self) -> StoryGraph {
+        let files = self.sorted_files();
+        let mut builder = GraphBuilder::new();
+        for file in &files {
+            builder.add_file(file.id, &file.parsed_content);
+        }
+        builder.build()
+    }
+
+    /// Analyzes the whole project, starting from the first path of the first file.
+    pub fn analyze(&self) -> StoryAnalysis {
+        let graph = self.build_graph();
+        let files = self.sorted_files();
+        analyze(&graph, &files.iter().map(|file| file.parsed_content.as_slice()).collect::<Vec<&[ASTBlock]>>(), None)
+    }
+
+    /// What can be typed at `offset` in the (possibly unsaved) `content` of a file.
+    pub fn complete(&self, file_id: BlobId, content: &str, offset: usize) -> Vec<Completion> where P: CompilerProvider<Context = JSONCompilerContext> {
+        let files = self.sorted_files();
+        let ctx = CompletionContext {
+            files: files.iter().filter(|file| file.id != file_id).map(|file| file.parsed_content.as_slice()).collect(),
+            variables: &self.ctx.magic_variables
+        };
+        complete(content, offset, self.host.line_endings, &ctx)
+    }
+
+    /// Reads and parses every file in the project, so ranges match the content on the disk.
+    fn read_project(&self) -> StorytellResult<Vec<(BlobId, String, Vec<ASTBlock>)>> {
+        self.sorted_files().iter().map(|file| {
+            let content = self.host.raw.read_file(self.host.build_path(&file.path, &file.name)).map_err(|error| file_error(&Diagnostics::FILE_NOT_READ, error))?;
+            let (blocks, _) = Parser::parse(&content, self.host.line_endings);
+            Ok((file.id, content, blocks))
+        }).collect()
+    }
+
+    /// Writes all edits to the disk. If a file can't be written, the files which were
+    /// already changed get their old content back. Changed files are recompiled.
+    fn apply_edits(&mut self, project: &[(BlobId, String, Vec<ASTBlock>)], edits: &[FileEdits]) -> StorytellResult<Vec<CompiledFileData<P::Output>>> {
+        let mut written: Vec<(PathBuf, &String)> = vec![];
+        for file_edits in edits {
+            let (_, content, _) = project.iter().find(|(id, _, _)| *id == file_edits.file).unwrap();
+            let path = {
+                let file = self.host.files[&file_edits.file].borrow();
+                self.host.build_path(&file.path, &file.name)
+            };
+            if let Err(error) = self.host.raw.write_file(&path, &file_edits.apply(content)) {
+                for (path, content) in &written {
+                    // Nothing more can be done if this fails too
+                    self.host.raw.write_file(path, content).ok();
+                }
+                return Err(file_error(&Diagnostics::FILE_NOT_WRITTEN, error));
+            }
+            written.push((path, content));
+        }
+        edits.iter().map(|file_edits| {
+            let (compiled_content, content, diagnostics) = self.compile_file(file_edits.file)?;
+            Ok(CompiledFileData { id: file_edits.file, compiled_content, content, diagnostics })
+        }).collect()
+    }
+
+    /// Renames the path at `path` and updates every divert which goes through it.
+    /// Returns the recompiled files.
+    pub fn rename_path(&mut self, path: &str, new_name: &str) -> StorytellResult<Vec<CompiledFileData<P::Output>>> {
+        let project = self.read_project()?;
+        let files = project.iter().map(|(id, content, blocks)| (*id, content.as_str(), blocks.as_slice())).collect::<Vec<(BlobId, &str, &[ASTBlock])>>();
+        let edits = rename_path(&files, path, new_name)?;
+        self.apply_edits(&project, &edits)
+    }
+
+    /// Renames a magic variable in every inline javascript snippet. Returns the recompiled files.
+    pub fn rename_variable(&mut self, name: &str, new_name: &str) -> StorytellResult<Vec<CompiledFileData<P::Output>>> where P: CompilerProvider<Context = JSONCompilerContext> {
+        let project = self.read_project()?;
+        let files = project.iter().map(|(id, content, blocks)| (*id, content.as_str(), blocks.as_slice())).collect::<Vec<(BlobId, &str, &[ASTBlock])>>();
+        let edits = rename_variable(&files, &self.ctx.magic_variables, name, new_name)?;
+        let result = self.apply_edits(&project, &edits)?;
+        if name != new_name {
+            self.ctx.magic_variables.variables.remove(name);
+        }
+        Ok(result)
+    }
+
+    /// Every divert, label and magic variable reference in the project, as of the last time a file was compiled.
+    /// Ranges are in the content the files were compiled with.
+    pub fn references(&self) -> &ReferenceIndex {
+        self.references.get_or_init(|| {
+            let files = self.sorted_files();
+            ReferenceIndex::build(&files.iter().map(|file| (file.id, file.parsed_content.as_slice())).collect::<Vec<(BlobId, &[ASTBlock])>>())
+        })
+    }
+
+    /// Word counts and reading times for every path and file in the project.
+    pub fn metrics(&self) -> ProjectMetrics {
+        let graph = self.build_graph();
+        let files = self.sorted_files();
+        collect_metrics(&graph, &files.iter().map(|file| (file.id, file.parsed_content.as_slice())).collect::<Vec<(BlobId, &[ASTBlock])>>())
+    }
 
 }
 
@@ -126,10 +711,256 @@ pub fn compile_str<P: CompilerProvider>(string: &str, mut ctx: P::Context, line_
         }
     }
     for header in headers {
-        match P::compile_header(&header, &mut ctx) {
+        let compiled = P::compile_header(&header, &mut ctx);
+        total_errors.append(&mut ctx.take_diagnostics());
+        match compiled {
             Ok(compiled) => result.push(compiled),
             Err(mut err) => total_errors.append(&mut err)
         }
     }
     (result, total_errors, ctx)
-}
\ No newline at end of file
+}
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_fs::memory::InMemoryFileHost;
+    use crate::json_compiler::JSONCompilerProvider;
+
+    fn project() -> Compiler<JSONCompilerProvider, InMemoryFileHost> {
+        let mut host = InMemoryFileHost::default();
+        host.insert_file("/story/main.md", "# Start\nHello, {name = \"Jim\"}!\n-> chapter_one\n");
+        host.insert_file("/story/chapters/one.md", "# Chapter One\n-> start\n");
+        host.insert_file("/story/chapters/two.md", "# Chapter Two\nThe end.\n");
+        let mut compiler = Compiler::new("/story", 1, host, JSONCompilerContext::new(Some("this".to_string())));
+        compiler.host.manifest_path = Some(PathBuf::from("/story/blobs.txt"));
+        compiler
+    }
+
+    fn path_of(compiler: &Compiler<JSONCompilerProvider, InMemoryFileHost>, id: BlobId) -> PathBuf {
+        let file = compiler.host.files[&id].borrow();
+        compiler.host.build_path(&file.path, &file.name)
+    }
+
+    #[test]
+    fn compile_in_memory_project() {
+        let mut compiler = project();
+        let (global, compiled, _) = compiler.init_fs().unwrap();
+        assert_eq!(global.len(), 2);
+        assert_eq!(compiled.len(), 3);
+        assert!(compiled.iter().all(|data| data.compiled_content.is_some() && data.diagnostics.is_empty()));
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        // Diverts to other files are resolved
+        let compiled_main = compiled.iter().find(|data| data.id == main).and_then(|data| data.compiled_content.as_ref()).unwrap();
+        assert!(compiled_main.contains("\"path\":[\"chapter_one\"],\"absolute\":false,\"target\":[\"chapter_one\"]"));
+        assert!(compiler.ctx.magic_variables.variables.contains_key("name"));
+    }
+
+    #[test]
+    fn init_missing_directory() {
+        let mut missing = Compiler::<JSONCompilerProvider, InMemoryFileHost>::new("/story/missing", 1, InMemoryFileHost::default(), JSONCompilerContext::new(None));
+        assert_eq!(missing.init_fs().err().unwrap()[0].msg, "Couldn't read the directory '/story/missing'. It doesn't exist.");
+    }
+
+    #[test]
+    fn edit_blobs() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let chapters = compiler.host.blob_id_from_path("/story/chapters").unwrap();
+        let new = compiler.host.create_blob("three.md".to_string(), Some(chapters), false).unwrap();
+        assert_eq!(compiler.host.raw.read_file("/story/chapters/three.md").as_deref(), Ok(""));
+        compiler.host.rename_blob(&chapters, "book".to_string()).unwrap();
+        assert_eq!(path_of(&compiler, new), PathBuf::from("/story/book/three.md"));
+        assert!(compiler.host.raw.is_dir("/story/book"));
+        compiler.host.delete_blob(&new).unwrap();
+        assert!(compiler.host.raw.read_file("/story/book/three.md").is_err());
+        assert_eq!(compiler.host.files.len() + compiler.host.dirs.len(), 4);
+    }
+
+    #[test]
+    fn keep_blobs_when_the_disk_fails() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        let counter = compiler.host.counter;
+        let errors = compiler.host.create_blob("chapters".to_string(), None, true).unwrap_err();
+        assert_eq!(errors[0].msg, "Couldn't create '/story/chapters'. Something with the same name already exists.");
+        assert_eq!(compiler.host.counter, counter);
+        assert!(compiler.host.rename_blob(&main, "chapters".to_string()).is_err());
+        assert_eq!(compiler.host.files[&main].borrow().name, "main.md");
+        assert_eq!(compiler.host.delete_blob(&999).unwrap_err()[0].msg, "There's no file or directory with the id 999.");
+        compiler.host.forget_blob(&999);
+        assert_eq!(compiler.host.files.len() + compiler.host.dirs.len(), 4);
+    }
+
+    #[test]
+    fn open_without_saving_the_manifest() {
+        let mut compiler = project();
+        compiler.host.manifest_path = Some(PathBuf::from("/missing/blobs.txt"));
+        let (_, compiled, warnings) = compiler.init_fs().unwrap();
+        assert_eq!(compiled.len(), 3);
+        assert_eq!(warnings.len(), 1);
+        assert_eq!(warnings[0].variant, DiagnosticVariants::Warning);
+    }
+
+    #[test]
+    fn run_out_of_blob_ids() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        compiler.host.counter = BlobId::MAX;
+        let errors = compiler.host.create_blob("three.md".to_string(), None, false).unwrap_err();
+        assert_eq!(errors[0].msg, "There are no ids left for new files and directories in this project.");
+        assert!(compiler.host.raw.read_file("/story/three.md").is_err());
+        compiler.host.raw.write_file("/story/four.md", "# Four\n").unwrap();
+        let changes = compiler.apply_file_events(&[FileEvent::Created(PathBuf::from("/story/four.md"))]);
+        assert!(matches!(changes.as_slice(), [ProjectChange::Failed(_)]));
+    }
+
+    #[test]
+    fn keep_ids_in_the_manifest() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        let chapters = compiler.host.blob_id_from_path("/story/chapters").unwrap();
+        compiler.host.rename_blob(&chapters, "book".to_string()).unwrap();
+        compiler.reset().unwrap();
+        assert_eq!(compiler.host.file_id_from_path("/story/main.md"), Some(main));
+        assert_eq!(compiler.host.blob_id_from_path("/story/book"), Some(chapters));
+    }
+
+    #[test]
+    fn rename_paths_across_files() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let edited = compiler.rename_path("chapter_one", "Prologue").unwrap();
+        assert_eq!(edited.len(), 2);
+        assert_eq!(compiler.host.raw.read_file("/story/main.md").as_deref(), Ok("# Start\nHello, {name = \"Jim\"}!\n-> prologue\n"));
+    }
+
+    #[test]
+    fn apply_changes_made_by_something_else() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        let chapters = compiler.host.blob_id_from_path("/story/chapters").unwrap();
+        compiler.host.raw.rename_item("/story/chapters", "book").unwrap();
+        compiler.host.raw.write_file("/story/main.md", "# Start\nBye.\n").unwrap();
+        compiler.host.raw.delete_file("/story/book/two.md").unwrap();
+        let changes = compiler.apply_file_events(&[
+            FileEvent::Renamed(PathBuf::from("/story/chapters"), PathBuf::from("/story/book")),
+            FileEvent::Modified(PathBuf::from("/story/main.md")),
+            FileEvent::Removed(PathBuf::from("/story/book/two.md"))
+        ]);
+        assert!(matches!(changes.as_slice(), [
+            ProjectChange::Moved(moved),
+            ProjectChange::Compiled(data),
+            ProjectChange::Deleted(_)
+        ] if *moved == chapters && data.id == main && data.content == "# Start\nBye.\n"));
+        assert_eq!(compiler.host.files.len(), 2);
+    }
+
+    #[test]
+    fn ignore_changes_made_by_the_compiler() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        compiler.compile_file_with_content(main, "# Start\nSaved.\n", 1);
+        compiler.host.save_file(&main, "# Start\nSaved.\n").unwrap();
+        assert!(compiler.apply_file_events(&[FileEvent::Modified(PathBuf::from("/story/main.md"))]).is_empty());
+        compiler.host.raw.write_file("/story/main.md", "# Start\nChanged.\n").unwrap();
+        let changes = compiler.apply_file_events(&[FileEvent::Modified(PathBuf::from("/story/main.md"))]);
+        assert!(matches!(changes.as_slice(), [ProjectChange::Compiled(data)] if data.id == main && data.content == "# Start\nChanged.\n"));
+    }
+
+    #[test]
+    fn resolve_includes_across_files() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        compiler.host.raw.write_file("/story/chapters/one.md", "# Chapter One\n#[Include]\n-> start\n#[Include]\n-> chapter_one.missing\n").unwrap();
+        let one = compiler.host.file_id_from_path("/story/chapters/one.md").unwrap();
+        let (_, _, diagnostics) = compiler.compile_file(one).unwrap();
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].msg, "There's no path 'chapter_one.missing' to include. Included paths start with the main path of a file.");
+        let (compiled, diagnostics) = compiler.compile_file_with_content(main, "# Start\n#[Include]\n-> chapter_one\n", 1);
+        assert!(compiled.unwrap().contains("{\"kind\":6,\"path\":[\"chapter_one\"]"));
+        assert_eq!(diagnostics[0].msg, "Including 'chapter_one' leads back to 'start', so it never ends.");
+        assert_eq!(compiler.check_includes()[&one].len(), 2);
+    }
+
+    #[test]
+    fn unique_main_paths() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        compiler.host.raw.write_file("/story/chapters/three.md", "# Start\nAgain.\n").unwrap();
+        let changes = compiler.apply_file_events(&[FileEvent::Created(PathBuf::from("/story/chapters/three.md"))]);
+        let three = compiler.host.file_id_from_path("/story/chapters/three.md").unwrap();
+        let compiled = |changes: &[ProjectChange<String>], id: BlobId| changes.iter().find_map(|change| match change {
+            ProjectChange::Compiled(data) if data.id == id => Some(data.diagnostics.iter().map(|dia| dia.msg.clone()).collect::<Vec<String>>()),
+            _ => None
+        });
+        assert_eq!(compiled(&changes, three), Some(vec!["There's already a main path called 'start' in 'main.md'. Main paths have to be unique.".to_string()]));
+        assert_eq!(compiled(&changes, main), Some(vec!["There's already a main path called 'start' in 'chapters/three.md'. Main paths have to be unique.".to_string()]));
+        compiler.host.delete_blob(&three).unwrap();
+        let stale = compiler.compile_stale_files();
+        assert!(stale.iter().any(|data| data.id == main && data.diagnostics.is_empty()));
+        assert!(compiler.compile_stale_files().is_empty());
+    }
+
+    #[test]
+    fn update_paths_of_changed_files() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        let one = compiler.host.file_id_from_path("/story/chapters/one.md").unwrap();
+        let two = compiler.host.file_id_from_path("/story/chapters/two.md").unwrap();
+        compiler.compile_file_with_content(main, "# Start\n#[Label(Door, global)]\nA door.\n", 1);
+        assert!(compiler.compile_file_with_content(two, "# Chapter Two\n{label_count(\"Door\")}\n", 1).1.is_empty());
+        compiler.compile_file_with_content(main, "# Begin\nNo door.\n", 1);
+        let (_, diagnostics) = compiler.compile_file_with_content(two, "# Chapter Two\n{label_count(\"Door\")}\n", 1);
+        assert_eq!(diagnostics[0].msg, "There's no label 'Door' which can be used from here.");
+        assert!(compiler.ctx.paths.children.contains_key("begin") && !compiler.ctx.paths.children.contains_key("start"));
+        // Files with the same main path share it, it stays as long as one of them has it
+        compiler.compile_file_with_content(one, "# Begin\n", 1);
+        compiler.compile_file_with_content(one, "# Chapter One\n", 1);
+        assert!(compiler.ctx.paths.children.contains_key("begin"));
+        assert!(compiler.check_project().is_empty());
+        compiler.host.delete_blob(&main).unwrap();
+        compiler.compile_file_with_content(one, "# Chapter One\n", 1);
+        assert!(!compiler.ctx.paths.children.contains_key("begin"));
+    }
+
+    #[test]
+    fn compile_with_change() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        let change = TextChange { range: Range::new(42, 45), text: "two".to_string() };
+        let content = change.apply("# Start\nHello, {name = \"Jim\"}!\n-> chapter_one\n");
+        let (compiled, diagnostics) = compiler.compile_file_with_change(main, &content, &change, 1);
+        let parsed = compiler.host.files[&main].borrow().parsed_content.clone();
+        assert_eq!((compiled, diagnostics), compiler.compile_file_with_content(main, &content, 1));
+        assert_eq!(parsed, compiler.host.files[&main].borrow().parsed_content);
+        assert_eq!(compiler.references().find("chapter_two")[0].file, main);
+    }
+
+    #[test]
+    fn references_follow_compiles() {
+        let mut compiler = project();
+        compiler.init_fs().unwrap();
+        let main = compiler.host.file_id_from_path("/story/main.md").unwrap();
+        let one = compiler.host.file_id_from_path("/story/chapters/one.md").unwrap();
+        let files = |compiler: &Compiler<JSONCompilerProvider, InMemoryFileHost>, symbol: &str| compiler.references().find(symbol).iter().map(|reference| reference.file).collect::<Vec<BlobId>>();
+        assert_eq!(files(&compiler, "start"), vec![main, one]);
+        assert_eq!(files(&compiler, "name"), vec![main]);
+        // Unsaved content is indexed, the disk isn't read
+        let content = "# Start\n{name = \"Jim\"}\n-> chapter_two\n";
+        compiler.compile_file_with_content(main, content, 1);
+        assert_eq!(files(&compiler, "chapter_one"), vec![one]);
+        let divert = compiler.references().find("chapter_two")[0];
+        assert_eq!((divert.file, &content[divert.range.start..divert.range.end]), (main, "-> chapter_two"));
+        compiler.host.delete_blob(&one).unwrap();
+        compiler.compile_stale_files();
+        assert_eq!(files(&compiler, "start"), vec![main]);
+    }
+}
@@ -0,0 +1,203 @@
+use rustc_hash::FxHashMap;
+use storytell_diagnostics::{diagnostic::*, make_diagnostics, dia, location::Range};
+use storytell_js_parser::{ast::*, input::InputPresenter};
+use storytell_parser::ast::model::*;
+use crate::path::{Path, label_visible_from, is_label_visible};
+use crate::visitors::{BlockVisitor, MagicVariableType, walk_header};
+
+make_diagnostics!(define [
+    WRONG_ARGUMENT_COUNT,
+    "'$' takes $ argument(s), but $ were given."
+], [
+    NOT_A_LABEL_NAME,
+    "The argument of '$' has to be the name of a label, like '$(\"MyChoice\")'."
+], [
+    UNKNOWN_LABEL,
+    "There's no label '$' which can be used from here."
+]);
+
+/// What a builtin function expects as one of its arguments.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum BuiltinParameter {
+    /// A string with the name of a label which can be used from where the function is called.
+    Label
+}
+
+/// A function the runtime provides to inline javascript. Builtins aren't magic variables,
+/// and they don't get prefixed when javascript is compiled.
+pub struct BuiltinFunction {
+    pub name: &'static str,
+    pub parameters: &'static [BuiltinParameter],
+    pub returns: MagicVariableType,
+    pub description: &'static str
+}
+
+pub static BUILTINS: [BuiltinFunction; 1] = [
+    BuiltinFunction {
+        name: "label_count",
+        parameters: &[BuiltinParameter::Label],
+        returns: MagicVariableType::Number,
+        description: "How many times the labeled block was shown, or how many times the labeled choice was picked."
+    }
+];
+
+pub fn get_builtin(name: &str) -> Option<&'static BuiltinFunction> {
+    BUILTINS.iter().find(|builtin| builtin.name == name)
+}
+
+/// Every label in the project, mapped by their canonical name to the paths they can be used from.
+#[derive(Clone, Debug, Default)]
+pub struct LabelTable {
+    labels: FxHashMap<String, Vec<String>>
+}
+
+impl LabelTable {
+
+    pub fn add_header(&mut self, header: &ASTHeader) {
+        walk_header(header, "", self);
+    }
+
+    /// Takes out the labels [Self::add_header] added for `header`.
+    pub fn remove_header(&mut self, header: &ASTHeader) {
+        let mut removed = Self::default();
+        removed.add_header(header);
+        for (name, scopes) in removed.labels {
+            let Some(current) = self.labels.get_mut(&name) else { continue };
+            for scope in scopes {
+                if let Some(ind) = current.iter().position(|visible_from| *visible_from == scope) {
+                    current.swap_remove(ind);
+                }
+            }
+            if current.is_empty() {
+                self.labels.remove(&name);
+            }
+        }
+    }
+
+    /// If a label called `name` (not canonicalized) can be used in `path`.
+    pub fn is_visible(&self, name: &str, path: &str) -> bool {
+        self.labels.get(&Path::canonicalize_name(name)).is_some_and(|scopes| scopes.iter().any(|visible_from| is_label_visible(visible_from, path)))
+    }
+
+    fn add<T: WithAttributes>(&mut self, block: &T, path: &str) {
+        if let Some(label) = block.get_attribute_n("Label", 0) {
+            self.labels.entry(Path::canonicalize_name(label)).or_default().push(label_visible_from(path, block.get_attribute_n("Label", 1)));
+        }
+    }
+
+}
+
+impl BlockVisitor for LabelTable {
+    fn visit_block(&mut self, block: &ASTBlock, path: &str, _position: &str) {
+        self.add(block, path);
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, path: &str, _position: &str) {
+        self.add(choice, path);
+    }
+}
+
+/// Checks the calls to builtin functions in `expressions`: the number of arguments, and that label
+/// arguments name a label which can be used in `path`. `offset` is where the javascript starts in the file.
+pub fn check_builtins(input: &InputPresenter, expressions: &[ASTExpression], offset: usize, labels: &LabelTable, path: &str) -> Vec<Diagnostic> {
+    let mut checker = BuiltinChecker { input, offset, labels, path, diagnostics: vec![] };
+    checker.list(expressions);
+    checker.diagnostics
+}
+
+struct BuiltinChecker<'a> {
+    input: &'a InputPresenter<'a>,
+    offset: usize,
+    labels: &'a LabelTable,
+    path: &'a str,
+    diagnostics: Vec<Diagnostic>
+}
+
+impl<'a> BuiltinChecker<'a> {
+
+    fn range(&self, range: &Range<usize>) -> Range<usize> {
+        Range::new(self.offset + range.start, self.offset + range.end)
+    }
+
+    fn check_call(&mut self, call: &ASTCall) {
+        let builtin = match &call.expression {
+            ASTExpression::Identifier(ident) => if let Some(builtin) = get_builtin(self.input.from_range(&ident.range)) { builtin } else { return },
+            _ => return
+        };
+        if call.arguments.len() != builtin.parameters.len() {
+            self.diagnostics.push(dia!(WRONG_ARGUMENT_COUNT, self.range(&call.range), builtin.name, &builtin.parameters.len().to_string(), &call.arguments.len().to_string()));
+            return;
+        }
+        for (argument, parameter) in call.arguments.iter().zip(builtin.parameters) {
+            match (parameter, argument) {
+                (BuiltinParameter::Label, ASTExpression::String(string)) => {
+                    let name = self.input.from_range(&Range::new(string.range.start + 1, string.range.end - 1));
+                    if !self.labels.is_visible(name, self.path) {
+                        self.diagnostics.push(dia!(UNKNOWN_LABEL, self.range(&string.range), name));
+                    }
+                },
+                (BuiltinParameter::Label, _) => self.diagnostics.push(dia!(NOT_A_LABEL_NAME, self.range(argument.range()), builtin.name, builtin.name))
+            }
+        }
+    }
+
+}
+
+impl<'a> Visitor for BuiltinChecker<'a> {
+    fn expression(&mut self, exp: &ASTExpression) {
+        match exp {
+            ASTExpression::Call(call) => self.check_call(call),
+            // The accessor isn't one of the children of an access
+            ASTExpression::Access(access) => if let ASTAccessContent::Expression(accessor) = &access.accessor {
+                self.expression(accessor);
+            },
+            _ => {}
+        }
+        exp.visit_each_child(self);
+    }
+
+    fn list<T: Visitable>(&mut self, exp: &[T]) {
+        for item in exp {
+            item.visit(self);
+        }
+    }
+
+    fn string_template_part(&mut self, exp: &ASTStringTemplatePart) {
+        exp.visit_each_child(self);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_js_parser::JsParser;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn check_label_count() {
+        let (blocks, _) = Parser::parse("
+# Main
+- #[Label(Choice1)] 4
+- #[Label(Choice2, global)] 5
+## Second
+#[Label(Hidden)]
+A paragraph.
+", 1);
+        let mut labels = LabelTable::default();
+        if let ASTBlock::Header(header) = &blocks[0] {
+            labels.add_header(header);
+        }
+        let check = |code: &str, path: &str| {
+            let (expressions, diagnostics, input) = JsParser::parse(code);
+            assert!(diagnostics.is_empty());
+            check_builtins(&input, &expressions, 0, &labels, path).into_iter().map(|dia| dia.msg).collect::<Vec<String>>()
+        };
+        assert!(check("!!label_count(\"Choice1\") && label_count(\"choice2\") > 1", "main.second").is_empty());
+        assert!(check("label_count(\"Hidden\")", "main.second").is_empty());
+        assert_eq!(check("label_count(\"Hidden\")", "main"), vec!["There's no label 'Hidden' which can be used from here."]);
+        assert_eq!(check("x = label_count(choice, 1)", "main"), vec!["'label_count' takes 1 argument(s), but 2 were given."]);
+        // Calls are found anywhere in an expression
+        assert_eq!(check("[a[label_count(\"Hidden\")], `$(label_count(\"Hidden\"))`, f(label_count())]", "main").len(), 3);
+        assert_eq!(check("label_count(choice)", "main"), vec!["The argument of 'label_count' has to be the name of a label, like 'label_count(\"MyChoice\")'."]);
+    }
+}
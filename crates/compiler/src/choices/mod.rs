@@ -0,0 +1,142 @@
+use rustc_hash::{FxHashMap, FxHashSet};
+use storytell_diagnostics::{diagnostic::*, make_diagnostics, dia};
+use storytell_parser::ast::model::*;
+use crate::localization::block_id;
+use crate::visitors::{BlockVisitor, walk_header};
+
+/// Choices with this attribute can only be picked once.
+pub const ONCE_ATTRIBUTE: &str = "once";
+/// Choice groups with this attribute are shown again after a choice is picked,
+/// until every choice has been picked or a choice leads somewhere else.
+pub const EXHAUST_ATTRIBUTE: &str = "exhaust";
+/// A misspelling of [EXHAUST_ATTRIBUTE] which older stories use. It still works,
+/// but it's reported.
+const EXHAUST_TYPO: &str = "exaust";
+
+make_diagnostics!(define [
+    MISSPELLED_EXHAUST,
+    "'$' is spelled '$'."
+], [
+    MISPLACED_ONCE,
+    "The '$' attribute only works on choices."
+], [
+    MISPLACED_EXHAUST,
+    "The '$' attribute only works on choice groups."
+], [
+    DUPLICATE_CHOICE_ID,
+    "Another choice already has the id '$', so picking one of them counts for both. Give one of them another label."
+]);
+
+pub fn is_once(choice: &ASTChoice) -> bool {
+    choice.has_attribute(ONCE_ATTRIBUTE)
+}
+
+pub fn is_exhaust(group: &ASTChoiceGroup) -> bool {
+    group.has_attribute(EXHAUST_ATTRIBUTE) || group.has_attribute(EXHAUST_TYPO)
+}
+
+/// Gives every choice inside a header an id, mapped by the start of the choice's range,
+/// and checks where the `once` and `exhaust` attributes are used.
+///
+/// The ids are the same as the ids of translatable strings: `path@label` for choices with
+/// a label, and `path:position` otherwise. A runtime can use them to remember which choices
+/// were picked. Only labels keep them stable, the position changes whenever a block is added
+/// or removed before the choice. Choices with the same label in the same path get the same id,
+/// which is reported.
+#[derive(Default)]
+pub struct ChoiceChecker {
+    pub ids: FxHashMap<usize, String>,
+    pub diagnostics: Vec<Diagnostic>,
+    taken: FxHashSet<String>
+}
+
+impl ChoiceChecker {
+
+    pub fn run(mut self, header: &ASTHeader) -> (FxHashMap<usize, String>, Vec<Diagnostic>) {
+        walk_header(header, "", &mut self);
+        (self.ids, self.diagnostics)
+    }
+
+    fn check(&mut self, attributes: &[ASTAttribute], is_choice: bool, is_group: bool) {
+        for attribute in attributes {
+            match attribute.name.as_str() {
+                ONCE_ATTRIBUTE if !is_choice => self.diagnostics.push(dia!(MISPLACED_ONCE, attribute.range.clone(), Warning, ONCE_ATTRIBUTE)),
+                EXHAUST_ATTRIBUTE | EXHAUST_TYPO if !is_group => self.diagnostics.push(dia!(MISPLACED_EXHAUST, attribute.range.clone(), Warning, EXHAUST_ATTRIBUTE)),
+                _ => {}
+            }
+            if attribute.name == EXHAUST_TYPO {
+                self.diagnostics.push(dia!(MISSPELLED_EXHAUST, attribute.range.clone(), Warning, EXHAUST_TYPO, EXHAUST_ATTRIBUTE));
+            }
+        }
+    }
+
+}
+
+impl BlockVisitor for ChoiceChecker {
+    fn visit_header(&mut self, header: &ASTHeader, _path: &str) {
+        self.check(&header.attributes, false, false);
+    }
+
+    fn visit_block(&mut self, block: &ASTBlock, _path: &str, _position: &str) {
+        let (attributes, is_group) = match block {
+            ASTBlock::Paragraph(paragraph) => (&paragraph.attributes, false),
+            ASTBlock::CodeBlock(code) => (&code.attributes, false),
+            ASTBlock::ChoiceGroup(group) => (&group.attributes, true),
+            ASTBlock::Divert(divert) => (&divert.attributes, false),
+            ASTBlock::Match(match_block) => (&match_block.attributes, false),
+            ASTBlock::Header(header) => (&header.attributes, false),
+            ASTBlock::Error(error) => (&error.attributes, false)
+        };
+        self.check(attributes, false, is_group);
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, path: &str, position: &str) {
+        self.check(&choice.attributes, true, false);
+        let id = block_id(choice, path, position);
+        if !self.taken.insert(id.clone()) {
+            let range = choice.attributes.iter().find(|attribute| attribute.name == "Label").map_or(&choice.range, |attribute| &attribute.range);
+            self.diagnostics.push(dia!(DUPLICATE_CHOICE_ID, range.clone(), Warning, &id));
+        }
+        self.ids.insert(choice.range.start, id);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn check_choices() {
+        let (blocks, _) = Parser::parse("
+# Graveyard
+#[exaust]
+- #[once] Left
+    #[once]
+    You went left!
+- #[Label(Right)] Right
+    #[exhaust]
+    - #[exhaust] Up
+    - Down
+    - #[Label(Right)] Again
+", 1);
+        let header = if let ASTBlock::Header(header) = &blocks[0] { header } else { unreachable!() };
+        let (ids, diagnostics) = ChoiceChecker::default().run(header);
+        let mut ids = ids.into_values().collect::<Vec<String>>();
+        ids.sort();
+        assert_eq!(ids, vec!["graveyard:0.0", "graveyard:0.1.0.0", "graveyard:0.1.0.1", "graveyard@right", "graveyard@right"]);
+        assert_eq!(diagnostics.iter().map(|dia| dia.msg.as_str()).collect::<Vec<&str>>(), vec![
+            "'exaust' is spelled 'exhaust'.",
+            "The 'once' attribute only works on choices.",
+            "The 'exhaust' attribute only works on choice groups.",
+            "Another choice already has the id 'graveyard@right', so picking one of them counts for both. Give one of them another label."
+        ]);
+        if let ASTBlock::ChoiceGroup(group) = &header.children[0] {
+            assert!(is_exhaust(group));
+            assert!(is_once(&group.choices[0]));
+            assert!(!is_once(&group.choices[1]));
+        } else {
+            unreachable!()
+        }
+    }
+}
@@ -0,0 +1,298 @@
+use storytell_parser::ast::{model::*, Parser};
+use crate::json;
+use crate::json_compiler::compile::JSONSafeCompilable;
+use crate::path::{Path, SpecialTarget};
+use crate::visitors::{BlockVisitor, MagicVariableCollectorContext, MagicVariableType, walk_blocks};
+
+/// Attributes the compiler and the front-end know about, with a short description.
+pub const ATTRIBUTES: [(&str, &str); 4] = [
+    ("Label", "Label(name, scope?) - makes the block reachable with a divert."),
+    ("Include", "The divert shows the path's content and then continues after it."),
+    ("once", "The choice can only be picked once."),
+    ("exhaust", "The choices in the group disappear after being picked.")
+];
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum CompletionKind {
+    Path,
+    Label,
+    Variable,
+    Field,
+    Attribute
+}
+
+impl CompletionKind {
+    pub fn get_id(&self) -> u8 {
+        match self {
+            Self::Path => 0,
+            Self::Label => 1,
+            Self::Variable => 2,
+            Self::Field => 3,
+            Self::Attribute => 4
+        }
+    }
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Completion {
+    pub label: String,
+    pub kind: CompletionKind,
+    /// The title of a path, the type of a variable, or the description of an attribute.
+    pub detail: String
+}
+
+impl JSONSafeCompilable for Completion {
+    fn safe_compile(&self) -> String {
+        json!({
+            label: self.label.safe_compile(),
+            kind: self.kind.get_id(),
+            detail: self.detail.safe_compile()
+        })
+    }
+}
+
+/// What the rest of the project knows about.
+pub struct CompletionContext<'a> {
+    /// The parsed content of every other file in the project.
+    pub files: Vec<&'a [ASTBlock]>,
+    pub variables: &'a MagicVariableCollectorContext
+}
+
+/// Returns what can be typed at `offset` in `text`:
+/// - Path names after `->`. Children and siblings of the current path can be used by their name,
+///   any other path has to be accessed from the top-level path. Only top-level paths after `-> /`.
+/// - Magic variables inside `{}`, or an object's fields after `variable.`
+/// - Attribute names after `#[`
+pub fn complete(text: &str, offset: usize, line_endings: usize, ctx: &CompletionContext) -> Vec<Completion> {
+    let mut offset = offset.min(text.len());
+    while !text.is_char_boundary(offset) {
+        offset -= 1;
+    }
+    let before = &text[..offset];
+    let line = &before[before.rfind('\n').map(|ind| ind + 1).unwrap_or_default()..];
+    let mut result = if let Some(typed) = typed_after(line, "#[").filter(|typed| !typed.contains('(')) {
+        let name = typed.rsplit(',').next().unwrap_or_default().trim();
+        if is_identifier(name) {
+            ATTRIBUTES.iter().map(|(name, detail)| Completion { label: name.to_string(), kind: CompletionKind::Attribute, detail: detail.to_string() }).collect()
+        } else {
+            vec![]
+        }
+    } else if let Some(typed) = typed_after(line, "->").map(|typed| typed.trim_start()).filter(|typed| typed.trim_start_matches('/').chars().all(|character| is_identifier_char(character) || character == '.')) {
+        let (blocks, _) = Parser::parse(text, line_endings);
+        match typed.strip_prefix('/') {
+            Some(typed) => complete_divert(&blocks, offset, typed, true, ctx),
+            None => complete_divert(&blocks, offset, typed, false, ctx)
+        }
+    } else if line.matches('{').count() > line.matches('}').count() {
+        let start = line.rfind(|character: char| !(is_identifier_char(character) || character == '.')).map(|ind| ind + 1).unwrap_or_default();
+        complete_variable(&line[start..], ctx.variables)
+    } else {
+        vec![]
+    };
+    result.sort_by(|a, b| a.label.cmp(&b.label));
+    result.dedup();
+    result
+}
+
+fn is_identifier_char(character: char) -> bool {
+    character.is_alphanumeric() || character == '_' || character == '$'
+}
+
+fn is_identifier(text: &str) -> bool {
+    text.chars().all(is_identifier_char)
+}
+
+/// The text after the last occurence of `pattern` in the line.
+fn typed_after<'a>(line: &'a str, pattern: &str) -> Option<&'a str> {
+    line.rfind(pattern).map(|ind| &line[ind + pattern.len()..])
+}
+
+fn path_completions<'a>(path: &'a Path, titles: &'a [(String, String)]) -> impl Iterator<Item = Completion> + 'a {
+    path.children.keys().map(move |name| Completion {
+        label: name.clone(),
+        kind: CompletionKind::Path,
+        detail: titles.iter().find(|(canonical, _)| canonical == name).map(|(_, title)| title.clone()).unwrap_or_default()
+    })
+}
+
+#[derive(Default)]
+struct LabelCollector {
+    /// Canonical name, original name and whether the label is global.
+    labels: Vec<(String, String, bool)>,
+    /// Canonical names of headers mapped to their titles.
+    titles: Vec<(String, String)>
+}
+
+impl LabelCollector {
+    fn collect<T: WithAttributes>(&mut self, block: &T) {
+        if let Some(label) = block.get_attribute_n("Label", 0) {
+            self.labels.push((Path::canonicalize_name(label), label.to_string(), block.get_attribute_n("Label", 1).map(|scope| scope.trim()) == Some("global")));
+        }
+    }
+}
+
+impl BlockVisitor for LabelCollector {
+    fn visit_header(&mut self, header: &ASTHeader, _path: &str) {
+        self.titles.push((Path::canonicalize_name(&header.title.text), header.title.text.clone()));
+    }
+
+    fn visit_block(&mut self, block: &ASTBlock, _path: &str, _position: &str) {
+        match block {
+            ASTBlock::Paragraph(paragraph) => self.collect(paragraph),
+            ASTBlock::ChoiceGroup(group) => self.collect(group),
+            ASTBlock::CodeBlock(code) => self.collect(code),
+            ASTBlock::Match(match_block) => self.collect(match_block),
+            ASTBlock::Divert(divert) => self.collect(divert),
+            ASTBlock::Header(_) | ASTBlock::Error(_) => {}
+        }
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, _path: &str, _position: &str) {
+        self.collect(choice);
+    }
+}
+
+fn complete_divert(blocks: &[ASTBlock], offset: usize, typed: &str, absolute: bool, ctx: &CompletionContext) -> Vec<Completion> {
+    let mut root = Path::new("");
+    let mut collector = LabelCollector::default();
+    for file in &ctx.files {
+        for block in file.iter() {
+            if let ASTBlock::Header(header) = block {
+                root.add_child_ast(header);
+            }
+        }
+        walk_blocks(file, &mut collector);
+    }
+    // Labels from other files are only visible if they're global
+    collector.labels.retain(|(_, _, global)| *global);
+    for block in blocks {
+        if let ASTBlock::Header(header) = block {
+            root.add_child_ast(header);
+        }
+    }
+    walk_blocks(blocks, &mut collector);
+    let mut current = vec![];
+    let mut headers = blocks;
+    while let Some(header) = headers.iter().find_map(|block| match block {
+        ASTBlock::Header(header) if header.range.start <= offset && offset <= header.range.end => Some(header),
+        _ => None
+    }) {
+        current.push(Path::canonicalize_name(&header.title.text));
+        headers = &header.children;
+    }
+    let access = typed.split('.').map(|part| part.to_string()).collect::<Vec<String>>();
+    let base = &access[..access.len() - 1];
+    // The same places diverts are resolved in, see [Path::resolve]
+    let scopes: Vec<&[String]> = if absolute || current.is_empty() {
+        vec![&[]]
+    } else {
+        vec![&current, &current[..current.len() - 1], &[]]
+    };
+    let mut result = vec![];
+    for scope in scopes {
+        let full = [scope, base].concat();
+        let path = if full.is_empty() { Some(&root) } else { root.get_child_by_path(&full) };
+        if let Some(path) = path {
+            result.extend(path_completions(path, &collector.titles));
+        }
+    }
+    if base.is_empty() && !absolute {
+        result.extend([SpecialTarget::End, SpecialTarget::Back].into_iter().map(|special| Completion {
+            label: special.name().to_string(),
+            kind: CompletionKind::Path,
+            detail: match special {
+                SpecialTarget::End => "Finishes the story.",
+                SpecialTarget::Back => "Goes back to the previous choice group."
+            }.to_string()
+        }));
+        result.extend(collector.labels.iter().map(|(name, label, _)| Completion { label: name.clone(), kind: CompletionKind::Label, detail: label.clone() }));
+    }
+    result
+}
+
+fn complete_variable(typed: &str, variables: &MagicVariableCollectorContext) -> Vec<Completion> {
+    let access = typed.split('.').collect::<Vec<&str>>();
+    let mut object = &variables.variables;
+    for name in &access[..access.len() - 1] {
+        match object.get(*name) {
+            Some(MagicVariableType::ObjectRef(id)) => match variables.objects.get(id) {
+                Some(found) => object = found,
+                None => return vec![]
+            },
+            _ => return vec![]
+        }
+    }
+    let kind = if access.len() == 1 { CompletionKind::Variable } else { CompletionKind::Field };
+    object.iter().map(|(name, var_type)| Completion { label: name.clone(), kind, detail: var_type.to_string() }).collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::json_compiler::{JSONCompilerProvider, JSONCompilerContext};
+    use crate::base::compile_str;
+
+    fn labels(completions: &[Completion]) -> Vec<&str> {
+        completions.iter().map(|completion| completion.label.as_str()).collect()
+    }
+
+    #[test]
+    fn complete_everything() {
+        let (other, _) = Parser::parse("
+# Epilogue
+#[Label(Credits, global)]
+The end.
+
+## After Credits
+", 1);
+        let (_, _, compiled) = compile_str::<JSONCompilerProvider>("
+# Setup
+{player.name = \"James\"} {player.stats.hp = 10} {day = 1}
+", JSONCompilerContext::new(None), 1);
+        let ctx = CompletionContext {
+            files: vec![&other],
+            variables: &compiled.magic_variables
+        };
+        let complete_at = |text: &str| {
+            let offset = text.find('|').unwrap();
+            labels(&complete(&text.replace('|', ""), offset, 1, &ctx)).into_iter().map(|label| label.to_string()).collect::<Vec<String>>()
+        };
+        let story = "
+# Main Path
+-> {}
+
+## Child 1
+-> {}
+
+### Child of child
+-> {}
+";
+        let with_cursor = |ind: usize, typed: &str| {
+            let mut parts = story.split("{}");
+            let mut result = String::new();
+            for part_ind in 0..3 {
+                result.push_str(parts.next().unwrap());
+                if part_ind == ind {
+                    result.push_str(typed);
+                    result.push('|');
+                }
+            }
+            result.push_str(parts.next().unwrap());
+            result
+        };
+        assert_eq!(complete_at(&with_cursor(0, "")), vec!["BACK", "END", "child_1", "credits", "epilogue", "main_path"]);
+        assert_eq!(complete_at(&with_cursor(1, "")), vec!["BACK", "END", "child_1", "child_of_child", "credits", "epilogue", "main_path"]);
+        assert_eq!(complete_at(&with_cursor(1, "main_path.")), vec!["child_1"]);
+        assert_eq!(complete_at(&with_cursor(1, "/")), vec!["epilogue", "main_path"]);
+        assert_eq!(complete_at(&with_cursor(1, "/child_1.")), Vec::<String>::new());
+        assert_eq!(complete_at(&with_cursor(2, "")), vec!["BACK", "END", "child_of_child", "credits", "epilogue", "main_path"]);
+        assert_eq!(complete_at(&with_cursor(2, "epilogue.")), vec!["after_credits"]);
+        let fields = complete("# A\n{player.stats.", 18, 1, &ctx);
+        assert_eq!(fields, vec![Completion { label: "hp".to_string(), kind: CompletionKind::Field, detail: "number".to_string() }]);
+        assert_eq!(complete_at("# A\n{player.|}"), vec!["name", "stats"]);
+        assert_eq!(complete_at("# A\nSome text {d|"), vec!["day", "player"]);
+        assert_eq!(complete_at("# A\n#[La|"), vec!["Include", "Label", "exhaust", "once"]);
+        assert_eq!(complete_at("# A\n- #[once, |"), vec!["Include", "Label", "exhaust", "once"]);
+        assert!(complete_at("# Ma|in").is_empty());
+    }
+}
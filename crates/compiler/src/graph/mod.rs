@@ -0,0 +1,571 @@
+use std::fmt::Write as _;
+use rustc_hash::{FxHashMap, FxHashSet};
+use storytell_diagnostics::location::Range;
+use storytell_parser::ast::model::*;
+use crate::base::files::BlobId;
+use crate::json;
+use crate::json_compiler::compile::JSONSafeCompilable;
+use crate::path::{Path, SpecialTarget, label_visible_from, is_label_visible};
+use crate::includes::INCLUDE_ATTRIBUTE;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GraphNodeKind {
+    Path,
+    Label,
+    Choice,
+    /// The special `end` path.
+    End,
+    /// The special `back` path, which goes back to the previous choice group.
+    Back
+}
+
+impl GraphNodeKind {
+    pub fn get_id(&self) -> u8 {
+        match self {
+            Self::Path => 0,
+            Self::Label => 1,
+            Self::Choice => 2,
+            Self::End => 3,
+            Self::Back => 4
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Hash)]
+pub enum GraphEdgeKind {
+    /// A `->` divert.
+    Divert,
+    /// A choice which is presented to the reader.
+    Choice,
+    /// The content after a choice group, reached once a choice's children are over.
+    FallThrough,
+    /// An `#[Include]` divert, the flow continues after it once the included path is over.
+    Include
+}
+
+impl GraphEdgeKind {
+    pub fn get_id(&self) -> u8 {
+        match self {
+            Self::Divert => 0,
+            Self::Choice => 1,
+            Self::FallThrough => 2,
+            Self::Include => 3
+        }
+    }
+}
+
+/// Node ids follow the same format as the ids of translatable strings:
+/// - Paths: `chapter_1.left`
+/// - Labels: `chapter_1.left@label_name`
+/// - Choices: `chapter_1.left:2.0`
+#[derive(Clone, Debug)]
+pub struct GraphNode {
+    pub id: String,
+    pub kind: GraphNodeKind,
+    /// Header title, choice text or label name.
+    pub text: String,
+    /// The canonical path the node is in.
+    pub path: String,
+    pub file: BlobId,
+    pub range: Range<usize>
+}
+
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
+pub struct GraphEdge {
+    pub from: usize,
+    pub to: usize,
+    pub kind: GraphEdgeKind
+}
+
+/// A divert whose target couldn't be found.
+#[derive(Clone, Debug)]
+pub struct UnresolvedDivert {
+    pub from: usize,
+    pub target: String,
+    pub file: BlobId,
+    pub range: Range<usize>
+}
+
+/// A divert in the source, and the node it points to.
+#[derive(Clone, Debug)]
+pub struct DivertReference {
+    pub from: usize,
+    pub to: Option<usize>,
+    pub file: BlobId,
+    pub range: Range<usize>
+}
+
+#[derive(Default, Debug)]
+pub struct StoryGraph {
+    pub nodes: Vec<GraphNode>,
+    pub edges: Vec<GraphEdge>,
+    pub unresolved: Vec<UnresolvedDivert>,
+    pub diverts: Vec<DivertReference>,
+    pub node_ids: FxHashMap<String, usize>
+}
+
+impl StoryGraph {
+
+    pub fn get(&self, id: &str) -> Option<&GraphNode> {
+        Some(&self.nodes[*self.node_ids.get(id)?])
+    }
+
+    pub fn outgoing(&self, node: usize) -> impl Iterator<Item = &GraphEdge> {
+        self.edges.iter().filter(move |edge| edge.from == node)
+    }
+
+    pub fn incoming(&self, node: usize) -> impl Iterator<Item = &GraphEdge> {
+        self.edges.iter().filter(move |edge| edge.to == node)
+    }
+
+    /// {
+    ///     nodes: { id: string, kind: number, text: string, path: string, file: number, range: Range }[],
+    ///     edges: { from: number, to: number, kind: number }[],
+    ///     unresolved: { from: number, target: string, file: number, range: Range }[]
+    /// }
+    ///
+    /// Edges and unresolved diverts refer to nodes by their index.
+    pub fn to_json(&self) -> String {
+        json!({
+            nodes: format!("[{}]", self.nodes.iter().map(|node| json!({
+                id: node.id.safe_compile(),
+                kind: node.kind.get_id(),
+                text: node.text.safe_compile(),
+                path: node.path.safe_compile(),
+                file: node.file,
+                range: node.range.safe_compile()
+            })).collect::<Vec<String>>().join(",")),
+            edges: format!("[{}]", self.edges.iter().map(|edge| json!({
+                from: edge.from,
+                to: edge.to,
+                kind: edge.kind.get_id()
+            })).collect::<Vec<String>>().join(",")),
+            unresolved: format!("[{}]", self.unresolved.iter().map(|divert| json!({
+                from: divert.from,
+                target: divert.target.safe_compile(),
+                file: divert.file,
+                range: divert.range.safe_compile()
+            })).collect::<Vec<String>>().join(","))
+        })
+    }
+
+    /// Graphviz representation of the graph. Paths are boxes, labels are ellipses,
+    /// choices are plain text. Fall-through edges are dashed, includes are dotted.
+    pub fn to_dot(&self) -> String {
+        let mut result = String::from("digraph story {\n");
+        for node in &self.nodes {
+            let shape = match node.kind {
+                GraphNodeKind::Path => "box",
+                GraphNodeKind::Label => "ellipse",
+                GraphNodeKind::Choice => "plaintext",
+                GraphNodeKind::End => "doublecircle",
+                GraphNodeKind::Back => "circle"
+            };
+            writeln!(result, "    \"{}\" [label=\"{}\", shape={}];", escape_dot(&node.id), escape_dot(&node.text), shape).unwrap();
+        }
+        for edge in &self.edges {
+            let style = match edge.kind {
+                GraphEdgeKind::Divert => "solid",
+                GraphEdgeKind::Choice => "bold",
+                GraphEdgeKind::FallThrough => "dashed",
+                GraphEdgeKind::Include => "dotted"
+            };
+            writeln!(result, "    \"{}\" -> \"{}\" [style={}];", escape_dot(&self.nodes[edge.from].id), escape_dot(&self.nodes[edge.to].id), style).unwrap();
+        }
+        result.push_str("}\n");
+        result
+    }
+
+}
+
+fn escape_dot(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n")
+}
+
+struct LabelDefinition {
+    /// Diverts from this path (or any of its children) can see the label.
+    /// An empty string means the label is global.
+    visible_from: String,
+    node: usize
+}
+
+#[derive(Clone, Copy, PartialEq, Eq, Hash)]
+struct Exit {
+    to: usize,
+    kind: GraphEdgeKind
+}
+
+/// Builds a [StoryGraph] out of multiple files. All files have to be added with
+/// [GraphBuilder::add_file] before calling [GraphBuilder::build], so diverts across
+/// files can be resolved.
+#[derive(Default)]
+pub struct GraphBuilder<'a> {
+    graph: StoryGraph,
+    /// Every path in the project, for resolving diverts.
+    root: Path,
+    labels: FxHashMap<String, Vec<LabelDefinition>>,
+    files: Vec<(BlobId, &'a [ASTBlock])>
+}
+
+impl<'a> GraphBuilder<'a> {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn add_file(&mut self, file: BlobId, blocks: &'a [ASTBlock]) {
+        for block in blocks {
+            if let ASTBlock::Header(header) = block {
+                self.root.add_child_ast(header);
+                self.register_header(header, "", file);
+            }
+        }
+        self.files.push((file, blocks));
+    }
+
+    pub fn build(mut self) -> StoryGraph {
+        let files = std::mem::take(&mut self.files);
+        let mut edges: FxHashSet<GraphEdge> = FxHashSet::default();
+        for (file, blocks) in files {
+            for block in blocks {
+                if let ASTBlock::Header(header) = block {
+                    self.connect_header(header, "", file, &mut edges);
+                }
+            }
+        }
+        self.graph.edges = edges.into_iter().collect();
+        self.graph.edges.sort_by_key(|edge| (edge.from, edge.to, edge.kind.get_id()));
+        self.graph
+    }
+
+    fn add_node(&mut self, id: String, kind: GraphNodeKind, text: String, path: &str, file: BlobId, range: Range<usize>) -> usize {
+        let index = self.graph.nodes.len();
+        self.graph.node_ids.insert(id.clone(), index);
+        self.graph.nodes.push(GraphNode {
+            id,
+            kind,
+            text,
+            path: path.to_string(),
+            file,
+            range
+        });
+        index
+    }
+
+    fn register_label<T: WithAttributes>(&mut self, block: &T, path: &str, file: BlobId, range: &Range<usize>) -> Option<usize> {
+        let label = block.get_attribute_n("Label", 0)?;
+        let name = Path::canonicalize_name(label);
+        let visible_from = label_visible_from(path, block.get_attribute_n("Label", 1));
+        let node = self.add_node(format!("{}@{}", path, name), GraphNodeKind::Label, label.to_string(), path, file, range.clone());
+        self.labels.entry(name).or_default().push(LabelDefinition { visible_from, node });
+        Some(node)
+    }
+
+    fn register_header(&mut self, header: &ASTHeader, parent_path: &str, file: BlobId) {
+        let name = Path::canonicalize_name(&header.title.text);
+        let path = if parent_path.is_empty() { name } else { format!("{}.{}", parent_path, name) };
+        self.add_node(path.clone(), GraphNodeKind::Path, header.title.text.clone(), &path, file, header.range.clone());
+        let mut position = 0;
+        for child in &header.children {
+            if let ASTBlock::Header(sub_header) = child {
+                self.register_header(sub_header, &path, file);
+            } else {
+                self.register_block(child, &path, &position.to_string(), file);
+                position += 1;
+            }
+        }
+    }
+
+    fn register_block(&mut self, block: &ASTBlock, path: &str, position: &str, file: BlobId) {
+        match block {
+            ASTBlock::Paragraph(paragraph) => { self.register_label(paragraph, path, file, &paragraph.range); },
+            ASTBlock::CodeBlock(code) => { self.register_label(code, path, file, &code.range); },
+            ASTBlock::Divert(divert) => { self.register_label(divert, path, file, &divert.range); },
+            ASTBlock::Error(_) => {},
+            ASTBlock::ChoiceGroup(group) => {
+                self.register_label(group, path, file, &group.range);
+                for (ind, choice) in group.choices.iter().enumerate() {
+                    let choice_position = format!("{}.{}", position, ind);
+                    let node = self.add_node(format!("{}:{}", path, choice_position), GraphNodeKind::Choice, choice.text.to_raw().trim().to_string(), path, file, choice.range.clone());
+                    if let Some(label) = choice.get_attribute_n("Label", 0) {
+                        // Labelled choices are the same node as the choice itself
+                        self.labels.entry(Path::canonicalize_name(label)).or_default().push(LabelDefinition { visible_from: label_visible_from(path, choice.get_attribute_n("Label", 1)), node });
+                    }
+                    for (child_ind, child) in choice.children.iter().enumerate() {
+                        self.register_block(child, path, &format!("{}.{}", choice_position, child_ind), file);
+                    }
+                }
+            },
+            ASTBlock::Match(match_block) => {
+                self.register_label(match_block, path, file, &match_block.range);
+                for (ind, child) in match_block.direct_children.iter().enumerate() {
+                    self.register_block(child, path, &format!("{}.{}", position, ind), file);
+                }
+                for (ind, arm) in match_block.choices.iter().enumerate() {
+                    for (child_ind, child) in arm.children.iter().enumerate() {
+                        self.register_block(child, path, &format!("{}.{}.{}", position, ind, child_ind), file);
+                    }
+                }
+            },
+            ASTBlock::Header(header) => self.register_header(header, path, file)
+        }
+    }
+
+    /// The index of the node of the label called `name` (canonicalized) which can be used in `current_path`.
+    /// Only labels of files which were already added are found.
+    pub fn resolve_label(&self, current_path: &str, name: &str) -> Option<usize> {
+        self.labels.get(name)?.iter().find(|label| is_label_visible(&label.visible_from, current_path)).map(|label| label.node)
+    }
+
+    /// Diverts can point to `END` or `BACK`, labels, or paths (see [Path::resolve]). When more than one path
+    /// matches, the divert goes to the first one.
+    fn resolve_divert(&mut self, current_path: &str, divert: &ASTDivert) -> Option<usize> {
+        let target = &divert.path;
+        if target.is_empty() {
+            return None;
+        }
+        if let Some(special) = SpecialTarget::from_path(target) {
+            let id = special.name().to_lowercase();
+            let kind = match special {
+                SpecialTarget::End => GraphNodeKind::End,
+                SpecialTarget::Back => GraphNodeKind::Back
+            };
+            return Some(match self.graph.node_ids.get(&id) {
+                Some(node) => *node,
+                None => self.add_node(id, kind, special.name().to_string(), "", 0, Range::default())
+            });
+        }
+        if target.len() == 1 && !divert.absolute {
+            if let Some(label) = self.resolve_label(current_path, &target[0]) {
+                return Some(label);
+            }
+        }
+        let current = current_path.split('.').map(|part| part.to_string()).collect::<Vec<String>>();
+        let full = self.root.resolve(&current, target, divert.absolute).into_iter().next()?;
+        self.graph.node_ids.get(&full.join(".")).copied()
+    }
+
+    /// Included paths are always written from the root.
+    fn resolve_include(&self, target: &[String]) -> Option<usize> {
+        let node = *self.graph.node_ids.get(&target.join("."))?;
+        if self.graph.nodes[node].kind == GraphNodeKind::Path { Some(node) } else { None }
+    }
+
+    fn connect_header(&mut self, header: &ASTHeader, parent_path: &str, file: BlobId, edges: &mut FxHashSet<GraphEdge>) {
+        let name = Path::canonicalize_name(&header.title.text);
+        let path = if parent_path.is_empty() { name } else { format!("{}.{}", parent_path, name) };
+        let node = self.graph.node_ids[&path];
+        let mut content = vec![];
+        let mut positions = vec![];
+        for child in &header.children {
+            if let ASTBlock::Header(sub_header) = child {
+                self.connect_header(sub_header, &path, file, edges);
+            } else {
+                positions.push(positions.len().to_string());
+                content.push(child);
+            }
+        }
+        let exits = self.sequence_exits(&content, &positions, 0, &path, file, node, &[], edges);
+        for exit in exits {
+            edges.insert(GraphEdge { from: node, to: exit.to, kind: exit.kind });
+        }
+    }
+
+    /// Returns the nodes the reader can reach by continuing from `blocks[start]`. Also
+    /// connects all choice groups and labels encountered on the way.
+    /// `continuation` is what comes after `blocks` is over.
+    #[allow(clippy::too_many_arguments)]
+    fn sequence_exits(&mut self, blocks: &[&ASTBlock], positions: &[String], start: usize, path: &str, file: BlobId, owner: usize, continuation: &[Exit], edges: &mut FxHashSet<GraphEdge>) -> Vec<Exit> {
+        let mut exits = vec![];
+        for ind in start..blocks.len() {
+            match blocks[ind] {
+                ASTBlock::Divert(divert) if divert.has_attribute(INCLUDE_ATTRIBUTE) => {
+                    let resolved = self.resolve_include(&divert.path);
+                    self.graph.diverts.push(DivertReference { from: owner, to: resolved, file, range: divert.range.clone() });
+                    match resolved {
+                        Some(target) => exits.push(Exit { to: target, kind: GraphEdgeKind::Include }),
+                        None => self.graph.unresolved.push(UnresolvedDivert { from: owner, target: divert.path.join("."), file, range: divert.range.clone() })
+                    }
+                },
+                ASTBlock::Divert(divert) => {
+                    let resolved = self.resolve_divert(path, divert);
+                    self.graph.diverts.push(DivertReference {
+                        from: owner,
+                        to: resolved,
+                        file,
+                        range: divert.range.clone()
+                    });
+                    match resolved {
+                        Some(target) if self.graph.nodes[target].kind == GraphNodeKind::Label => {
+                            // Diverting to a label returns to the content after the divert
+                            exits.push(Exit { to: target, kind: GraphEdgeKind::Divert });
+                        },
+                        Some(target) => {
+                            exits.push(Exit { to: target, kind: GraphEdgeKind::Divert });
+                            self.record_unreachable_diverts(&blocks[ind + 1..], path, file, owner);
+                            return exits;
+                        },
+                        None => {
+                            self.graph.unresolved.push(UnresolvedDivert {
+                                from: owner,
+                                target: divert.path.join("."),
+                                file,
+                                range: divert.range.clone()
+                            });
+                            self.record_unreachable_diverts(&blocks[ind + 1..], path, file, owner);
+                            return exits;
+                        }
+                    }
+                },
+                ASTBlock::ChoiceGroup(group) => {
+                    let after = self.sequence_exits(blocks, positions, ind + 1, path, file, owner, continuation, edges).into_iter()
+                        .map(|exit| Exit { to: exit.to, kind: if exit.kind == GraphEdgeKind::Include { exit.kind } else { GraphEdgeKind::FallThrough } })
+                        .collect::<Vec<Exit>>();
+                    let mut choices = vec![];
+                    for (choice_ind, choice) in group.choices.iter().enumerate() {
+                        let choice_position = format!("{}.{}", positions[ind], choice_ind);
+                        let choice_node = self.graph.node_ids[&format!("{}:{}", path, choice_position)];
+                        let children = choice.children.iter().collect::<Vec<&ASTBlock>>();
+                        let child_positions = (0..children.len()).map(|child_ind| format!("{}.{}", choice_position, child_ind)).collect::<Vec<String>>();
+                        for exit in self.sequence_exits(&children, &child_positions, 0, path, file, choice_node, &after, edges) {
+                            edges.insert(GraphEdge { from: choice_node, to: exit.to, kind: exit.kind });
+                        }
+                        choices.push(Exit { to: choice_node, kind: GraphEdgeKind::Choice });
+                    }
+                    if let Some(label) = group.get_attribute_n("Label", 0) {
+                        let label_node = self.graph.node_ids[&format!("{}@{}", path, Path::canonicalize_name(label))];
+                        for choice in &choices {
+                            edges.insert(GraphEdge { from: label_node, to: choice.to, kind: choice.kind });
+                        }
+                    }
+                    exits.extend(choices);
+                    return exits;
+                },
+                ASTBlock::Match(match_block) => {
+                    let after = self.sequence_exits(blocks, positions, ind + 1, path, file, owner, continuation, edges);
+                    let direct_children = match_block.direct_children.iter().collect::<Vec<&ASTBlock>>();
+                    let direct_positions = (0..direct_children.len()).map(|child_ind| format!("{}.{}", positions[ind], child_ind)).collect::<Vec<String>>();
+                    exits.extend(self.sequence_exits(&direct_children, &direct_positions, 0, path, file, owner, &after, edges));
+                    for (arm_ind, arm) in match_block.choices.iter().enumerate() {
+                        let children = arm.children.iter().collect::<Vec<&ASTBlock>>();
+                        let child_positions = (0..children.len()).map(|child_ind| format!("{}.{}.{}", positions[ind], arm_ind, child_ind)).collect::<Vec<String>>();
+                        exits.extend(self.sequence_exits(&children, &child_positions, 0, path, file, owner, &after, edges));
+                    }
+                    // None of the arms might match
+                    exits.extend(after);
+                    let mut seen = FxHashSet::default();
+                    exits.retain(|exit| seen.insert(*exit));
+                    return exits;
+                },
+                _ => {}
+            }
+        }
+        exits.extend_from_slice(continuation);
+        exits
+    }
+
+    /// Content after a divert can never be reached, but the diverts in it still point somewhere.
+    fn record_unreachable_diverts(&mut self, blocks: &[&ASTBlock], path: &str, file: BlobId, owner: usize) {
+        for block in blocks {
+            match block {
+                ASTBlock::Divert(divert) => {
+                    let to = if divert.has_attribute(INCLUDE_ATTRIBUTE) { self.resolve_include(&divert.path) } else { self.resolve_divert(path, divert) };
+                    self.graph.diverts.push(DivertReference { from: owner, to, file, range: divert.range.clone() });
+                },
+                ASTBlock::ChoiceGroup(group) => {
+                    for choice in &group.choices {
+                        self.record_unreachable_diverts(&choice.children.iter().collect::<Vec<&ASTBlock>>(), path, file, owner);
+                    }
+                },
+                ASTBlock::Match(match_block) => {
+                    self.record_unreachable_diverts(&match_block.direct_children.iter().collect::<Vec<&ASTBlock>>(), path, file, owner);
+                    for arm in &match_block.choices {
+                        self.record_unreachable_diverts(&arm.children.iter().collect::<Vec<&ASTBlock>>(), path, file, owner);
+                    }
+                },
+                _ => {}
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn build_graph() {
+        let (main, _) = Parser::parse("
+# Graveyard
+James and Alicia arrive at the graveyard.
+
+- Left
+    Nothing here.
+- Right
+    -> graveyard_right
+
+-> church.entrance
+
+## Graveyard Right
+#[Label(Grave)]
+- Peek
+    -> END
+- Run
+    -> grave
+", 1);
+        let (other, _) = Parser::parse("
+# Church
+## Entrance
+You enter the church.
+## Porch
+#[Include]
+-> church.entrance
+You leave again.
+-> BACK
+", 1);
+        let mut builder = GraphBuilder::new();
+        builder.add_file(1, &main);
+        builder.add_file(2, &other);
+        let graph = builder.build();
+        assert!(graph.unresolved.is_empty());
+        assert_eq!(graph.diverts.len(), 6);
+        let edge = |from: &str, to: &str| graph.edges.iter().find(|edge| graph.nodes[edge.from].id == from && graph.nodes[edge.to].id == to).map(|edge| edge.kind);
+        assert_eq!(edge("graveyard", "graveyard:1.0"), Some(GraphEdgeKind::Choice));
+        assert_eq!(edge("graveyard", "graveyard:1.1"), Some(GraphEdgeKind::Choice));
+        assert_eq!(edge("graveyard:1.0", "church.entrance"), Some(GraphEdgeKind::FallThrough));
+        assert_eq!(edge("graveyard:1.1", "graveyard.graveyard_right"), Some(GraphEdgeKind::Divert));
+        assert_eq!(edge("graveyard:1.1", "church.entrance"), None);
+        assert_eq!(edge("graveyard", "church.entrance"), None);
+        assert_eq!(edge("graveyard.graveyard_right@grave", "graveyard.graveyard_right:0.0"), Some(GraphEdgeKind::Choice));
+        assert_eq!(edge("graveyard.graveyard_right:0.0", "end"), Some(GraphEdgeKind::Divert));
+        assert_eq!(edge("graveyard.graveyard_right:0.1", "graveyard.graveyard_right@grave"), Some(GraphEdgeKind::Divert));
+        assert_eq!(edge("church.porch", "church.entrance"), Some(GraphEdgeKind::Include));
+        assert_eq!(edge("church.porch", "back"), Some(GraphEdgeKind::Divert));
+        let dot = graph.to_dot();
+        assert!(dot.contains("\"graveyard:1.1\" -> \"graveyard.graveyard_right\" [style=solid];"));
+        assert!(graph.to_json().starts_with("{\"nodes\":[{\"id\":\"graveyard\",\"kind\":0,"));
+    }
+
+    #[test]
+    fn labelled_choice_scopes() {
+        let (main, _) = Parser::parse("
+# Graveyard
+## Grave
+- #[Label(Dig, global)] Dig
+- #[Label(Leave)] Leave
+## Church
+-> dig
+## Gate
+-> leave
+", 1);
+        let mut builder = GraphBuilder::new();
+        builder.add_file(1, &main);
+        let graph = builder.build();
+        assert_eq!(graph.unresolved.len(), 1);
+        let edge = |from: &str, to: &str| graph.edges.iter().any(|edge| graph.nodes[edge.from].id == from && graph.nodes[edge.to].id == to);
+        assert!(edge("graveyard.church", "graveyard.grave:0.0"));
+        assert!(!edge("graveyard.gate", "graveyard.grave:0.1"));
+    }
+}
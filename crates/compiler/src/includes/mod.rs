@@ -0,0 +1,129 @@
+use rustc_hash::{FxHashMap, FxHashSet};
+use storytell_diagnostics::{diagnostic::*, make_diagnostics, dia, location::Range};
+use storytell_parser::ast::model::*;
+use crate::base::files::BlobId;
+use crate::visitors::{BlockVisitor, walk_blocks};
+
+/// Diverts with this attribute show the content of the path and then continue after
+/// the divert, like diverts to labels. The path is always written from the main path
+/// of a file (`-> inventory.menu`), so the same include works from every file.
+pub const INCLUDE_ATTRIBUTE: &str = "Include";
+
+make_diagnostics!(define [
+    UNKNOWN_INCLUDE,
+    "There's no path '$' to include. Included paths start with the main path of a file."
+], [
+    INCLUDE_CYCLE,
+    "Including '$' leads back to '$', so it never ends."
+]);
+
+/// A divert with the [INCLUDE_ATTRIBUTE].
+#[derive(Clone, Debug)]
+pub struct Include {
+    /// The canonical path the include is in.
+    pub from: String,
+    /// The canonical path which is included.
+    pub target: String,
+    pub file: BlobId,
+    pub range: Range<usize>
+}
+
+struct IncludeCollector {
+    file: BlobId,
+    includes: Vec<Include>
+}
+
+impl BlockVisitor for IncludeCollector {
+    fn visit_block(&mut self, block: &ASTBlock, path: &str, _position: &str) {
+        if let ASTBlock::Divert(divert) = block {
+            if divert.has_attribute(INCLUDE_ATTRIBUTE) {
+                self.includes.push(Include {
+                    from: path.to_string(),
+                    target: divert.path.join("."),
+                    file: self.file,
+                    range: divert.range.clone()
+                });
+            }
+        }
+    }
+}
+
+/// Every include in `blocks`, in the order they're written.
+pub fn collect_includes(file: BlobId, blocks: &[ASTBlock]) -> Vec<Include> {
+    let mut collector = IncludeCollector { file, includes: vec![] };
+    walk_blocks(blocks, &mut collector);
+    collector.includes
+}
+
+/// Checks that every include points to an existing path, and that no path ends up including itself.
+/// `resolve` finds the file an included path is in. Returns the diagnostics of each file.
+pub fn check_includes<R: Fn(&[String]) -> Option<BlobId>>(includes: &[Include], resolve: R) -> FxHashMap<BlobId, Vec<Diagnostic>> {
+    let mut result: FxHashMap<BlobId, Vec<Diagnostic>> = FxHashMap::default();
+    // The paths each path includes directly
+    let mut included: FxHashMap<&str, Vec<&str>> = FxHashMap::default();
+    for include in includes {
+        let target = include.target.split('.').map(|part| part.to_string()).collect::<Vec<String>>();
+        if resolve(&target).is_some() {
+            included.entry(&include.from).or_default().push(&include.target);
+        } else {
+            result.entry(include.file).or_default().push(dia!(UNKNOWN_INCLUDE, include.range.clone(), &include.target));
+        }
+    }
+    for include in includes {
+        let mut visited: FxHashSet<&str> = FxHashSet::default();
+        let mut stack = vec![include.target.as_str()];
+        while let Some(path) = stack.pop() {
+            if path == include.from {
+                result.entry(include.file).or_default().push(dia!(INCLUDE_CYCLE, include.range.clone(), &include.target, &include.from));
+                break;
+            }
+            if visited.insert(path) {
+                stack.extend(included.get(path).into_iter().flatten());
+            }
+        }
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn check_cycles() {
+        let (main, _) = Parser::parse("
+# Main
+#[Include]
+-> inventory.menu
+- Look around
+    #[Include]
+    -> inventory.missing
+", 1);
+        let (inventory, _) = Parser::parse("
+# Inventory
+## Menu
+You open your bag.
+#[Include]
+-> inventory.menu.items
+### Items
+#[Include]
+-> main
+", 1);
+        let mut includes = collect_includes(1, &main);
+        includes.append(&mut collect_includes(2, &inventory));
+        assert_eq!(includes.iter().map(|include| (include.from.as_str(), include.target.as_str())).collect::<Vec<(&str, &str)>>(), vec![
+            ("main", "inventory.menu"),
+            ("main", "inventory.missing"),
+            ("inventory.menu", "inventory.menu.items"),
+            ("inventory.menu.items", "main")
+        ]);
+        let paths = ["main", "inventory", "inventory.menu", "inventory.menu.items"];
+        let diagnostics = check_includes(&includes, |target| paths.iter().position(|path| *path == target.join(".")).map(|ind| if ind == 0 { 1 } else { 2 }));
+        assert_eq!(diagnostics[&1].iter().map(|dia| dia.msg.as_str()).collect::<Vec<&str>>(), vec![
+            "There's no path 'inventory.missing' to include. Included paths start with the main path of a file.",
+            "Including 'inventory.menu' leads back to 'main', so it never ends."
+        ]);
+        assert_eq!(diagnostics[&2].len(), 2);
+    }
+}
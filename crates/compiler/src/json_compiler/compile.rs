@@ -1,4 +1,4 @@
-use storytell_diagnostics::diagnostic::{StorytellResult, Diagnostic};
+use storytell_diagnostics::{diagnostic::*, make_diagnostics, dia};
 use storytell_diagnostics::location::Range;
 use storytell_js_parser::JsParser;
 use storytell_js_parser::ast::Visitable;
@@ -6,7 +6,11 @@ use storytell_parser::ast::model::*;
 use std::{stringify, concat};
 
 use crate::json_compiler::JSONCompilerContext;
-use crate::path::Path;
+use crate::localization::TranslatedString;
+use crate::path::{Path, SpecialTarget};
+use crate::includes::INCLUDE_ATTRIBUTE;
+use crate::choices::{is_once, is_exhaust};
+use crate::builtins::check_builtins;
 use crate::visitors::{MagicVarCollector, Rebuilder, transform_js};
 
 #[macro_export]
@@ -23,6 +27,14 @@ macro_rules! json {
     };
 }
 
+make_diagnostics!(define [
+    AMBIGUOUS_DIVERT,
+    "The divert to '$' matches more than one path: $. It goes to the first one, write the full path with a '/' before it to go to another one."
+], [
+    RESERVED_PATH_NAME,
+    "A path can't be called '$', '-> $' is a special divert."
+]);
+
 pub trait JSONCompilable {
     fn compile(&self, ctx: &mut JSONCompilerContext) -> StorytellResult<String>;
 }
@@ -37,9 +49,15 @@ impl JSONCompilable for ASTHeader {
     ///  "title": string,
     ///  "canonicalTitle": string,
     ///  "childPaths": Path[],
+    ///  "trailingComments": Comment[],
     ///  "children": []
     /// }
     fn compile(&self, ctx: &mut JSONCompilerContext) -> StorytellResult<String> {
+        let canonical = Path::canonicalize_name(&self.title.text);
+        if let Some(special) = SpecialTarget::from_name(&canonical) {
+            ctx.diagnostics.push(dia!(RESERVED_PATH_NAME, self.title.range.clone(), &self.title.text, special.name()));
+        }
+        ctx.current_path.push(canonical);
         let mut header_children: Vec<String> = vec![];
         let mut others: Vec<&ASTBlock> = vec![];
         for child in &self.children {
@@ -49,13 +67,21 @@ impl JSONCompilable for ASTHeader {
                 others.push(child)
             }
         }
-        Ok(json!({
-            title: self.title.text.safe_compile(),
+        let title = match ctx.translated.get(&self.range.start) {
+            Some(TranslatedString::Title(title)) => title.safe_compile(),
+            _ => self.title.text.safe_compile()
+        };
+        let result = json!({
+            title: title,
             canonicalTitle: Path::canonicalize_name(&self.title.text).safe_compile(),
             childPaths: format!("{{{}}}", header_children.join(",")),
             range: self.range.safe_compile(),
+            comments: self.comments.safe_compile(),
+            trailingComments: self.trailing_comments.safe_compile(),
             children: others.compile(ctx)?
-        }))
+        });
+        ctx.current_path.pop();
+        Ok(result)
     }
 }
 
@@ -114,6 +140,8 @@ impl JSONCompilable for ASTInline {
                 } else {
                     let mut magic_vars_collector = MagicVarCollector::new(input, Range::new(self.range.start + 1, self.range.end - 1), &mut ctx.magic_variables);
                     expressions.visit_each_child(&mut magic_vars_collector);
+                    let offset = magic_vars_collector.start_pos.start;
+                    magic_vars_collector.diagnostics.append(&mut check_builtins(&magic_vars_collector.input, &expressions, offset, &ctx.labels, &ctx.current_path.join(".")));
                     if !magic_vars_collector.diagnostics.is_empty() {
                         return Err(magic_vars_collector.diagnostics)
                     } else {
@@ -155,12 +183,23 @@ impl JSONCompilable for ASTParagraph {
     ///     tail: string
     /// }
     fn compile(&self, ctx: &mut JSONCompilerContext) -> StorytellResult<String> {
+        if let Some(TranslatedString::Text(text)) = ctx.translated.get(&self.range.start).cloned() {
+            return Ok(json!({
+                kind: 0,
+                parts: text.parts.compile(ctx)?,
+                tail: text.tail.safe_compile(),
+                range: self.range.safe_compile(),
+                attributes: self.attributes.safe_compile(),
+                comments: self.comments.safe_compile()
+            }))
+        }
         Ok(json!({
             kind: 0,
             parts: self.parts.compile(ctx)?,
             tail: self.tail.safe_compile(),
             range: self.range.safe_compile(),
-            attributes: self.attributes.safe_compile()
+            attributes: self.attributes.safe_compile(),
+            comments: self.comments.safe_compile()
         }))
     }
 }
@@ -192,7 +231,8 @@ impl JSONCompilable for ASTCodeBlock {
             code: self.text.safe_compile(),
             language: self.language.safe_compile(),
             range: self.range.safe_compile(),
-            attributes: self.attributes.safe_compile()
+            attributes: self.attributes.safe_compile(),
+            comments: self.comments.safe_compile()
         }))
     }
 }
@@ -200,6 +240,8 @@ impl JSONCompilable for ASTCodeBlock {
 impl JSONCompilable for ASTChoice {
     /// `Choice` type
     /// {
+    ///     id: string,
+    ///     once: boolean,
     ///     text: Text,
     ///     children: Block[],
     ///     condition?: {
@@ -209,11 +251,18 @@ impl JSONCompilable for ASTChoice {
     /// }
     /// 
     fn compile(&self, ctx: &mut JSONCompilerContext) -> StorytellResult<String> {
+        let text = match ctx.translated.get(&self.range.start).cloned() {
+            Some(TranslatedString::Text(text)) => text.compile(ctx)?,
+            _ => self.text.compile(ctx)?
+        };
         Ok(json!({
-            text: self.text.compile(ctx)?,
+            id: ctx.choice_ids.get(&self.range.start).cloned().safe_compile(),
+            once: is_once(self),
+            text: text,
             children: self.children.compile(ctx)?,
             range: self.range.safe_compile(),
             attributes: self.attributes.safe_compile(),
+            comments: self.comments.safe_compile(),
             condition: self.condition.as_ref().map(|c| json!({
                 modifier: c.0.safe_compile(),
                 text: c.1.safe_compile()
@@ -226,14 +275,17 @@ impl JSONCompilable for ASTChoiceGroup {
     /// `ChoiceGroup` type
     /// {
     ///     kind: 2,
+    ///     exhaust: boolean,
     ///     choices: Choice[]
     /// }
     fn compile(&self, ctx: &mut JSONCompilerContext) -> StorytellResult<String> {
         Ok(json!({
             kind: 2,
+            exhaust: is_exhaust(self),
             choices: self.choices.compile(ctx)?,
             range: self.range.safe_compile(),
-            attributes: self.attributes.safe_compile()
+            attributes: self.attributes.safe_compile(),
+            comments: self.comments.safe_compile()
         }))
     }
 }
@@ -242,15 +294,74 @@ impl JSONCompilable for ASTDivert {
     /// `Divert` type
     /// {
     ///     kind: 3,
-    ///     path: string[]
+    ///     path: string[],
+    ///     absolute: boolean,
+    ///     target: string[] | null
+    /// }
+    ///
+    /// `target` is the full path the divert goes to (see [Path::resolve]), or `null`
+    /// when it doesn't go to a path, like when it goes to a label.
+    ///
+    /// `Include` type, a divert with the `#[Include]` attribute. The path starts with the
+    /// main path of a file, the flow continues after the include once the path is over.
+    /// {
+    ///     kind: 6,
+    ///     path: string[],
+    ///     absolute: boolean,
+    ///     target: string[] | null
+    /// }
+    ///
+    /// `End` (`-> END`) and `Back` (`-> BACK`) types. `End` finishes the story, `Back`
+    /// goes back to the previous choice group.
+    /// {
+    ///     kind: 7 | 8
     /// }
     /// 
-    fn compile(&self, _ctx: &mut JSONCompilerContext) -> StorytellResult<String> {
+    fn compile(&self, ctx: &mut JSONCompilerContext) -> StorytellResult<String> {
+        if let Some(special) = SpecialTarget::from_path(&self.path) {
+            return Ok(json!({
+                kind: match special {
+                    SpecialTarget::End => 7,
+                    SpecialTarget::Back => 8
+                },
+                range: self.range.safe_compile(),
+                attributes: self.attributes.safe_compile(),
+                comments: self.comments.safe_compile()
+            }));
+        }
+        let include = self.has_attribute(INCLUDE_ATTRIBUTE);
+        // Diverts look for labels before paths, like the graph does
+        let to_label = !include && !self.absolute && self.path.len() == 1 && ctx.labels.is_visible(&self.path[0], &ctx.current_path.join("."));
+        let targets = if to_label { vec![] } else { ctx.paths.resolve(&ctx.current_path, &self.path, self.absolute || include) };
+        if targets.len() > 1 {
+            let matches = targets.iter().map(|target| format!("'{}'", target.join("."))).collect::<Vec<String>>().join(", ");
+            ctx.diagnostics.push(dia!(AMBIGUOUS_DIVERT, self.range.clone(), Warning, &self.path.join("."), &matches));
+        }
         Ok(json!({
-            kind: 3,
+            kind: if include { 6 } else { 3 },
             path: self.path.safe_compile(),
+            absolute: self.absolute,
+            target: targets.into_iter().next().safe_compile(),
             range: self.range.safe_compile(),
-            attributes: self.attributes.safe_compile()
+            attributes: self.attributes.safe_compile(),
+            comments: self.comments.safe_compile()
+        }))
+    }
+}
+
+impl JSONCompilable for ASTError {
+    /// `Error` type
+    /// {
+    ///     kind: 5,
+    ///     text: string
+    /// }
+    fn compile(&self, _ctx: &mut JSONCompilerContext) -> StorytellResult<String> {
+        Ok(json!({
+            kind: 5,
+            text: self.text.safe_compile(),
+            range: self.range.safe_compile(),
+            attributes: self.attributes.safe_compile(),
+            comments: self.comments.safe_compile()
         }))
     }
 }
@@ -271,16 +382,26 @@ impl JSONCompilable for ASTMatch {
                 text: format!("\"{}\"", transform_js(&choice.text.parts[0].text.to_raw(), ctx.prefix_js_idents.clone())?),
                 children: choice.children.compile(ctx)?,
                 range: choice.range.safe_compile(),
-                attributes: choice.attributes.safe_compile()
+                attributes: choice.attributes.safe_compile(),
+                comments: choice.comments.safe_compile()
             }));
         }
+        let (expressions, diagnostics, input) = JsParser::parse(&self.matched.text);
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
+        let diagnostics = check_builtins(&input, &expressions, self.matched.range.start, &ctx.labels, &ctx.current_path.join("."));
+        if !diagnostics.is_empty() {
+            return Err(diagnostics);
+        }
         Ok(json!({
             kind: 4,
-            condition: format!("\"{}\"", transform_js(&self.matched, ctx.prefix_js_idents.clone())?),
+            condition: format!("\"{}\"", Rebuilder::run(input, &expressions, ctx.prefix_js_idents.clone())),
             modifier: self.kind.safe_compile(),
             arms: format!("[{}]", choices.join(",")),
             range: self.range.safe_compile(),
-            attributes: self.attributes.safe_compile()
+            attributes: self.attributes.safe_compile(),
+            comments: self.comments.safe_compile()
         }))
     }
 }
@@ -294,6 +415,10 @@ impl JSONCompilable for ASTBlock {
     /// ChoiceGroup - 2
     /// Divert - 3
     /// Match - 4
+    /// Error - 5
+    /// Include - 6
+    /// End - 7
+    /// Back - 8
     fn compile(&self, ctx: &mut JSONCompilerContext) -> StorytellResult<String> {
         match self {
             Self::Header(header) => header.compile(ctx),
@@ -301,7 +426,8 @@ impl JSONCompilable for ASTBlock {
             Self::CodeBlock(code) => code.compile(ctx),
             Self::ChoiceGroup(group) => group.compile(ctx),
             Self::Divert(divert) => divert.compile(ctx),
-            Self::Match(match_exp) => match_exp.compile(ctx)
+            Self::Match(match_exp) => match_exp.compile(ctx),
+            Self::Error(error) => error.compile(ctx)
         }
     }
 }
@@ -314,7 +440,8 @@ impl JSONCompilable for &ASTBlock {
             ASTBlock::CodeBlock(code) => code.compile(ctx),
             ASTBlock::ChoiceGroup(group) => group.compile(ctx),
             ASTBlock::Divert(divert) => divert.compile(ctx),
-            ASTBlock::Match(match_exp) => match_exp.compile(ctx)
+            ASTBlock::Match(match_exp) => match_exp.compile(ctx),
+            ASTBlock::Error(error) => error.compile(ctx)
         }
     }
 }
@@ -329,6 +456,15 @@ impl JSONSafeCompilable for ASTAttribute {
     }
 }
 
+impl JSONSafeCompilable for ASTComment {
+    fn safe_compile(&self) -> String {
+        json!({
+            text: self.text.safe_compile(),
+            range: self.range.safe_compile()
+        })
+    }
+}
+
 impl JSONSafeCompilable for Range<usize> {
     fn safe_compile(&self) -> String {
         json!({
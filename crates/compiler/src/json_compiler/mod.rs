@@ -1,6 +1,8 @@
-use storytell_diagnostics::diagnostic::StorytellResult;
+use std::sync::Arc;
+use rustc_hash::FxHashMap;
+use storytell_diagnostics::{diagnostic::{StorytellResult, Diagnostic}, location::Range};
 use storytell_parser::ast::model::ASTHeader;
-use crate::{base::*, visitors::MagicVariableCollectorContext};
+use crate::{base::*, path::Path, visitors::MagicVariableCollectorContext, localization::{TranslationTable, TranslatedString, Translator}, choices::ChoiceChecker, builtins::LabelTable};
 use self::compile::JSONCompilable;
 
 pub mod compile;
@@ -12,6 +14,15 @@ impl CompilerProvider for JSONCompilerProvider {
     type Context = JSONCompilerContext;
 
     fn compile_header(file: &ASTHeader, ctx: &mut Self::Context) -> StorytellResult<Self::Output> {
+        ctx.current_path.clear();
+        if let Some(table) = &ctx.translations {
+            let (translated, mut diagnostics) = Translator::new(table).run(file);
+            ctx.translated = translated;
+            ctx.diagnostics.append(&mut diagnostics);
+        }
+        let (choice_ids, mut diagnostics) = ChoiceChecker::default().run(file);
+        ctx.choice_ids = choice_ids;
+        ctx.diagnostics.append(&mut diagnostics);
         file.compile(ctx)
     }
 }
@@ -19,13 +30,58 @@ impl CompilerProvider for JSONCompilerProvider {
 #[derive(Default)]
 pub struct JSONCompilerContext {
     pub magic_variables: MagicVariableCollectorContext,
-    pub prefix_js_idents: Option<String>
+    pub prefix_js_idents: Option<String>,
+    /// When set, titles, paragraphs and choices get compiled with their translated text.
+    pub translations: Option<Arc<TranslationTable>>,
+    /// Translations of the header which is currently being compiled, mapped by the start of the block's range.
+    pub translated: FxHashMap<usize, TranslatedString>,
+    /// Ids of the choices of the header which is currently being compiled, mapped by the start of the choice's range.
+    pub choice_ids: FxHashMap<usize, String>,
+    /// Diagnostics which don't stop the compilation (warnings).
+    pub diagnostics: Vec<Diagnostic>,
+    /// Every path of the project, diverts are resolved with it.
+    pub paths: Arc<Path>,
+    /// Every label of the project, builtins which take a label are checked with it.
+    pub labels: Arc<LabelTable>,
+    /// The canonical path of the header which is currently being compiled.
+    pub current_path: Vec<String>
 }
 
 impl CompilerContext for JSONCompilerContext {
 
-    fn process_path(&mut self, _path: &ASTHeader) {
-        // Not needed, for now the front-end handles this.
+    fn process_path(&mut self, path: &ASTHeader) {
+        Arc::make_mut(&mut self.paths).add_child_ast(path);
+        Arc::make_mut(&mut self.labels).add_header(path);
+    }
+
+    fn remove_path(&mut self, path: &ASTHeader) {
+        Arc::make_mut(&mut self.paths).children.remove(&Path::canonicalize_name(&path.title.text));
+        Arc::make_mut(&mut self.labels).remove_header(path);
+    }
+
+    fn clear_paths(&mut self) {
+        self.paths = Arc::default();
+        self.labels = Arc::default();
+    }
+
+    fn take_diagnostics(&mut self) -> Vec<Diagnostic> {
+        std::mem::take(&mut self.diagnostics)
+    }
+
+    fn fork(&self) -> Self {
+        Self {
+            prefix_js_idents: self.prefix_js_idents.clone(),
+            translations: self.translations.clone(),
+            paths: self.paths.clone(),
+            labels: self.labels.clone(),
+            ..Default::default()
+        }
+    }
+
+    fn merge(&mut self, forked: Self, range: Range<usize>) -> Vec<Diagnostic> {
+        let mut diagnostics = forked.diagnostics;
+        self.magic_variables.merge(forked.magic_variables, &range, &mut diagnostics);
+        diagnostics
     }
 
 }
@@ -35,7 +91,8 @@ impl JSONCompilerContext {
     pub fn new(prefix_js_idents: Option<String>) -> Self {
         Self { 
             magic_variables: MagicVariableCollectorContext::new(),
-            prefix_js_idents
+            prefix_js_idents,
+            ..Default::default()
         }
     }
 
@@ -44,11 +101,11 @@ impl JSONCompilerContext {
 #[cfg(test)]
 mod tests {
     use crate::json_compiler::*;
-    use std::time::{Instant};
+    use crate::visitors::MagicVariableType;
+    use storytell_diagnostics::diagnostic::DiagnosticVariants;
 
     #[test]
     fn compile() {
-        let before = Instant::now();
         let (result, diagnostics, ctx) = compile_str::<JSONCompilerProvider>("
 # Hello, World!
 How's it going on this {a += 1} {b += 5; c.push(123); c.pop(); v = d = 33}? `Test!`
@@ -76,8 +133,138 @@ Hello!
 {e.b.c.d += 1}
 {e.b.c.d}
 ", JSONCompilerContext::new(None), 1);
-        println!("Parsing took {} nanoseconds", before.elapsed().as_nanos());
         println!("[{}] {:?} {:?}", result.join(","), diagnostics, ctx.magic_variables);
     }
 
-}
\ No newline at end of file
+    #[test]
+    fn resolve_diverts() {
+        let (result, diagnostics, _) = compile_str::<JSONCompilerProvider>("
+# Main
+-> shop
+-> nowhere
+## Shop
+-> intro
+### Intro
+## Intro
+-> /main.intro
+", JSONCompilerContext::new(None), 1);
+        let result = result.join(",");
+        assert!(result.contains("\"path\":[\"shop\"],\"absolute\":false,\"target\":[\"main\",\"shop\"]"));
+        assert!(result.contains("\"path\":[\"nowhere\"],\"absolute\":false,\"target\":null"));
+        assert!(result.contains("\"path\":[\"main\",\"intro\"],\"absolute\":true,\"target\":[\"main\",\"intro\"]"));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].msg, "The divert to 'intro' matches more than one path: 'main.shop.intro', 'main.intro'. It goes to the first one, write the full path with a '/' before it to go to another one.");
+        assert_eq!(diagnostics[0].variant, DiagnosticVariants::Warning);
+    }
+
+    #[test]
+    fn diverts_to_labels_before_paths() {
+        let (result, diagnostics, _) = compile_str::<JSONCompilerProvider>("
+# Main
+-> shop
+-> /main.shop
+#[Label(Shop)]
+A shop.
+## Shop
+", JSONCompilerContext::new(None), 1);
+        let result = result.join(",");
+        assert!(result.contains("\"path\":[\"shop\"],\"absolute\":false,\"target\":null"));
+        assert!(result.contains("\"path\":[\"main\",\"shop\"],\"absolute\":true,\"target\":[\"main\",\"shop\"]"));
+        assert!(diagnostics.is_empty());
+    }
+
+    #[test]
+    fn special_diverts() {
+        let (result, diagnostics, _) = compile_str::<JSONCompilerProvider>("
+# Main
+- Left
+    -> BACK
+- Right
+    -> END
+## End
+", JSONCompilerContext::new(None), 1);
+        let result = result.join(",");
+        assert!(result.contains("{\"kind\":8,\"range\""));
+        assert!(result.contains("{\"kind\":7,\"range\""));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].msg, "A path can't be called 'End', '-> END' is a special divert.");
+    }
+
+    #[test]
+    fn choice_attributes() {
+        let (result, diagnostics, _) = compile_str::<JSONCompilerProvider>("
+# Graveyard
+#[exaust]
+- #[once] Left
+    -> BACK
+- #[Label(Exit)] Exit graveyard
+    -> END
+", JSONCompilerContext::new(None), 1);
+        let result = result.join(",");
+        assert!(result.contains("{\"kind\":2,\"exhaust\":true,"));
+        assert!(result.contains("{\"id\":\"graveyard:0.0\",\"once\":true,"));
+        assert!(result.contains("{\"id\":\"graveyard@exit\",\"once\":false,"));
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].msg, "'exaust' is spelled 'exhaust'.");
+    }
+
+    #[test]
+    fn builtins() {
+        let (result, diagnostics, ctx) = compile_str::<JSONCompilerProvider>("
+# Quiz
+#[Label(MyChoice)]
+- #[Label(Choice1)] 4
+- #[Label(Choice2)] 5
+{tries = label_count(\"MyChoice\")}
+@{!!label_count(\"Choice1\")}
+- {true}
+    Correct!
+", JSONCompilerContext::new(Some("this".to_string())), 1);
+        assert!(diagnostics.is_empty());
+        let result = result.join(",");
+        assert!(result.contains("this.tries=label_count("));
+        assert!(result.contains("\"condition\":\"!!label_count("));
+        assert_eq!(ctx.magic_variables.variables.get("tries"), Some(&MagicVariableType::Number));
+        let source = "# Quiz\n@{label_count(\"Missing\")}\n- {1}\n    One\n";
+        let (_, diagnostics, _) = compile_str::<JSONCompilerProvider>(source, JSONCompilerContext::new(None), 1);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].msg, "There's no label 'Missing' which can be used from here.");
+        assert_eq!(&source[diagnostics[0].range.start..diagnostics[0].range.end], "\"Missing\"");
+        // Match conditions can start with a kind and spaces
+        let source = "# Quiz\n@{:if  label_count(\"Missing\") > 1}\n- One\n";
+        let (_, diagnostics, _) = compile_str::<JSONCompilerProvider>(source, JSONCompilerContext::new(None), 1);
+        assert_eq!(&source[diagnostics[0].range.start..diagnostics[0].range.end], "\"Missing\"");
+        let (_, diagnostics, _) = compile_str::<JSONCompilerProvider>("# Quiz\n{label_count = 1}\n", JSONCompilerContext::new(None), 1);
+        assert_eq!(diagnostics.len(), 1);
+        assert_eq!(diagnostics[0].msg, "'label_count' is a builtin function, it can't be assigned to.");
+    }
+
+    #[test]
+    fn merge_forked_contexts() {
+        let main = JSONCompilerContext::new(None);
+        let (_, first, first_ctx) = compile_str::<JSONCompilerProvider>("
+# First
+{player.name = \"James\"} {gold = 1} {mood = 1}
+", main.fork(), 1);
+        let second_source = "
+# Second
+{player.hp = 10} {gold = 5} {mood = \"happy\"} {score = other} {player.name = 1}
+";
+        let (_, second, second_ctx) = compile_str::<JSONCompilerProvider>(second_source, main.fork(), 1);
+        assert!(first.is_empty() && second.is_empty());
+        let mut main = main;
+        assert!(main.merge(first_ctx, Range::new(0, 10)).is_empty());
+        let diagnostics = main.merge(second_ctx, Range::new(0, 20));
+        // Conflicts are reported where the forked context assigned the variable
+        let conflicts = diagnostics.iter().map(|dia| &second_source[dia.range.start..dia.range.end]).collect::<Vec<&str>>();
+        assert_eq!(conflicts, vec!["mood = \"happy\"", "player.name = 1"]);
+        let variables = &main.magic_variables;
+        assert_eq!(variables.variables["gold"], MagicVariableType::Number);
+        assert_eq!(variables.variables["mood"], MagicVariableType::String);
+        assert_eq!(variables.variables["score"], MagicVariableType::Unknown);
+        let player = &variables.objects[&variables.get_obj_id_from_name("player").unwrap()];
+        assert_eq!(player["name"], MagicVariableType::Number);
+        assert_eq!(player["hp"], MagicVariableType::Number);
+    }
+
+}
@@ -1,4 +1,14 @@
 pub mod visitors;
 pub mod path;
 pub mod base;
-pub mod json_compiler;
\ No newline at end of file
+pub mod json_compiler;
+pub mod script;
+pub mod localization;pub mod graph;
+pub mod analysis;
+pub mod metrics;
+pub mod completion;
+pub mod rename;
+pub mod references;
+pub mod includes;
+pub mod choices;
+pub mod builtins;
\ No newline at end of file
@@ -0,0 +1,447 @@
+use std::fmt::Write as _;
+use rustc_hash::FxHashMap;
+use storytell_diagnostics::{diagnostic::*, make_diagnostics, dia, location::Range};
+use storytell_parser::{ast::{model::*, Parser}, input::ParsingContext};
+use crate::path::Path;
+use crate::visitors::{BlockVisitor, walk_header, walk_blocks};
+
+make_diagnostics!(define [
+    MISSING_TRANSLATION,
+    "Missing translation for '$'."
+], [
+    STALE_TRANSLATION,
+    "Translation for '$' is stale, the source text has changed since it was extracted."
+], [
+    CHANGED_JAVASCRIPT,
+    "Translation for '$' must contain the same inline javascript as the source text."
+]);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum TranslatableKind {
+    Title,
+    Paragraph,
+    Choice
+}
+
+impl TranslatableKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Title => "title",
+            Self::Paragraph => "paragraph",
+            Self::Choice => "choice"
+        }
+    }
+}
+
+/// A piece of text which can be translated.
+///
+/// Ids are made out of the canonical path of the header the text is in, and either:
+/// - `title` for header titles (`chapter_1:title`)
+/// - the canonical name of the block's label (`chapter_1@my_choice`)
+/// - the position of the block (`chapter_1:2.0`), see [BlockVisitor]
+///
+/// `source` is storytell markup - formatting and inline javascript are kept.
+#[derive(Clone, Debug)]
+pub struct TranslatableString {
+    pub id: String,
+    pub path: String,
+    pub kind: TranslatableKind,
+    pub source: String,
+    pub range: Range<usize>
+}
+
+#[derive(Default)]
+pub struct StringExtractor {
+    pub strings: Vec<TranslatableString>
+}
+
+impl StringExtractor {
+
+    fn push(&mut self, id: String, path: &str, kind: TranslatableKind, source: String, range: &Range<usize>) {
+        self.strings.push(TranslatableString {
+            id,
+            path: path.to_string(),
+            kind,
+            source,
+            range: range.clone()
+        });
+    }
+
+}
+
+pub(crate) fn block_id<T: WithAttributes>(block: &T, path: &str, position: &str) -> String {
+    match block.get_attribute_n("Label", 0) {
+        Some(label) => format!("{}@{}", path, Path::canonicalize_name(label)),
+        None => format!("{}:{}", path, position)
+    }
+}
+
+impl BlockVisitor for StringExtractor {
+    fn visit_header(&mut self, header: &ASTHeader, path: &str) {
+        self.push(format!("{}:title", path), path, TranslatableKind::Title, header.title.text.clone(), &header.range);
+    }
+
+    fn visit_block(&mut self, block: &ASTBlock, path: &str, position: &str) {
+        if let ASTBlock::Paragraph(paragraph) = block {
+            self.push(block_id(paragraph, path, position), path, TranslatableKind::Paragraph, paragraph.to_source(), &paragraph.range);
+        }
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, path: &str, position: &str) {
+        self.push(block_id(choice, path, position), path, TranslatableKind::Choice, choice.text.to_source(), &choice.range);
+    }
+}
+
+pub fn extract_strings(blocks: &[ASTBlock]) -> Vec<TranslatableString> {
+    let mut extractor = StringExtractor::default();
+    walk_blocks(blocks, &mut extractor);
+    extractor.strings
+}
+
+fn escape_po(text: &str) -> String {
+    text.replace('\\', "\\\\").replace('"', "\\\"").replace('\n', "\\n").replace('\t', "\\t")
+}
+
+fn unescape_po(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut characters = text.chars();
+    while let Some(character) = characters.next() {
+        if character == '\\' {
+            match characters.next() {
+                Some('n') => result.push('\n'),
+                Some('t') => result.push('\t'),
+                Some(other) => result.push(other),
+                None => {}
+            }
+        } else {
+            result.push(character);
+        }
+    }
+    result
+}
+
+/// Creates a gettext PO template. Every string is an entry whose
+/// `msgctxt` is the string's id and `msgid` is the source text.
+pub fn to_po(strings: &[TranslatableString]) -> String {
+    let mut result = String::from("msgid \"\"\nmsgstr \"\"\n\"Content-Type: text/plain; charset=UTF-8\\n\"\n");
+    for string in strings {
+        write!(result, "\n#: {} ({})\nmsgctxt \"{}\"\nmsgid \"{}\"\nmsgstr \"\"\n", string.path, string.kind.as_str(), escape_po(&string.id), escape_po(&string.source)).unwrap();
+    }
+    result
+}
+
+pub struct Translation {
+    /// The source text the translation was made for.
+    pub source: String,
+    pub text: String
+}
+
+/// Translations, mapped by string id.
+#[derive(Default)]
+pub struct TranslationTable {
+    pub entries: FxHashMap<String, Translation>
+}
+
+#[derive(PartialEq, Eq)]
+enum PoField {
+    Context,
+    Id,
+    Str,
+    None
+}
+
+impl TranslationTable {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Reads a gettext PO file. Entries without a `msgctxt` (like the header entry) are ignored.
+    pub fn from_po(content: &str) -> Self {
+        let mut table = Self::new();
+        let mut context: Option<String> = None;
+        let mut id = String::new();
+        let mut translated = String::new();
+        let mut field = PoField::None;
+        for line in content.lines().map(|line| line.trim()).chain(std::iter::once("")) {
+            let value = if let Some(rest) = line.strip_prefix("msgctxt ") {
+                if let Some(ctx) = context.take() {
+                    table.insert(ctx, &id, &translated);
+                }
+                id.clear();
+                translated.clear();
+                context = Some(String::new());
+                field = PoField::Context;
+                rest
+            } else if let Some(rest) = line.strip_prefix("msgid ") {
+                field = PoField::Id;
+                rest
+            } else if let Some(rest) = line.strip_prefix("msgstr ") {
+                field = PoField::Str;
+                rest
+            } else if line.starts_with('"') {
+                line
+            } else {
+                if line.is_empty() {
+                    if let Some(ctx) = context.take() {
+                        table.insert(ctx, &id, &translated);
+                    }
+                    id.clear();
+                    translated.clear();
+                }
+                field = PoField::None;
+                continue;
+            };
+            let value = value.trim();
+            let value = unescape_po(value.strip_prefix('"').and_then(|value| value.strip_suffix('"')).unwrap_or(value));
+            match field {
+                PoField::Context => if let Some(ctx) = &mut context { ctx.push_str(&value) },
+                PoField::Id => id.push_str(&value),
+                PoField::Str => translated.push_str(&value),
+                PoField::None => {}
+            }
+        }
+        table
+    }
+
+    fn insert(&mut self, id: String, source: &str, text: &str) {
+        if !text.is_empty() {
+            self.entries.insert(id, Translation {
+                source: source.to_string(),
+                text: text.to_string()
+            });
+        }
+    }
+
+}
+
+#[derive(Clone, Debug)]
+pub enum TranslatedString {
+    Title(String),
+    Text(ASTText)
+}
+
+fn collect_js<'a>(parts: &'a [TextPart], result: &mut Vec<&'a ASTInline>) {
+    for part in parts {
+        match &part.text.kind {
+            ASTInlineKind::Javascript(_) => result.push(&part.text),
+            ASTInlineKind::Bold(text) | ASTInlineKind::Italics(text) | ASTInlineKind::Underline(text) | ASTInlineKind::Code(text) => collect_js(&text.parts, result),
+            ASTInlineKind::Join => {}
+        }
+    }
+}
+
+/// Moves the ranges of the formatting in a translated text by `offset`, so they're positions in the
+/// translated string as if it started where the source text starts. Inline javascript is skipped,
+/// it gets the ranges of the source text.
+fn shift_ranges(parts: &mut [TextPart], offset: usize) {
+    for part in parts {
+        match &mut part.text.kind {
+            ASTInlineKind::Javascript(_) => continue,
+            ASTInlineKind::Bold(text) | ASTInlineKind::Italics(text) | ASTInlineKind::Underline(text) | ASTInlineKind::Code(text) => {
+                text.range = Range::new(text.range.start + offset, text.range.end + offset);
+                shift_ranges(&mut text.parts, offset);
+            },
+            ASTInlineKind::Join => {}
+        }
+        part.text.range = Range::new(part.text.range.start + offset, part.text.range.end + offset);
+    }
+}
+
+fn collect_js_ranges<'a>(parts: &'a mut [TextPart], result: &mut Vec<&'a mut Range<usize>>) {
+    for part in parts {
+        match &mut part.text.kind {
+            ASTInlineKind::Javascript(_) => result.push(&mut part.text.range),
+            ASTInlineKind::Bold(text) | ASTInlineKind::Italics(text) | ASTInlineKind::Underline(text) | ASTInlineKind::Code(text) => collect_js_ranges(&mut text.parts, result),
+            ASTInlineKind::Join => {}
+        }
+    }
+}
+
+/// Parses translations for all strings inside `header`, mapped by the start of the
+/// range of the translated block.
+///
+/// Inline javascript in the translation must match the inline javascript in the source
+/// text, because it's compiled with the source's ranges, so diagnostics point to the
+/// original file.
+pub struct Translator<'a> {
+    pub table: &'a TranslationTable,
+    pub translated: FxHashMap<usize, TranslatedString>,
+    pub diagnostics: Vec<Diagnostic>
+}
+
+impl<'a> Translator<'a> {
+
+    pub fn new(table: &'a TranslationTable) -> Self {
+        Self {
+            table,
+            translated: FxHashMap::default(),
+            diagnostics: vec![]
+        }
+    }
+
+    pub fn run(mut self, header: &ASTHeader) -> (FxHashMap<usize, TranslatedString>, Vec<Diagnostic>) {
+        walk_header(header, "", &mut self);
+        (self.translated, self.diagnostics)
+    }
+
+    fn find(&mut self, id: &str, source: &str, range: &Range<usize>) -> Option<&'a Translation> {
+        match self.table.entries.get(id) {
+            Some(translation) => {
+                if translation.source != source {
+                    self.diagnostics.push(dia!(STALE_TRANSLATION, range.clone(), Warning, id));
+                }
+                Some(translation)
+            },
+            None => {
+                self.diagnostics.push(dia!(MISSING_TRANSLATION, range.clone(), Warning, id));
+                None
+            }
+        }
+    }
+
+    fn translate_text(&mut self, id: &str, original: &ASTText) {
+        let source = original.to_source();
+        let translation = if let Some(translation) = self.find(id, &source, &original.range) { translation } else {
+            return;
+        };
+        let mut text = match Parser::new(&translation.text, ParsingContext::new(1)).parse_text("\n", true) {
+            Some(text) => text,
+            None => return
+        };
+        let mut original_js = vec![];
+        collect_js(&original.parts, &mut original_js);
+        let mut translated_js = vec![];
+        collect_js(&text.parts, &mut translated_js);
+        if original_js.len() != translated_js.len() || original_js.iter().zip(&translated_js).any(|(a, b)| a.kind.to_raw().trim() != b.kind.to_raw().trim()) {
+            self.diagnostics.push(dia!(CHANGED_JAVASCRIPT, original.range.clone(), Warning, id));
+            return;
+        }
+        shift_ranges(&mut text.parts, original.range.start);
+        let original_ranges = original_js.into_iter().map(|inline| inline.range.clone()).collect::<Vec<Range<usize>>>();
+        let mut translated_ranges = vec![];
+        collect_js_ranges(&mut text.parts, &mut translated_ranges);
+        for (range, original_range) in translated_ranges.into_iter().zip(original_ranges) {
+            *range = original_range;
+        }
+        text.range = original.range.clone();
+        self.translated.insert(original.range.start, TranslatedString::Text(text));
+    }
+
+}
+
+impl<'a> BlockVisitor for Translator<'a> {
+    fn visit_header(&mut self, header: &ASTHeader, path: &str) {
+        let id = format!("{}:title", path);
+        if let Some(translation) = self.find(&id, &header.title.text, &header.title.range) {
+            self.translated.insert(header.range.start, TranslatedString::Title(translation.text.clone()));
+        }
+    }
+
+    fn visit_block(&mut self, block: &ASTBlock, path: &str, position: &str) {
+        if let ASTBlock::Paragraph(paragraph) = block {
+            let text = ASTText {
+                parts: paragraph.parts.clone(),
+                tail: paragraph.tail.clone(),
+                range: paragraph.range.clone()
+            };
+            self.translate_text(&block_id(paragraph, path, position), &text);
+        }
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, path: &str, position: &str) {
+        self.translate_text(&block_id(choice, path, position), &choice.text);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::sync::Arc;
+    use super::*;
+    use crate::base::compile_str;
+    use crate::json_compiler::{JSONCompilerProvider, JSONCompilerContext};
+
+    const STORY: &str = "
+# Graveyard
+James and {companion} arrive at the **graveyard**.
+
+#[Label(Pick)]
+- Left
+    Nothing \\* here.
+- Right
+";
+
+    #[test]
+    fn extract_and_write_po() {
+        let (blocks, _) = Parser::parse(STORY, 1);
+        let strings = extract_strings(&blocks);
+        assert_eq!(strings.len(), 5);
+        assert_eq!(strings[0].id, "graveyard:title");
+        assert_eq!(strings[1].id, "graveyard:0");
+        assert_eq!(strings[1].source, "James and {companion} arrive at the **graveyard**.");
+        assert_eq!(strings[2].id, "graveyard:1.0");
+        assert_eq!(strings[3].id, "graveyard:1.0.0");
+        assert_eq!(strings[3].source, "Nothing \\* here.");
+        let po = to_po(&strings);
+        assert!(po.contains("msgctxt \"graveyard:1.0.0\"\nmsgid \"Nothing \\\\* here.\"\nmsgstr \"\""));
+        let table = TranslationTable::from_po(&po);
+        assert!(table.entries.is_empty());
+    }
+
+    #[test]
+    fn compile_with_translations() {
+        let table = TranslationTable::from_po("
+msgid \"\"
+msgstr \"\"
+
+msgctxt \"graveyard:title\"
+msgid \"Graveyard\"
+msgstr \"Friedhof\"
+
+msgctxt \"graveyard:0\"
+msgid \"James and {companion} arrive at the **graveyard**.\"
+msgstr \"James und {companion} kommen am **Friedhof** an.\"
+
+msgctxt \"graveyard:1.0\"
+msgid \"Left\"
+msgstr \"Links\"
+
+msgctxt \"graveyard:1.0.0\"
+msgid \"Nothing here.\"
+msgstr \"Hier ist \"
+\"nichts.\"
+
+msgctxt \"graveyard:1.1\"
+msgid \"Right\"
+msgstr \"Rechts {oops}\"
+");
+        assert_eq!(table.entries.get("graveyard:1.0.0").unwrap().text, "Hier ist nichts.");
+        let mut ctx = JSONCompilerContext::new(None);
+        ctx.translations = Some(Arc::new(table));
+        let (result, diagnostics, _) = compile_str::<JSONCompilerProvider>(STORY, ctx, 1);
+        assert!(result[0].contains("\"title\":\"Friedhof\""));
+        assert!(result[0].contains("\"canonicalTitle\":\"graveyard\""));
+        assert!(result[0].contains("\"before\":\"James und \""));
+        assert!(result[0].contains("\"tail\":\"Links\""));
+        assert!(result[0].contains("\"tail\":\"Right"));
+        assert_eq!(diagnostics.len(), 2);
+        assert_eq!(diagnostics[0].msg, "Translation for 'graveyard:1.0.0' is stale, the source text has changed since it was extracted.");
+        assert_eq!(diagnostics[1].msg, "Translation for 'graveyard:1.1' must contain the same inline javascript as the source text.");
+    }
+
+    #[test]
+    fn translated_ranges() {
+        let translation = "James und {companion} kommen am **Friedhof** an.";
+        let table = TranslationTable::from_po(&format!("msgctxt \"graveyard:0\"\nmsgid \"James and {{companion}} arrive at the **graveyard**.\"\nmsgstr \"{}\"\n", translation));
+        let (blocks, _) = Parser::parse(STORY, 1);
+        let header = if let ASTBlock::Header(header) = &blocks[0] { header } else { unreachable!() };
+        let paragraph = if let ASTBlock::Paragraph(paragraph) = &header.children[0] { paragraph } else { unreachable!() };
+        let (translated, _) = Translator::new(&table).run(header);
+        let text = if let Some(TranslatedString::Text(text)) = translated.get(&paragraph.range.start) { text } else { unreachable!() };
+        // Javascript keeps the range of the source, so diagnostics point to the file
+        assert_eq!(text.parts[0].text.range, paragraph.parts[0].text.range);
+        // Formatting points into the translated string
+        let bold = &text.parts[1].text.range;
+        assert_eq!(&translation[bold.start - paragraph.range.start..bold.end - paragraph.range.start], "**Friedhof**");
+    }
+}
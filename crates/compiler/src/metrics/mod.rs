@@ -0,0 +1,261 @@
+use std::fmt::Write as _;
+use std::ops::AddAssign;
+use rustc_hash::FxHashMap;
+use storytell_parser::ast::model::*;
+use crate::analysis::{count_words, text_without_js, walk_routes, MAX_ROUTES};
+use crate::base::files::BlobId;
+use crate::graph::{StoryGraph, GraphNodeKind};
+use crate::json;
+use crate::json_compiler::compile::JSONSafeCompilable;
+use crate::visitors::{BlockVisitor, walk_blocks};
+
+/// Average silent reading speed.
+pub const WORDS_PER_MINUTE: usize = 200;
+
+#[derive(Clone, Copy, Debug, Default, PartialEq, Eq)]
+pub struct TextMetrics {
+    pub words: usize,
+    pub characters: usize
+}
+
+impl TextMetrics {
+
+    pub fn from_text(parts: &[TextPart], tail: &str) -> Self {
+        let text = text_without_js(parts, tail);
+        let text = text.trim();
+        Self {
+            words: count_words(text),
+            characters: text.chars().count()
+        }
+    }
+
+    /// Estimated reading time, in minutes.
+    pub fn reading_time(&self) -> f32 {
+        self.words as f32 / WORDS_PER_MINUTE as f32
+    }
+
+}
+
+impl AddAssign for TextMetrics {
+    fn add_assign(&mut self, other: Self) {
+        self.words += other.words;
+        self.characters += other.characters;
+    }
+}
+
+impl JSONSafeCompilable for TextMetrics {
+    fn safe_compile(&self) -> String {
+        json!({
+            words: self.words,
+            characters: self.characters,
+            readingTime: self.reading_time()
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct PathMetrics {
+    pub path: String,
+    pub file: BlobId,
+    /// Only the content of the path itself, without sub-paths.
+    pub metrics: TextMetrics
+}
+
+impl JSONSafeCompilable for PathMetrics {
+    fn safe_compile(&self) -> String {
+        json!({
+            path: self.path.safe_compile(),
+            file: self.file,
+            metrics: self.metrics.safe_compile()
+        })
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct FileMetrics {
+    pub file: BlobId,
+    pub metrics: TextMetrics
+}
+
+impl JSONSafeCompilable for FileMetrics {
+    fn safe_compile(&self) -> String {
+        json!({
+            file: self.file,
+            metrics: self.metrics.safe_compile()
+        })
+    }
+}
+
+#[derive(Clone, Debug, Default)]
+pub struct ProjectMetrics {
+    pub paths: Vec<PathMetrics>,
+    pub files: Vec<FileMetrics>,
+    pub total: TextMetrics,
+    /// The least words a reader could see from the start to the end of the story.
+    pub min_route_words: usize,
+    /// The most words a reader could see, without going through the same path or choice twice.
+    pub max_route_words: usize,
+    /// `true` if the story has more than [MAX_ROUTES] routes, and not all of them were checked.
+    pub routes_capped: bool
+}
+
+impl ProjectMetrics {
+
+    /// {
+    ///     paths: { path: string, file: number, metrics: Metrics }[],
+    ///     files: { file: number, metrics: Metrics }[],
+    ///     total: Metrics,
+    ///     minRouteWords: number,
+    ///     maxRouteWords: number,
+    ///     routesCapped: boolean
+    /// }
+    ///
+    /// `Metrics` is `{ words: number, characters: number, readingTime: number }`, reading time is in minutes.
+    pub fn to_json(&self) -> String {
+        json!({
+            paths: self.paths.safe_compile(),
+            files: self.files.safe_compile(),
+            total: self.total.safe_compile(),
+            minRouteWords: self.min_route_words,
+            maxRouteWords: self.max_route_words,
+            routesCapped: self.routes_capped
+        })
+    }
+
+    /// Human readable report, used by the CLI.
+    pub fn to_report(&self) -> String {
+        let mut result = String::new();
+        writeln!(result, "Words: {}", self.total.words).unwrap();
+        writeln!(result, "Characters: {}", self.total.characters).unwrap();
+        writeln!(result, "Reading time: {:.1} minutes", self.total.reading_time()).unwrap();
+        writeln!(result, "Words along a route: {} - {}{}", self.min_route_words, self.max_route_words, if self.routes_capped { "+" } else { "" }).unwrap();
+        writeln!(result, "\nPaths:").unwrap();
+        for path in &self.paths {
+            writeln!(result, "    {} - {} words, {} characters, {:.1} minutes", path.path, path.metrics.words, path.metrics.characters, path.metrics.reading_time()).unwrap();
+        }
+        result
+    }
+
+}
+
+/// Assigns text to the graph node it's shown in - the choice it's under, or the path otherwise.
+struct NodeMetricsCollector<'a> {
+    graph: &'a StoryGraph,
+    metrics: Vec<TextMetrics>
+}
+
+impl<'a> NodeMetricsCollector<'a> {
+    fn add(&mut self, path: &str, position: &str, metrics: TextMetrics) {
+        let mut position = position;
+        loop {
+            if let Some(node) = self.graph.node_ids.get(&format!("{}:{}", path, position)) {
+                self.metrics[*node] += metrics;
+                return;
+            }
+            match position.rfind('.') {
+                Some(ind) => position = &position[..ind],
+                None => break
+            }
+        }
+        if let Some(node) = self.graph.node_ids.get(path) {
+            self.metrics[*node] += metrics;
+        }
+    }
+}
+
+impl<'a> BlockVisitor for NodeMetricsCollector<'a> {
+    fn visit_block(&mut self, block: &ASTBlock, path: &str, position: &str) {
+        if let ASTBlock::Paragraph(paragraph) = block {
+            self.add(path, position, TextMetrics::from_text(&paragraph.parts, &paragraph.tail));
+        }
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, path: &str, position: &str) {
+        self.add(path, position, TextMetrics::from_text(&choice.text.parts, &choice.text.tail));
+    }
+}
+
+/// Collects metrics for every path and file in the story graph. Routes start at the first top-level path.
+pub fn collect_metrics(graph: &StoryGraph, files: &[(BlobId, &[ASTBlock])]) -> ProjectMetrics {
+    let mut collector = NodeMetricsCollector {
+        graph,
+        metrics: vec![TextMetrics::default(); graph.nodes.len()]
+    };
+    for (_, blocks) in files {
+        walk_blocks(blocks, &mut collector);
+    }
+    let node_metrics = collector.metrics;
+    let mut result = ProjectMetrics::default();
+    let mut path_indexes: FxHashMap<&str, usize> = FxHashMap::default();
+    for node in graph.nodes.iter().filter(|node| node.kind == GraphNodeKind::Path) {
+        path_indexes.insert(&node.path, result.paths.len());
+        result.paths.push(PathMetrics { path: node.path.clone(), file: node.file, metrics: TextMetrics::default() });
+    }
+    for (ind, node) in graph.nodes.iter().enumerate() {
+        if let Some(path) = path_indexes.get(node.path.as_str()) {
+            result.paths[*path].metrics += node_metrics[ind];
+        }
+    }
+    for (file, _) in files {
+        let mut metrics = TextMetrics::default();
+        for path in result.paths.iter().filter(|path| path.file == *file) {
+            metrics += path.metrics;
+        }
+        result.total += metrics;
+        result.files.push(FileMetrics { file: *file, metrics });
+    }
+    if let Some(start) = graph.nodes.iter().position(|node| node.kind == GraphNodeKind::Path && !node.id.contains('.')) {
+        let words: Vec<usize> = node_metrics.iter().map(|metrics| metrics.words).collect();
+        let routes = walk_routes(graph, start, &words);
+        result.min_route_words = routes.min_weight;
+        result.max_route_words = routes.max_weight;
+        result.routes_capped = routes.count >= MAX_ROUTES;
+    }
+    result
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::graph::GraphBuilder;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn count_metrics() {
+        let (main, _) = Parser::parse("
+# Start
+You wake up, {name}.
+// This comment doesn't count.
+
+- Get up
+    You get up and look around the room.
+    -> room
+- Sleep
+    Zzz.
+
+## Room
+An *empty* room.
+", 1);
+        let (other, _) = Parser::parse("
+# Epilogue
+The end.
+", 1);
+        let mut builder = GraphBuilder::new();
+        builder.add_file(1, &main);
+        builder.add_file(2, &other);
+        let graph = builder.build();
+        let metrics = collect_metrics(&graph, &[(1, &main), (2, &other)]);
+        assert_eq!(metrics.paths[0].path, "start");
+        // You wake up, | Get up | You get up and look around the room. | Sleep | Zzz.
+        assert_eq!(metrics.paths[0].metrics.words, 3 + 2 + 8 + 1 + 1);
+        assert_eq!(metrics.paths[1].metrics, TextMetrics { words: 3, characters: 14 });
+        assert_eq!(metrics.files[0].metrics.words, 18);
+        assert_eq!(metrics.files[1].metrics.words, 2);
+        assert_eq!(metrics.total.words, 20);
+        // Start -> Sleep
+        assert_eq!(metrics.min_route_words, 3 + 1 + 1);
+        // Start -> Get up -> Room
+        assert_eq!(metrics.max_route_words, 3 + 2 + 8 + 3);
+        assert_eq!(metrics.paths[0].metrics.reading_time(), 15.0 / 200.0);
+    }
+}
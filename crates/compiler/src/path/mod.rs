@@ -1,6 +1,44 @@
 use std::collections::HashMap;
 use storytell_parser::ast::model::{ASTHeader, ASTBlock};
 
+/// Divert targets which aren't paths. Their names are reserved, paths can't be called like them.
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SpecialTarget {
+    /// `-> END` finishes the story.
+    End,
+    /// `-> BACK` goes back to the previous choice group.
+    Back
+}
+
+impl SpecialTarget {
+
+    /// The target a canonical name stands for.
+    pub fn from_name(name: &str) -> Option<Self> {
+        match name {
+            "end" => Some(Self::End),
+            "back" => Some(Self::Back),
+            _ => None
+        }
+    }
+
+    pub fn from_path(path: &[String]) -> Option<Self> {
+        match path {
+            [name] => Self::from_name(name),
+            _ => None
+        }
+    }
+
+    /// How the target is written in a divert.
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::End => "END",
+            Self::Back => "BACK"
+        }
+    }
+
+}
+
+#[derive(Clone, Default)]
 pub struct Path {
     pub name: String,
     pub depth: u8,
@@ -35,7 +73,7 @@ impl Path {
         Some(found_path)
     }
 
-    pub fn try_get_child_by_path(&self, path: &Vec<String>) -> Result<&Path, usize> {
+    pub fn try_get_child_by_path(&self, path: &[String]) -> Result<&Path, usize> {
         let mut found_path = if let Some(path) = self.children.get(&path[0]) {
             path
         } else {
@@ -51,6 +89,28 @@ impl Path {
         Ok(found_path)
     }
 
+    /// Finds the paths a divert written in `current` can go to. Relative diverts are looked up in the
+    /// children of the current path, then in its siblings, and then from the root. Absolute diverts
+    /// are only looked up from the root. `self` is the root, the matches are full paths, in that order.
+    pub fn resolve(&self, current: &[String], target: &[String], absolute: bool) -> Vec<Vec<String>> {
+        if target.is_empty() {
+            return vec![];
+        }
+        let scopes: Vec<&[String]> = if absolute || current.is_empty() {
+            vec![&[]]
+        } else {
+            vec![current, &current[..current.len() - 1], &[]]
+        };
+        let mut result: Vec<Vec<String>> = vec![];
+        for scope in scopes {
+            let full = [scope, target].concat();
+            if self.get_child_by_path(&full).is_some() && !result.contains(&full) {
+                result.push(full);
+            }
+        }
+        result
+    }
+
     /// Path names can only contain lowercase letters, digits and underscores.
     /// Empty spaces are replaced with underscores.
     /// Capital letters are replaced with their lowercase variants.
@@ -68,4 +128,49 @@ impl Path {
         canonical
     }
 
-}
\ No newline at end of file
+}
+
+/// The path a label in `path` can be used from, `scope` is the second parameter of the
+/// `Label` attribute. An empty string means the label is global.
+pub fn label_visible_from(path: &str, scope: Option<&str>) -> String {
+    match scope.map(|scope| scope.trim()) {
+        Some("global") => String::new(),
+        Some(levels) if levels.parse::<usize>().is_ok() => {
+            let segments = path.split('.').collect::<Vec<&str>>();
+            segments[..segments.len().saturating_sub(levels.parse::<usize>().unwrap())].join(".")
+        },
+        _ => path.to_string()
+    }
+}
+
+/// If a label which is visible from `visible_from` (see [label_visible_from]) can be used in `path`.
+pub fn is_label_visible(visible_from: &str, path: &str) -> bool {
+    visible_from.is_empty() || path == visible_from || path.starts_with(&format!("{}.", visible_from))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn resolve_diverts() {
+        let (blocks, _) = Parser::parse("# Main\n## Intro\n### Details\n## Shop\n### Intro\n# Intro\n", 1);
+        let mut root = Path::default();
+        for block in &blocks {
+            if let ASTBlock::Header(header) = block {
+                root.add_child_ast(header);
+            }
+        }
+        let split = |path: &str| path.split('.').map(|part| part.to_string()).collect::<Vec<String>>();
+        let resolve = |current: &str, target: &str, absolute: bool| root.resolve(&split(current), &split(target), absolute).into_iter().map(|path| path.join(".")).collect::<Vec<String>>();
+        // Children first, then siblings, then the root
+        assert_eq!(resolve("main.shop", "intro", false), vec!["main.shop.intro", "main.intro", "intro"]);
+        assert_eq!(resolve("main.intro", "details", false), vec!["main.intro.details"]);
+        assert_eq!(resolve("main.intro", "shop.intro", false), vec!["main.shop.intro"]);
+        assert_eq!(resolve("main.shop", "intro", true), vec!["intro"]);
+        assert!(resolve("main", "details", false).is_empty());
+        assert_eq!(SpecialTarget::from_path(&split("end")), Some(SpecialTarget::End));
+        assert_eq!(SpecialTarget::from_path(&split("main.back")), None);
+    }
+}
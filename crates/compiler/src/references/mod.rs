@@ -0,0 +1,360 @@
+use rustc_hash::FxHashMap;
+use storytell_diagnostics::location::Range;
+use storytell_js_parser::{ast::*, tokenizer::TokenKind, input::InputPresenter, JsParser};
+use storytell_parser::ast::model::*;
+use crate::base::files::BlobId;
+use crate::builtins::{BuiltinParameter, get_builtin};
+use crate::graph::{GraphBuilder, GraphNodeKind};
+use crate::json;
+use crate::json_compiler::compile::JSONSafeCompilable;
+use crate::path::Path;
+use crate::visitors::{BlockVisitor, walk_blocks};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum SymbolKind {
+    Path,
+    Label,
+    Variable
+}
+
+impl SymbolKind {
+    pub fn get_id(&self) -> u8 {
+        match self {
+            Self::Path => 0,
+            Self::Label => 1,
+            Self::Variable => 2
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum ReferenceKind {
+    /// The header of a path, or the block a label is attached to.
+    Definition,
+    /// A `->` divert to a path or label.
+    Divert,
+    /// A variable is used in inline javascript, or a label is passed to a builtin like `label_count`.
+    Read,
+    /// A variable is assigned to, or one of its fields is.
+    Write
+}
+
+impl ReferenceKind {
+    pub fn get_id(&self) -> u8 {
+        match self {
+            Self::Definition => 0,
+            Self::Divert => 1,
+            Self::Read => 2,
+            Self::Write => 3
+        }
+    }
+
+    pub fn name(&self) -> &'static str {
+        match self {
+            Self::Definition => "definition",
+            Self::Divert => "divert",
+            Self::Read => "read",
+            Self::Write => "write"
+        }
+    }
+}
+
+/// Symbols of paths and labels are the ids of their graph nodes (`chapter_1.left`,
+/// `chapter_1.left@label_name`), symbols of magic variables are their names.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Reference {
+    pub symbol: String,
+    pub symbol_kind: SymbolKind,
+    pub kind: ReferenceKind,
+    pub file: BlobId,
+    pub range: Range<usize>
+}
+
+impl JSONSafeCompilable for Reference {
+    fn safe_compile(&self) -> String {
+        json!({
+            symbol: self.symbol.safe_compile(),
+            symbolKind: self.symbol_kind.get_id(),
+            kind: self.kind.get_id(),
+            file: self.file,
+            range: self.range.safe_compile()
+        })
+    }
+}
+
+#[derive(Default, Debug)]
+pub struct ReferenceIndex {
+    /// Ordered by file, then by position.
+    pub references: Vec<Reference>,
+    symbols: FxHashMap<String, Vec<usize>>
+}
+
+impl ReferenceIndex {
+
+    /// `files` contains the id and the parsed content of every file in the project.
+    pub fn build(files: &[(BlobId, &[ASTBlock])]) -> Self {
+        let mut builder = GraphBuilder::new();
+        for (file, blocks) in files {
+            builder.add_file(*file, blocks);
+        }
+        let (mut references, label_uses) = javascript_references(files);
+        // Labels have to be resolved before the builder is consumed, the nodes keep their indexes
+        let label_uses = label_uses.into_iter().filter_map(|label_use| {
+            builder.resolve_label(&label_use.path, &label_use.name).map(|node| (node, label_use))
+        }).collect::<Vec<(usize, LabelUse)>>();
+        let graph = builder.build();
+        for node in &graph.nodes {
+            let symbol_kind = match node.kind {
+                GraphNodeKind::Path => SymbolKind::Path,
+                GraphNodeKind::Label => SymbolKind::Label,
+                _ => continue
+            };
+            references.push(Reference { symbol: node.id.clone(), symbol_kind, kind: ReferenceKind::Definition, file: node.file, range: node.range.clone() });
+        }
+        for divert in &graph.diverts {
+            if let Some(target) = divert.to {
+                let node = &graph.nodes[target];
+                let symbol_kind = match node.kind {
+                    GraphNodeKind::Path => SymbolKind::Path,
+                    GraphNodeKind::Label | GraphNodeKind::Choice => SymbolKind::Label,
+                    GraphNodeKind::End | GraphNodeKind::Back => continue
+                };
+                references.push(Reference { symbol: node.id.clone(), symbol_kind, kind: ReferenceKind::Divert, file: divert.file, range: divert.range.clone() });
+            }
+        }
+        for (node, label_use) in label_uses {
+            references.push(Reference { symbol: graph.nodes[node].id.clone(), symbol_kind: SymbolKind::Label, kind: ReferenceKind::Read, file: label_use.file, range: label_use.range });
+        }
+        references.sort_by_key(|reference| (reference.file, reference.range.start));
+        let mut symbols: FxHashMap<String, Vec<usize>> = FxHashMap::default();
+        for (ind, reference) in references.iter().enumerate() {
+            symbols.entry(reference.symbol.clone()).or_default().push(ind);
+        }
+        Self { references, symbols }
+    }
+
+    /// Every reference to the symbol, including its definition.
+    pub fn find(&self, symbol: &str) -> Vec<&Reference> {
+        self.symbols.get(symbol).map(|indexes| indexes.iter().map(|ind| &self.references[*ind]).collect()).unwrap_or_default()
+    }
+
+}
+
+/// Inline javascript in the text, where it starts in the file, and the path it's in.
+#[derive(Default)]
+struct JavascriptCollector {
+    snippets: Vec<(usize, String, String)>
+}
+
+impl JavascriptCollector {
+    fn collect(&mut self, parts: &[TextPart], path: &str) {
+        for part in parts {
+            match &part.text.kind {
+                ASTInlineKind::Javascript(code) => self.snippets.push((part.text.range.start + 1, code.clone(), path.to_string())),
+                ASTInlineKind::Bold(text) | ASTInlineKind::Italics(text) | ASTInlineKind::Underline(text) | ASTInlineKind::Code(text) => self.collect(&text.parts, path),
+                ASTInlineKind::Join => {}
+            }
+        }
+    }
+}
+
+impl BlockVisitor for JavascriptCollector {
+    fn visit_block(&mut self, block: &ASTBlock, path: &str, _position: &str) {
+        match block {
+            ASTBlock::Paragraph(paragraph) => self.collect(&paragraph.parts, path),
+            ASTBlock::Match(match_block) => {
+                self.snippets.push((match_block.matched.range.start, match_block.matched.text.clone(), path.to_string()));
+                for arm in &match_block.choices {
+                    self.collect(&arm.text.parts, path);
+                }
+            },
+            _ => {}
+        }
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, path: &str, _position: &str) {
+        self.collect(&choice.text.parts, path);
+    }
+}
+
+/// A label name passed to a builtin function, like `label_count("Name")`.
+struct LabelUse {
+    file: BlobId,
+    /// The path the javascript is in, labels are resolved from there.
+    path: String,
+    /// The canonical name of the label.
+    name: String,
+    /// The range of the string, including the quotes.
+    range: Range<usize>
+}
+
+struct VariableReferences<'a> {
+    input: InputPresenter<'a>,
+    /// Where the snippet starts in the file.
+    offset: usize,
+    file: BlobId,
+    path: &'a str,
+    references: Vec<Reference>,
+    label_uses: Vec<LabelUse>
+}
+
+impl<'a> VariableReferences<'a> {
+
+    fn add(&mut self, ident: &ASTIdentifier, kind: ReferenceKind) {
+        let name = self.input.from_range(&ident.range);
+        // The same globals the rebuilder doesn't turn into magic variables
+        if name == "window" || name == "location" || name == "document" {
+            return;
+        }
+        self.references.push(Reference {
+            symbol: name.to_string(),
+            symbol_kind: SymbolKind::Variable,
+            kind,
+            file: self.file,
+            range: Range::new(self.offset + ident.range.start, self.offset + ident.range.end)
+        });
+    }
+
+    fn label_arguments(&mut self, call: &ASTCall) {
+        let builtin = match &call.expression {
+            ASTExpression::Identifier(ident) => if let Some(builtin) = get_builtin(self.input.from_range(&ident.range)) { builtin } else { return },
+            _ => return
+        };
+        for (argument, parameter) in call.arguments.iter().zip(builtin.parameters) {
+            if let (BuiltinParameter::Label, ASTExpression::String(string)) = (parameter, argument) {
+                let name = self.input.from_range(&Range::new(string.range.start + 1, string.range.end - 1));
+                self.label_uses.push(LabelUse {
+                    file: self.file,
+                    path: self.path.to_string(),
+                    name: Path::canonicalize_name(name),
+                    range: Range::new(self.offset + string.range.start, self.offset + string.range.end)
+                });
+            }
+        }
+    }
+
+    /// Visits the object being assigned to. Only the variable the access chain starts from is recorded.
+    fn target(&mut self, exp: &ASTExpression) {
+        match exp {
+            ASTExpression::Identifier(ident) => self.add(ident, ReferenceKind::Write),
+            ASTExpression::Access(access) => {
+                self.target(&access.expression);
+                if let ASTAccessContent::Expression(accessor) = &access.accessor {
+                    self.expression(accessor);
+                }
+            },
+            _ => self.expression(exp)
+        }
+    }
+
+    fn expression(&mut self, exp: &ASTExpression) {
+        match exp {
+            ASTExpression::Identifier(ident) => self.add(ident, ReferenceKind::Read),
+            ASTExpression::Binary(binary) => {
+                if matches!(binary.operator, TokenKind::EqualsOp | TokenKind::PlusEqualsOp | TokenKind::MinusEqualsOp | TokenKind::StarEqualsOp | TokenKind::SlashEqualsOp) {
+                    self.target(&binary.left);
+                } else {
+                    self.expression(&binary.left);
+                }
+                self.expression(&binary.right);
+            },
+            ASTExpression::Unary(unary) => self.expression(&unary.expression),
+            ASTExpression::Access(access) => {
+                // Properties aren't variables, `player.name` only references `player`
+                self.expression(&access.expression);
+                if let ASTAccessContent::Expression(accessor) = &access.accessor {
+                    self.expression(accessor);
+                }
+            },
+            ASTExpression::ArrayLit(lit) => lit.elements.iter().for_each(|element| self.expression(element)),
+            ASTExpression::New(new) => {
+                self.expression(&new.expression);
+                new.arguments.iter().for_each(|argument| self.expression(argument));
+            },
+            ASTExpression::Call(call) => {
+                self.label_arguments(call);
+                self.expression(&call.expression);
+                call.arguments.iter().for_each(|argument| self.expression(argument));
+            },
+            ASTExpression::Ternary(ternary) => {
+                self.expression(&ternary.condition);
+                self.expression(&ternary.left);
+                self.expression(&ternary.right);
+            },
+            ASTExpression::StringTemplate(temp) => temp.spans.iter().for_each(|span| self.expression(&span.expression)),
+            ASTExpression::String(_) | ASTExpression::Number(_) | ASTExpression::Boolean(_) => {}
+        }
+    }
+
+}
+
+/// Reads and writes of magic variables, and label names passed to builtins, in every inline
+/// javascript snippet. Snippets which can't be parsed are skipped.
+fn javascript_references(files: &[(BlobId, &[ASTBlock])]) -> (Vec<Reference>, Vec<LabelUse>) {
+    let mut references = vec![];
+    let mut label_uses = vec![];
+    for (file, blocks) in files {
+        let mut collector = JavascriptCollector::default();
+        walk_blocks(blocks, &mut collector);
+        for (offset, code, path) in &collector.snippets {
+            let (expressions, diagnostics, input) = JsParser::parse(code);
+            if !diagnostics.is_empty() {
+                continue;
+            }
+            let mut visitor = VariableReferences { input, offset: *offset, file: *file, path, references: vec![], label_uses: vec![] };
+            for exp in &expressions {
+                visitor.expression(exp);
+            }
+            references.append(&mut visitor.references);
+            label_uses.append(&mut visitor.label_uses);
+        }
+    }
+    (references, label_uses)
+}
+
+/// Reads and writes of magic variables in every inline javascript snippet.
+/// Snippets which can't be parsed are skipped.
+pub fn variable_references(files: &[(BlobId, &[ASTBlock])]) -> Vec<Reference> {
+    javascript_references(files).0
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn find_references() {
+        let main = "# Start
+{gold = 10} {player.gold += gold}
+@{gold > 5}
+- {true}
+    -> shop
+-> Shop
+
+## Shop
+#[Label(Counter, global)]
+You have {player.gold} gold.
+@{:if label_count(\"counter\") > 1}
+    Welcome back!
+";
+        let other = "# Epilogue
+-> start.shop
+-> counter
+";
+        let (main_blocks, _) = Parser::parse(main, 1);
+        let (other_blocks, _) = Parser::parse(other, 1);
+        let index = ReferenceIndex::build(&[(1, main_blocks.as_slice()), (2, other_blocks.as_slice())]);
+        let kinds = |symbol: &str| index.find(symbol).iter().map(|reference| (reference.file, reference.kind)).collect::<Vec<(BlobId, ReferenceKind)>>();
+        assert_eq!(kinds("start.shop"), vec![(1, ReferenceKind::Divert), (1, ReferenceKind::Divert), (1, ReferenceKind::Definition), (2, ReferenceKind::Divert)]);
+        assert_eq!(kinds("start.shop@counter"), vec![(1, ReferenceKind::Definition), (1, ReferenceKind::Read), (2, ReferenceKind::Divert)]);
+        let label_use = index.find("start.shop@counter")[1];
+        assert_eq!(&main[label_use.range.start..label_use.range.end], "\"counter\"");
+        assert_eq!(kinds("gold"), vec![(1, ReferenceKind::Write), (1, ReferenceKind::Read), (1, ReferenceKind::Read)]);
+        assert_eq!(kinds("player"), vec![(1, ReferenceKind::Write), (1, ReferenceKind::Read)]);
+        let reads = index.find("gold");
+        assert_eq!(&main[reads[2].range.start..reads[2].range.end], "gold");
+        assert_eq!(reads[2].range.start, main.find("gold >").unwrap());
+        assert!(index.find("nothing").is_empty());
+    }
+}
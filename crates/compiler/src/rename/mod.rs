@@ -0,0 +1,305 @@
+use storytell_diagnostics::{diagnostic::*, make_diagnostics, dia, location::Range};
+use storytell_parser::ast::model::*;
+use crate::base::files::BlobId;
+use crate::builtins::LabelTable;
+use crate::graph::{GraphBuilder, GraphNodeKind};
+use crate::json;
+use crate::json_compiler::compile::JSONSafeCompilable;
+use crate::path::{Path, SpecialTarget};
+use crate::references::variable_references;
+use crate::visitors::MagicVariableCollectorContext;
+
+make_diagnostics!(define [
+    UNKNOWN_PATH,
+    "Path '$' doesn't exist."
+], [
+    UNKNOWN_VARIABLE,
+    "Variable '$' doesn't exist."
+], [
+    INVALID_NAME,
+    "'$' is not a valid name."
+], [
+    PATH_TAKEN,
+    "There's already a path called '$' here."
+], [
+    VARIABLE_TAKEN,
+    "There's already a variable called '$'."
+], [
+    LABEL_TAKEN,
+    "There's already a label called '$' which can be used here, diverts to the path would go to the label."
+]);
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextEdit {
+    pub range: Range<usize>,
+    pub text: String
+}
+
+impl JSONSafeCompilable for TextEdit {
+    fn safe_compile(&self) -> String {
+        json!({
+            range: self.range.safe_compile(),
+            text: self.text.safe_compile()
+        })
+    }
+}
+
+/// All edits in a single file, ordered by their position.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileEdits {
+    pub file: BlobId,
+    pub edits: Vec<TextEdit>
+}
+
+impl FileEdits {
+
+    /// Applies the edits to the content of the file they were created for.
+    pub fn apply(&self, text: &str) -> String {
+        let mut result = text.to_string();
+        for edit in self.edits.iter().rev() {
+            result.replace_range(edit.range.start..edit.range.end, &edit.text);
+        }
+        result
+    }
+
+}
+
+impl JSONSafeCompilable for FileEdits {
+    fn safe_compile(&self) -> String {
+        json!({
+            file: self.file,
+            edits: self.edits.safe_compile()
+        })
+    }
+}
+
+fn group_edits(mut edits: Vec<(BlobId, TextEdit)>) -> Vec<FileEdits> {
+    edits.sort_by_key(|(file, edit)| (*file, edit.range.start));
+    edits.dedup();
+    let mut result: Vec<FileEdits> = vec![];
+    for (file, edit) in edits {
+        match result.last_mut() {
+            Some(last) if last.file == file => last.edits.push(edit),
+            _ => result.push(FileEdits { file, edits: vec![edit] })
+        }
+    }
+    result
+}
+
+fn find_header<'a>(blocks: &'a [ASTBlock], path: &[String]) -> Option<&'a ASTHeader> {
+    let header = blocks.iter().find_map(|block| match block {
+        ASTBlock::Header(header) if Path::canonicalize_name(&header.title.text) == path[0] => Some(header),
+        _ => None
+    })?;
+    if path.len() == 1 { Some(header) } else { find_header(&header.children, &path[1..]) }
+}
+
+fn headers(blocks: &[ASTBlock]) -> impl Iterator<Item = &ASTHeader> {
+    blocks.iter().filter_map(|block| match block {
+        ASTBlock::Header(header) => Some(header),
+        _ => None
+    })
+}
+
+/// Byte ranges of the segments of the path written after `->`, `range` being the range of the divert.
+fn divert_segments(text: &str, range: &Range<usize>) -> Vec<Range<usize>> {
+    let source = &text[range.start..range.end];
+    let mut start = range.start + source.find("->").map(|ind| ind + 2).unwrap_or_default();
+    if text[start..].starts_with(' ') {
+        start += 1;
+    }
+    if text[start..].starts_with('/') {
+        start += 1;
+    }
+    let mut result = vec![];
+    let mut segment_start = start;
+    for (ind, character) in text[start..range.end].char_indices() {
+        if character == '.' {
+            result.push(Range::new(segment_start, start + ind));
+            segment_start = start + ind + 1;
+        }
+    }
+    if segment_start < range.end {
+        result.push(Range::new(segment_start, range.end));
+    }
+    result
+}
+
+/// Renames the path at `path` (e.g. `chapter_1.left`) to `new_name`. Returns the edits to the
+/// title of the header and to every divert which goes through the path.
+/// `files` contains the id, the content and the parsed content of every file in the project.
+pub fn rename_path(files: &[(BlobId, &str, &[ASTBlock])], path: &str, new_name: &str) -> StorytellResult<Vec<FileEdits>> {
+    let segments = path.split('.').map(Path::canonicalize_name).collect::<Vec<String>>();
+    let new_canonical = Path::canonicalize_name(new_name.trim());
+    if new_canonical.is_empty() || new_name.contains('\n') || SpecialTarget::from_name(&new_canonical).is_some() {
+        return Err(vec![dia!(INVALID_NAME, Range::default(), new_name)]);
+    }
+    let (file, text, header) = files.iter().find_map(|(file, text, blocks)| Some((*file, *text, find_header(blocks, &segments)?)))
+        .ok_or_else(|| vec![dia!(UNKNOWN_PATH, Range::default(), path)])?;
+    let old_canonical = &segments[segments.len() - 1];
+    if *old_canonical != new_canonical {
+        let taken = if segments.len() == 1 {
+            files.iter().flat_map(|(_, _, blocks)| headers(blocks)).any(|other| Path::canonicalize_name(&other.title.text) == new_canonical)
+        } else {
+            let parent = files.iter().find_map(|(_, _, blocks)| find_header(blocks, &segments[..segments.len() - 1])).unwrap();
+            headers(&parent.children).any(|other| Path::canonicalize_name(&other.title.text) == new_canonical)
+        };
+        if taken {
+            return Err(vec![dia!(PATH_TAKEN, header.title.range.clone(), &new_canonical)]);
+        }
+    }
+    // Diverts look for labels before paths, so a label with the new name would take the diverts
+    let mut labels = LabelTable::default();
+    for (_, _, blocks) in files {
+        headers(blocks).for_each(|header| labels.add_header(header));
+    }
+    let label_taken = |from: &str| *old_canonical != new_canonical && labels.is_visible(&new_canonical, from);
+    if label_taken(&segments.join(".")) {
+        return Err(vec![dia!(LABEL_TAKEN, header.title.range.clone(), &new_canonical)]);
+    }
+    let title_start = header.title.range.start + text[header.title.range.start..header.title.range.end].find(&header.title.text).unwrap_or_default();
+    let mut edits = vec![(file, TextEdit { range: Range::new(title_start, title_start + header.title.text.len()), text: new_name.trim().to_string() })];
+    let mut builder = GraphBuilder::new();
+    for (file, _, blocks) in files {
+        builder.add_file(*file, blocks);
+    }
+    let graph = builder.build();
+    let joined = segments.join(".");
+    for divert in &graph.diverts {
+        let target = match divert.to {
+            Some(target) if graph.nodes[target].kind == GraphNodeKind::Path => &graph.nodes[target],
+            _ => continue
+        };
+        if target.id != joined && !target.id.starts_with(&format!("{}.", joined)) {
+            continue;
+        }
+        let written = match files.iter().find(|(file, _, _)| *file == divert.file) {
+            Some((_, text, _)) => divert_segments(text, &divert.range),
+            None => continue
+        };
+        // The written path is either the full path, or the end of it when it's relative
+        let target_len = target.id.split('.').count();
+        if let Some(ind) = (segments.len() + written.len()).checked_sub(1 + target_len) {
+            if let Some(range) = written.get(ind) {
+                if written.len() == 1 && label_taken(&graph.nodes[divert.from].path) {
+                    return Err(vec![dia!(LABEL_TAKEN, divert.range.clone(), &new_canonical)]);
+                }
+                edits.push((divert.file, TextEdit { range: range.clone(), text: new_canonical.clone() }));
+            }
+        }
+    }
+    Ok(group_edits(edits))
+}
+
+fn is_identifier(name: &str) -> bool {
+    !name.is_empty() && !name.starts_with(|character: char| character.is_ascii_digit()) && name.chars().all(|character| character.is_alphanumeric() || character == '_' || character == '$')
+}
+
+/// Renames the top-level magic variable `name` to `new_name` in every inline javascript snippet.
+/// Snippets which can't be parsed are skipped.
+pub fn rename_variable(files: &[(BlobId, &str, &[ASTBlock])], variables: &MagicVariableCollectorContext, name: &str, new_name: &str) -> StorytellResult<Vec<FileEdits>> {
+    if !variables.variables.contains_key(name) {
+        return Err(vec![dia!(UNKNOWN_VARIABLE, Range::default(), name)]);
+    }
+    if !is_identifier(new_name) {
+        return Err(vec![dia!(INVALID_NAME, Range::default(), new_name)]);
+    }
+    if name != new_name && variables.variables.contains_key(new_name) {
+        return Err(vec![dia!(VARIABLE_TAKEN, Range::default(), new_name)]);
+    }
+    let edits = variable_references(&files.iter().map(|(id, _, blocks)| (*id, *blocks)).collect::<Vec<(BlobId, &[ASTBlock])>>()).into_iter()
+        .filter(|reference| reference.symbol == name)
+        .map(|reference| (reference.file, TextEdit { range: reference.range, text: new_name.to_string() }))
+        .collect();
+    Ok(group_edits(edits))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_parser::ast::Parser;
+    use crate::json_compiler::{JSONCompilerProvider, JSONCompilerContext};
+    use crate::base::compile_str;
+
+    #[test]
+    fn rename_paths_and_variables() {
+        let main = "# Start
+Hello, {name = \"Jim\"}! {player.name = name}
+-> Middle
+-> start.middle.deep
+
+## Middle
+@{name}
+- {\"James\"}
+    -> deep
+
+### Deep
+-> epilogue
+";
+        let other = "# Epilogue
+-> start.middle
+";
+        let (main_blocks, _) = Parser::parse(main, 1);
+        let (other_blocks, _) = Parser::parse(other, 1);
+        let files = [(1, main, main_blocks.as_slice()), (2, other, other_blocks.as_slice())];
+        let edits = rename_path(&files, "start.middle", "The Center").unwrap();
+        assert_eq!(edits[0].apply(main), "# Start
+Hello, {name = \"Jim\"}! {player.name = name}
+-> the_center
+-> start.the_center.deep
+
+## The Center
+@{name}
+- {\"James\"}
+    -> deep
+
+### Deep
+-> epilogue
+");
+        assert_eq!(edits[1].apply(other), "# Epilogue
+-> start.the_center
+");
+        assert!(rename_path(&files, "start", "Epilogue").is_err());
+        assert!(rename_path(&files, "start.middle.deep", "Epilogue").is_ok());
+        assert!(rename_path(&files, "start.nowhere", "Somewhere").is_err());
+        assert!(rename_path(&files, "start", "!!").is_err());
+        assert_eq!(rename_path(&files, "start.middle", "End").unwrap_err()[0].msg, "'End' is not a valid name.");
+        let (_, _, ctx) = compile_str::<JSONCompilerProvider>(main, JSONCompilerContext::new(None), 1);
+        let edits = rename_variable(&files, &ctx.magic_variables, "name", "hero").unwrap();
+        assert_eq!(edits.len(), 1);
+        assert_eq!(edits[0].apply(main).lines().take(7).collect::<Vec<&str>>().join("\n"), "# Start
+Hello, {hero = \"Jim\"}! {player.name = hero}
+-> Middle
+-> start.middle.deep
+
+## Middle
+@{hero}");
+        assert!(rename_variable(&files, &ctx.magic_variables, "name", "player").is_err());
+        assert!(rename_variable(&files, &ctx.magic_variables, "name", "1abc").is_err());
+        assert!(rename_variable(&files, &ctx.magic_variables, "nobody", "somebody").is_err());
+    }
+
+    #[test]
+    fn rename_path_to_label() {
+        let main = "# Start
+-> middle
+## Middle
+Hello.
+## Gate
+#[Label(Door)]
+- Knock
+-> /start.middle
+## Hall
+#[Label(Stairs, global)]
+Some stairs.
+";
+        let (blocks, _) = Parser::parse(main, 1);
+        let files = [(1, main, blocks.as_slice())];
+        // `-> stairs` in Start would go to the global label
+        assert_eq!(rename_path(&files, "start.middle", "Stairs").unwrap_err()[0].msg, "There's already a label called 'stairs' which can be used here, diverts to the path would go to the label.");
+        // The door label is only visible in Gate, whose divert is written in full
+        assert!(rename_path(&files, "start.middle", "Door").is_ok());
+        // A label visible from the path itself
+        assert!(rename_path(&files, "start.gate", "Stairs").is_err());
+    }
+}
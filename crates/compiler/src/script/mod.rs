@@ -0,0 +1,204 @@
+use std::fmt::Write as _;
+use storytell_parser::ast::model::*;
+use crate::visitors::{BlockVisitor, walk_blocks};
+
+/// What kind of line the reader (or voice actor) is dealing with.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ScriptLineKind {
+    Paragraph,
+    Choice
+}
+
+impl ScriptLineKind {
+    pub fn as_str(&self) -> &'static str {
+        match self {
+            Self::Paragraph => "paragraph",
+            Self::Choice => "choice"
+        }
+    }
+}
+
+/// A single line of the linear script.
+///
+/// The `id` is derived from the canonical path of the header the line is in,
+/// followed by the position of the block inside that header. Nested blocks
+/// (choice children, match arm children) add their position to the parent's id,
+/// separated by a dot, so `chapter_1.left:2.0.1` is the second block inside the
+/// first choice of the third block of the `chapter_1.left` path.
+#[derive(Clone, Debug)]
+pub struct ScriptLine {
+    pub number: usize,
+    pub id: String,
+    pub path: String,
+    pub kind: ScriptLineKind,
+    pub text: String,
+    /// Inline javascript which got replaced with `[n]` placeholders, in order.
+    pub placeholders: Vec<String>,
+    /// Only for choices - the path the first divert in the choice's children leads to.
+    pub leads_to: Option<String>
+}
+
+#[derive(Default)]
+pub struct Script {
+    pub lines: Vec<ScriptLine>
+}
+
+impl Script {
+
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Flattens all headers (and their sub-headers) in `blocks`.
+    pub fn add_blocks(&mut self, blocks: &[ASTBlock]) {
+        walk_blocks(blocks, self);
+    }
+
+    fn push_line(&mut self, path: &str, position: &str, kind: ScriptLineKind, text: String, placeholders: Vec<String>, leads_to: Option<String>) {
+        self.lines.push(ScriptLine {
+            number: self.lines.len() + 1,
+            id: format!("{}:{}", path, position),
+            path: path.to_string(),
+            kind,
+            text,
+            placeholders,
+            leads_to
+        });
+    }
+
+    /// Plain text script, grouped by path:
+    /// ```text
+    /// == chapter_1 ==
+    /// 1. [chapter_1:0] Some text with a [1] placeholder.
+    ///    [1] = player_name
+    /// 2. [chapter_1:1.0] (choice) Left -> graveyard_left
+    /// ```
+    pub fn to_text(&self) -> String {
+        let mut result = String::new();
+        let mut current_path: Option<&str> = None;
+        for line in &self.lines {
+            if current_path != Some(&line.path) {
+                if current_path.is_some() {
+                    result.push('\n');
+                }
+                writeln!(result, "== {} ==", line.path).unwrap();
+                current_path = Some(&line.path);
+            }
+            write!(result, "{}. [{}] ", line.number, line.id).unwrap();
+            if line.kind == ScriptLineKind::Choice {
+                result.push_str("(choice) ");
+            }
+            result.push_str(&line.text);
+            if let Some(leads_to) = &line.leads_to {
+                write!(result, " -> {}", leads_to).unwrap();
+            }
+            result.push('\n');
+            for (ind, placeholder) in line.placeholders.iter().enumerate() {
+                writeln!(result, "   [{}] = {}", ind + 1, placeholder).unwrap();
+            }
+        }
+        result
+    }
+
+    /// CSV with the header `number,id,path,kind,text,leads_to,placeholders`.
+    /// Placeholders are joined with `; `.
+    pub fn to_csv(&self) -> String {
+        let mut result = String::from("number,id,path,kind,text,leads_to,placeholders\n");
+        for line in &self.lines {
+            writeln!(result, "{},{},{},{},{},{},{}",
+                line.number,
+                escape_csv(&line.id),
+                escape_csv(&line.path),
+                line.kind.as_str(),
+                escape_csv(&line.text),
+                escape_csv(line.leads_to.as_deref().unwrap_or_default()),
+                escape_csv(&line.placeholders.join("; "))
+            ).unwrap();
+        }
+        result
+    }
+
+}
+
+impl BlockVisitor for Script {
+    fn visit_block(&mut self, block: &ASTBlock, path: &str, position: &str) {
+        if let ASTBlock::Paragraph(paragraph) = block {
+            let (text, placeholders) = flatten_text(&paragraph.parts, &paragraph.tail);
+            self.push_line(path, position, ScriptLineKind::Paragraph, text, placeholders, None);
+        }
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, path: &str, position: &str) {
+        let (text, placeholders) = flatten_text(&choice.text.parts, &choice.text.tail);
+        let leads_to = choice.children.iter().find_map(|child| match child {
+            ASTBlock::Divert(divert) => Some(divert.path.join(".")),
+            _ => None
+        });
+        self.push_line(path, position, ScriptLineKind::Choice, text, placeholders, leads_to);
+    }
+}
+
+/// Turns text parts into plain text, replacing inline javascript with
+/// numbered `[n]` placeholders.
+pub fn flatten_text(parts: &[TextPart], tail: &str) -> (String, Vec<String>) {
+    let mut result = String::new();
+    let mut placeholders = vec![];
+    for part in parts {
+        result.push_str(&part.before);
+        match &part.text.kind {
+            ASTInlineKind::Javascript(code) => {
+                placeholders.push(code.trim().to_string());
+                write!(result, "[{}]", placeholders.len()).unwrap();
+            },
+            ASTInlineKind::Join => {},
+            other => result.push_str(&other.to_raw())
+        }
+    }
+    result.push_str(tail);
+    (result.trim().to_string(), placeholders)
+}
+
+fn escape_csv(value: &str) -> String {
+    if value.contains([',', '"', '\n', '\r']) {
+        format!("\"{}\"", value.replace('"', "\"\""))
+    } else {
+        value.to_string()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use storytell_parser::ast::Parser;
+
+    #[test]
+    fn export_script() {
+        let (blocks, _) = Parser::parse("
+# Graveyard
+James and {companion} arrive at the graveyard.
+
+- Left
+    They found nothing, \"sadly\".
+- Right
+    -> graveyard_right
+
+## Graveyard Right
+An open grave!
+", 1);
+        let mut script = Script::new();
+        script.add_blocks(&blocks);
+        assert_eq!(script.lines.len(), 5);
+        assert_eq!(script.lines[0].id, "graveyard:0");
+        assert_eq!(script.lines[0].text, "James and [1] arrive at the graveyard.");
+        assert_eq!(script.lines[0].placeholders, vec!["companion".to_string()]);
+        assert_eq!(script.lines[1].id, "graveyard:1.0");
+        assert_eq!(script.lines[2].id, "graveyard:1.0.0");
+        assert_eq!(script.lines[3].leads_to, Some("graveyard_right".to_string()));
+        assert_eq!(script.lines[4].id, "graveyard.graveyard_right:0");
+        let csv = script.to_csv();
+        assert!(csv.contains("3,graveyard:1.0.0,graveyard,paragraph,\"They found nothing, \"\"sadly\"\".\",,"));
+        let text = script.to_text();
+        assert!(text.contains("== graveyard.graveyard_right ==\n5. [graveyard.graveyard_right:0] An open grave!"));
+        assert!(text.contains("4. [graveyard:1.1] (choice) Right -> graveyard_right"));
+    }
+}
@@ -0,0 +1,74 @@
+use storytell_parser::ast::model::*;
+use crate::path::Path;
+
+/// Visits storytell blocks with the canonical path of the header they're in
+/// and a stable position inside that header.
+///
+/// Positions count only the non-header children of a header. Nested blocks
+/// (choices, choice children, match children) append their index to the parent
+/// position, separated by a dot - `2.0.1` is the second block inside the first
+/// choice of the third block.
+pub trait BlockVisitor {
+    fn visit_header(&mut self, _header: &ASTHeader, _path: &str) {}
+    fn visit_block(&mut self, _block: &ASTBlock, _path: &str, _position: &str) {}
+    fn visit_choice(&mut self, _choice: &ASTChoice, _path: &str, _position: &str) {}
+}
+
+/// Walks all headers in `blocks`. Sub-headers are visited after all other
+/// children of their parent.
+pub fn walk_blocks<V: BlockVisitor + ?Sized>(blocks: &[ASTBlock], visitor: &mut V) {
+    for block in blocks {
+        if let ASTBlock::Header(header) = block {
+            walk_header(header, "", visitor);
+        }
+    }
+}
+
+pub fn walk_header<V: BlockVisitor + ?Sized>(header: &ASTHeader, parent_path: &str, visitor: &mut V) {
+    let name = Path::canonicalize_name(&header.title.text);
+    let path = if parent_path.is_empty() { name } else { format!("{}.{}", parent_path, name) };
+    visitor.visit_header(header, &path);
+    let mut position = 0;
+    let mut sub_headers = vec![];
+    for child in &header.children {
+        if let ASTBlock::Header(sub_header) = child {
+            sub_headers.push(sub_header);
+        } else {
+            walk_block(child, &path, &position.to_string(), visitor);
+            position += 1;
+        }
+    }
+    for sub_header in sub_headers {
+        walk_header(sub_header, &path, visitor);
+    }
+}
+
+fn walk_children<V: BlockVisitor + ?Sized>(blocks: &[ASTBlock], path: &str, parent_position: &str, visitor: &mut V) {
+    for (ind, block) in blocks.iter().enumerate() {
+        walk_block(block, path, &format!("{}.{}", parent_position, ind), visitor);
+    }
+}
+
+fn walk_block<V: BlockVisitor + ?Sized>(block: &ASTBlock, path: &str, position: &str, visitor: &mut V) {
+    if let ASTBlock::Header(header) = block {
+        walk_header(header, path, visitor);
+        return;
+    }
+    visitor.visit_block(block, path, position);
+    match block {
+        ASTBlock::ChoiceGroup(group) => {
+            for (ind, choice) in group.choices.iter().enumerate() {
+                let choice_position = format!("{}.{}", position, ind);
+                visitor.visit_choice(choice, path, &choice_position);
+                walk_children(&choice.children, path, &choice_position, visitor);
+            }
+        },
+        ASTBlock::Match(match_block) => {
+            walk_children(&match_block.direct_children, path, position, visitor);
+            for (ind, arm) in match_block.choices.iter().enumerate() {
+                walk_children(&arm.children, path, &format!("{}.{}", position, ind), visitor);
+            }
+        },
+        _ => {}
+    }
+}
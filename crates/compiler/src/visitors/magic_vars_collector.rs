@@ -1,6 +1,7 @@
 use storytell_diagnostics::{diagnostic::*, make_diagnostics, location::Range, dia };
 use storytell_js_parser::{ast::*, tokenizer::TokenKind, input::InputPresenter};
 use std::{collections::HashMap, fmt::Display};
+use crate::builtins::get_builtin;
 
 make_diagnostics!(define [
     MUST_BE_OBJ,
@@ -8,6 +9,9 @@ make_diagnostics!(define [
 ], [
     DIFFERENT_TYPE,
     "Variable '$' is a '$', but '$' is being assigned to it."
+], [
+    ASSIGN_TO_BUILTIN,
+    "'$' is a builtin function, it can't be assigned to."
 ]);
 
 macro_rules! match_str {
@@ -53,13 +57,19 @@ impl ResolveChainResult {
         }
     }
 
-    pub fn set_value<'a>(&self, val_type: MagicVariableType, collector: &'a mut MagicVarCollector, err_range: Range<usize>) {
+    pub fn set_value(&self, val_type: MagicVariableType, collector: &mut MagicVarCollector, err_range: Range<usize>) {
+        let key = match self {
+            Self::Top(name) => (None, name.clone()),
+            Self::Nested(id, name) => (Some(*id), name.clone()),
+            Self::None => return
+        };
         if let Some((store, var_name)) = self.get_store(collector.ctx) {
             if let Some(prev) = store.insert(var_name.clone(), val_type.clone()) {
                 if prev != val_type && !matches!(prev, MagicVariableType::Unknown) {
-                    collector.diagnostics.push(dia!(DIFFERENT_TYPE, err_range, var_name, &prev.to_string(), &val_type.to_string()))
+                    collector.diagnostics.push(dia!(DIFFERENT_TYPE, err_range.clone(), var_name, &prev.to_string(), &val_type.to_string()))
                 }
             }
+            collector.ctx.assignments.entry(key).or_insert(err_range);
         }
     }
 
@@ -97,7 +107,10 @@ pub type MagicObject = HashMap<String, MagicVariableType>;
 pub struct MagicVariableCollectorContext {
     pub variables: MagicObject,
     pub objects: HashMap<u32, MagicObject>,
-    pub counter: u32
+    pub counter: u32,
+    /// Where each variable (the id of its object, if it's a property, and its name) was assigned to
+    /// for the first time. Conflicts found when the context is merged are reported there.
+    pub assignments: HashMap<(Option<u32>, String), Range<usize>>
 }
 
 impl MagicVariableCollectorContext {
@@ -128,6 +141,53 @@ impl MagicVariableCollectorContext {
         } 
     }
 
+    /// Adds the variables collected in `other`, its objects get new ids. Like when a variable is assigned
+    /// again, a different type replaces the old one and is reported where `other` assigned it first (or at
+    /// `range`, if that isn't known), but an unknown type never replaces a known one.
+    pub fn merge(&mut self, mut other: MagicVariableCollectorContext, range: &Range<usize>, diagnostics: &mut Vec<Diagnostic>) {
+        let variables = std::mem::take(&mut other.variables);
+        self.merge_object(None, None, variables, &mut other, range, diagnostics);
+    }
+
+    fn store(&mut self, object: Option<u32>) -> &mut MagicObject {
+        match object {
+            Some(id) => self.objects.get_mut(&id).unwrap(),
+            None => &mut self.variables
+        }
+    }
+
+    /// `source_object` is the id of the object in `other`.
+    fn merge_object(&mut self, object: Option<u32>, source_object: Option<u32>, source: MagicObject, other: &mut MagicVariableCollectorContext, range: &Range<usize>, diagnostics: &mut Vec<Diagnostic>) {
+        // Sorted, so new objects always get the same ids
+        let mut source = source.into_iter().collect::<Vec<(String, MagicVariableType)>>();
+        source.sort_by(|a, b| a.0.cmp(&b.0));
+        for (name, var_type) in source {
+            let prev = self.store(object).get(&name).cloned();
+            let var_type = match (&prev, var_type) {
+                (Some(MagicVariableType::ObjectRef(id)), MagicVariableType::ObjectRef(other_id)) => {
+                    let properties = other.objects.remove(&other_id).unwrap_or_default();
+                    self.merge_object(Some(*id), Some(other_id), properties, other, range, diagnostics);
+                    continue;
+                },
+                (Some(_), MagicVariableType::Unknown) => continue,
+                (_, MagicVariableType::ObjectRef(other_id)) => {
+                    let id = self.create_obj();
+                    let properties = other.objects.remove(&other_id).unwrap_or_default();
+                    self.merge_object(Some(id), Some(other_id), properties, other, range, diagnostics);
+                    MagicVariableType::ObjectRef(id)
+                },
+                (_, var_type) => var_type
+            };
+            if let Some(prev) = prev {
+                if prev != var_type && !matches!(prev, MagicVariableType::Unknown) {
+                    let range = other.assignments.get(&(source_object, name.clone())).unwrap_or(range);
+                    diagnostics.push(dia!(DIFFERENT_TYPE, range.clone(), &name, &prev.to_string(), &var_type.to_string()));
+                }
+            }
+            self.store(object).insert(name, var_type);
+        }
+    }
+
 }
 
 pub struct MagicVarCollector<'a> {
@@ -180,7 +240,7 @@ impl<'a> MagicVarCollector<'a> {
     }
 
     fn resolve_chain(&mut self, chain: &ASTAccess) -> ResolveChainResult {
-        if let ASTExpression::Access(_) = &chain.expression {
+        if let ASTExpression::Access(_) | ASTExpression::Identifier(_) = &chain.expression {
             let mut result = vec![];
             let mut left = &chain.expression;
             while let ASTExpression::Access(acc) = left {
@@ -246,8 +306,14 @@ impl<'a> MagicVarCollector<'a> {
                 match &exp.left {
                     ASTExpression::Identifier(left_ident) => {
                         let left_name = self.input.from_range(&left_ident.range).to_string();
+                        if get_builtin(&left_name).is_some() {
+                            self.diagnostics.push(dia!(ASSIGN_TO_BUILTIN, self.range(&left_ident.range), &left_name));
+                            return MagicVariableType::Unknown;
+                        }
                         let var_type = self.resolve_binary(&exp.operator, &exp.right);
                         self.collected.push((left_name.clone(), var_type.get_id()));
+                        let range = self.range(&exp.range);
+                        self.ctx.assignments.entry((None, left_name.clone())).or_insert(range);
                         if let Some(prev) = self.ctx.variables.insert(left_name.to_string(), var_type.clone()) {
                             if prev != var_type && !matches!(prev, MagicVariableType::Unknown) {
                                 self.diagnostics.push(dia!(DIFFERENT_TYPE, self.range(&exp.range), &left_name, &prev.to_string(), &var_type.to_string()))
@@ -285,6 +351,11 @@ impl<'a> MagicVarCollector<'a> {
                }
             },
             ASTExpression::Call(call) => {
+                if let ASTExpression::Identifier(ident) = &call.expression {
+                    if let Some(builtin) = get_builtin(self.input.from_range(&ident.range)) {
+                        return builtin.returns.clone();
+                    }
+                }
                 if let ASTExpression::Access(access) = &call.expression {
                     if let Some(access_text) = self.get_string_from_accessor(&access.accessor) {
                         if match_str!(access_text, "push", "pop", "join", "slice", "splice") {
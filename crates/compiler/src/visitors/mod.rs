@@ -1,6 +1,7 @@
-
 pub mod magic_vars_collector;
 pub mod rebuilder;
+pub mod blocks;
 
 pub use magic_vars_collector::*;
-pub use rebuilder::*;
\ No newline at end of file
+pub use rebuilder::*;
+pub use blocks::*;
\ No newline at end of file
@@ -1,6 +1,7 @@
 use storytell_diagnostics::{location::Range, diagnostic::StorytellResult};
 use storytell_js_parser::{ast::*, input::InputPresenter, JsParser};
 use std::fmt::Write as _;
+use crate::builtins::get_builtin;
 
 /// Receives JSParsed content, returns a **valid**
 /// javascript string which can be evaled.
@@ -9,7 +10,8 @@ pub struct Rebuilder<'a> {
     /// Prefixes all global identifiers with a property access,
     /// so for example if this property is set to "this",
     /// All variables will have `this.` before them.
-    /// This excludes variables like "window", "document" and "location"
+    /// This excludes variables like "window", "document" and "location",
+    /// and builtin functions.
     prefix_idents: Option<String>
 }
 
@@ -33,7 +35,7 @@ impl<'a> Rebuilder<'a> {
     fn resolve_ident(&self, range: &Range<usize>) -> String {
         let ident_text = self.input.from_range(range);
         match &self.prefix_idents {
-            Some(prefix) if ident_text != prefix && ident_text != "window" && ident_text != "location" && ident_text != "document" => format!("{}.{}", prefix, ident_text),
+            Some(prefix) if ident_text != prefix && ident_text != "window" && ident_text != "location" && ident_text != "document" && get_builtin(ident_text).is_none() => format!("{}.{}", prefix, ident_text),
             _ => ident_text.to_string()
         }
     }
@@ -90,18 +90,46 @@ macro_rules! dia {
             variant: DiagnosticVariants::Error
         }
     };
-    ($diagnostic: ident, $range: expr, $($vars: expr),*) => {
+    ($diagnostic: ident, $range: expr, Warning $(, $vars: expr)*) => {
         Diagnostic {
             msg: format_diagnostic(&Diagnostics::$diagnostic, vec![$($vars),*]),
             range: $range,
-            variant: DiagnosticVariants::Error
+            variant: DiagnosticVariants::Warning
         }
     };
-    ($diagnostic: ident, $range: expr, $variant: ident, $($vars: expr),*) => {
+    ($diagnostic: ident, $range: expr, Suggestion $(, $vars: expr)*) => {
         Diagnostic {
             msg: format_diagnostic(&Diagnostics::$diagnostic, vec![$($vars),*]),
             range: $range,
-            variant: DiagnosticVariants::$variant
+            variant: DiagnosticVariants::Suggestion
         }
+    };
+    ($diagnostic: ident, $range: expr, $($vars: expr),*) => {
+        Diagnostic {
+            msg: format_diagnostic(&Diagnostics::$diagnostic, vec![$($vars),*]),
+            range: $range,
+            variant: DiagnosticVariants::Error
+        }
+    };
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    make_diagnostics!(define [
+        UNKNOWN,
+        "There's nothing called '$' in '$'."
+    ]);
+
+    #[test]
+    fn diagnostic_variants() {
+        let error = dia!(UNKNOWN, Range::new(0, 1), "a", "b");
+        assert_eq!(error.msg, "There's nothing called 'a' in 'b'.");
+        assert_eq!(error.variant, DiagnosticVariants::Error);
+        let warning = dia!(UNKNOWN, Range::new(0, 1), Warning, "a", "b");
+        assert_eq!(warning.msg, "There's nothing called 'a' in 'b'.");
+        assert_eq!(warning.variant, DiagnosticVariants::Warning);
+        assert_eq!(dia!(UNKNOWN, Range::new(0, 1), Suggestion, "a", "b").variant, DiagnosticVariants::Suggestion);
     }
-}
\ No newline at end of file
+}
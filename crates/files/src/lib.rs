@@ -1,16 +1,96 @@
+pub mod watcher;
+pub mod memory;
 
-use std::{fs::{read_dir, write, rename, read_to_string, remove_file, remove_dir_all, remove_dir, create_dir, ReadDir}, path::Path, iter::Flatten};
+use std::{fs::{read_dir, write, rename, read_to_string, remove_file, remove_dir_all, remove_dir, create_dir}, path::{Path, PathBuf}, io::{Error, ErrorKind}, fmt::{Display, Formatter, Result as FmtResult}};
+
+/// A file or directory inside a directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct Entry {
+    pub name: String,
+    pub path: PathBuf,
+    pub is_dir: bool
+}
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileErrorKind {
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    NotADirectory,
+    IsADirectory,
+    DirectoryNotEmpty,
+    Other(String)
+}
+
+impl Display for FileErrorKind {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        match self {
+            Self::NotFound => write!(f, "It doesn't exist."),
+            Self::AlreadyExists => write!(f, "Something with the same name already exists."),
+            Self::PermissionDenied => write!(f, "Permission denied."),
+            Self::NotADirectory => write!(f, "It's not a directory."),
+            Self::IsADirectory => write!(f, "It's a directory."),
+            Self::DirectoryNotEmpty => write!(f, "The directory isn't empty."),
+            Self::Other(reason) => write!(f, "{}.", reason)
+        }
+    }
+}
+
+/// A file operation which failed, and the path it failed on.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FileError {
+    pub path: PathBuf,
+    pub kind: FileErrorKind
+}
+
+impl FileError {
+
+    pub fn new<P: AsRef<Path>>(path: P, kind: FileErrorKind) -> Self {
+        Self { path: path.as_ref().to_path_buf(), kind }
+    }
+
+    pub fn from_io<P: AsRef<Path>>(path: P, error: Error) -> Self {
+        Self::new(path, match error.kind() {
+            ErrorKind::NotFound => FileErrorKind::NotFound,
+            ErrorKind::AlreadyExists => FileErrorKind::AlreadyExists,
+            ErrorKind::PermissionDenied => FileErrorKind::PermissionDenied,
+            _ => FileErrorKind::Other(error.to_string())
+        })
+    }
+
+}
+
+impl Display for FileError {
+    fn fmt(&self, f: &mut Formatter<'_>) -> FmtResult {
+        write!(f, "'{}': {}", self.path.display(), self.kind)
+    }
+}
+
+pub type FileResult<T> = Result<T, FileError>;
 
 pub trait FileHost {
-    fn write_file<P: AsRef<Path>>(&mut self, path: P, content: &str) -> Option<()>;
-    fn rename_item<P: AsRef<Path>>(&mut self, path: P, name: &str) -> Option<(String, bool)>;
-    fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> Option<()>;
-    fn delete_dir_recursive<P: AsRef<Path>>(&mut self, path: P) -> Option<()>;
-    fn delete_dir<P: AsRef<Path>>(&mut self, path: P) -> Option<()>;
-    fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> Option<()>;
-    fn read_file<P: AsRef<Path>>(&self, path: P) -> Option<String>;
-    fn get_entries_from_directory<P: AsRef<Path>>(&self, path: P) -> Flatten<ReadDir>;
-    fn get_files_from_directory<P: AsRef<Path>>(&self, path: P) -> Vec<String>;
+    fn write_file<P: AsRef<Path>>(&mut self, path: P, content: &str) -> FileResult<()>;
+    /// Gives the item a new name in the same directory. Returns the new path and whether the item is a directory.
+    fn rename_item<P: AsRef<Path>>(&mut self, path: P, name: &str) -> FileResult<(String, bool)>;
+    fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()>;
+    fn delete_dir_recursive<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()>;
+    fn delete_dir<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()>;
+    fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()>;
+    fn read_file<P: AsRef<Path>>(&self, path: P) -> FileResult<String>;
+    fn is_dir<P: AsRef<Path>>(&self, path: P) -> bool;
+    fn get_entries_from_directory<P: AsRef<Path>>(&self, path: P) -> FileResult<Vec<Entry>>;
+
+    fn get_files_from_directory<P: AsRef<Path>>(&self, directory: P) -> FileResult<Vec<String>> {
+        let mut files: Vec<String> = vec![];
+        for entry in self.get_entries_from_directory(directory)? {
+            if entry.is_dir {
+                files.append(&mut self.get_files_from_directory(&entry.path)?);
+            } else {
+                files.push(entry.path.to_string_lossy().to_string());
+            }
+        }
+        Ok(files)
+    }
 }
 
 #[derive(Default)]
@@ -18,53 +98,50 @@ pub struct SysFileHost;
 
 impl FileHost for SysFileHost {
 
-    fn write_file<P: AsRef<Path>>(&mut self, path: P, content: &str) -> Option<()> {
-        write(path, content).ok()
+    fn write_file<P: AsRef<Path>>(&mut self, path: P, content: &str) -> FileResult<()> {
+        write(&path, content).map_err(|error| FileError::from_io(path, error))
     }
 
-    fn rename_item<P: AsRef<Path>>(&mut self, path: P, name: &str) -> Option<(String, bool)> {
+    fn rename_item<P: AsRef<Path>>(&mut self, path: P, name: &str) -> FileResult<(String, bool)> {
         let old_path = path.as_ref();
-        let new_path = old_path.parent()?.join(name).to_str()?.to_string();
-        rename(&old_path, &new_path).ok()?;
-        Some((new_path, old_path.is_dir()))
+        let new_path = old_path.parent().ok_or_else(|| FileError::new(old_path, FileErrorKind::NotFound))?.join(name);
+        rename(old_path, &new_path).map_err(|error| FileError::from_io(old_path, error))?;
+        let is_dir = new_path.is_dir();
+        Ok((new_path.to_string_lossy().to_string(), is_dir))
     }
 
-    fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> Option<()> {
-        remove_file(path).ok()
+    fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()> {
+        remove_file(&path).map_err(|error| FileError::from_io(path, error))
     }
 
-    fn delete_dir_recursive<P: AsRef<Path>>(&mut self, path: P) -> Option<()> {
-        remove_dir_all(path).ok()
+    fn delete_dir_recursive<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()> {
+        remove_dir_all(&path).map_err(|error| FileError::from_io(path, error))
     }
 
-    fn read_file<P: AsRef<Path>>(&self, path: P) -> Option<String> {
-        read_to_string(path).ok()
+    fn read_file<P: AsRef<Path>>(&self, path: P) -> FileResult<String> {
+        read_to_string(&path).map_err(|error| FileError::from_io(path, error))
     }
 
-    fn delete_dir<P: AsRef<Path>>(&mut self, path: P) -> Option<()> {
-        remove_dir(path).ok()
+    fn delete_dir<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()> {
+        remove_dir(&path).map_err(|error| FileError::from_io(path, error))
     }
 
-    fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> Option<()> {
-        create_dir(path).ok()
+    fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()> {
+        create_dir(&path).map_err(|error| FileError::from_io(path, error))
     }
 
-    fn get_entries_from_directory<P: AsRef<Path>>(&self, path: P) -> Flatten<ReadDir> {
-        read_dir(path).unwrap().flatten()
+    fn is_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        path.as_ref().is_dir()
     }
 
-    fn get_files_from_directory<P: AsRef<Path>>(&self, directory: P) -> Vec<String> {
-        let mut files: Vec<String> = vec![];
-        for entry in read_dir(directory).unwrap().flatten() {
-            if entry.file_type().unwrap().is_dir() {
-                let path = entry.path();
-                let path_str = path.to_str().unwrap();
-                files.append(&mut self.get_files_from_directory(path_str));
-            } else {
-                files.push(entry.path().to_str().unwrap().to_string());
-            }
-        }
-        files
+    fn get_entries_from_directory<P: AsRef<Path>>(&self, path: P) -> FileResult<Vec<Entry>> {
+        let entries = read_dir(&path).map_err(|error| FileError::from_io(&path, error))?;
+        // Entries which disappeared while reading the directory are skipped
+        Ok(entries.flatten().filter_map(|entry| Some(Entry {
+            name: entry.file_name().to_string_lossy().to_string(),
+            path: entry.path(),
+            is_dir: entry.file_type().ok()?.is_dir()
+        })).collect())
     }
 
 }
@@ -0,0 +1,207 @@
+use std::{collections::BTreeMap, path::{Path, PathBuf}};
+use crate::{Entry, FileHost, FileError, FileErrorKind, FileResult};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum MemoryBlob {
+    File(String),
+    Dir
+}
+
+/// A file system which only exists in memory, for tests and for running the compiler
+/// where there's no disk. Behaves like [crate::SysFileHost]: files can only be created
+/// in existing directories, and only empty directories can be deleted with [FileHost::delete_dir].
+/// Only the root exists from the start, other directories are created with [FileHost::create_dir]
+/// or [InMemoryFileHost::insert_file].
+#[derive(Clone, Debug, Default)]
+pub struct InMemoryFileHost {
+    blobs: BTreeMap<PathBuf, MemoryBlob>
+}
+
+impl InMemoryFileHost {
+
+    /// Adds a file, together with all of the directories it's in.
+    pub fn insert_file<P: AsRef<Path>>(&mut self, path: P, content: &str) {
+        let path = path.as_ref();
+        for ancestor in path.ancestors().skip(1) {
+            if !Self::is_root(ancestor) {
+                self.blobs.insert(ancestor.to_path_buf(), MemoryBlob::Dir);
+            }
+        }
+        self.blobs.insert(path.to_path_buf(), MemoryBlob::File(content.to_string()));
+    }
+
+    fn is_root(path: &Path) -> bool {
+        path.parent().is_none()
+    }
+
+    fn dir_exists(&self, path: &Path) -> bool {
+        Self::is_root(path) || self.blobs.get(path) == Some(&MemoryBlob::Dir)
+    }
+
+    fn check_parent(&self, path: &Path) -> FileResult<()> {
+        match path.parent() {
+            Some(parent) if self.dir_exists(parent) => Ok(()),
+            Some(parent) => Err(FileError::new(parent, FileErrorKind::NotFound)),
+            None => Err(FileError::new(path, FileErrorKind::AlreadyExists))
+        }
+    }
+
+    fn get(&self, path: &Path) -> FileResult<&MemoryBlob> {
+        self.blobs.get(path).ok_or_else(|| FileError::new(path, FileErrorKind::NotFound))
+    }
+
+    /// Removes `path` and everything inside it.
+    fn remove_all(&mut self, path: &Path) -> Vec<(PathBuf, MemoryBlob)> {
+        let removed = self.blobs.keys().filter(|blob| blob.starts_with(path)).cloned().collect::<Vec<PathBuf>>();
+        removed.into_iter().map(|blob| {
+            let value = self.blobs.remove(&blob).unwrap();
+            (blob, value)
+        }).collect()
+    }
+
+}
+
+impl FileHost for InMemoryFileHost {
+
+    fn write_file<P: AsRef<Path>>(&mut self, path: P, content: &str) -> FileResult<()> {
+        let path = path.as_ref();
+        self.check_parent(path)?;
+        if self.dir_exists(path) {
+            return Err(FileError::new(path, FileErrorKind::IsADirectory));
+        }
+        self.blobs.insert(path.to_path_buf(), MemoryBlob::File(content.to_string()));
+        Ok(())
+    }
+
+    fn rename_item<P: AsRef<Path>>(&mut self, path: P, name: &str) -> FileResult<(String, bool)> {
+        let old_path = path.as_ref();
+        let new_path = old_path.parent().ok_or_else(|| FileError::new(old_path, FileErrorKind::NotFound))?.join(name);
+        let is_dir = match (self.get(old_path)?, self.blobs.get(&new_path)) {
+            // Files replace other files, like on the disk
+            (MemoryBlob::File(_), None | Some(MemoryBlob::File(_))) => false,
+            (MemoryBlob::Dir, None) => true,
+            (MemoryBlob::File(_), Some(MemoryBlob::Dir)) => return Err(FileError::new(new_path, FileErrorKind::IsADirectory)),
+            (MemoryBlob::Dir, Some(_)) => return Err(FileError::new(new_path, FileErrorKind::AlreadyExists))
+        };
+        if old_path != new_path {
+            for (blob, value) in self.remove_all(old_path) {
+                self.blobs.insert(new_path.join(blob.strip_prefix(old_path).unwrap()), value);
+            }
+        }
+        Ok((new_path.to_string_lossy().to_string(), is_dir))
+    }
+
+    fn delete_file<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()> {
+        let path = path.as_ref();
+        match self.get(path)? {
+            MemoryBlob::File(_) => {
+                self.blobs.remove(path);
+                Ok(())
+            },
+            MemoryBlob::Dir => Err(FileError::new(path, FileErrorKind::IsADirectory))
+        }
+    }
+
+    fn delete_dir_recursive<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()> {
+        let path = path.as_ref();
+        match self.get(path)? {
+            MemoryBlob::Dir => {
+                self.remove_all(path);
+                Ok(())
+            },
+            MemoryBlob::File(_) => Err(FileError::new(path, FileErrorKind::NotADirectory))
+        }
+    }
+
+    fn delete_dir<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()> {
+        if !self.get_entries_from_directory(&path)?.is_empty() {
+            return Err(FileError::new(path, FileErrorKind::DirectoryNotEmpty));
+        }
+        self.delete_dir_recursive(path)
+    }
+
+    fn create_dir<P: AsRef<Path>>(&mut self, path: P) -> FileResult<()> {
+        let path = path.as_ref();
+        self.check_parent(path)?;
+        if self.dir_exists(path) || self.blobs.contains_key(path) {
+            return Err(FileError::new(path, FileErrorKind::AlreadyExists));
+        }
+        self.blobs.insert(path.to_path_buf(), MemoryBlob::Dir);
+        Ok(())
+    }
+
+    fn read_file<P: AsRef<Path>>(&self, path: P) -> FileResult<String> {
+        let path = path.as_ref();
+        match self.get(path)? {
+            MemoryBlob::File(content) => Ok(content.clone()),
+            MemoryBlob::Dir => Err(FileError::new(path, FileErrorKind::IsADirectory))
+        }
+    }
+
+    fn is_dir<P: AsRef<Path>>(&self, path: P) -> bool {
+        self.dir_exists(path.as_ref())
+    }
+
+    fn get_entries_from_directory<P: AsRef<Path>>(&self, path: P) -> FileResult<Vec<Entry>> {
+        let path = path.as_ref();
+        if !self.dir_exists(path) {
+            let kind = if self.blobs.contains_key(path) { FileErrorKind::NotADirectory } else { FileErrorKind::NotFound };
+            return Err(FileError::new(path, kind));
+        }
+        Ok(self.blobs.iter().filter(|(blob, _)| blob.parent() == Some(path)).filter_map(|(blob, value)| Some(Entry {
+            name: blob.file_name()?.to_str()?.to_string(),
+            path: blob.clone(),
+            is_dir: *value == MemoryBlob::Dir
+        })).collect())
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn file_operations() {
+        let mut host = InMemoryFileHost::default();
+        host.insert_file("/story/chapters/intro.md", "# Intro");
+        assert!(host.is_dir("/story/chapters"));
+        assert_eq!(host.write_file("/story/missing/end.md", ""), Err(FileError::new("/story/missing", FileErrorKind::NotFound)));
+        assert_eq!(host.create_dir("/story/extras"), Ok(()));
+        assert_eq!(host.create_dir("/story/extras"), Err(FileError::new("/story/extras", FileErrorKind::AlreadyExists)));
+        assert_eq!(host.write_file("/story/extras/notes.md", "Notes"), Ok(()));
+        assert_eq!(host.rename_item("/story/chapters", "book"), Ok(("/story/book".to_string(), true)));
+        assert_eq!(host.read_file("/story/book/intro.md").as_deref(), Ok("# Intro"));
+        assert_eq!(host.read_file("/story/chapters/intro.md"), Err(FileError::new("/story/chapters/intro.md", FileErrorKind::NotFound)));
+        assert!(host.rename_item("/story/book", "extras").is_err());
+        assert_eq!(host.get_entries_from_directory("/story"), Ok(vec![
+            Entry { name: "book".to_string(), path: PathBuf::from("/story/book"), is_dir: true },
+            Entry { name: "extras".to_string(), path: PathBuf::from("/story/extras"), is_dir: true }
+        ]));
+        assert_eq!(host.get_files_from_directory("/story"), Ok(vec!["/story/book/intro.md".to_string(), "/story/extras/notes.md".to_string()]));
+        assert_eq!(host.delete_dir("/story/extras"), Err(FileError::new("/story/extras", FileErrorKind::DirectoryNotEmpty)));
+        assert_eq!(host.delete_file("/story/extras"), Err(FileError::new("/story/extras", FileErrorKind::IsADirectory)));
+        assert_eq!(host.delete_file("/story/extras/notes.md"), Ok(()));
+        assert_eq!(host.delete_dir("/story/extras"), Ok(()));
+        assert_eq!(host.delete_dir_recursive("/story/book"), Ok(()));
+        assert_eq!(host.get_entries_from_directory("/story"), Ok(vec![]));
+        assert_eq!(host.get_entries_from_directory("/story/book"), Err(FileError::new("/story/book", FileErrorKind::NotFound)));
+    }
+
+    #[test]
+    fn directories_exist_once_created() {
+        let mut host = InMemoryFileHost::default();
+        assert!(host.is_dir("/"));
+        assert!(!host.is_dir("/story"));
+        assert_eq!(host.write_file("/story/main.md", ""), Err(FileError::new("/story", FileErrorKind::NotFound)));
+        assert_eq!(host.get_entries_from_directory("/story"), Err(FileError::new("/story", FileErrorKind::NotFound)));
+        assert_eq!(host.create_dir("/story"), Ok(()));
+        assert_eq!(host.write_file("/story/main.md", ""), Ok(()));
+        assert_eq!(host.write_file("/notes.md", ""), Ok(()));
+        assert!(!host.is_dir("/notes.md"));
+        assert_eq!(host.get_entries_from_directory("/"), Ok(vec![
+            Entry { name: "notes.md".to_string(), path: PathBuf::from("/notes.md"), is_dir: false },
+            Entry { name: "story".to_string(), path: PathBuf::from("/story"), is_dir: true }
+        ]));
+    }
+}
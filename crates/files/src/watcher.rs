@@ -0,0 +1,164 @@
+use std::{path::{Path, PathBuf}, sync::mpsc::{channel, Receiver, RecvTimeoutError}, time::Duration};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher, event::{ModifyKind, RenameMode}};
+
+/// A change in a watched directory.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum FileEvent {
+    Created(PathBuf),
+    Modified(PathBuf),
+    Removed(PathBuf),
+    Renamed(PathBuf, PathBuf)
+}
+
+/// Turns raw events into [FileEvent]s. Some platforms report the two halves of a
+/// rename separately, they're paired back into a single event.
+#[derive(Default)]
+pub struct EventTranslator {
+    /// Renamed paths which are still waiting for their new name.
+    pending: Vec<(Option<usize>, PathBuf)>,
+    /// Renames which were made from two halves, the event with both paths is skipped.
+    paired: Vec<(PathBuf, PathBuf)>
+}
+
+impl EventTranslator {
+
+    pub fn translate(&mut self, event: Event) -> Vec<FileEvent> {
+        let tracker = event.attrs.tracker();
+        match event.kind {
+            EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+                self.pending.extend(event.paths.into_iter().map(|path| (tracker, path)));
+                return vec![];
+            },
+            EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+                return event.paths.into_iter().map(|path| {
+                    match self.pending.iter().position(|(pending_tracker, _)| *pending_tracker == tracker) {
+                        Some(ind) => {
+                            let (_, from) = self.pending.remove(ind);
+                            self.paired.push((from.clone(), path.clone()));
+                            FileEvent::Renamed(from, path)
+                        },
+                        None => FileEvent::Created(path)
+                    }
+                }).collect();
+            },
+            EventKind::Modify(ModifyKind::Name(RenameMode::Both)) if event.paths.len() == 2 => {
+                let mut paths = event.paths.into_iter();
+                let pair = (paths.next().unwrap(), paths.next().unwrap());
+                return match self.paired.iter().position(|paired| *paired == pair) {
+                    Some(ind) => {
+                        self.paired.remove(ind);
+                        vec![]
+                    },
+                    None => vec![FileEvent::Renamed(pair.0, pair.1)]
+                };
+            },
+            _ => {}
+        }
+        let mut result = self.flush();
+        match event.kind {
+            EventKind::Create(_) => result.extend(event.paths.into_iter().map(FileEvent::Created)),
+            // The platform doesn't say which half of the rename the path is
+            EventKind::Modify(ModifyKind::Name(_)) => result.extend(event.paths.into_iter().map(|path| {
+                if path.exists() { FileEvent::Created(path) } else { FileEvent::Removed(path) }
+            })),
+            EventKind::Modify(ModifyKind::Metadata(_)) => {},
+            EventKind::Modify(_) => result.extend(event.paths.into_iter().map(FileEvent::Modified)),
+            EventKind::Remove(_) => result.extend(event.paths.into_iter().map(FileEvent::Removed)),
+            _ => {}
+        }
+        result
+    }
+
+    /// Renames which never got their other half moved the path out of the watched directory.
+    pub fn flush(&mut self) -> Vec<FileEvent> {
+        self.paired.clear();
+        self.pending.drain(..).map(|(_, path)| FileEvent::Removed(path)).collect()
+    }
+
+}
+
+/// Watches a directory and everything inside it.
+pub struct FileWatcher {
+    _watcher: RecommendedWatcher,
+    receiver: Receiver<notify::Result<Event>>,
+    translator: EventTranslator
+}
+
+impl FileWatcher {
+
+    pub fn new<P: AsRef<Path>>(path: P) -> Option<Self> {
+        let (sender, receiver) = channel();
+        let mut watcher = notify::recommended_watcher(sender).ok()?;
+        watcher.watch(path.as_ref(), RecursiveMode::Recursive).ok()?;
+        Some(Self {
+            _watcher: watcher,
+            receiver,
+            translator: EventTranslator::default()
+        })
+    }
+
+    /// Waits for changes. Events which come in quick succession (like the ones of a `git pull`)
+    /// are returned together. Returns `None` once the watcher stops.
+    pub fn next_events(&mut self) -> Option<Vec<FileEvent>> {
+        let mut events = vec![];
+        let mut next = self.receiver.recv().ok()?;
+        loop {
+            if let Ok(event) = next {
+                events.append(&mut self.translator.translate(event));
+            }
+            next = match self.receiver.recv_timeout(Duration::from_millis(50)) {
+                Ok(event) => event,
+                Err(RecvTimeoutError::Timeout) => break,
+                Err(RecvTimeoutError::Disconnected) => return None
+            };
+        }
+        events.append(&mut self.translator.flush());
+        events.dedup();
+        Some(events)
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use notify::event::{CreateKind, DataChange};
+
+    fn event(kind: EventKind, paths: &[&str], tracker: Option<usize>) -> Event {
+        let mut event = Event::new(kind);
+        event.paths = paths.iter().map(PathBuf::from).collect();
+        if let Some(tracker) = tracker {
+            event = event.set_tracker(tracker);
+        }
+        event
+    }
+
+    #[test]
+    fn translate_events() {
+        let mut translator = EventTranslator::default();
+        let mut events = vec![];
+        for raw in [
+            event(EventKind::Create(CreateKind::File), &["/story/a.md"], None),
+            event(EventKind::Modify(ModifyKind::Data(DataChange::Any)), &["/story/a.md"], None),
+            // A rename in two halves, followed by the event with both paths
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &["/story/a.md"], Some(1)),
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &["/story/b.md"], Some(1)),
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::Both)), &["/story/a.md", "/story/b.md"], Some(1)),
+            // Moved out of the directory
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::From)), &["/story/c.md"], Some(2)),
+            event(EventKind::Modify(ModifyKind::Metadata(notify::event::MetadataKind::Any)), &["/story/b.md"], None),
+            // Moved into the directory
+            event(EventKind::Modify(ModifyKind::Name(RenameMode::To)), &["/story/d.md"], Some(3))
+        ] {
+            events.append(&mut translator.translate(raw));
+        }
+        events.append(&mut translator.flush());
+        assert_eq!(events, vec![
+            FileEvent::Created(PathBuf::from("/story/a.md")),
+            FileEvent::Modified(PathBuf::from("/story/a.md")),
+            FileEvent::Renamed(PathBuf::from("/story/a.md"), PathBuf::from("/story/b.md")),
+            FileEvent::Removed(PathBuf::from("/story/c.md")),
+            FileEvent::Created(PathBuf::from("/story/d.md"))
+        ]);
+    }
+}
@@ -0,0 +1,44 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use storytell_js_parser::JsParser;
+
+/// `count` statements, separated by semicolons.
+fn statements(count: usize) -> String {
+    (0..count).map(|index| match index % 4 {
+        0 => format!("gold += {index}"),
+        1 => format!("player.stats.hp = player.stats.hp - {index} * 2"),
+        2 => format!("inventory.push(\"item {index}\")"),
+        _ => format!("visited = gold > {index} && !visited")
+    }).collect::<Vec<String>>().join("; ")
+}
+
+/// An expression with parentheses and calls nested `depth` levels deep.
+fn nested(depth: usize) -> String {
+    let mut result = String::from("gold");
+    for level in 0..depth {
+        result = if level % 2 == 0 { format!("({result} + {level})") } else { format!("max({result}, a.b[{level}])") };
+    }
+    result
+}
+
+fn parse_by_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("js_parse_by_size");
+    for count in [10, 100, 1000] {
+        let content = statements(count);
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(count), &content, |b, content| b.iter(|| JsParser::parse(content)));
+    }
+    group.finish();
+}
+
+fn parse_by_nesting(c: &mut Criterion) {
+    let mut group = c.benchmark_group("js_parse_by_nesting");
+    for depth in [4, 16, 64] {
+        let content = nested(depth);
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &content, |b, content| b.iter(|| JsParser::parse(content)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_by_size, parse_by_nesting);
+criterion_main!(benches);
@@ -411,9 +411,9 @@ mod tests {
         if let ASTExpression::ArrayLit(expr) = &tokens[0] {
             println!("{:?}", expr.elements);
             assert_eq!(expr.elements.len(), 6);
-            assert_eq!(input.from_range(&expr.elements[0].range()), "1");
-            assert_eq!(input.from_range(&expr.elements[1].range()), "2");
-            assert_eq!(input.from_range(&expr.elements[2].range()), "3");
+            assert_eq!(input.from_range(expr.elements[0].range()), "1");
+            assert_eq!(input.from_range(expr.elements[1].range()), "2");
+            assert_eq!(input.from_range(expr.elements[2].range()), "3");
             if let ASTExpression::ArrayLit(expr) = &expr.elements[3] {
                 assert_eq!(expr.elements.len(), 2);
             } else {
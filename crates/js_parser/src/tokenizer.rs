@@ -312,7 +312,7 @@ impl<'a> Tokenizer<'a> {
         self.input.is_eof()
     }
 
-    pub fn parse_full(content: &str) -> (Vec<Token>, InputPresenter, Vec<Diagnostic>) {
+    pub fn parse_full(content: &str) -> (Vec<Token>, InputPresenter<'_>, Vec<Diagnostic>) {
         let mut parser = Tokenizer::new(content);
         let mut res: Vec<Token> = vec![];
         while !parser.is_eof() {
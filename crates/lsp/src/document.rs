@@ -0,0 +1,83 @@
+use lsp_types::Position;
+use storytell_compiler::base::files::BlobId;
+use storytell_diagnostics::location::Range;
+use storytell_parser::ast::model::ASTBlock;
+
+/// A file which is open in the editor.
+pub struct Document {
+    pub text: String,
+    /// Either the id of the file in the project, or an id given to a file which is outside of the project.
+    pub id: BlobId,
+    pub in_project: bool,
+    /// Only used for files outside of the project, project files are parsed by the compiler.
+    pub blocks: Vec<ASTBlock>,
+    line_starts: Vec<usize>
+}
+
+impl Document {
+
+    pub fn new(text: String, id: BlobId, in_project: bool) -> Self {
+        let line_starts = std::iter::once(0).chain(text.match_indices('\n').map(|(ind, _)| ind + 1)).collect();
+        Self {
+            text,
+            id,
+            in_project,
+            blocks: vec![],
+            line_starts
+        }
+    }
+
+    pub fn line_endings(&self) -> usize {
+        if self.text.contains("\r\n") { 2 } else { 1 }
+    }
+
+    /// LSP positions count characters in UTF-16 code units, storytell ranges are byte offsets.
+    pub fn position(&self, offset: usize) -> Position {
+        let mut offset = offset.min(self.text.len());
+        while !self.text.is_char_boundary(offset) {
+            offset -= 1;
+        }
+        let line = self.line_starts.partition_point(|start| *start <= offset) - 1;
+        let line_start = self.line_starts[line];
+        Position::new(line as u32, self.text[line_start..offset].encode_utf16().count() as u32)
+    }
+
+    pub fn offset(&self, position: Position) -> usize {
+        let line_start = match self.line_starts.get(position.line as usize) {
+            Some(start) => *start,
+            None => return self.text.len()
+        };
+        let line = &self.text[line_start..];
+        let mut units = 0;
+        for (ind, character) in line.char_indices() {
+            if units >= position.character as usize || character == '\n' || character == '\r' {
+                return line_start + ind;
+            }
+            units += character.len_utf16();
+        }
+        self.text.len()
+    }
+
+    pub fn range(&self, range: &Range<usize>) -> lsp_types::Range {
+        lsp_types::Range::new(self.position(range.start), self.position(range.end))
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn convert_positions() {
+        let document = Document::new("# Päth\nLine two\r\nö -> x".to_string(), 1, false);
+        assert_eq!(document.position(0), Position::new(0, 0));
+        assert_eq!(document.position(5), Position::new(0, 4));
+        assert_eq!(document.position(9), Position::new(1, 1));
+        assert_eq!(document.position(document.text.len()), Position::new(2, 6));
+        assert_eq!(document.offset(Position::new(0, 4)), 5);
+        assert_eq!(document.offset(Position::new(2, 1)), 20);
+        assert_eq!(document.offset(Position::new(1, 100)), 16);
+        assert_eq!(document.line_endings(), 2);
+    }
+}
@@ -0,0 +1,100 @@
+use std::error::Error;
+use lsp_server::{Connection, Message, Notification, Request, Response};
+use lsp_types::{notification::{self, Notification as _}, request::{self, Request as _}, *};
+use storytell_diagnostics::diagnostic::DiagnosticVariants;
+use server::Server;
+
+pub mod document;
+pub mod server;
+
+fn capabilities() -> ServerCapabilities {
+    ServerCapabilities {
+        text_document_sync: Some(TextDocumentSyncCapability::Kind(TextDocumentSyncKind::INCREMENTAL)),
+        definition_provider: Some(OneOf::Left(true)),
+        hover_provider: Some(HoverProviderCapability::Simple(true)),
+        completion_provider: Some(CompletionOptions {
+            trigger_characters: Some(vec![">".to_string(), "{".to_string(), ".".to_string()]),
+            ..Default::default()
+        }),
+        document_symbol_provider: Some(OneOf::Left(true)),
+        ..Default::default()
+    }
+}
+
+fn publish_diagnostics(connection: &Connection, uri: Url, diagnostics: Vec<Diagnostic>) -> Result<(), Box<dyn Error + Sync + Send>> {
+    let params = PublishDiagnosticsParams::new(uri, diagnostics, None);
+    connection.sender.send(Message::Notification(Notification::new(notification::PublishDiagnostics::METHOD.to_string(), params)))?;
+    Ok(())
+}
+
+fn handle_request(server: &Server, request: Request) -> Response {
+    let result = match request.method.as_str() {
+        request::GotoDefinition::METHOD => serde_json::from_value::<GotoDefinitionParams>(request.params)
+            .map(|params| serde_json::to_value(server.definition(&params.text_document_position_params.text_document.uri, params.text_document_position_params.position).map(GotoDefinitionResponse::Scalar))),
+        request::HoverRequest::METHOD => serde_json::from_value::<HoverParams>(request.params)
+            .map(|params| serde_json::to_value(server.hover(&params.text_document_position_params.text_document.uri, params.text_document_position_params.position))),
+        request::Completion::METHOD => serde_json::from_value::<CompletionParams>(request.params)
+            .map(|params| serde_json::to_value(server.completion(&params.text_document_position.text_document.uri, params.text_document_position.position).map(CompletionResponse::Array))),
+        request::DocumentSymbolRequest::METHOD => serde_json::from_value::<DocumentSymbolParams>(request.params)
+            .map(|params| serde_json::to_value(server.document_symbols(&params.text_document.uri).map(DocumentSymbolResponse::Nested))),
+        _ => return Response::new_err(request.id, lsp_server::ErrorCode::MethodNotFound as i32, format!("Unknown method {}", request.method))
+    };
+    match result {
+        Ok(Ok(value)) => Response { id: request.id, result: Some(value), error: None },
+        Ok(Err(error)) | Err(error) => Response::new_err(request.id, lsp_server::ErrorCode::InvalidParams as i32, error.to_string())
+    }
+}
+
+fn handle_notification(connection: &Connection, server: &mut Server, notification: Notification) -> Result<(), Box<dyn Error + Sync + Send>> {
+    match notification.method.as_str() {
+        notification::DidOpenTextDocument::METHOD => {
+            let params: DidOpenTextDocumentParams = serde_json::from_value(notification.params)?;
+            let diagnostics = server.update_document(params.text_document.uri.clone(), params.text_document.text);
+            publish_diagnostics(connection, params.text_document.uri, diagnostics)?;
+        },
+        notification::DidChangeTextDocument::METHOD => {
+            let params: DidChangeTextDocumentParams = serde_json::from_value(notification.params)?;
+            let diagnostics = server.change_document(params.text_document.uri.clone(), params.content_changes);
+            publish_diagnostics(connection, params.text_document.uri, diagnostics)?;
+        },
+        notification::DidCloseTextDocument::METHOD => {
+            let params: DidCloseTextDocumentParams = serde_json::from_value(notification.params)?;
+            server.close_document(&params.text_document.uri);
+        },
+        _ => {}
+    }
+    Ok(())
+}
+
+fn main() -> Result<(), Box<dyn Error + Sync + Send>> {
+    let (connection, io_threads) = Connection::stdio();
+    let params: InitializeParams = serde_json::from_value(connection.initialize(serde_json::to_value(capabilities())?)?)?;
+    #[allow(deprecated)]
+    let root = params.workspace_folders.as_ref().and_then(|folders| folders.first()).map(|folder| folder.uri.clone()).or(params.root_uri)
+        .and_then(|uri| uri.to_file_path().ok());
+    let (server, errors) = Server::new(root);
+    for error in errors {
+        let typ = if error.variant == DiagnosticVariants::Warning { MessageType::WARNING } else { MessageType::ERROR };
+        let params = ShowMessageParams { typ, message: error.msg };
+        connection.sender.send(Message::Notification(Notification::new(notification::ShowMessage::METHOD.to_string(), params)))?;
+    }
+    main_loop(connection, server)?;
+    io_threads.join()?;
+    Ok(())
+}
+
+fn main_loop(connection: Connection, mut server: Server) -> Result<(), Box<dyn Error + Sync + Send>> {
+    for message in &connection.receiver {
+        match message {
+            Message::Request(request) => {
+                if connection.handle_shutdown(&request)? {
+                    break;
+                }
+                connection.sender.send(Message::Response(handle_request(&server, request)))?;
+            },
+            Message::Notification(notification) => handle_notification(&connection, &mut server, notification)?,
+            Message::Response(_) => {}
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,326 @@
+use std::path::PathBuf;
+use lsp_types::*;
+use rustc_hash::FxHashMap;
+use storytell_compiler::{base::{Compiler, files::BlobId}, json_compiler::{JSONCompilerProvider, JSONCompilerContext}, graph::{GraphBuilder, StoryGraph, GraphNodeKind}, completion::{CompletionContext, CompletionKind, complete}, visitors::{BlockVisitor, walk_blocks, MagicVariableType}};
+use storytell_diagnostics::{diagnostic::{Diagnostic as StorytellDiagnostic, DiagnosticVariants}, location::Range as StorytellRange};
+use storytell_fs::{FileHost, SysFileHost};
+use storytell_parser::ast::{model::*, incremental::TextChange};
+use crate::document::Document;
+
+pub type ProjectCompiler = Compiler<JSONCompilerProvider, SysFileHost>;
+
+pub struct Server {
+    pub compiler: ProjectCompiler,
+    pub documents: FxHashMap<Url, Document>,
+    /// Ids for files outside of the project are given out from the end.
+    detached_counter: BlobId
+}
+
+impl Server {
+
+    /// `root` is the directory of the project. Without a project, only open files are known.
+    /// Also returns the errors of reading the project directory, or the warnings about it.
+    pub fn new(root: Option<PathBuf>) -> (Self, Vec<StorytellDiagnostic>) {
+        let mut compiler = ProjectCompiler::new(root.as_ref().and_then(|root| root.to_str()).unwrap_or_default(), 1, SysFileHost, JSONCompilerContext::new(Some("this".to_string())));
+        let errors = match root {
+            Some(_) => match compiler.init_fs() {
+                Ok((_, _, warnings)) => warnings,
+                Err(errors) => errors
+            },
+            None => vec![]
+        };
+        (Self {
+            compiler,
+            documents: FxHashMap::default(),
+            detached_counter: BlobId::MAX
+        }, errors)
+    }
+
+    /// Parses the new content of the document and returns its diagnostics.
+    pub fn update_document(&mut self, uri: Url, text: String) -> Vec<Diagnostic> {
+        let id = match self.documents.get(&uri) {
+            Some(document) => Some((document.id, document.in_project)),
+            None => uri.to_file_path().ok().and_then(|path| self.compiler.host.file_id_from_path(path)).map(|id| (id, true))
+        };
+        let (id, in_project) = id.unwrap_or_else(|| {
+            self.detached_counter -= 1;
+            (self.detached_counter, false)
+        });
+        let mut document = Document::new(text, id, in_project);
+        let result = self.compile_document(&mut document, None);
+        self.documents.insert(uri, document);
+        result
+    }
+
+    /// Applies the changes the editor made to an open document, and returns its diagnostics.
+    /// Project files are only parsed again where they changed.
+    pub fn change_document(&mut self, uri: Url, changes: Vec<TextDocumentContentChangeEvent>) -> Vec<Diagnostic> {
+        let mut result = vec![];
+        for change in changes {
+            // Without a range, the change is the whole document
+            let Some(range) = change.range else {
+                result = self.update_document(uri.clone(), change.text);
+                continue;
+            };
+            let Some(document) = self.documents.remove(&uri) else { continue };
+            let text_change = TextChange { range: StorytellRange::new(document.offset(range.start), document.offset(range.end)), text: change.text };
+            let mut document = Document::new(text_change.apply(&document.text), document.id, document.in_project);
+            result = self.compile_document(&mut document, Some(&text_change));
+            self.documents.insert(uri.clone(), document);
+        }
+        result
+    }
+
+    /// `change` is what changed since the document was compiled the last time, if it's known.
+    fn compile_document(&mut self, document: &mut Document, change: Option<&TextChange>) -> Vec<Diagnostic> {
+        let diagnostics = match (document.in_project, change) {
+            (true, Some(change)) => self.compiler.compile_file_with_change(document.id, &document.text, change, document.line_endings()).1,
+            (true, None) => self.compiler.compile_file_with_content(document.id, &document.text, document.line_endings()).1,
+            (false, _) => {
+                let (_, blocks, diagnostics) = ProjectCompiler::compile_string(&mut self.compiler.ctx, document.line_endings(), &document.text);
+                document.blocks = blocks;
+                diagnostics
+            }
+        };
+        diagnostics.iter().map(|diagnostic| convert_diagnostic(document, diagnostic)).collect()
+    }
+
+    /// Project files go back to the content on the disk.
+    pub fn close_document(&mut self, uri: &Url) {
+        if let Some(document) = self.documents.remove(uri) {
+            // The file might have been deleted while it was open
+            if document.in_project && self.compiler.compile_file(document.id).is_err() {
+                self.compiler.host.forget_blob(&document.id);
+            }
+        }
+    }
+
+    fn with_blocks<R>(&self, document: &Document, callback: impl FnOnce(&[ASTBlock]) -> R) -> R {
+        if document.in_project {
+            callback(&self.compiler.host.files[&document.id].borrow().parsed_content)
+        } else {
+            callback(&document.blocks)
+        }
+    }
+
+    /// The graph of every file in the project, plus the open files outside of it.
+    pub fn graph(&self) -> StoryGraph {
+        let mut ids = self.compiler.host.files.keys().collect::<Vec<&BlobId>>();
+        ids.sort();
+        let files = ids.into_iter().map(|id| self.compiler.host.files[id].borrow()).collect::<Vec<_>>();
+        let mut builder = GraphBuilder::new();
+        for file in &files {
+            builder.add_file(file.id, &file.parsed_content);
+        }
+        for document in self.documents.values().filter(|document| !document.in_project) {
+            builder.add_file(document.id, &document.blocks);
+        }
+        builder.build()
+    }
+
+    fn location(&self, file: BlobId, range: &StorytellRange<usize>) -> Option<Location> {
+        if let Some((uri, document)) = self.documents.iter().find(|(_, document)| document.id == file) {
+            return Some(Location::new(uri.clone(), document.range(range)));
+        }
+        let file = self.compiler.host.files.get(&file)?.borrow();
+        let path = self.compiler.host.build_path(&file.path, &file.name);
+        let document = Document::new(self.compiler.host.raw.read_file(&path).ok()?, file.id, true);
+        Some(Location::new(Url::from_file_path(path).ok()?, document.range(range)))
+    }
+
+    /// Diverts lead to the path or label they point to.
+    pub fn definition(&self, uri: &Url, position: Position) -> Option<Location> {
+        let document = self.documents.get(uri)?;
+        let offset = document.offset(position);
+        let graph = self.graph();
+        let divert = graph.diverts.iter().find(|divert| divert.file == document.id && divert.range.start <= offset && offset <= divert.range.end)?;
+        let node = &graph.nodes[divert.to?];
+        if matches!(node.kind, GraphNodeKind::End | GraphNodeKind::Back) {
+            return None;
+        }
+        self.location(node.file, &StorytellRange::new(node.range.start, node.range.start))
+    }
+
+    /// Shows the inferred type of magic variables in inline javascript.
+    pub fn hover(&self, uri: &Url, position: Position) -> Option<Hover> {
+        let document = self.documents.get(uri)?;
+        let offset = document.offset(position);
+        let mut collector = JavascriptRangeCollector::default();
+        self.with_blocks(document, |blocks| walk_blocks(blocks, &mut collector));
+        collector.ranges.iter().find(|range| range.start < offset && offset < range.end)?;
+        let (chain, range) = identifier_chain(&document.text, offset)?;
+        let variables = &self.compiler.ctx.magic_variables;
+        let mut var_type = variables.variables.get(chain[0])?;
+        for field in &chain[1..] {
+            match var_type {
+                MagicVariableType::ObjectRef(id) => var_type = variables.objects.get(id)?.get(*field)?,
+                _ => return None
+            }
+        }
+        Some(Hover {
+            contents: HoverContents::Markup(MarkupContent {
+                kind: MarkupKind::Markdown,
+                value: format!("```ts\n{}: {}\n```", chain.join("."), var_type)
+            }),
+            range: Some(document.range(&range))
+        })
+    }
+
+    /// Path and label names after `->`, magic variables inside `{}`, attributes after `#[`.
+    pub fn completion(&self, uri: &Url, position: Position) -> Option<Vec<CompletionItem>> {
+        let document = self.documents.get(uri)?;
+        let files = self.compiler.host.files.values().map(|file| file.borrow()).filter(|file| file.id != document.id).collect::<Vec<_>>();
+        let ctx = CompletionContext {
+            files: files.iter().map(|file| file.parsed_content.as_slice())
+                .chain(self.documents.values().filter(|other| !other.in_project && other.id != document.id).map(|other| other.blocks.as_slice()))
+                .collect(),
+            variables: &self.compiler.ctx.magic_variables
+        };
+        Some(complete(&document.text, document.offset(position), document.line_endings(), &ctx).into_iter().map(|completion| CompletionItem {
+            label: completion.label,
+            kind: Some(match completion.kind {
+                CompletionKind::Path => CompletionItemKind::MODULE,
+                CompletionKind::Label => CompletionItemKind::REFERENCE,
+                CompletionKind::Variable => CompletionItemKind::VARIABLE,
+                CompletionKind::Field => CompletionItemKind::FIELD,
+                CompletionKind::Attribute => CompletionItemKind::PROPERTY
+            }),
+            detail: Some(completion.detail),
+            ..Default::default()
+        }).collect())
+    }
+
+    pub fn document_symbols(&self, uri: &Url) -> Option<Vec<DocumentSymbol>> {
+        let document = self.documents.get(uri)?;
+        Some(self.with_blocks(document, |blocks| header_symbols(document, blocks)))
+    }
+
+}
+
+pub fn convert_diagnostic(document: &Document, diagnostic: &StorytellDiagnostic) -> Diagnostic {
+    Diagnostic {
+        range: document.range(&diagnostic.range),
+        severity: Some(match diagnostic.variant {
+            DiagnosticVariants::Error => DiagnosticSeverity::ERROR,
+            DiagnosticVariants::Warning => DiagnosticSeverity::WARNING,
+            DiagnosticVariants::Suggestion => DiagnosticSeverity::HINT
+        }),
+        source: Some(String::from("storytell")),
+        message: diagnostic.msg.clone(),
+        ..Default::default()
+    }
+}
+
+#[allow(deprecated)]
+fn header_symbols(document: &Document, blocks: &[ASTBlock]) -> Vec<DocumentSymbol> {
+    blocks.iter().filter_map(|block| match block {
+        ASTBlock::Header(header) => Some(DocumentSymbol {
+            name: header.title.text.clone(),
+            detail: None,
+            kind: SymbolKind::NAMESPACE,
+            tags: None,
+            deprecated: None,
+            range: document.range(&header.range),
+            selection_range: document.range(&header.title.range),
+            children: Some(header_symbols(document, &header.children))
+        }),
+        _ => None
+    }).collect()
+}
+
+/// The dotted identifier under `offset`, up to the end of the hovered identifier.
+fn identifier_chain(text: &str, offset: usize) -> Option<(Vec<&str>, StorytellRange<usize>)> {
+    let is_ident = |character: u8| character.is_ascii_alphanumeric() || character == b'_' || character == b'$';
+    let bytes = text.as_bytes();
+    let mut start = offset;
+    while start > 0 && (is_ident(bytes[start - 1]) || bytes[start - 1] == b'.') {
+        start -= 1;
+    }
+    let mut end = offset;
+    while end < bytes.len() && is_ident(bytes[end]) {
+        end += 1;
+    }
+    if start == end {
+        return None;
+    }
+    let chain = text[start..end].split('.').collect::<Vec<&str>>();
+    if chain.iter().any(|part| part.is_empty()) {
+        return None;
+    }
+    Some((chain, StorytellRange::new(start, end)))
+}
+
+#[derive(Default)]
+struct JavascriptRangeCollector {
+    ranges: Vec<StorytellRange<usize>>
+}
+
+impl JavascriptRangeCollector {
+    fn collect(&mut self, parts: &[TextPart]) {
+        for part in parts {
+            if let ASTInlineKind::Javascript(_) = part.text.kind {
+                self.ranges.push(part.text.range.clone());
+            }
+        }
+    }
+}
+
+impl BlockVisitor for JavascriptRangeCollector {
+    fn visit_block(&mut self, block: &ASTBlock, _path: &str, _position: &str) {
+        if let ASTBlock::Paragraph(paragraph) = block {
+            self.collect(&paragraph.parts);
+        }
+    }
+
+    fn visit_choice(&mut self, choice: &ASTChoice, _path: &str, _position: &str) {
+        self.collect(&choice.text.parts);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn language_features() {
+        let (mut server, errors) = Server::new(None);
+        assert!(errors.is_empty());
+        let uri = Url::parse("file:///story/main.md").unwrap();
+        let diagnostics = server.update_document(uri.clone(), "# Start
+Hello, {player.name = \"James\"}!
+-> ending
+
+## Ending
+The end.
+-> nowhere
+".to_string());
+        assert!(diagnostics.is_empty());
+        let definition = server.definition(&uri, Position::new(2, 4)).unwrap();
+        assert_eq!(definition.range.start, Position::new(4, 0));
+        let hover = server.hover(&uri, Position::new(1, 17)).unwrap();
+        assert!(matches!(hover.contents, HoverContents::Markup(markup) if markup.value.contains("player.name: string")));
+        assert!(server.hover(&uri, Position::new(5, 2)).is_none());
+        let completions = server.completion(&uri, Position::new(2, 5)).unwrap();
+        assert!(completions.iter().any(|item| item.label == "ending"));
+        assert!(completions.iter().any(|item| item.label == "start"));
+        let symbols = server.document_symbols(&uri).unwrap();
+        assert_eq!(symbols[0].name, "Start");
+        assert_eq!(symbols[0].children.as_ref().unwrap()[0].name, "Ending");
+    }
+
+    #[test]
+    fn change_documents() {
+        let (mut server, _) = Server::new(None);
+        let uri = Url::parse("file:///story/main.md").unwrap();
+        server.update_document(uri.clone(), "# Start\nHellö.\n-> start\n".to_string());
+        let change = |range: Option<lsp_types::Range>, text: &str| TextDocumentContentChangeEvent { range, range_length: None, text: text.to_string() };
+        let diagnostics = server.change_document(uri.clone(), vec![
+            change(Some(lsp_types::Range::new(Position::new(1, 5), Position::new(1, 6))), "!"),
+            change(Some(lsp_types::Range::new(Position::new(2, 3), Position::new(2, 8))), "nowhere")
+        ]);
+        assert_eq!(server.documents[&uri].text, "# Start\nHellö!\n-> nowhere\n");
+        assert!(diagnostics.is_empty());
+        server.change_document(uri.clone(), vec![change(None, "# Other\n")]);
+        assert_eq!(server.documents[&uri].text, "# Other\n");
+    }
+}
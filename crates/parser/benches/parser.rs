@@ -0,0 +1,50 @@
+use criterion::{criterion_group, criterion_main, BenchmarkId, Criterion, Throughput};
+use storytell_parser::ast::Parser;
+
+/// A choice group whose choices contain another group, `depth` levels deep.
+fn choices(out: &mut String, level: usize, depth: usize) {
+    let indent = "    ".repeat(level);
+    for choice in 0..2 {
+        out.push_str(&format!("{indent}- {{:if gold > {choice}}} Choice **{choice}** on level {level}\n"));
+        out.push_str(&format!("{indent}    Some _text_ with {{gold += {choice}}} and `code`.\n"));
+        if level + 1 < depth {
+            choices(out, level + 1, depth);
+        } else {
+            out.push_str(&format!("{indent}    -> part_{choice}\n"));
+        }
+    }
+}
+
+/// A story with `paths` second-level paths, each one with choices nested `depth` levels deep.
+fn story(paths: usize, depth: usize) -> String {
+    let mut out = String::from("# Story\nThe story starts.\n");
+    for path in 0..paths {
+        out.push_str(&format!("\n## Part {path}\n// A comment\nSomeone says **something** about {{player.name}}.\n\n```js\nlet x = {path};\n```\n\n#[Label(Pick{path})]\n"));
+        choices(&mut out, 0, depth);
+        out.push_str("\n@{gold}\n- {1}\n    One gold.\n- {2}\n    Two gold.\n");
+    }
+    out
+}
+
+fn parse_by_size(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_by_size");
+    for paths in [10, 100, 1000] {
+        let content = story(paths, 2);
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(paths), &content, |b, content| b.iter(|| Parser::parse(content, 1)));
+    }
+    group.finish();
+}
+
+fn parse_by_nesting(c: &mut Criterion) {
+    let mut group = c.benchmark_group("parse_by_nesting");
+    for depth in [1, 4, 8] {
+        let content = story(10, depth);
+        group.throughput(Throughput::Bytes(content.len() as u64));
+        group.bench_with_input(BenchmarkId::from_parameter(depth), &content, |b, content| b.iter(|| Parser::parse(content, 1)));
+    }
+    group.finish();
+}
+
+criterion_group!(benches, parse_by_size, parse_by_nesting);
+criterion_main!(benches);
@@ -0,0 +1,406 @@
+use super::model::*;
+use super::utils::resolve_line_endings;
+use super::Parser;
+
+const INDENTATION: &str = "    ";
+
+/// Parses `source` and prints it back in a canonical form:
+/// - Nested blocks are indented with 4 spaces per level.
+/// - Header depth follows how deep the header is nested.
+/// - Attributes are written as `#[Name(a, b), Other]`.
+/// - Runs of blank lines become a single blank line, and every header has a blank line before it.
+///
+/// Comments are kept, and for source without diagnostics `parse(format(x)) == parse(x)`.
+pub fn format(source: &str, line_endings: usize) -> String {
+    let (blocks, _) = Parser::parse(source, line_endings);
+    format_blocks(&blocks, line_endings)
+}
+
+/// Prints parsed blocks in the canonical form of [format]. Source which already is in that
+/// form is printed back exactly as it was.
+pub fn format_blocks(blocks: &[ASTBlock], line_endings: usize) -> String {
+    let mut formatter = Formatter {
+        line_ending: resolve_line_endings(line_endings),
+        result: String::new()
+    };
+    formatter.blocks(blocks, 0, 0, true);
+    formatter.result
+}
+
+pub fn attributes_to_source(attributes: &[ASTAttribute]) -> String {
+    format!("#[{}]", attributes.iter().map(|attribute| if attribute.parameters.is_empty() {
+        attribute.name.clone()
+    } else {
+        format!("{}({})", attribute.name, attribute.parameters.join(", "))
+    }).collect::<Vec<String>>().join(", "))
+}
+
+/// Escapes the first character of a line of text, so it isn't parsed as another block.
+fn escape_line_start(text: String) -> String {
+    if text.starts_with(['#', '-', '@', '/', ' ']) {
+        format!("\\{}", text)
+    } else {
+        text
+    }
+}
+
+fn block_trivia(block: &ASTBlock) -> (&[ASTComment], usize) {
+    match block {
+        ASTBlock::Paragraph(paragraph) => (&paragraph.comments, paragraph.blank_lines),
+        ASTBlock::CodeBlock(code) => (&code.comments, code.blank_lines),
+        ASTBlock::ChoiceGroup(group) => (&group.comments, group.blank_lines),
+        ASTBlock::Divert(divert) => (&divert.comments, divert.blank_lines),
+        ASTBlock::Match(match_block) => (&match_block.comments, match_block.blank_lines),
+        ASTBlock::Header(header) => (&header.comments, header.blank_lines),
+        ASTBlock::Error(error) => (&error.comments, error.blank_lines)
+    }
+}
+
+/// A choice list directly after these blocks would get merged into them.
+fn takes_choices(block: &ASTBlock) -> bool {
+    match block {
+        ASTBlock::ChoiceGroup(_) => true,
+        ASTBlock::Match(match_block) => match_block.kind.is_none(),
+        _ => false
+    }
+}
+
+struct Formatter<'a> {
+    line_ending: &'a str,
+    result: String
+}
+
+impl<'a> Formatter<'a> {
+
+    fn line(&mut self, depth: usize, text: &str) {
+        for _ in 0..depth {
+            self.result.push_str(INDENTATION);
+        }
+        self.result.push_str(text);
+        self.result.push_str(self.line_ending);
+    }
+
+    fn blank_line(&mut self) {
+        let double = format!("{}{}", self.line_ending, self.line_ending);
+        if !self.result.is_empty() && !self.result.ends_with(&double) {
+            self.result.push_str(self.line_ending);
+        }
+    }
+
+    /// With `top`, the blank lines before the comments are kept too.
+    fn comments(&mut self, comments: &[ASTComment], depth: usize, top: bool) {
+        for comment in comments {
+            if top && comment.blank_lines > 0 {
+                self.blank_line();
+            }
+            self.line(depth, &format!("//{}", if comment.text.is_empty() { String::new() } else { format!(" {}", comment.text) }));
+        }
+    }
+
+    /// `top` is true for the content of headers, which can have blank lines in it.
+    fn blocks(&mut self, blocks: &[ASTBlock], depth: usize, level: usize, top: bool) {
+        let mut previous: Option<&ASTBlock> = None;
+        for block in blocks {
+            let (comments, blank_lines) = block_trivia(block);
+            if top && (matches!(block, ASTBlock::Header(_)) || (matches!(block, ASTBlock::ChoiceGroup(_)) && previous.map(takes_choices).unwrap_or_default())) {
+                self.blank_line();
+            }
+            self.comments(comments, depth, top);
+            if top && blank_lines > 0 {
+                self.blank_line();
+            }
+            self.block(block, depth, level);
+            previous = Some(block);
+        }
+    }
+
+    fn attributes(&mut self, attributes: &[ASTAttribute], depth: usize) {
+        if !attributes.is_empty() {
+            self.line(depth, &attributes_to_source(attributes));
+        }
+    }
+
+    fn choice(&mut self, choice: &ASTChoice, depth: usize, level: usize) {
+        let mut line = String::from("-");
+        if !choice.attributes.is_empty() {
+            line.push(' ');
+            line.push_str(&attributes_to_source(&choice.attributes));
+        }
+        if let Some((kind, condition)) = &choice.condition {
+            // The condition has to follow the attributes directly
+            if choice.attributes.is_empty() {
+                line.push(' ');
+            }
+            line.push_str(&format!("{{:{} {}}}", kind, condition));
+        }
+        let text = escape_line_start(choice.text.to_source());
+        if !text.is_empty() {
+            line.push(' ');
+            line.push_str(&text);
+        }
+        self.line(depth, &line);
+        self.blocks(&choice.children, depth + 1, level, false);
+    }
+
+    fn block(&mut self, block: &ASTBlock, depth: usize, level: usize) {
+        match block {
+            ASTBlock::Header(header) => {
+                self.attributes(&header.attributes, depth);
+                self.line(depth, format!("{} {}", "#".repeat(level + 1), header.title.text).trim_end());
+                self.blocks(&header.children, depth, level + 1, true);
+                self.comments(&header.trailing_comments, depth, true);
+            },
+            ASTBlock::Paragraph(paragraph) => {
+                self.attributes(&paragraph.attributes, depth);
+                self.line(depth, &escape_line_start(paragraph.to_source()));
+            },
+            ASTBlock::CodeBlock(code) => {
+                self.attributes(&code.attributes, depth);
+                // The code is kept as it is, including its indentation
+                self.line(depth, &format!("```{}", code.language));
+                self.result.push_str(&code.text);
+                self.result.push_str("```");
+                self.result.push_str(self.line_ending);
+            },
+            ASTBlock::ChoiceGroup(group) => {
+                self.attributes(&group.attributes, depth);
+                for choice in &group.choices {
+                    self.comments(&choice.comments, depth, false);
+                    self.choice(choice, depth, level);
+                }
+            },
+            ASTBlock::Match(match_block) => {
+                self.attributes(&match_block.attributes, depth);
+                match &match_block.kind {
+                    Some(kind) => {
+                        self.line(depth, &format!("@{{:{} {}}}", kind, match_block.matched.text));
+                        self.blocks(&match_block.direct_children, depth + 1, level, false);
+                    },
+                    None => {
+                        self.line(depth, &format!("@{{{}}}", match_block.matched.text));
+                        for arm in &match_block.choices {
+                            self.comments(&arm.comments, depth, false);
+                            self.choice(arm, depth, level);
+                        }
+                    }
+                }
+            },
+            ASTBlock::Divert(divert) => {
+                self.attributes(&divert.attributes, depth);
+                self.line(depth, format!("-> {}{}", if divert.absolute { "/" } else { "" }, divert.path.join(".")).trim_end());
+            },
+            ASTBlock::Error(error) => {
+                self.attributes(&error.attributes, depth);
+                // Printed as it was written, the file has to be fixed by hand
+                self.line(depth, &error.text);
+            }
+        }
+    }
+
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::test_utils::Random;
+
+    #[test]
+    fn format_source() {
+        let source = "#   Start   
+#[Label(Intro),once]
+Hello **there**, {name}!
+  // A comment
+
+
+
+- #[once] Left
+      Nested text.
+      @{:if gold > 1}
+         -> shop
+- {:not tired} Right
+     -> end
+// Before the child
+## Child
+\\# Not a header
+```js
+  let x = 1;
+```
+- {:if a}Once
+- Twice
+";
+        assert_eq!(format(source, 1), "# Start
+#[Label(Intro), once]
+Hello **there**, {name}!
+// A comment
+
+- #[once] Left
+    Nested text.
+    @{:if gold > 1}
+        -> shop
+- {:not tired} Right
+    -> end
+
+// Before the child
+## Child
+\\# Not a header
+```js
+  let x = 1;
+```
+- {:if a} Once
+- Twice
+");
+    }
+
+    fn text(random: &mut Random) -> String {
+        let mut result = vec![random.pick(&["James", "Alicia", "The grave", "Nothing"]).to_string()];
+        for _ in 0..random.below(6) {
+            result.push(random.pick(&["arrives", "at the", "graveyard.", "**bold**", "*italics*", "_underline_", "`code`", "{gold + 1}", "++", "again!", "**bold *and* italics**"]).to_string());
+        }
+        result.join(" ")
+    }
+
+    fn attributes(random: &mut Random) -> Option<&'static str> {
+        match random.below(4) {
+            0 => Some(random.pick(&["#[once]", "#[exhaust]", "#[Label(Grave)]", "#[Label(Grave, global),once]", "#[Label( Spaced )]"])),
+            _ => None
+        }
+    }
+
+    /// Returns true for matches without a kind, a choice list after them would become their arms.
+    fn block(random: &mut Random, depth: usize, after_match: bool, out: &mut Vec<String>) -> bool {
+        let indentation = format!("{}{}", INDENTATION.repeat(depth), random.spaces());
+        if let Some(attributes) = attributes(random) {
+            out.push(format!("{}{}", indentation, attributes));
+        }
+        match random.below(if depth > 2 { 3 } else { 6 }) {
+            3 if after_match => out.push(format!("{}{}", indentation, text(random))),
+            0 | 1 => out.push(format!("{}{}", indentation, text(random))),
+            2 => out.push(format!("{}->{}{}", indentation, random.pick(&[" ", ""]), random.pick(&["end", "Chapter.Part", "some_path"]))),
+            3 => {
+                for _ in 0..random.below(3) + 1 {
+                    let condition = random.pick(&["", "{:if gold > 1}", "{:not tired}"]);
+                    let attributes = attributes(random).unwrap_or_default();
+                    let mut line = format!("{}-", indentation);
+                    if !attributes.is_empty() {
+                        line.push(' ');
+                        line.push_str(attributes);
+                    }
+                    if !condition.is_empty() {
+                        if attributes.is_empty() {
+                            line.push(' ');
+                        }
+                        line.push_str(condition);
+                    }
+                    out.push(format!("{} {}", line, text(random)));
+                    children(random, depth + 1, out);
+                }
+            },
+            4 => {
+                if random.below(2) == 0 {
+                    out.push(format!("{}@{{:{} gold > 1}}", indentation, random.pick(&["if", "not"])));
+                    children(random, depth + 1, out);
+                } else {
+                    out.push(format!("{}@{{gold}}", indentation));
+                    for value in 0..random.below(3) + 1 {
+                        out.push(format!("{}- {{{}}}", indentation, value));
+                        children(random, depth + 1, out);
+                    }
+                    return true;
+                }
+            },
+            _ => {
+                out.push(format!("{}```{}", indentation, random.pick(&["js", "", "md"])));
+                out.push(format!("{}    let x = {{ a: 1 }};", indentation));
+                out.push(format!("{}```", indentation));
+            }
+        }
+        false
+    }
+
+    fn children(random: &mut Random, depth: usize, out: &mut Vec<String>) {
+        let mut after_match = false;
+        for _ in 0..random.below(3) {
+            after_match = block(random, depth, after_match, out);
+        }
+    }
+
+    fn header(random: &mut Random, level: usize, out: &mut Vec<String>) {
+        if let Some(attributes) = attributes(random) {
+            out.push(attributes.to_string());
+        }
+        out.push(format!("{}{}{}", "#".repeat(level), random.pick(&[" ", "   "]), text(random).replace(['*', '_', '`', '{', '}', '+'], "")));
+        let mut after_match = false;
+        for _ in 0..random.below(5) {
+            match random.below(4) {
+                0 => out.push(String::new()),
+                1 => out.push(format!("{}// {}", random.spaces(), text(random))),
+                _ => {}
+            }
+            after_match = block(random, 0, after_match, out);
+        }
+        if level < 3 {
+            for _ in 0..random.below(3) {
+                header(random, level + 1, out);
+            }
+        }
+    }
+
+    #[test]
+    fn format_keeps_blank_lines_and_comments() {
+        let source = "# Start
+// About the start
+
+Hello.
+
+// After a blank line
+
+#[once]
+- Left
+    // Inside the choice
+    -> end
+- Right
+
+## Child
+Text.
+
+// At the end
+
+// Really at the end
+";
+        let (blocks, _) = Parser::parse(source, 1);
+        if let ASTBlock::Header(start) = &blocks[0] {
+            assert!(matches!(&start.children[1], ASTBlock::ChoiceGroup(group) if group.blank_lines == 1 && group.comments[0].blank_lines == 1));
+            if let ASTBlock::Header(child) = &start.children[2] {
+                assert_eq!(child.trailing_comments.iter().map(|comment| (comment.text.as_str(), comment.blank_lines)).collect::<Vec<(&str, usize)>>(), vec![("At the end", 1), ("Really at the end", 1)]);
+            } else {
+                panic!("Expected a header.")
+            }
+        } else {
+            panic!("Expected a header.")
+        }
+        assert_eq!(format_blocks(&blocks, 1), source);
+    }
+
+    #[test]
+    fn format_round_trip() {
+        let mut random = Random(0x2545F4914F6CDD1D);
+        for _ in 0..500 {
+            let mut lines = vec![];
+            for _ in 0..random.below(2) + 1 {
+                header(&mut random, 1, &mut lines);
+            }
+            let source = lines.join("\n") + "\n";
+            let (parsed, diagnostics) = Parser::parse(&source, 1);
+            assert!(diagnostics.is_empty(), "{}\n{:?}", source, diagnostics);
+            let formatted = format(&source, 1);
+            let (reparsed, diagnostics) = Parser::parse(&formatted, 1);
+            assert!(diagnostics.is_empty(), "{}\n{:?}", formatted, diagnostics);
+            assert_eq!(parsed, reparsed, "\n{}\n---\n{}", source, formatted);
+            assert_eq!(format(&formatted, 1), formatted);
+            let comments = |text: &str| text.lines().filter(|line| line.trim().starts_with("//")).count();
+            assert_eq!(comments(&source), comments(&formatted), "\n{}\n---\n{}", source, formatted);
+            let crlf = source.replace('\n', "\r\n");
+            assert_eq!(format(&crlf, 2), formatted.replace('\n', "\r\n"));
+        }
+    }
+}
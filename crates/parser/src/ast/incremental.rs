@@ -0,0 +1,362 @@
+use storytell_diagnostics::{diagnostic::Diagnostic, location::Range};
+use crate::input::ParsingContext;
+use super::model::*;
+use super::Parser;
+use super::trivia::count_blank_lines;
+
+/// Replaces `range` of the old content with `text`.
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct TextChange {
+    pub range: Range<usize>,
+    pub text: String
+}
+
+impl TextChange {
+
+    pub fn apply(&self, content: &str) -> String {
+        let mut result = content.to_string();
+        result.replace_range(self.range.start..self.range.end, &self.text);
+        result
+    }
+
+    /// How far the content after the change moves.
+    pub fn delta(&self) -> isize {
+        self.text.len() as isize - (self.range.end - self.range.start) as isize
+    }
+
+}
+
+fn shift(range: &mut Range<usize>, delta: isize) {
+    range.start = range.start.wrapping_add_signed(delta);
+    range.end = range.end.wrapping_add_signed(delta);
+}
+
+fn text_ranges(parts: &mut [TextPart], callback: &mut dyn FnMut(&mut Range<usize>)) {
+    for part in parts {
+        callback(&mut part.text.range);
+        match &mut part.text.kind {
+            ASTInlineKind::Bold(text) | ASTInlineKind::Italics(text) | ASTInlineKind::Underline(text) | ASTInlineKind::Code(text) => {
+                callback(&mut text.range);
+                text_ranges(&mut text.parts, callback);
+            },
+            ASTInlineKind::Javascript(_) | ASTInlineKind::Join => {}
+        }
+    }
+}
+
+fn choice_ranges(choice: &mut ASTChoice, callback: &mut dyn FnMut(&mut Range<usize>)) {
+    callback(&mut choice.range);
+    choice.attributes.iter_mut().for_each(|attribute| callback(&mut attribute.range));
+    choice.comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+    callback(&mut choice.text.range);
+    text_ranges(&mut choice.text.parts, callback);
+    choice.children.iter_mut().for_each(|child| block_ranges(child, callback));
+}
+
+/// Calls `callback` with every range in the block, including the ones of its children.
+pub fn block_ranges(block: &mut ASTBlock, callback: &mut dyn FnMut(&mut Range<usize>)) {
+    match block {
+        ASTBlock::Paragraph(paragraph) => {
+            callback(&mut paragraph.range);
+            paragraph.attributes.iter_mut().for_each(|attribute| callback(&mut attribute.range));
+            paragraph.comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+            text_ranges(&mut paragraph.parts, callback);
+        },
+        ASTBlock::CodeBlock(code) => {
+            callback(&mut code.range);
+            code.attributes.iter_mut().for_each(|attribute| callback(&mut attribute.range));
+            code.comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+        },
+        ASTBlock::ChoiceGroup(group) => {
+            callback(&mut group.range);
+            group.attributes.iter_mut().for_each(|attribute| callback(&mut attribute.range));
+            group.comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+            group.choices.iter_mut().for_each(|choice| choice_ranges(choice, callback));
+        },
+        ASTBlock::Divert(divert) => {
+            callback(&mut divert.range);
+            divert.attributes.iter_mut().for_each(|attribute| callback(&mut attribute.range));
+            divert.comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+        },
+        ASTBlock::Match(match_block) => {
+            callback(&mut match_block.range);
+            callback(&mut match_block.matched.range);
+            match_block.attributes.iter_mut().for_each(|attribute| callback(&mut attribute.range));
+            match_block.comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+            match_block.choices.iter_mut().for_each(|choice| choice_ranges(choice, callback));
+            match_block.direct_children.iter_mut().for_each(|child| block_ranges(child, callback));
+        },
+        ASTBlock::Header(header) => {
+            callback(&mut header.range);
+            header.attributes.iter_mut().for_each(|attribute| callback(&mut attribute.range));
+            header.comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+            header.trailing_comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+            callback(&mut header.title.range);
+            header.children.iter_mut().for_each(|child| block_ranges(child, callback));
+        },
+        ASTBlock::Error(error) => {
+            callback(&mut error.range);
+            error.attributes.iter_mut().for_each(|attribute| callback(&mut attribute.range));
+            error.comments.iter_mut().for_each(|comment| callback(&mut comment.range));
+        }
+    }
+}
+
+fn block_parts(block: &ASTBlock) -> (&Range<usize>, &[ASTAttribute], &[ASTComment]) {
+    match block {
+        ASTBlock::Paragraph(paragraph) => (&paragraph.range, &paragraph.attributes, &paragraph.comments),
+        ASTBlock::CodeBlock(code) => (&code.range, &code.attributes, &code.comments),
+        ASTBlock::ChoiceGroup(group) => (&group.range, &group.attributes, &group.comments),
+        ASTBlock::Divert(divert) => (&divert.range, &divert.attributes, &divert.comments),
+        ASTBlock::Match(match_block) => (&match_block.range, &match_block.attributes, &match_block.comments),
+        ASTBlock::Header(header) => (&header.range, &header.attributes, &header.comments),
+        ASTBlock::Error(error) => (&error.range, &error.attributes, &error.comments)
+    }
+}
+
+fn has_open_code_block(block: &ASTBlock) -> bool {
+    match block {
+        ASTBlock::Error(error) => error.text.starts_with("```"),
+        ASTBlock::ChoiceGroup(group) => group.choices.iter().any(|choice| choice.children.iter().any(has_open_code_block)),
+        ASTBlock::Match(match_block) => match_block.choices.iter().any(|choice| choice.children.iter().any(has_open_code_block)) || match_block.direct_children.iter().any(has_open_code_block),
+        ASTBlock::Header(header) => header.children.iter().any(has_open_code_block),
+        _ => false
+    }
+}
+
+/// Where parsing the block has to start from, comments and attributes before it included.
+fn block_start(block: &ASTBlock) -> usize {
+    let (range, attributes, comments) = block_parts(block);
+    // An attribute's range starts after `#[`
+    let attributes = attributes.first().map(|attribute| attribute.range.start.saturating_sub(2)).unwrap_or(range.start);
+    let comments = comments.first().map(|comment| comment.range.start).unwrap_or(range.start);
+    range.start.min(attributes).min(comments)
+}
+
+/// Parses the lists of siblings the change is in again, and reuses the old blocks after it.
+struct Reparser<'a> {
+    parser: Parser<'a>,
+    content: &'a [u8],
+    change: &'a TextChange,
+    delta: isize,
+    /// Where the changed text ends in the new content.
+    changed_end: usize,
+    /// Where parsing started, the old diagnostics before it are kept.
+    restart: usize,
+    /// Where the old blocks were reused from, in the old content.
+    reused: Option<usize>
+}
+
+impl<'a> Reparser<'a> {
+
+    fn shift_blocks(&self, blocks: Vec<ASTBlock>) -> impl Iterator<Item = ASTBlock> {
+        let delta = self.delta;
+        blocks.into_iter().map(move |mut block| {
+            block_ranges(&mut block, &mut |range| shift(range, delta));
+            block
+        })
+    }
+
+    /// Reparses a list of siblings: the top-level blocks, or the children of a header `header_depth` deep.
+    /// `list_start` is where the list starts. Only the children of a header the change is inside of are parsed again.
+    fn siblings(&mut self, mut old: Vec<ASTBlock>, header_depth: Option<usize>, list_start: usize) -> Vec<ASTBlock> {
+        let change = self.change;
+        let touched = old.iter().position(|block| block_parts(block).0.end >= change.range.start).unwrap_or(old.len());
+        let inside_header = match old.get(touched) {
+            Some(ASTBlock::Header(header)) => change.range.start > header.title.range.end
+                && old.get(touched + 1).is_none_or(|next| change.range.end < block_start(next))
+                && !old[..touched].iter().any(has_open_code_block),
+            _ => false
+        };
+        if inside_header {
+            let rest = old.split_off(touched + 1);
+            let mut header = match old.pop() {
+                Some(ASTBlock::Header(header)) => header,
+                _ => unreachable!()
+            };
+            let old_end = header.range.end;
+            let children = std::mem::take(&mut header.children);
+            header.children = self.siblings(children, Some(header.depth as usize), header.title.range.end);
+            if self.reused.is_some() {
+                header.range.end = header.range.end.wrapping_add_signed(self.delta);
+                header.trailing_comments.iter_mut().for_each(|comment| shift(&mut comment.range, self.delta));
+                old.push(ASTBlock::Header(header));
+                old.extend(self.shift_blocks(rest));
+                return old;
+            }
+            // The children ended somewhere else, so the header does too
+            header.trailing_comments = if self.parser.input.is_eof() { self.parser.take_comments() } else { vec![] };
+            header.range.end = self.parser.input.pos;
+            old.push(ASTBlock::Header(header));
+            count_blank_lines(&mut old[touched..], self.content);
+            return self.continue_siblings(old, rest, header_depth, old_end);
+        }
+        // The block before the change is parsed again as well, the change can make it continue further
+        let mut first = touched.saturating_sub(1);
+        // An unclosed code block looks for its end in the rest of the file
+        if let Some(ind) = old[..first].iter().position(has_open_code_block) {
+            first = ind;
+        }
+        while first > 0 && block_start(&old[first]) < block_parts(&old[first - 1]).0.end {
+            first -= 1;
+        }
+        self.restart = if first == 0 { list_start } else { block_start(&old[first]) };
+        self.parser.input.set_pos(self.restart);
+        let rest = old.split_off(first);
+        let last_end = old.last().map(|block| block_parts(block).0.end).unwrap_or_default();
+        self.continue_siblings(old, rest, header_depth, last_end)
+    }
+
+    /// Parses siblings from where the parser is, until the old siblings in `rest` can be reused
+    /// or the list ends. `last_end` is where the old sibling before `rest` ended.
+    fn continue_siblings(&mut self, mut blocks: Vec<ASTBlock>, rest: Vec<ASTBlock>, header_depth: Option<usize>, mut last_end: usize) -> Vec<ASTBlock> {
+        let parsed_from = blocks.len();
+        // The old blocks before `next` were parsed again
+        let mut next = 0;
+        let mut next_start = rest.first().map(block_start);
+        loop {
+            // Once the parser is past the change with nothing pending, it can be where the old parser was
+            let position = self.parser.collected_comments.first().map(|comment| comment.range.start).unwrap_or(self.parser.input.pos);
+            if self.parser.collected_attributes.data.is_empty() && position >= self.changed_end {
+                while next_start.filter(|start| (*start as isize + self.delta) < position as isize).is_some() {
+                    last_end = block_parts(&rest[next]).0.end;
+                    next += 1;
+                    next_start = rest.get(next).map(block_start);
+                }
+                if let Some(start) = next_start {
+                    let new_start = (start as isize + self.delta) as usize;
+                    if start >= last_end && self.content[position..new_start].iter().all(|character| matches!(character, b' ' | b'\n' | b'\r')) {
+                        self.reused = Some(start);
+                        break;
+                    }
+                }
+            }
+            match header_depth {
+                Some(depth) => match self.parser.parse_header_child(depth, 0) {
+                    Some(block) => blocks.push(block),
+                    None => break
+                },
+                None => {
+                    if self.parser.input.is_eof() {
+                        break;
+                    }
+                    if let Some(block) = self.parser.parse_block(0) {
+                        blocks.push(block);
+                    }
+                }
+            }
+        }
+        count_blank_lines(&mut blocks[parsed_from..], self.content);
+        if self.reused.is_some() {
+            let reused_from = blocks.len();
+            blocks.extend(self.shift_blocks(rest.into_iter().skip(next).collect()));
+            // The change can add or remove blank lines right before the first reused block
+            count_blank_lines(&mut blocks[reused_from..=reused_from], self.content);
+        }
+        blocks
+    }
+
+}
+
+/// Reparses `content`, the result of applying `change` to the content `previous` was parsed from.
+/// The parser goes down into the header the change is in, and its children, until it finds the list
+/// of siblings the change is in. Only the blocks of that list which the change touches are parsed
+/// again, the blocks after them are reused with their ranges moved. The result is the same as
+/// parsing the whole content again.
+pub fn reparse(previous: Vec<ASTBlock>, diagnostics: Vec<Diagnostic>, content: &str, change: &TextChange, line_endings: usize) -> (Vec<ASTBlock>, Vec<Diagnostic>) {
+    let mut reparser = Reparser {
+        parser: Parser::new(content, ParsingContext::new(line_endings)),
+        content: content.as_bytes(),
+        change,
+        delta: change.delta(),
+        changed_end: change.range.start + change.text.len(),
+        restart: 0,
+        reused: None
+    };
+    let blocks = reparser.siblings(previous, None, 0);
+    let mut result_diagnostics = diagnostics.iter().filter(|diagnostic| diagnostic.range.start < reparser.restart).cloned().collect::<Vec<Diagnostic>>();
+    result_diagnostics.append(&mut reparser.parser.input.ctx.diagnostics);
+    if let Some(reused) = reparser.reused {
+        for mut diagnostic in diagnostics.into_iter().filter(|diagnostic| diagnostic.range.start >= reused) {
+            shift(&mut diagnostic.range, reparser.delta);
+            result_diagnostics.push(diagnostic);
+        }
+    }
+    result_diagnostics.sort_by_key(|diagnostic| diagnostic.range.start);
+    (blocks, result_diagnostics)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::ast::test_utils::Random;
+    use crate::ast::format::format_blocks;
+
+    const LINES: [&str; 20] = [
+        "# Chapter", "## Part", "### Scene", "Some text.", "**Bold** and {gold + 1}", "- Choice", "- {:if gold > 1} Buy",
+        "    Nested text.", "    - Nested choice", "        -> end", "-> chapter.part", "@{gold}", "- {1}", "@{:if gold > 1}",
+        "```js", "let x = 1;", "```", "// A comment", "#[Label(Grave, global)]", ""
+    ];
+
+    const INSERTS: [&str; 14] = ["", "\n", "\n\n", "# ", "## ", "- ", "    ", "```", "{", "}", "// ", "#[once]\n", "text", "-> end\n"];
+
+    fn parse(content: &str) -> (Vec<ASTBlock>, Vec<Diagnostic>) {
+        Parser::parse(content, 1)
+    }
+
+    fn ranges(blocks: &[ASTBlock]) -> Vec<Range<usize>> {
+        let mut result = vec![];
+        for block in blocks {
+            block_ranges(&mut block.clone(), &mut |range| result.push(range.clone()));
+        }
+        result
+    }
+
+    fn paragraph<'a>(blocks: &'a mut [ASTBlock], path: &[usize]) -> &'a mut ASTParagraph {
+        match (&mut blocks[path[0]], path.len()) {
+            (ASTBlock::Paragraph(paragraph), 1) => paragraph,
+            (ASTBlock::Header(header), _) => paragraph(&mut header.children, &path[1..]),
+            _ => panic!("Expected a paragraph.")
+        }
+    }
+
+    #[test]
+    fn reparse_reuses_siblings() {
+        let content = "# Main\n## First\nSome text.\n## Second\nMore text.\n### Third\nThe end.\n";
+        let (mut blocks, diagnostics) = parse(content);
+        // Text which doesn't match the content can only be there when the blocks are reused
+        paragraph(&mut blocks, &[0, 1, 0]).tail = String::from("Reused.");
+        paragraph(&mut blocks, &[0, 1, 1, 0]).tail = String::from("Reused too.");
+        let change = TextChange { range: Range::new(16, 20), text: String::from("Any") };
+        let new_content = change.apply(content);
+        let (mut reparsed, _) = reparse(blocks, diagnostics, &new_content, &change, 1);
+        assert_eq!(paragraph(&mut reparsed, &[0, 0, 0]).tail, "Any text.");
+        assert_eq!(paragraph(&mut reparsed, &[0, 1, 0]).tail, "Reused.");
+        assert_eq!(paragraph(&mut reparsed, &[0, 1, 1, 0]).tail, "Reused too.");
+        assert_eq!(ranges(&reparsed), ranges(&parse(&new_content).0));
+    }
+
+    #[test]
+    fn reparse_like_full_parse() {
+        let mut random = Random(0x9E3779B97F4A7C15);
+        for _ in 0..300 {
+            let mut content = (0..random.below(30) + 1).map(|_| random.pick(&LINES)).collect::<Vec<&str>>().join("\n");
+            let (mut blocks, mut diagnostics) = parse(&content);
+            for _ in 0..10 {
+                let start = random.below(content.len() + 1);
+                let end = (start + random.below(12)).min(content.len());
+                let change = TextChange { range: Range::new(start, end), text: random.pick(&INSERTS).to_string() };
+                let new_content = change.apply(&content);
+                let (reparsed, reparsed_diagnostics) = reparse(blocks, diagnostics, &new_content, &change, 1);
+                let (expected, expected_diagnostics) = parse(&new_content);
+                assert_eq!(reparsed, expected, "{:?} in\n{}", change, content);
+                assert_eq!(ranges(&reparsed), ranges(&expected), "{:?} in\n{}", change, content);
+                assert_eq!(format_blocks(&reparsed, 1), format_blocks(&expected, 1), "{:?} in\n{}", change, content);
+                assert_eq!(reparsed_diagnostics, expected_diagnostics, "{:?} in\n{}", change, content);
+                content = new_content;
+                blocks = reparsed;
+                diagnostics = reparsed_diagnostics;
+            }
+        }
+    }
+}
@@ -1,10 +1,16 @@
 pub mod model;
 pub mod utils;
+pub mod format;
+pub mod incremental;
+pub mod trivia;
+#[cfg(test)]
+mod test_utils;
 
 use self::utils::*;
+use self::trivia::count_blank_lines;
 use crate::input::*;
 use model::*;
-use storytell_diagnostics::{diagnostic::*, make_diagnostics, dia};
+use storytell_diagnostics::{diagnostic::*, location::Range, make_diagnostics, dia};
 
 make_diagnostics!(define [
     REQUIRED_JS,
@@ -31,14 +37,17 @@ pub enum InlineTextParseResult {
 
 pub struct Parser<'a> {
     input: InputConsumer<'a>,
-    collected_attributes: VecStack<ASTAttribute>
+    collected_attributes: VecStack<ASTAttribute>,
+    /// Comments which weren't attached to a block yet.
+    collected_comments: Vec<ASTComment>
 }
 
 impl<'a> Parser<'a> {
     pub fn new(text: &'a str, ctx: ParsingContext) -> Self {
         Self {
             input: InputConsumer::new(text, ctx),
-            collected_attributes: VecStack::new()
+            collected_attributes: VecStack::new(),
+            collected_comments: vec![]
         }
     }
 
@@ -51,7 +60,8 @@ impl<'a> Parser<'a> {
                 if self.input.peek().is('[') {
                     self.input.skip();
                     let attrs = self.parse_attributes();
-                    self.collected_attributes.push_vec(attrs);
+                    // Attributes on consecutive lines all belong to the next block
+                    self.collected_attributes.extend_top(attrs);
                     return self.parse_block(depth);
                 }
                 if depth != 0 {
@@ -65,38 +75,33 @@ impl<'a> Parser<'a> {
                     },
                     depth: header_depth as u8,
                     attributes: self.collected_attributes.pop_vec(),
+                    comments: self.take_comments(),
+                    blank_lines: 0,
                     children: {
                         let mut res = vec![];
-                        loop {
-                            let hash_count = self.input.count('#');
-                            if hash_count > 0 && hash_count <= header_depth {
-                                break;
-                            }
-                            else if let Some(block) = self.parse_block(depth) {
-                                if let ASTBlock::Header(header) = &block {
-                                    if (header.depth - 1) != (header_depth as u8) {
-                                        self.input.ctx.diagnostics.push(dia!(INCORRECT_HEADER_SIZE, header.title.range.clone(), &(header.depth - 1).to_string()));
-                                    }
-                                }
-                                res.push(block);
-                            } else {
-                                break;
-                            }
+                        while let Some(block) = self.parse_header_child(header_depth, depth) {
+                            res.push(block);
                         }
                         res
                     },
+                    // Nothing comes after the comments which are left at the end of the file
+                    trailing_comments: if self.input.is_eof() { self.take_comments() } else { vec![] },
                     range: self.input.range_here(start),
                 }))
             }
             '`' if self.input.peek_n(1).is('`') && self.input.peek_n(2).is('`') => {
                 self.input.skip_n(3);
+                let language = self.input.consume_until_end_of_line().to_string();
+                let code = match self.input.consume_until("```") {
+                    Some(code) => code.to_string(),
+                    None => return Some(self.error_line(start, "```"))
+                };
+                self.input.skip_until_end_of_line();
                 Some(ASTBlock::CodeBlock(ASTCodeBlock {
-                    language: self.input.consume_until_end_of_line().to_string(),
-                    text: {
-                        let code = self.input.consume_until("```")?.to_string();
-                        self.input.skip_until_end_of_line();
-                        code
-                    },
+                    comments: self.take_comments(),
+                    blank_lines: 0,
+                    language,
+                    text: code,
                     attributes: self.collected_attributes.pop_vec(),
                     range: self.input.range_here(start),
                 }))
@@ -105,10 +110,20 @@ impl<'a> Parser<'a> {
                 self.input.skip_n(2);
                 let kind = if self.input.peek().is(':') {
                     self.input.skip();
-                    self.input.consume_until(" ").map(|v| v.to_string())
+                    self.input.consume_until_of_eol(" ").map(|v| v.to_string())
                 } else { None };
+                let matched_start = self.input.pos;
+                let matched = match self.input.consume_until_of_eol("}") {
+                    Some(matched) => ASTPlainText {
+                        text: matched.to_string(),
+                        range: Range::new(matched_start, matched_start + matched.len())
+                    },
+                    None => return Some(self.error_line(start, "}"))
+                };
                 Some(ASTBlock::Match(ASTMatch {
-                    matched: self.input.consume_until("}")?.to_string(),
+                    comments: self.take_comments(),
+                    blank_lines: 0,
+                    matched,
                     attributes: self.collected_attributes.pop_vec(),
                     direct_children: if kind.is_some() {
                         self.input.skip_until_end_of_line();
@@ -116,7 +131,7 @@ impl<'a> Parser<'a> {
                     } else { vec![] },
                     choices: if kind.is_none() {
                         self.input.skip_until_end_of_line();
-                        self.parse_choice_list(depth, true, false)?.choices
+                        self.parse_choice_list(depth, true, false).choices
                     } else { vec![] },
                     range: self.input.range_here(start),
                     kind
@@ -127,22 +142,33 @@ impl<'a> Parser<'a> {
                     if self.input.peek().is(' ') {
                         self.input.skip();
                     }
+                    let absolute = self.input.peek().is('/');
+                    if absolute {
+                        self.input.skip();
+                    }
                     let path = self.parse_path_access();
                     Some(ASTBlock::Divert(ASTDivert {
                         path,
+                        absolute,
                         range: {
                             let range = self.input.range_here(start);
                             self.input.consume_until_end_of_line();
                             range
                         },
-                        attributes: self.collected_attributes.pop_vec()
+                        attributes: self.collected_attributes.pop_vec(),
+                        comments: self.take_comments(),
+                        blank_lines: 0
                     }))
             },
             '-' => {
-                Some(ASTBlock::ChoiceGroup(self.parse_choice_list(depth, false, true)?))
+                Some(ASTBlock::ChoiceGroup(self.parse_choice_list(depth, false, true)))
             },
             '/' if self.input.peek_n(1).is('/') => {
-                self.input.skip_until_end_of_line();
+                self.parse_comment();
+                // A comment at the end of nested content belongs to whatever comes after it
+                if depth != 0 && self.input.get_identation().0 != depth {
+                    return None;
+                }
                 self.parse_block(depth)
             },
             ' ' | '\n' | '\r' => {
@@ -158,18 +184,103 @@ impl<'a> Parser<'a> {
                         parts: paragraph.parts,
                         tail: paragraph.tail,
                         range: paragraph.range,
-                        attributes: self.collected_attributes.pop_vec()
+                        attributes: self.collected_attributes.pop_vec(),
+                        comments: self.take_comments(),
+                        blank_lines: 0
                     }))
                 }
             }
         }
     }
 
+    /// Comments are kept in the context, and attached to the block after them. Comments at the end
+    /// of the file are the trailing comments of the header they end.
+    fn parse_comment(&mut self) {
+        let start = self.input.pos;
+        self.input.skip_n(2);
+        let text = self.input.consume_until_end_of_line();
+        let comment = ASTComment {
+            text: text.trim().to_string(),
+            blank_lines: 0,
+            range: Range::new(start, start + 2 + text.len())
+        };
+        self.input.ctx.comments.push(comment.clone());
+        self.collected_comments.push(comment);
+    }
+
+    /// The line starting at `start` is missing `closing`, so it becomes an error node,
+    /// and parsing goes on from the next line.
+    fn error_line(&mut self, start: usize, closing: &str) -> ASTBlock {
+        self.input.set_pos(start);
+        let text = self.input.consume_until_end_of_line().to_string();
+        let range = Range::new(start, start + text.len());
+        self.input.ctx.diagnostics.push(dia!(MISSING_CLOSING, range.clone(), closing));
+        ASTBlock::Error(ASTError {
+            text,
+            attributes: self.collected_attributes.pop_vec(),
+            comments: self.take_comments(),
+            blank_lines: 0,
+            range
+        })
+    }
+
+    /// The next child of a header which is `header_depth` deep, or `None` once the header ends.
+    fn parse_header_child(&mut self, header_depth: usize, depth: u8) -> Option<ASTBlock> {
+        // Blank lines and comments before a sibling header shouldn't nest it in this one
+        loop {
+            match self.input.peek() {
+                Some(' ' | '\n' | '\r') => self.input.skip(),
+                Some('/') if self.input.peek_n(1).is('/') => self.parse_comment(),
+                _ => break
+            }
+        }
+        let mut hash_count = self.input.count('#');
+        // `#[` starts an attribute, which belongs to the block after it
+        if hash_count == 1 && self.input.peek_n(1).is('[') {
+            hash_count = self.depth_after_attributes();
+        }
+        if hash_count > 0 && hash_count <= header_depth {
+            return None;
+        }
+        let block = self.parse_block(depth)?;
+        if let ASTBlock::Header(header) = &block {
+            if (header.depth - 1) != (header_depth as u8) {
+                self.input.ctx.diagnostics.push(dia!(INCORRECT_HEADER_SIZE, header.title.range.clone(), &(header.depth - 1).to_string()));
+            }
+        }
+        Some(block)
+    }
+
+    fn take_comments(&mut self) -> Vec<ASTComment> {
+        std::mem::take(&mut self.collected_comments)
+    }
+
+    /// The depth of the header the attributes at the current position belong to,
+    /// or 0 if they belong to a different block.
+    fn depth_after_attributes(&self) -> usize {
+        let data = self.input.data;
+        let mut pos = self.input.pos;
+        loop {
+            while pos < data.len() && matches!(data[pos], b' ' | b'\n' | b'\r') {
+                pos += 1;
+            }
+            let hashes = data[pos..].iter().take_while(|character| **character == b'#').count();
+            if hashes == 1 && data.get(pos + 1) == Some(&b'[') {
+                while pos < data.len() && data[pos] != b'\n' {
+                    pos += 1;
+                }
+            } else {
+                return hashes;
+            }
+        }
+    }
+
     /// "skip_depth_check" will only skip the depth check of the first line it encounters,
     /// this is because in some cases the identation may already be skipped
-    pub fn parse_choice_list(&mut self, current_depth: u8, require_js: bool, skip_depth_check: bool) -> Option<ASTChoiceGroup> {
+    pub fn parse_choice_list(&mut self, current_depth: u8, require_js: bool, skip_depth_check: bool) -> ASTChoiceGroup {
         let mut choices: Vec<ASTChoice> = vec![];
         let attributes = self.collected_attributes.pop_vec();
+        let comments = self.take_comments();
         let start = self.input.pos;
         while !self.input.is_eof() {
             if !skip_depth_check || !choices.is_empty() {
@@ -179,8 +290,9 @@ impl<'a> Parser<'a> {
                 }
                 self.input.set_pos(ident.1);
             }
-            match self.input.peek()? {
-                '-' => {
+            match self.input.peek() {
+                // A divert after the last choice isn't another choice
+                Some('-') if !self.input.peek_n(1).is('>') => {
                     self.input.skip();
                     if self.input.peek().is(' ') { self.input.skip() };
                     let attributes = if self.input.peek().is('#') && self.input.peek_n(1).is('[') {
@@ -191,16 +303,24 @@ impl<'a> Parser<'a> {
                         if require_js {
                             self.input.ctx.diagnostics.push(dia!(NO_CONDITION, self.input.range_single()));
                         }
+                        let condition_start = self.input.pos;
                         self.input.skip_n(2);
-                        let kind = self.input.consume_until(" ")?.to_string();
-                        Some((kind, self.input.consume_until("}")?.to_string()))
+                        let condition = self.parse_condition();
+                        // Without the closing bracket, the condition is read as part of the text
+                        if condition.is_none() {
+                            self.input.set_pos(condition_start);
+                        }
+                        condition
                     } else { None };
                     if self.input.peek().is(' ') { self.input.skip() };
                     let start = self.input.pos;
+                    let comments = self.take_comments();
                     choices.push(ASTChoice {
                         text: {
-                            let text = self.parse_paragraph();
-                            let unwrapped = text?;
+                            let unwrapped = match self.parse_paragraph() {
+                                Some(text) => text,
+                                None => break
+                            };
                             if require_js && (unwrapped.parts.is_empty() || !matches!(unwrapped.parts[0].text.kind, ASTInlineKind::Javascript(_))) {
                                 self.input.ctx.diagnostics.push(dia!(REQUIRED_JS, self.input.range_here(start)));
                                 continue;
@@ -208,6 +328,8 @@ impl<'a> Parser<'a> {
                         },
                         children: self.parse_children(current_depth + 1),
                         attributes,
+                        comments,
+                        blank_lines: 0,
                         condition,
                         range: self.input.range_here(start),
                     })
@@ -215,11 +337,19 @@ impl<'a> Parser<'a> {
                 _ => break,
             }
         }
-        Some(ASTChoiceGroup {
+        ASTChoiceGroup {
             choices,
             range: self.input.range_here(start),
             attributes,
-        })
+            comments,
+            blank_lines: 0
+        }
+    }
+
+    /// `kind condition}`, on a single line.
+    fn parse_condition(&mut self) -> Option<(String, String)> {
+        let kind = self.input.consume_until_of_eol(" ")?.to_string();
+        Some((kind, self.input.consume_until_of_eol("}")?.to_string()))
     }
 
     pub fn parse_children(&mut self, depth: u8) -> Vec<ASTBlock> {
@@ -349,7 +479,12 @@ impl<'a> Parser<'a> {
         let mut parts: Vec<TextPart> = vec![];
         let mut result = String::new();
         let start = self.input.pos;
-        let pos_end = self.input.get_pos_of(until).unwrap_or({
+        let line_ending = resolve_line_endings(self.input.ctx.line_endings);
+        let pos_end = match self.input.get_pos_of(until) {
+            // Formatting has to be closed on the same line
+            Some(pos) if until != line_ending && self.input.get_pos_of(line_ending).is_some_and(|line_end| line_end < pos) => None,
+            found => found
+        }.unwrap_or({
             if optional {
                 self.input.data.len()
             } else {
@@ -397,7 +532,7 @@ impl<'a> Parser<'a> {
                             });
                             result.clear()
                         } else {
-                            result.push_str("_");
+                            result.push('_');
                         }
                     },
                     '+' if self.input.peek().is('+') => {
@@ -457,7 +592,9 @@ impl<'a> Parser<'a> {
                             });
                             result.clear()
                         } else {
-                            result.push('}')
+                            self.input.set_pos(start + 1);
+                            self.input.ctx.diagnostics.push(dia!(MISSING_CLOSING, self.input.range_here(start), "}"));
+                            result.push('{')
                         }
                     },
                     other => result.push(other),
@@ -478,11 +615,14 @@ impl<'a> Parser<'a> {
                 res.push(block);
             }
         }
+        count_blank_lines(&mut res, self.input.data);
         (res, self.input.ctx)
     }
 
     pub fn parse(content: &str, line_endings: usize) -> (Vec<ASTBlock>, Vec<Diagnostic>) {
-        let (content, ctx) = Parser::new(content, ParsingContext::new(line_endings)).parse_full();
+        let (content, mut ctx) = Parser::new(content, ParsingContext::new(line_endings)).parse_full();
+        // Headers report their size after their children were parsed
+        ctx.diagnostics.sort_by_key(|diagnostic| diagnostic.range.start);
         (content, ctx.diagnostics)
     }
 
@@ -492,7 +632,7 @@ impl<'a> Parser<'a> {
 mod tests {
     use super::*;
 
-    fn get_header_children(input: &Vec<ASTBlock>) -> &Vec<ASTBlock> {
+    fn get_header_children(input: &[ASTBlock]) -> &Vec<ASTBlock> {
         if let ASTBlock::Header(header) = &input[0] {
             &header.children
         } else {
@@ -590,6 +730,19 @@ Text...
         }
     }
 
+    #[test]
+    fn parse_unclosed_formatting() {
+        let (input, _) = Parser::new("# Header\nThis *isn't closed\nThe *next* line.\n", ParsingContext::new(1)).parse_full();
+        let children = get_header_children(&input);
+        assert_eq!(children.len(), 2);
+        if let (ASTBlock::Paragraph(first), ASTBlock::Paragraph(second)) = (&children[0], &children[1]) {
+            assert_eq!(first.to_raw(), "This *isn't closed");
+            assert!(matches!(second.parts[0].text.kind, ASTInlineKind::Italics(_)));
+        } else {
+            panic!("Expected paragraphs")
+        }
+    }
+
     #[test]
     fn parse_choice_list() {
         let (input, ctx) = Parser::new(
@@ -632,7 +785,8 @@ ParsingContext::new(1),
         println!("{:?}", ctx.diagnostics);
         assert_eq!(ctx.diagnostics.len(), 2);
         if let ASTBlock::Match(matcher) = &children[1] {
-            assert_eq!(matcher.matched, "match_condition");
+            assert_eq!(matcher.matched.text, "match_condition");
+            assert_eq!(matcher.matched.range, Range::new(52, 67));
             // 3 because "Third option..." doesn't get included because JS is required
             assert_eq!(matcher.choices.len(), 3);
             assert_eq!(matcher.choices[0].text.to_raw(), "true");
@@ -688,6 +842,116 @@ Another paragraph...
         }
     }
 
+    #[test]
+    fn parse_attributes_after_block() {
+        let (input, _) = Parser::new("
+# Hello World!
+A paragraph.
+#[Label(Second)]
+Another paragraph.
+## Sub path
+", ParsingContext::new(1)).parse_full();
+        assert_eq!(input.len(), 1);
+        let children = get_header_children(&input);
+        assert_eq!(children.len(), 3);
+        if let ASTBlock::Paragraph(para) = &children[1] {
+            assert_eq!(para.get_attribute_n("Label", 0), Some("Second"));
+        } else {
+            panic!("Paragraph")
+        }
+        assert!(matches!(&children[2], ASTBlock::Header(_)));
+    }
+
+    #[test]
+    fn parse_attributes_on_consecutive_lines() {
+        let (input, _) = Parser::new("# Hello World!\n#[Label(First)]\n#[Uppercase]\nA paragraph.\nAnother paragraph.\n", ParsingContext::new(1)).parse_full();
+        let children = get_header_children(&input);
+        if let (ASTBlock::Paragraph(first), ASTBlock::Paragraph(second)) = (&children[0], &children[1]) {
+            assert_eq!(first.attributes.len(), 2);
+            assert_eq!(first.get_attribute_n("Label", 0), Some("First"));
+            assert!(second.attributes.is_empty());
+        } else {
+            panic!("Paragraphs")
+        }
+    }
+
+    #[test]
+    fn parse_attributes_right_after_title() {
+        let (input, _) = Parser::parse("# Hello World!\n#[Label(First)]\nA paragraph.\n", 1);
+        assert_eq!(input.len(), 1);
+        let children = get_header_children(&input);
+        assert_eq!(children.len(), 1);
+        assert_eq!(children[0].get_attribute_n("Label", 0), Some("First"));
+    }
+
+    #[test]
+    fn parse_sibling_headers_after_blank_lines() {
+        let (input, _) = Parser::new("
+# Hello World!
+## First
+
+Some text.
+
+
+## Second
+
+More text.
+", ParsingContext::new(1)).parse_full();
+        let children = get_header_children(&input);
+        assert_eq!(children.len(), 2);
+        for (child, title) in children.iter().zip(["First", "Second"]) {
+            if let ASTBlock::Header(header) = child {
+                assert_eq!(header.title.text, title);
+                assert_eq!(header.children.len(), 1);
+            } else {
+                panic!("Header")
+            }
+        }
+    }
+
+    #[test]
+    fn parse_sibling_headers_after_comments() {
+        let (input, _) = Parser::parse("# Hello World!\n## First\nSome text.\n// The second header\n## Second\nMore text.\n", 1);
+        let children = get_header_children(&input);
+        assert_eq!(children.len(), 2);
+        assert!(matches!(&children[1], ASTBlock::Header(header) if header.title.text == "Second"));
+    }
+
+    #[test]
+    fn parse_sibling_headers_after_attributes() {
+        let (input, _) = Parser::parse("# Hello World!\n## First\nSome text.\n#[Label(Second)]\n## Second\nMore text.\n", 1);
+        let children = get_header_children(&input);
+        assert_eq!(children.len(), 2);
+        assert!(matches!(&children[1], ASTBlock::Header(header) if header.get_attribute_n("Label", 0) == Some("Second")));
+    }
+
+    #[test]
+    fn parse_sibling_headers_after_attributes_and_comments() {
+        let (input, _) = Parser::new("
+# Hello World!
+## First
+#[Label(Greeting)]
+Some text.
+// About the second header
+#[Label(Second)]
+#[once]
+## Second
+More text.
+", ParsingContext::new(1)).parse_full();
+        let children = get_header_children(&input);
+        assert_eq!(children.len(), 2);
+        if let (ASTBlock::Header(first), ASTBlock::Header(second)) = (&children[0], &children[1]) {
+            assert_eq!(first.children.len(), 1);
+            assert_eq!(first.children[0].get_attribute_n("Label", 0), Some("Greeting"));
+            assert_eq!(second.title.text, "Second");
+            assert_eq!(second.get_attribute_n("Label", 0), Some("Second"));
+            assert!(second.has_attribute("once"));
+            assert_eq!(second.comments.iter().map(|comment| comment.text.trim()).collect::<Vec<&str>>(), vec!["About the second header"]);
+        } else {
+            panic!("Headers")
+        }
+    }
+
     #[test]
     fn parse_choice_group() {
         let (input, _) = Parser::new("
@@ -726,13 +990,87 @@ It's time to choose...
 
     #[test]
     fn parse_comment() {
-        let (input, _) = Parser::new("
+        let source = "
 # Hello World!
 // A comment
 // # A second comment...
-## A sub-path", ParsingContext::new(1)).parse_full();
+## A sub-path
+- Choice
+    Text
+    // Before the next choice
+- Another choice
+// At the end";
+        let (input, ctx) = Parser::new(source, ParsingContext::new(1)).parse_full();
         let children = get_header_children(&input);
         assert!(matches!(input[0], ASTBlock::Header(_)));
-        assert!(matches!(children[0], ASTBlock::Header(_)));
+        if let ASTBlock::Header(header) = &children[0] {
+            assert_eq!(header.comments.iter().map(|comment| comment.text.as_str()).collect::<Vec<&str>>(), vec!["A comment", "# A second comment..."]);
+            if let ASTBlock::ChoiceGroup(group) = &header.children[0] {
+                assert_eq!(group.choices[1].comments[0].text, "Before the next choice");
+                assert!(matches!(group.choices[0].children[..], [ASTBlock::Paragraph(_)]));
+            } else {
+                panic!("Expected a choice group.")
+            }
+        } else {
+            panic!("Expected a header.")
+        }
+        assert_eq!(ctx.comments.len(), 4);
+        assert_eq!(&source[ctx.comments[3].range.start..ctx.comments[3].range.end], "// At the end");
+    }
+
+    #[test]
+    fn parse_divert() {
+        let (input, _) = Parser::parse("# Start\n-> chapter.Intro\n->/start.end\n", 1);
+        let children = get_header_children(&input);
+        assert!(matches!(&children[0], ASTBlock::Divert(divert) if divert.path == vec!["chapter", "intro"] && !divert.absolute));
+        assert!(matches!(&children[1], ASTBlock::Divert(divert) if divert.path == vec!["start", "end"] && divert.absolute));
+    }
+
+    #[test]
+    fn parse_divert_after_choices() {
+        let (input, _) = Parser::parse("# Start\n- First\n    One.\n- Second\n    Two.\n-> end\n", 1);
+        let children = get_header_children(&input);
+        assert_eq!(children.len(), 2);
+        assert!(matches!(&children[0], ASTBlock::ChoiceGroup(group) if group.choices.len() == 2));
+        assert!(matches!(&children[1], ASTBlock::Divert(divert) if divert.path == vec!["end"]));
+    }
+
+    #[test]
+    fn recover_from_errors() {
+        let (input, ctx) = Parser::new("
+# Broken
+```js
+let a = 1;
+@{player.gold > 1
+- {:if gold > 1 Buy
+    Bought.
+- Leave
+Still in the path.
+@{:if gold > 1
+-> end
+## Child", ParsingContext::new(1)).parse_full();
+        assert_eq!(ctx.diagnostics.iter().map(|diagnostic| diagnostic.msg.as_str()).collect::<Vec<&str>>(), vec![
+            "Missing closing character '```'.",
+            "Missing closing character '}'.",
+            "Missing closing character '}'.",
+            "Missing closing character '}'."
+        ]);
+        assert_eq!(input.len(), 1);
+        let children = get_header_children(&input);
+        assert!(matches!(&children[0], ASTBlock::Error(error) if error.text == "```js"));
+        assert!(matches!(&children[1], ASTBlock::Paragraph(paragraph) if paragraph.tail == "let a = 1;"));
+        assert!(matches!(&children[2], ASTBlock::Error(error) if error.text == "@{player.gold > 1"));
+        if let ASTBlock::ChoiceGroup(group) = &children[3] {
+            assert_eq!(group.choices.len(), 2);
+            assert_eq!(group.choices[0].condition, None);
+            assert_eq!(group.choices[0].text.to_raw(), "{:if gold > 1 Buy");
+            assert_eq!(group.choices[0].children.len(), 1);
+        } else {
+            panic!("Expected a choice group.")
+        }
+        assert!(matches!(&children[4], ASTBlock::Paragraph(_)));
+        assert!(matches!(&children[5], ASTBlock::Error(_)));
+        assert!(matches!(&children[6], ASTBlock::Divert(_)));
+        assert!(matches!(&children[7], ASTBlock::Header(_)));
     }
 }
@@ -4,6 +4,7 @@ use std::fmt;
 
 pub trait WithAttributes {
     fn get_attribute_n(&self, att: &str, ind: usize) -> Option<&str>;
+    fn has_attribute(&self, att: &str) -> bool;
 }
 
 macro_rules! create_nodes {
@@ -13,6 +14,10 @@ macro_rules! create_nodes {
             pub struct $name {
                 $(pub $field_name: $field_type,)*
                 pub attributes: Vec<ASTAttribute>,
+                /// The comments right before the node.
+                pub comments: Vec<ASTComment>,
+                /// How many blank lines are right before the node (or its attributes), after its comments.
+                pub blank_lines: usize,
                 pub range: Range<usize>
             }
 
@@ -26,7 +31,7 @@ macro_rules! create_nodes {
 
             impl PartialEq for $name {
                 fn eq(&self, other: &Self) -> bool {
-                    self.attributes == other.attributes && $(self.$field_name == other.$field_name)&&*
+                    self.attributes == other.attributes && self.comments == other.comments && $(self.$field_name == other.$field_name)&&*
                 }
             }
 
@@ -34,11 +39,15 @@ macro_rules! create_nodes {
                 fn get_attribute_n(&self, att: &str, ind: usize) -> Option<&str> {
                     for item in &self.attributes {
                         if item.name == att {
-                            return Some(&item.parameters[ind]);
+                            return item.parameters.get(ind).map(|param| param.as_str());
                         }
                     }
                     return None;
                 }
+
+                fn has_attribute(&self, att: &str) -> bool {
+                    self.attributes.iter().any(|item| item.name == att)
+                }
             }
         )+
     };
@@ -116,6 +125,29 @@ create_nodes!(NoAttribute
 
 );
 
+/// `// ...`, the text doesn't include the slashes.
+#[derive(Clone, Eq)]
+pub struct ASTComment {
+    pub text: String,
+    /// How many blank lines are right before the comment.
+    pub blank_lines: usize,
+    pub range: Range<usize>
+}
+
+impl PartialEq for ASTComment {
+    fn eq(&self, other: &Self) -> bool {
+        self.text == other.text
+    }
+}
+
+impl fmt::Debug for ASTComment {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("ASTComment")
+         .field("text", &self.text)
+         .finish()
+    }
+}
+
 create_nodes!(
 
     ASTParagraph {
@@ -139,7 +171,7 @@ create_nodes!(
     }
 
     ASTMatch {
-        matched: String,
+        matched: ASTPlainText,
         kind: Option<String>,
         choices: Vec<ASTChoice>,
         direct_children: Vec<ASTBlock>
@@ -148,11 +180,20 @@ create_nodes!(
     ASTHeader {
         title: ASTPlainText,
         children: Vec<ASTBlock>,
+        // Comments at the end of the file, after the last child.
+        trailing_comments: Vec<ASTComment>,
         depth: u8
     }
 
+    // `-> path`, or `-> /path` when the path is written from the root.
     ASTDivert {
-        path: Vec<String>
+        path: Vec<String>,
+        absolute: bool
+    }
+
+    // A line which couldn't be parsed, kept so the rest of the file still is.
+    ASTError {
+        text: String
     }
 
 );
@@ -164,7 +205,8 @@ pub enum ASTBlock {
     ChoiceGroup(ASTChoiceGroup),
     Divert(ASTDivert),
     Match(ASTMatch),
-    Header(ASTHeader)
+    Header(ASTHeader),
+    Error(ASTError)
 }
 
 impl WithAttributes for ASTBlock {
@@ -175,13 +217,55 @@ impl WithAttributes for ASTBlock {
             Self::Divert(div) => div.get_attribute_n(att, ind),
             Self::Header(h) => h.get_attribute_n(att, ind),
             Self::Match(m) => m.get_attribute_n(att, ind),
-            Self::Paragraph(p) => p.get_attribute_n(att, ind)
+            Self::Paragraph(p) => p.get_attribute_n(att, ind),
+            Self::Error(e) => e.get_attribute_n(att, ind)
+        }
+    }
+
+    fn has_attribute(&self, att: &str) -> bool {
+        match self {
+            Self::ChoiceGroup(ch) => ch.has_attribute(att),
+            Self::CodeBlock(code) => code.has_attribute(att),
+            Self::Divert(div) => div.has_attribute(att),
+            Self::Header(h) => h.has_attribute(att),
+            Self::Match(m) => m.has_attribute(att),
+            Self::Paragraph(p) => p.has_attribute(att),
+            Self::Error(e) => e.has_attribute(att)
+        }
+    }
+}
+
+/// Escapes characters which would otherwise be parsed as inline formatting.
+pub fn escape_text(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut characters = text.chars().peekable();
+    while let Some(character) = characters.next() {
+        if matches!(character, '\\' | '*' | '_' | '`' | '{') || (character == '+' && characters.peek() == Some(&'+')) {
+            result.push('\\');
         }
+        result.push(character);
     }
+    result
+}
+
+/// Turns text parts back into storytell markup.
+pub fn parts_to_source(parts: &[TextPart], tail: &str) -> String {
+    let mut result = String::new();
+    for part in parts {
+        result.push_str(&escape_text(&part.before));
+        result.push_str(&part.text.kind.to_source());
+    }
+    result.push_str(&escape_text(tail));
+    result
 }
 
 impl ASTText {
 
+    /// The text as storytell markup, with formatting and inline javascript included.
+    pub fn to_source(&self) -> String {
+        parts_to_source(&self.parts, &self.tail)
+    }
+
     pub fn to_raw(&self) -> String {
         if self.parts.is_empty() {
             return self.tail.clone()
@@ -199,6 +283,11 @@ impl ASTText {
 
 impl ASTParagraph {
 
+    /// The text as storytell markup, with formatting and inline javascript included.
+    pub fn to_source(&self) -> String {
+        parts_to_source(&self.parts, &self.tail)
+    }
+
     pub fn to_raw(&self) -> String {
         if self.parts.is_empty() {
             return self.tail.clone()
@@ -232,4 +321,15 @@ impl ASTInlineKind {
             Self::Join => "++".to_string()
         }
     }
+
+    pub fn to_source(&self) -> String {
+        match self {
+            Self::Bold(text) => format!("**{}**", text.to_source()),
+            Self::Code(text) => format!("`{}`", text.to_source()),
+            Self::Italics(text) => format!("*{}*", text.to_source()),
+            Self::Javascript(text) => format!("{{{}}}", text),
+            Self::Underline(text) => format!("_{}_", text.to_source()),
+            Self::Join => "++".to_string()
+        }
+    }
 }
\ No newline at end of file
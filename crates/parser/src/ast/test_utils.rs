@@ -0,0 +1,21 @@
+//! Helpers for the tests of the parser.
+
+/// Xorshift, so generated input is the same on every run.
+pub struct Random(pub u64);
+
+impl Random {
+    pub fn below(&mut self, max: usize) -> usize {
+        self.0 ^= self.0 << 13;
+        self.0 ^= self.0 >> 7;
+        self.0 ^= self.0 << 17;
+        (self.0 % max as u64) as usize
+    }
+
+    pub fn pick<'a>(&mut self, items: &[&'a str]) -> &'a str {
+        items[self.below(items.len())]
+    }
+
+    pub fn spaces(&mut self) -> String {
+        " ".repeat(self.below(3))
+    }
+}
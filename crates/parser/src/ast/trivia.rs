@@ -0,0 +1,93 @@
+use storytell_diagnostics::location::Range;
+use super::model::*;
+
+fn is_blank(line: &[u8]) -> bool {
+    line.iter().all(|character| matches!(character, b' ' | b'\t' | b'\r'))
+}
+
+/// How many blank lines are right before the line `pos` is in,
+/// or 0 when something else comes before `pos` on the same line.
+pub fn blank_lines_before(data: &[u8], pos: usize) -> usize {
+    let mut line_start = pos.min(data.len());
+    while line_start > 0 && data[line_start - 1] != b'\n' {
+        line_start -= 1;
+    }
+    if !is_blank(&data[line_start..pos.min(data.len())]) {
+        return 0;
+    }
+    let mut count = 0;
+    while line_start > 0 {
+        let line_end = line_start - 1;
+        let mut previous_start = line_end;
+        while previous_start > 0 && data[previous_start - 1] != b'\n' {
+            previous_start -= 1;
+        }
+        if !is_blank(&data[previous_start..line_end]) {
+            break;
+        }
+        count += 1;
+        line_start = previous_start;
+    }
+    count
+}
+
+fn count_in_comments(comments: &mut [ASTComment], data: &[u8]) {
+    for comment in comments {
+        comment.blank_lines = blank_lines_before(data, comment.range.start);
+    }
+}
+
+/// Blank lines are counted before the attributes of a node, when they're above it.
+fn count_in_node(blank_lines: &mut usize, range: &Range<usize>, attributes: &[ASTAttribute], comments: &mut [ASTComment], data: &[u8]) {
+    // An attribute's range starts after `#[`
+    let start = attributes.first().map(|attribute| attribute.range.start.saturating_sub(2)).unwrap_or(range.start).min(range.start);
+    *blank_lines = blank_lines_before(data, start);
+    count_in_comments(comments, data);
+}
+
+fn count_in_choice(choice: &mut ASTChoice, data: &[u8]) {
+    count_in_node(&mut choice.blank_lines, &choice.range, &choice.attributes, &mut choice.comments, data);
+    count_blank_lines(&mut choice.children, data);
+}
+
+/// Sets the blank lines of the blocks, their children and their comments. `data` is
+/// the content the blocks were parsed from.
+pub fn count_blank_lines(blocks: &mut [ASTBlock], data: &[u8]) {
+    for block in blocks {
+        match block {
+            ASTBlock::Paragraph(paragraph) => count_in_node(&mut paragraph.blank_lines, &paragraph.range, &paragraph.attributes, &mut paragraph.comments, data),
+            ASTBlock::CodeBlock(code) => count_in_node(&mut code.blank_lines, &code.range, &code.attributes, &mut code.comments, data),
+            ASTBlock::Divert(divert) => count_in_node(&mut divert.blank_lines, &divert.range, &divert.attributes, &mut divert.comments, data),
+            ASTBlock::Error(error) => count_in_node(&mut error.blank_lines, &error.range, &error.attributes, &mut error.comments, data),
+            ASTBlock::ChoiceGroup(group) => {
+                count_in_node(&mut group.blank_lines, &group.range, &group.attributes, &mut group.comments, data);
+                group.choices.iter_mut().for_each(|choice| count_in_choice(choice, data));
+            },
+            ASTBlock::Match(match_block) => {
+                count_in_node(&mut match_block.blank_lines, &match_block.range, &match_block.attributes, &mut match_block.comments, data);
+                match_block.choices.iter_mut().for_each(|choice| count_in_choice(choice, data));
+                count_blank_lines(&mut match_block.direct_children, data);
+            },
+            ASTBlock::Header(header) => {
+                count_in_node(&mut header.blank_lines, &header.range, &header.attributes, &mut header.comments, data);
+                count_in_comments(&mut header.trailing_comments, data);
+                count_blank_lines(&mut header.children, data);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn count_blank_lines_before() {
+        let data = b"# Start\n\n  \nText\n  // A comment\n\r\n- Choice";
+        assert_eq!(blank_lines_before(data, 0), 0);
+        assert_eq!(blank_lines_before(data, 12), 2);
+        assert_eq!(blank_lines_before(data, 19), 0);
+        assert_eq!(blank_lines_before(data, 2), 0);
+        assert_eq!(blank_lines_before(data, 34), 1);
+    }
+}
@@ -46,6 +46,14 @@ impl<T> VecStack<T> {
         self.data.push(data);
     }
 
+    /// Adds the items to the vector on the top of the stack.
+    pub fn extend_top(&mut self, data: Vec<T>) {
+        match self.data.last_mut() {
+            Some(top) => top.extend(data),
+            None => self.data.push(data)
+        }
+    }
+
     pub fn pop_vec(&mut self) -> Vec<T> {
         self.data.pop().unwrap_or_default()
     }
@@ -1,16 +1,19 @@
 use storytell_diagnostics::{diagnostic::Diagnostic, location::*};
-use crate::ast::utils::ExtendedOption;
+use crate::ast::{utils::ExtendedOption, model::ASTComment};
 
 pub struct ParsingContext {
     pub line_endings: usize,
-    pub diagnostics: Vec<Diagnostic>
+    pub diagnostics: Vec<Diagnostic>,
+    /// Every comment in the file, including the ones which aren't before any block.
+    pub comments: Vec<ASTComment>
 }
 
 impl ParsingContext {
     pub fn new(line_endings: usize) -> Self {
         Self { 
             line_endings,
-            diagnostics: vec![]
+            diagnostics: vec![],
+            comments: vec![]
         }
     }
 }
@@ -153,7 +156,7 @@ impl<'a> InputConsumer<'a> {
 
     pub fn get_pos_of(&mut self, pattern: &str) -> Option<usize> {
         let mut pos = self.pos;
-        while !self.is_eof() && (pos + pattern.len() < self.data.len()) {
+        while !self.is_eof() && (pos + pattern.len() <= self.data.len()) {
             if unsafe { std::str::from_utf8_unchecked(&self.data[pos..(pattern.len() + pos)]) }
                 == pattern
             {
@@ -257,7 +260,16 @@ mod tests {
         assert_eq!(input.consume(), Some('l'));
         assert_eq!(input.consume(), Some('o'));
         assert_eq!(input.consume(), None);
-        assert_eq!(input.is_eof(), true);
+        assert!(input.is_eof());
+    }
+
+    #[test]
+    fn get_pos_of_at_the_end() {
+        let mut input = InputConsumer::new("**bold**", ParsingContext::new(1));
+        input.skip_n(2);
+        assert_eq!(input.get_pos_of("**"), Some(6));
+        assert_eq!(input.get_pos_of("***"), None);
+        assert_eq!(input.pos, 2);
     }
 
     #[test]